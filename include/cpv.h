@@ -0,0 +1,98 @@
+#ifndef CPV_H
+#define CPV_H
+
+/* Generated by cbindgen from src/ffi.rs; do not edit. */
+
+#include <stdint.h>
+
+/**
+ * Every file was copied.
+ */
+#define CPV_OK 0
+
+/**
+ * The copy stopped at an error.
+ */
+#define CPV_FAILED 1
+
+/**
+ * Some files could not be copied with `keep_going` and the rest were.
+ */
+#define CPV_PARTIAL 2
+
+/**
+ * A copy did not match its source with `verify`.
+ */
+#define CPV_VERIFY_FAILED 3
+
+/**
+ * The progress callback asked to stop.
+ */
+#define CPV_INTERRUPTED 4
+
+/**
+ * A path was not valid, or the options could not be read.
+ */
+#define CPV_INVALID_ARGUMENT -1
+
+/**
+ * The statistics of a copy, filled in by `cpv_copy`.
+ */
+typedef struct CpvStats {
+  uint64_t bytes_copied;
+  uint64_t files_copied;
+  uint64_t files_skipped;
+  uint64_t files_failed;
+  uint64_t dirs_created;
+  uint64_t elapsed_ms;
+} CpvStats;
+
+/**
+ * Called with the bytes copied so far and the bytes to copy in total,
+ * from the threads doing the copy, and again as each file ends.
+ * Returning nonzero cancels the copy.
+ */
+typedef int (*CpvProgressFn)(uint64_t done, uint64_t total, void *user_data);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Copies `source` to `dest` with the options of the JSON object
+ * `options_json`, like `cpv::copy`, and returns one of the `CPV_` codes.
+ *
+ * `options_json` may be NULL for the defaults, `progress` NULL for no
+ * callback and `stats` NULL when the statistics are not wanted. On any
+ * code but `CPV_OK`, `cpv_last_error` says what went wrong.
+ *
+ * # Safety
+ *
+ * `source`, `dest` and `options_json` must be NULL or NUL-terminated
+ * strings, and `stats` NULL or valid for writes. `progress` is called
+ * from the threads doing the copy, with `user_data`, until `cpv_copy`
+ * returns.
+ */
+int cpv_copy(const char *source,
+             const char *dest,
+             const char *options_json,
+             CpvProgressFn progress,
+             void *user_data,
+             struct CpvStats *stats);
+
+/**
+ * The message of the last error `cpv_copy` reported on this thread, or
+ * NULL. It stays valid until the next call to `cpv_copy` on the thread.
+ */
+const char *cpv_last_error(void);
+
+/**
+ * The version of cpv, as a static string.
+ */
+const char *cpv_version(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* CPV_H */
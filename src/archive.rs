@@ -0,0 +1,312 @@
+//! Archives as sources: `ArchiveFs` reads the entries of a tar, gzipped
+//! tar or zip file as a tree, so that extracting one is a copy out of it
+//! with progress, hooks, conflict policies and verification.
+
+use crate::vfs::{FileInfo, Filesystem};
+use crate::{tar, zip};
+use flate2::read::MultiGzDecoder;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+use tempfile::NamedTempFile;
+
+/// Where the data of an entry is.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Data {
+    Dir,
+    /// `len` bytes at `offset` of a tar.
+    Tar {
+        offset: u64,
+    },
+    Zip(zip::Member),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Entry {
+    pub(crate) info: FileInfo,
+    pub(crate) data: Data,
+}
+
+impl Entry {
+    pub(crate) fn dir(modified: Option<SystemTime>, mode: Option<u32>) -> Self {
+        Self {
+            info: FileInfo {
+                is_dir: true,
+                len: 0,
+                modified,
+                mode,
+            },
+            data: Data::Dir,
+        }
+    }
+
+    pub(crate) fn file(
+        len: u64,
+        modified: Option<SystemTime>,
+        mode: Option<u32>,
+        data: Data,
+    ) -> Self {
+        Self {
+            info: FileInfo {
+                is_dir: false,
+                len,
+                modified,
+                mode,
+            },
+            data,
+        }
+    }
+}
+
+/// The entries of an archive as they are read, by path.
+#[derive(Default)]
+pub(crate) struct Index {
+    entries: HashMap<PathBuf, Entry>,
+    /// Links with their targets, and whether they are symbolic.
+    links: Vec<(PathBuf, Vec<u8>, bool)>,
+}
+
+impl Index {
+    /// Adds the entry `name`, replacing an earlier one of the same name.
+    /// Names that would leave the archive's root are left out.
+    pub(crate) fn add(&mut self, name: &[u8], entry: Entry) {
+        if let Some(path) = member_path(Path::new(""), name) {
+            self.entries.insert(path, entry);
+        }
+    }
+
+    /// Adds the link `name` to `target`, resolved once all entries are
+    /// read.
+    pub(crate) fn link(&mut self, name: &[u8], target: &[u8], symbolic: bool) {
+        if let Some(path) = member_path(Path::new(""), name) {
+            self.links.push((path, target.to_vec(), symbolic));
+        }
+    }
+
+    /// Resolves the links to the files they lead to, leaving out those to
+    /// directories or outside the archive, and adds the directories above
+    /// entries that have none, returning the entries with the children
+    /// of each directory.
+    fn finish(mut self) -> (HashMap<PathBuf, Entry>, HashMap<PathBuf, BTreeSet<PathBuf>>) {
+        let mut links = std::mem::take(&mut self.links);
+        while !links.is_empty() {
+            let before = links.len();
+            links.retain(|(path, target, symbolic)| {
+                let base = match symbolic {
+                    true if target.starts_with(b"/") => return false,
+                    true => path.parent().unwrap_or(Path::new("")),
+                    false => Path::new(""),
+                };
+                let Some(target) = member_path(base, target) else {
+                    return false;
+                };
+                match self.entries.get(&target) {
+                    Some(entry) if !entry.info.is_dir => {
+                        let entry = entry.clone();
+                        self.entries.insert(path.clone(), entry);
+                        false
+                    }
+                    Some(_) => false,
+                    None => true,
+                }
+            });
+            if links.len() == before {
+                break;
+            }
+        }
+
+        let mut children: HashMap<PathBuf, BTreeSet<PathBuf>> = HashMap::new();
+        let paths: Vec<_> = self.entries.keys().cloned().collect();
+        for path in paths {
+            let mut child = path.as_path();
+            while let Some(parent) = child.parent() {
+                children
+                    .entry(parent.to_path_buf())
+                    .or_default()
+                    .insert(child.to_path_buf());
+                if parent.as_os_str().is_empty() {
+                    break;
+                }
+                self.entries
+                    .entry(parent.to_path_buf())
+                    .or_insert_with(|| Entry::dir(None, None));
+                child = parent;
+            }
+        }
+        (self.entries, children)
+    }
+}
+
+/// The path of the member `name`, with `/` between components, under
+/// `base`, or `None` for the root or a name that leaves it.
+fn member_path(base: &Path, name: &[u8]) -> Option<PathBuf> {
+    let mut path = base.to_path_buf();
+    for part in name.split(|&byte| byte == b'/') {
+        match part {
+            b"" | b"." => {}
+            b".." => {
+                if !path.pop() {
+                    return None;
+                }
+            }
+            part => path.push(local_name(part)?),
+        }
+    }
+    (!path.as_os_str().is_empty()).then_some(path)
+}
+
+/// The component `name` of a member name, or `None` if it would be taken
+/// for more than one, or for a drive or root, on some platform.
+fn local_name(name: &[u8]) -> Option<PathBuf> {
+    if name.contains(&b'\\') || name.contains(&b':') {
+        return None;
+    }
+    #[cfg(unix)]
+    let name: PathBuf = {
+        use std::os::unix::ffi::OsStrExt;
+        std::ffi::OsStr::from_bytes(name).into()
+    };
+    #[cfg(not(unix))]
+    let name: PathBuf = String::from_utf8_lossy(name).into_owned().into();
+    let mut components = name.components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Some(name),
+        _ => None,
+    }
+}
+
+/// A tar, gzipped tar or zip archive, as a read-only source.
+///
+/// The archive's root is the empty path, a directory: copying it
+/// recursively extracts the archive into the destination. Entries keep
+/// the modification times and permission bits the archive records.
+/// Names that would leave the root are left out, as are those with a `\`
+/// or `:` in a component, devices and links to directories; links to
+/// files are copied as those files. A gzipped tar is decompressed to a
+/// temporary file first; zip entries must be stored or deflated, and are
+/// checked against their CRC-32.
+pub struct ArchiveFs {
+    /// The file the data of entries is in: the archive, or the tar of a
+    /// gzipped one.
+    file: PathBuf,
+    _decompressed: Option<NamedTempFile>,
+    entries: HashMap<PathBuf, Entry>,
+    children: HashMap<PathBuf, BTreeSet<PathBuf>>,
+}
+
+impl ArchiveFs {
+    /// Reads the list of entries of the archive `path`, whose format is
+    /// told by its first bytes.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut file = File::open(path)?;
+        let mut magic = Vec::new();
+        Read::by_ref(&mut file).take(4).read_to_end(&mut magic)?;
+        file.rewind()?;
+        let mut index = Index::default();
+        let mut decompressed = None;
+        let read = match magic.as_slice() {
+            [0x1f, 0x8b, ..] => {
+                let mut temp = NamedTempFile::new()?;
+                io::copy(&mut MultiGzDecoder::new(BufReader::new(file)), &mut temp)?;
+                temp.flush()?;
+                temp.rewind()?;
+                let read = tar::read_index(&mut BufReader::new(temp.as_file()), &mut index);
+                decompressed = Some(temp);
+                read
+            }
+            [b'P', b'K', 3, 4] | [b'P', b'K', 5, 6] => zip::read_index(&mut file, &mut index),
+            _ => tar::read_index(&mut BufReader::new(file), &mut index),
+        };
+        read.map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("cannot read archive '{}': {}", path.display(), err),
+            )
+        })?;
+        let (entries, children) = index.finish();
+        Ok(Self {
+            file: decompressed
+                .as_ref()
+                .map_or(path.to_path_buf(), |temp| temp.path().to_path_buf()),
+            _decompressed: decompressed,
+            entries,
+            children,
+        })
+    }
+
+    fn entry(&self, path: &Path) -> io::Result<&Entry> {
+        self.entries.get(path).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("'{}' is not in the archive", path.display()),
+            )
+        })
+    }
+}
+
+impl fmt::Debug for ArchiveFs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ArchiveFs")
+            .field("file", &self.file)
+            .field("entries", &self.entries.len())
+            .finish()
+    }
+}
+
+fn read_only() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "an archive being read cannot be written to",
+    )
+}
+
+impl Filesystem for ArchiveFs {
+    fn metadata(&self, path: &Path) -> io::Result<FileInfo> {
+        if path.as_os_str().is_empty() {
+            return Ok(Entry::dir(None, None).info);
+        }
+        Ok(self.entry(path)?.info.clone())
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        if !path.as_os_str().is_empty() && !self.entry(path)?.info.is_dir {
+            return Err(io::Error::other(format!(
+                "'{}' is not a directory",
+                path.display()
+            )));
+        }
+        Ok(self
+            .children
+            .get(path)
+            .map(|children| children.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        let entry = self.entry(path)?;
+        let mut file = File::open(&self.file)?;
+        match entry.data {
+            Data::Dir => Err(io::Error::other(format!(
+                "'{}' is a directory",
+                path.display()
+            ))),
+            Data::Tar { offset } => {
+                file.seek(SeekFrom::Start(offset))?;
+                Ok(Box::new(BufReader::new(file).take(entry.info.len)))
+            }
+            Data::Zip(member) => zip::open_entry(file, path, &member, entry.info.len),
+        }
+    }
+
+    fn create(&self, _path: &Path) -> io::Result<Box<dyn Write + Send>> {
+        Err(read_only())
+    }
+
+    fn create_dir_all(&self, _path: &Path) -> io::Result<()> {
+        Err(read_only())
+    }
+}
@@ -0,0 +1,42 @@
+//! The portable userspace read/write loop.
+
+use super::Backend;
+use crate::buffer::IoBuffer;
+use crate::progress::Progress;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::time::Instant;
+
+pub(crate) struct Buffered;
+
+impl Backend for Buffered {
+    fn copy(
+        &self,
+        source: &File,
+        dest: &File,
+        progress: &mut Progress<'_>,
+        buffer: &mut IoBuffer,
+    ) -> io::Result<Option<u64>> {
+        let mut copied = 0;
+        let (mut reader, mut writer) = (source, dest);
+
+        loop {
+            let started = Instant::now();
+            let n = match reader.read(buffer) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            writer.write_all(&buffer[..n])?;
+            copied += n as u64;
+            progress.inc_data(&buffer[..n])?;
+            if n == buffer.len() {
+                buffer.observe(started.elapsed());
+            }
+        }
+
+        Ok(Some(copied))
+    }
+}
@@ -0,0 +1,69 @@
+//! In-kernel copying with `copy_file_range(2)` on Linux.
+
+use super::Backend;
+use crate::buffer::IoBuffer;
+use crate::progress::Progress;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+
+/// Bytes requested per call; also the progress bar update granularity.
+const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Declines when the kernel or filesystem pair does not support
+/// `copy_file_range` (old kernels across filesystems, some FUSE and network
+/// filesystems). A first call that copies nothing is treated the same way,
+/// since pseudo-files under `/proc` and `/sys` report EOF to
+/// `copy_file_range` even though `read` returns data.
+pub(crate) struct CopyFileRange;
+
+impl Backend for CopyFileRange {
+    fn copy(
+        &self,
+        source: &File,
+        dest: &File,
+        progress: &mut Progress<'_>,
+        _buffer: &mut IoBuffer,
+    ) -> io::Result<Option<u64>> {
+        copy(source, dest, progress)
+    }
+}
+
+fn copy(source: &File, dest: &File, progress: &mut Progress<'_>) -> io::Result<Option<u64>> {
+    let mut copied = 0u64;
+
+    loop {
+        // SAFETY: both descriptors are open for the duration of the call and
+        // null offsets make the kernel use (and advance) the file offsets.
+        let n = unsafe {
+            libc::copy_file_range(
+                source.as_raw_fd(),
+                ptr::null_mut(),
+                dest.as_raw_fd(),
+                ptr::null_mut(),
+                CHUNK_SIZE,
+                0,
+            )
+        };
+
+        if n < 0 {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                Some(libc::EXDEV | libc::ENOSYS | libc::EOPNOTSUPP | libc::EINVAL)
+                    if copied == 0 =>
+                {
+                    return Ok(None)
+                }
+                _ => return Err(err),
+            }
+        }
+        if n == 0 {
+            return Ok((copied > 0).then_some(copied));
+        }
+
+        copied += n as u64;
+        progress.inc(n as u64)?;
+    }
+}
@@ -0,0 +1,57 @@
+//! Writing straight from a memory mapping of the source.
+
+use super::Backend;
+use crate::buffer::IoBuffer;
+use crate::progress::Progress;
+use memmap2::MmapOptions;
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+
+/// Bytes written per call; also the progress bar update granularity.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Avoids the copy into a userspace buffer that the buffered loop makes.
+/// Declines for empty sources and for files that cannot be mapped (pipes,
+/// pseudo-files reporting a zero length, some network filesystems).
+///
+/// A source truncated by another process while mapped faults on access, so
+/// this backend is only used when selected explicitly.
+pub(crate) struct Mmap;
+
+impl Backend for Mmap {
+    fn copy(
+        &self,
+        source: &File,
+        dest: &File,
+        progress: &mut Progress<'_>,
+        _buffer: &mut IoBuffer,
+    ) -> io::Result<Option<u64>> {
+        let start = { source }.stream_position()?;
+        let len = source.metadata()?.len().saturating_sub(start);
+        if len == 0 {
+            return Ok(None);
+        }
+
+        // SAFETY: the mapping is read-only and dropped before returning; a
+        // concurrent truncation is the documented hazard above.
+        let Ok(map) = (unsafe {
+            MmapOptions::new()
+                .offset(start)
+                .len(len as usize)
+                .map(source)
+        }) else {
+            return Ok(None);
+        };
+        #[cfg(unix)]
+        let _ = map.advise(memmap2::Advice::Sequential);
+
+        let mut dest = dest;
+        for chunk in map.chunks(CHUNK_SIZE) {
+            dest.write_all(chunk)?;
+            progress.inc_data(chunk)?;
+        }
+        // Leave the source offset where a read loop would have.
+        { source }.seek(SeekFrom::Start(start + len))?;
+        Ok(Some(len))
+    }
+}
@@ -0,0 +1,163 @@
+//! Strategies for copying the contents of one open file into another.
+//!
+//! Every backend either copies the whole remainder of the source or
+//! declines up front, so [`copy`] can walk a chain of backends and finish
+//! with the portable buffered loop, which always works.
+
+use crate::buffer::IoBuffer;
+use crate::progress::Progress;
+use crate::CopyOptions;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::str::FromStr;
+
+mod buffered;
+#[cfg(target_os = "linux")]
+mod copy_range;
+mod mmap;
+mod pipelined;
+#[cfg(target_os = "linux")]
+mod splice;
+#[cfg(target_os = "linux")]
+mod uring;
+
+/// Selects how file contents are copied.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CopyBackend {
+    /// In-kernel copying where available, the pipelined loop for large
+    /// files and the buffered loop otherwise.
+    #[default]
+    Auto,
+    /// Userspace read/write loop.
+    Buffered,
+    /// `copy_file_range(2)` (Linux).
+    CopyFileRange,
+    /// `sendfile(2)`, or `splice(2)` through a pipe (Linux).
+    Sendfile,
+    /// Batched reads and writes through `io_uring` (Linux).
+    IoUring,
+    /// Writes from a memory mapping of the source.
+    Mmap,
+    /// Reads and writes overlapped on two threads with two buffers.
+    Pipelined,
+}
+
+impl CopyBackend {
+    /// The names of the backends, as `from_str` takes them and `Display`
+    /// writes them.
+    pub const NAMES: &[&str] = &[
+        "auto",
+        "buffered",
+        "copy-file-range",
+        "sendfile",
+        "io-uring",
+        "mmap",
+        "pipelined",
+    ];
+
+    /// The backends to try, in order, for this selection. `Auto` keeps to
+    /// loops that see the data when it is being hashed.
+    fn chain(self, needs_data: bool) -> Vec<&'static dyn Backend> {
+        #[cfg(target_os = "linux")]
+        match self {
+            Self::Auto if needs_data => vec![&pipelined::Pipelined, &buffered::Buffered],
+            Self::Auto => vec![
+                &copy_range::CopyFileRange,
+                &splice::Sendfile,
+                &pipelined::Pipelined,
+                &buffered::Buffered,
+            ],
+            Self::CopyFileRange => vec![
+                &copy_range::CopyFileRange,
+                &splice::Sendfile,
+                &buffered::Buffered,
+            ],
+            Self::Sendfile => vec![&splice::Sendfile, &buffered::Buffered],
+            Self::IoUring => vec![&uring::IoUring, &buffered::Buffered],
+            Self::Mmap => vec![&mmap::Mmap, &buffered::Buffered],
+            Self::Pipelined => vec![&pipelined::Pipelined, &buffered::Buffered],
+            Self::Buffered => vec![&buffered::Buffered],
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = needs_data;
+        #[cfg(not(target_os = "linux"))]
+        match self {
+            // WASI has no threads to overlap reads and writes on.
+            Self::Auto | Self::Pipelined if cfg!(target_os = "wasi") => vec![&buffered::Buffered],
+            Self::Auto | Self::Pipelined => vec![&pipelined::Pipelined, &buffered::Buffered],
+            Self::Mmap => vec![&mmap::Mmap, &buffered::Buffered],
+            _ => vec![&buffered::Buffered],
+        }
+    }
+}
+
+impl FromStr for CopyBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "buffered" => Ok(Self::Buffered),
+            "copy-file-range" => Ok(Self::CopyFileRange),
+            "sendfile" => Ok(Self::Sendfile),
+            "io-uring" => Ok(Self::IoUring),
+            "mmap" => Ok(Self::Mmap),
+            "pipelined" => Ok(Self::Pipelined),
+            _ => Err(format!(
+                "invalid backend '{}' (expected auto, buffered, copy-file-range, sendfile, io-uring, mmap or pipelined)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for CopyBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Auto => "auto",
+            Self::Buffered => "buffered",
+            Self::CopyFileRange => "copy-file-range",
+            Self::Sendfile => "sendfile",
+            Self::IoUring => "io-uring",
+            Self::Mmap => "mmap",
+            Self::Pipelined => "pipelined",
+        })
+    }
+}
+
+/// A way of copying file contents.
+pub(crate) trait Backend: Sync {
+    /// Copies from the current offset of `source` to EOF into `dest`,
+    /// advancing `progress` as data is written.
+    ///
+    /// Returns `Ok(None)`, having written nothing, when the backend is not
+    /// usable for this pair of files.
+    fn copy(
+        &self,
+        source: &File,
+        dest: &File,
+        progress: &mut Progress<'_>,
+        buffer: &mut IoBuffer,
+    ) -> io::Result<Option<u64>>;
+}
+
+/// Copies `source` into `dest` with the first backend of `options.backend`
+/// that accepts the pair.
+pub(crate) fn copy(
+    source: &File,
+    dest: &File,
+    progress: &mut Progress<'_>,
+    buffer: &mut IoBuffer,
+    options: &CopyOptions,
+) -> io::Result<u64> {
+    if options.drop_cache {
+        progress.drop_cache(source, dest);
+    }
+    for backend in options.backend.chain(progress.wants_data()) {
+        if let Some(copied) = backend.copy(source, dest, progress, buffer)? {
+            return Ok(copied);
+        }
+    }
+    unreachable!("the buffered backend accepts every file")
+}
@@ -0,0 +1,85 @@
+//! Double-buffered copying with reads and writes on separate threads.
+
+use super::Backend;
+use crate::buffer::IoBuffer;
+use crate::progress::Progress;
+use std::fs::File;
+use std::io::{self, Read, Seek, Write};
+use std::sync::mpsc;
+use std::thread;
+
+/// Sources with less than this left are not worth a second thread.
+const MIN_LEN: u64 = 1024 * 1024;
+
+/// A reader thread fills one buffer while the calling thread writes the
+/// other, so a copy between two devices runs at the speed of the slower one
+/// rather than the sum of both. Declines for small files, and when a cap on
+/// memory leaves no room for the two buffers.
+pub(crate) struct Pipelined;
+
+impl Backend for Pipelined {
+    fn copy(
+        &self,
+        source: &File,
+        dest: &File,
+        progress: &mut Progress<'_>,
+        buffer: &mut IoBuffer,
+    ) -> io::Result<Option<u64>> {
+        let position = { source }.stream_position()?;
+        if source.metadata()?.len().saturating_sub(position) < MIN_LEN {
+            return Ok(None);
+        }
+
+        let size = buffer.limit();
+        // The two buffers count against a cap on memory; without room for
+        // them the copy goes on in one thread.
+        let Some(_reserved) = buffer.reserve(2 * size) else {
+            return Ok(None);
+        };
+        let (full_tx, full_rx) = mpsc::sync_channel::<(Vec<u8>, usize)>(1);
+        let (empty_tx, empty_rx) = mpsc::channel::<Vec<u8>>();
+        for _ in 0..2 {
+            empty_tx.send(vec![0; size]).expect("receiver is alive");
+        }
+
+        thread::scope(|scope| {
+            let reader = scope.spawn(move || -> io::Result<()> {
+                let mut source = source;
+                // Ends when the writer hangs up or the source is exhausted;
+                // dropping `full_tx` then ends the writer's loop.
+                while let Ok(mut chunk) = empty_rx.recv() {
+                    let n = loop {
+                        match source.read(&mut chunk) {
+                            Ok(n) => break n,
+                            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                            Err(e) => return Err(e),
+                        }
+                    };
+                    if n == 0 || full_tx.send((chunk, n)).is_err() {
+                        break;
+                    }
+                }
+                Ok(())
+            });
+
+            let mut copied = 0u64;
+            let mut write = || -> io::Result<()> {
+                let mut dest = dest;
+                for (chunk, n) in full_rx.iter() {
+                    dest.write_all(&chunk[..n])?;
+                    copied += n as u64;
+                    progress.inc_data(&chunk[..n])?;
+                    let _ = empty_tx.send(chunk);
+                }
+                Ok(())
+            };
+            let written = write();
+            // Unblocks the reader if the writer stopped early.
+            drop(full_rx);
+            drop(empty_tx);
+
+            reader.join().expect("reader thread panicked")?;
+            written.map(|()| Some(copied))
+        })
+    }
+}
@@ -0,0 +1,140 @@
+//! In-kernel copying with `sendfile(2)`, or `splice(2)` through a pipe, on
+//! Linux.
+
+use super::Backend;
+use crate::buffer::IoBuffer;
+use crate::progress::Progress;
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+
+/// Bytes requested per call; also the progress bar update granularity.
+const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Bytes moved through the pipe per `splice` round; the default pipe
+/// capacity.
+const PIPE_SIZE: usize = 64 * 1024;
+
+/// Keeps the copy in the kernel where `copy_file_range` is refused (older
+/// kernels, some FUSE filesystems). `sendfile` is tried first and `splice`
+/// through a pipe second; like `copy_file_range`, a first call reporting
+/// EOF declines, since pseudo-files report EOF to both.
+pub(crate) struct Sendfile;
+
+impl Backend for Sendfile {
+    fn copy(
+        &self,
+        source: &File,
+        dest: &File,
+        progress: &mut Progress<'_>,
+        _buffer: &mut IoBuffer,
+    ) -> io::Result<Option<u64>> {
+        match sendfile(source, dest, progress)? {
+            Some(copied) => Ok(Some(copied)),
+            None => splice(source, dest, progress),
+        }
+    }
+}
+
+fn declines(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::EINVAL | libc::ENOSYS | libc::EOPNOTSUPP | libc::EXDEV)
+    )
+}
+
+fn sendfile(source: &File, dest: &File, progress: &mut Progress<'_>) -> io::Result<Option<u64>> {
+    let mut copied = 0u64;
+
+    loop {
+        // SAFETY: both descriptors are open for the duration of the call and
+        // a null offset makes the kernel use (and advance) the source offset.
+        let n = unsafe {
+            libc::sendfile(
+                dest.as_raw_fd(),
+                source.as_raw_fd(),
+                ptr::null_mut(),
+                CHUNK_SIZE,
+            )
+        };
+
+        if n < 0 {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                _ if copied == 0 && declines(&err) => return Ok(None),
+                _ => return Err(err),
+            }
+        }
+        if n == 0 {
+            return Ok((copied > 0).then_some(copied));
+        }
+
+        copied += n as u64;
+        progress.inc(n as u64)?;
+    }
+}
+
+fn splice(source: &File, dest: &File, progress: &mut Progress<'_>) -> io::Result<Option<u64>> {
+    let mut fds = [0; 2];
+    // SAFETY: `fds` has room for the two descriptors pipe2 returns.
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Ok(None);
+    }
+    // SAFETY: pipe2 succeeded, so both descriptors are open and ours.
+    let (pipe_read, pipe_write) =
+        unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+    let mut copied = 0u64;
+
+    loop {
+        let n = match splice_once(source.as_raw_fd(), pipe_write.as_raw_fd(), PIPE_SIZE) {
+            Ok(0) => return Ok((copied > 0).then_some(copied)),
+            Ok(n) => n,
+            Err(err) if copied == 0 && declines(&err) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let mut pending = n;
+        while pending > 0 {
+            match splice_once(pipe_read.as_raw_fd(), dest.as_raw_fd(), pending) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(m) => pending -= m,
+                Err(err) if copied == 0 && pending == n && declines(&err) => {
+                    // Nothing reached the destination; hand the data back
+                    // to the next backend by rewinding the source.
+                    { source }.seek(SeekFrom::Current(-(n as i64)))?;
+                    return Ok(None);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        copied += n as u64;
+        progress.inc(n as u64)?;
+    }
+}
+
+fn splice_once(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    loop {
+        // SAFETY: both descriptors are open and null offsets use (and
+        // advance) the file offsets.
+        let n = unsafe {
+            libc::splice(
+                from,
+                ptr::null_mut(),
+                to,
+                ptr::null_mut(),
+                len,
+                libc::SPLICE_F_MOVE,
+            )
+        };
+        if n >= 0 {
+            return Ok(n as usize);
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EINTR) {
+            return Err(err);
+        }
+    }
+}
@@ -0,0 +1,212 @@
+//! The `cpv bench` comparison of copy strategies.
+
+use crate::{copy_with_progress, CopyBackend, CopyError, CopyOptions, Parallelism, ReflinkMode};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How the payload size is spread over its files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Distribution {
+    /// Every file has the same size.
+    #[default]
+    Uniform,
+    /// Nine in ten files share a tenth of the payload, like a source tree
+    /// with a few large assets.
+    Mixed,
+}
+
+impl Distribution {
+    /// The names of the distributions, as `from_str` takes them and `Display`
+    /// writes them.
+    pub const NAMES: &[&str] = &["uniform", "mixed"];
+}
+
+impl FromStr for Distribution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "uniform" => Ok(Self::Uniform),
+            "mixed" => Ok(Self::Mixed),
+            _ => Err(format!(
+                "invalid distribution '{}' (expected uniform or mixed)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Distribution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Uniform => "uniform",
+            Self::Mixed => "mixed",
+        })
+    }
+}
+
+/// The payload copied by every benchmark run.
+#[derive(Debug, Clone, Copy)]
+pub struct BenchConfig {
+    /// Total payload size in bytes.
+    pub size: u64,
+    /// Number of files the payload is split into.
+    pub files: usize,
+    pub distribution: Distribution,
+}
+
+impl BenchConfig {
+    /// The size of each payload file.
+    pub(crate) fn file_sizes(&self) -> Vec<u64> {
+        let files = self.files.max(1) as u64;
+        let small = match self.distribution {
+            Distribution::Uniform => 0,
+            Distribution::Mixed => files * 9 / 10,
+        };
+        let large = files - small;
+        let small_share = if small > 0 { self.size / 10 } else { 0 };
+        (0..files)
+            .map(|i| {
+                if i < small {
+                    small_share / small
+                } else {
+                    (self.size - small_share) / large
+                }
+            })
+            .collect()
+    }
+}
+
+/// One strategy's result.
+#[derive(Debug)]
+pub struct BenchResult {
+    pub name: String,
+    /// Wall-clock time of the copy, or why the strategy could not run.
+    pub outcome: Result<Duration, String>,
+}
+
+/// Generates the payload in a scratch directory under `target` and copies
+/// it once with each strategy available on this platform, returning the
+/// timings in run order. The scratch directory is removed afterwards.
+pub fn run(target: &Path, config: &BenchConfig) -> Result<Vec<BenchResult>, CopyError> {
+    let scratch = tempfile::Builder::new()
+        .prefix(".cpv-bench")
+        .tempdir_in(target)?;
+    let source = scratch.path().join("payload");
+    generate_payload(&source, config)?;
+
+    let base = CopyOptions {
+        recursive: true,
+        reflink: ReflinkMode::Never,
+        parallelism: Parallelism::Disabled,
+        ..Default::default()
+    };
+    let mut strategies = vec![(
+        "buffered",
+        CopyOptions {
+            backend: CopyBackend::Buffered,
+            ..base.clone()
+        },
+    )];
+    #[cfg(target_os = "linux")]
+    for (name, backend) in [
+        ("copy_file_range", CopyBackend::CopyFileRange),
+        ("sendfile", CopyBackend::Sendfile),
+        ("io_uring", CopyBackend::IoUring),
+    ] {
+        strategies.push((
+            name,
+            CopyOptions {
+                backend,
+                ..base.clone()
+            },
+        ));
+    }
+    strategies.extend([
+        (
+            "mmap",
+            CopyOptions {
+                backend: CopyBackend::Mmap,
+                ..base.clone()
+            },
+        ),
+        (
+            "pipelined",
+            CopyOptions {
+                backend: CopyBackend::Pipelined,
+                ..base.clone()
+            },
+        ),
+        (
+            "reflink",
+            CopyOptions {
+                reflink: ReflinkMode::Always,
+                ..base.clone()
+            },
+        ),
+    ]);
+    let threads = std::thread::available_parallelism().map_or(4, |n| n.get());
+    if config.files > 1 {
+        strategies.push((
+            "parallel",
+            CopyOptions {
+                parallelism: Parallelism::Exact(threads),
+                ..base.clone()
+            },
+        ));
+    } else {
+        strategies.push((
+            "chunked",
+            CopyOptions {
+                chunked_threads: threads,
+                ..base.clone()
+            },
+        ));
+    }
+
+    let mut results = Vec::new();
+    for (i, (name, options)) in strategies.iter().enumerate() {
+        let dest = scratch.path().join(format!("copy-{}", i));
+        let outcome = match copy_with_progress(&source, &dest, options) {
+            Ok(stats) => Ok(stats.time_taken),
+            Err(err) => Err(err.to_string()),
+        };
+        let _ = fs::remove_dir_all(&dest);
+        results.push(BenchResult {
+            name: name.to_string(),
+            outcome,
+        });
+    }
+    Ok(results)
+}
+
+/// Writes the payload files into `dir` with incompressible, non-zero
+/// content so that no strategy can shortcut the data.
+fn generate_payload(dir: &Path, config: &BenchConfig) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let mut state = 0x9e37_79b9_7f4a_7c15u64;
+    let mut block = vec![0u8; 64 * 1024];
+
+    for (i, size) in config.file_sizes().into_iter().enumerate() {
+        let mut file = BufWriter::new(File::create(dir.join(format!("{:06}.bin", i)))?);
+        let mut remaining = size;
+        while remaining > 0 {
+            for chunk in block.chunks_mut(8) {
+                // xorshift64
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                chunk.copy_from_slice(&state.to_le_bytes()[..chunk.len()]);
+            }
+            let n = remaining.min(block.len() as u64) as usize;
+            file.write_all(&block[..n])?;
+            remaining -= n as u64;
+        }
+        file.flush()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,206 @@
+//! Sizing of the userspace copy buffer, and the cap on the memory the
+//! buffers of a copy take together.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// Size a buffer starts at for files larger than it.
+pub(crate) const INITIAL_SIZE: usize = 128 * 1024;
+
+/// Size an adaptive buffer never grows beyond.
+pub(crate) const MAX_SIZE: usize = 8 * 1024 * 1024;
+
+/// A read and write round finishing faster than this grows the buffer, so
+/// fast devices get large transfers while slow ones still advance the
+/// progress bar several times per second.
+pub(crate) const GROW_BELOW: Duration = Duration::from_millis(50);
+
+/// Largest buffer accepted by `--buffer-size`.
+const MAX_EXPLICIT_SIZE: usize = 1024 * 1024 * 1024;
+
+/// Parses a `--buffer-size` value such as `4M`, between one byte and 1 GiB.
+pub fn parse_buffer_size(s: &str) -> Result<usize, String> {
+    let size = crate::parse_size(s)?;
+    match usize::try_from(size) {
+        Ok(size) if (1..=MAX_EXPLICIT_SIZE).contains(&size) => Ok(size),
+        _ => Err(format!("buffer size '{}' must be between 1 byte and 1G", s)),
+    }
+}
+
+/// The buffer copy loops read into, growing while rounds stay fast.
+pub(crate) struct IoBuffer {
+    data: Vec<u8>,
+    limit: usize,
+    /// The bytes of `data` under a cap on memory, if there is one.
+    lease: Option<Lease>,
+}
+
+impl IoBuffer {
+    /// A buffer of `size` bytes if given, else an adaptive one for a file
+    /// of `len` bytes, leasing its memory from `memory` if the copy is
+    /// capped: it then waits for room, never outgrows the cap, and only
+    /// grows while there is room to spare.
+    pub(crate) fn new(len: u64, size: Option<usize>, memory: Option<&Arc<Memory>>) -> Self {
+        let mut buffer = match size {
+            Some(size) => Self::fixed(size),
+            None => Self::for_file(len),
+        };
+        if let Some(memory) = memory {
+            let lease = memory.lease(buffer.data.len());
+            buffer.data.truncate(lease.bytes);
+            buffer.limit = buffer.limit.min(memory.cap);
+            buffer.lease = Some(lease);
+        }
+        buffer
+    }
+
+    /// An adaptive buffer for a file of `len` bytes, starting at
+    /// [`INITIAL_SIZE`] and growing up to the file size or [`MAX_SIZE`],
+    /// whichever is smaller.
+    pub(crate) fn for_file(len: u64) -> Self {
+        let len = usize::try_from(len).unwrap_or(usize::MAX);
+        Self {
+            data: vec![0; INITIAL_SIZE],
+            limit: len.clamp(INITIAL_SIZE, MAX_SIZE),
+            lease: None,
+        }
+    }
+
+    /// A buffer that stays at `size` bytes.
+    pub(crate) fn fixed(size: usize) -> Self {
+        Self {
+            data: vec![0; size],
+            limit: size,
+            lease: None,
+        }
+    }
+
+    /// The size this buffer may grow to.
+    pub(crate) fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Leases `bytes` more for buffers of the same copy, such as those of
+    /// a second thread. Returns `None` if the copy is capped and they are
+    /// not free now.
+    pub(crate) fn reserve(&self, bytes: usize) -> Option<Lease> {
+        match &self.lease {
+            Some(lease) => lease.memory.as_ref()?.try_lease(bytes),
+            None => Some(Lease {
+                memory: None,
+                bytes,
+            }),
+        }
+    }
+
+    /// Records that a round over the whole buffer took `elapsed`, doubling
+    /// the buffer if it was quick and may still grow.
+    pub(crate) fn observe(&mut self, elapsed: Duration) {
+        if elapsed < GROW_BELOW && self.data.len() < self.limit {
+            let size = (self.data.len() * 2).min(self.limit);
+            if let Some(lease) = &mut self.lease {
+                if !lease.grow(size - self.data.len()) {
+                    return;
+                }
+            }
+            self.data.resize(size, 0);
+        }
+    }
+}
+
+impl Deref for IoBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl DerefMut for IoBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+}
+
+/// A cap on the memory the copy buffers of all workers take together, for
+/// `CopyOptions::max_memory`. Buffers lease their bytes from it, waiting
+/// while the cap is reached, and give them back when dropped.
+#[derive(Debug)]
+pub(crate) struct Memory {
+    cap: usize,
+    used: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl Memory {
+    pub(crate) fn new(cap: u64) -> Arc<Self> {
+        Arc::new(Self {
+            cap: usize::try_from(cap).unwrap_or(usize::MAX).max(1),
+            used: Mutex::new(0),
+            freed: Condvar::new(),
+        })
+    }
+
+    /// Waits until `bytes` are free, or the whole cap for more than it,
+    /// and leases them.
+    fn lease(self: &Arc<Self>, bytes: usize) -> Lease {
+        let bytes = bytes.min(self.cap);
+        let mut used = self.used.lock().unwrap_or_else(|err| err.into_inner());
+        while *used + bytes > self.cap {
+            used = self.freed.wait(used).unwrap_or_else(|err| err.into_inner());
+        }
+        *used += bytes;
+        Lease {
+            memory: Some(Arc::clone(self)),
+            bytes,
+        }
+    }
+
+    /// Leases `bytes` if they are free now.
+    fn try_lease(self: &Arc<Self>, bytes: usize) -> Option<Lease> {
+        let mut used = self.used.lock().unwrap_or_else(|err| err.into_inner());
+        if *used + bytes > self.cap {
+            return None;
+        }
+        *used += bytes;
+        Some(Lease {
+            memory: Some(Arc::clone(self)),
+            bytes,
+        })
+    }
+}
+
+/// Bytes leased from a [`Memory`], given back when dropped; without a cap,
+/// a lease of nothing.
+#[derive(Debug)]
+pub(crate) struct Lease {
+    memory: Option<Arc<Memory>>,
+    bytes: usize,
+}
+
+impl Lease {
+    /// Leases `extra` more bytes if they are free now. Returns whether they
+    /// were.
+    fn grow(&mut self, extra: usize) -> bool {
+        let Some(memory) = &self.memory else {
+            return true;
+        };
+        let mut used = memory.used.lock().unwrap_or_else(|err| err.into_inner());
+        if *used + extra > memory.cap {
+            return false;
+        }
+        *used += extra;
+        self.bytes += extra;
+        true
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        if let Some(memory) = &self.memory {
+            *memory.used.lock().unwrap_or_else(|err| err.into_inner()) -= self.bytes;
+            memory.freed.notify_all();
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! `CopyOptions::builder`, which sets options one by one on top of the
+//! defaults, so that adding an option does not break callers.
+
+use crate::{
+    ByteRange, CancellationToken, ChangePolicy, Checkpoint, ChecksumAlgo, ChecksumCache,
+    ChmodRules, Chown, CollisionPolicy, Compression, CopyBackend, CopyHooks, CopyOptions, Dedupe,
+    ErrorRules, FileOrder, Filesystem, IdMap, JsonEvents, LinkMode, LiveStats, LogFile,
+    NameNormalization, OverwritePolicy, Parallelism, Porcelain, ProgressMode, ProgressReporter,
+    ProgressThrottle, RateLimiter, ReflinkMode, SparseMode, SyncMode, Theme, ThroughputHistory,
+    TimeWindow, UndoLog, Volumes,
+};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// Builds `CopyOptions`, starting from `CopyOptions::default()`. Each
+/// method sets the field of the same name.
+#[derive(Debug, Clone, Default)]
+pub struct CopyOptionsBuilder {
+    options: CopyOptions,
+}
+
+impl CopyOptionsBuilder {
+    pub fn build(self) -> CopyOptions {
+        self.options
+    }
+}
+
+macro_rules! setters {
+    ($($field:ident: $ty:ty,)* ; $($optional:ident: $inner:ty,)*) => {
+        impl CopyOptionsBuilder {
+            $(
+                #[doc = concat!("Sets `CopyOptions::", stringify!($field), "`.")]
+                pub fn $field(mut self, $field: $ty) -> Self {
+                    self.options.$field = $field;
+                    self
+                }
+            )*
+            $(
+                #[doc = concat!("Sets `CopyOptions::", stringify!($optional), "` to a value or `None`.")]
+                pub fn $optional(mut self, $optional: impl Into<Option<$inner>>) -> Self {
+                    self.options.$optional = $optional.into();
+                    self
+                }
+            )*
+        }
+    };
+}
+
+setters! {
+    preserve_attrs: bool,
+    verbose: bool,
+    recursive: bool,
+    normalization: NameNormalization,
+    case_collisions: CollisionPolicy,
+    fat_compat: bool,
+    usermap: IdMap,
+    groupmap: IdMap,
+    sparse: SparseMode,
+    preserve_flags: bool,
+    vss: bool,
+    reflink: ReflinkMode,
+    share_extents: bool,
+    parallelism: Parallelism,
+    per_device: bool,
+    chunked_threads: usize,
+    backend: CopyBackend,
+    direct_io: bool,
+    no_preallocate: bool,
+    drop_cache: bool,
+    order: FileOrder,
+    auto_tune: bool,
+    adaptive_throttle: bool,
+    sync: SyncMode,
+    verify: bool,
+    checksum: ChecksumAlgo,
+    record_checksums: bool,
+    resume: bool,
+    partial: bool,
+    wait_for_space: bool,
+    take_turns: bool,
+    retries: u32,
+    repairs: u32,
+    retry_delay: Duration,
+    keep_going: bool,
+    on_error: ErrorRules,
+    source_changes: ChangePolicy,
+    delta: bool,
+    link: LinkMode,
+    relative_links: bool,
+    overwrite: OverwritePolicy,
+    mirror: bool,
+    delete: bool,
+    join: bool,
+    exclude: Vec<String>,
+    deselected: Vec<PathBuf>,
+    concat: Vec<PathBuf>,
+    record_files: bool,
+    progress: ProgressMode,
+    progress_throttle: ProgressThrottle,
+    theme: Theme,
+    status_signals: bool,
+    terminal_title: bool,
+    systemd_notify: bool,
+    desktop_progress: bool,
+    progress_threshold: u64,
+    ;
+    chmod: ChmodRules,
+    chown: Chown,
+    buffer_size: usize,
+    max_memory: u64,
+    rate_limiter: Arc<dyn RateLimiter>,
+    start_at: SystemTime,
+    pause_between: TimeWindow,
+    verify_sample: f64,
+    follow: Duration,
+    checkpoint: Checkpoint,
+    manifest: PathBuf,
+    link_dest: PathBuf,
+    dedupe: Dedupe,
+    checksum_cache: Arc<ChecksumCache>,
+    throughput_history: Arc<ThroughputHistory>,
+    progress_json: Arc<JsonEvents>,
+    progress_template: String,
+    progress_chars: String,
+    log_file: Arc<LogFile>,
+    undo_log: Arc<UndoLog>,
+    span: Arc<Volumes>,
+    porcelain: Arc<Porcelain>,
+    reporter: Arc<dyn ProgressReporter>,
+    live_stats: Arc<LiveStats>,
+    cancel: CancellationToken,
+    hooks: Arc<dyn CopyHooks>,
+    compress: Compression,
+    split: u64,
+    range: ByteRange,
+    source_fs: Arc<dyn Filesystem>,
+    dest_fs: Arc<dyn Filesystem>,
+}
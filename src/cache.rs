@@ -0,0 +1,117 @@
+//! Keeping large copies out of the page cache for `--drop-cache`.
+//!
+//! On Linux the source is read with `POSIX_FADV_SEQUENTIAL` and every
+//! `WINDOW` bytes the range just copied is written back and dropped from
+//! the cache on both sides, so a copy holds at most a few windows of cache
+//! instead of pushing out the working set of every other process. macOS has
+//! no ranged equivalent and disables caching on both files instead. All of
+//! this is advisory; failures are ignored.
+
+use std::fs::File;
+
+/// Bytes copied between write-back and eviction of the preceding range.
+#[cfg(target_os = "linux")]
+const WINDOW: u64 = 8 * 1024 * 1024;
+
+#[cfg(target_os = "linux")]
+pub(crate) struct CacheDropper {
+    source: std::os::unix::io::RawFd,
+    dest: std::os::unix::io::RawFd,
+    source_start: u64,
+    dest_start: u64,
+    dropped: u64,
+    copied: u64,
+}
+
+#[cfg(target_os = "linux")]
+impl CacheDropper {
+    pub(crate) fn new(source: &File, dest: &File) -> Self {
+        use std::io::Seek;
+        use std::os::unix::io::AsRawFd;
+
+        // SAFETY: advice only; the descriptor stays open.
+        unsafe { libc::posix_fadvise(source.as_raw_fd(), 0, 0, libc::POSIX_FADV_SEQUENTIAL) };
+        Self {
+            source: source.as_raw_fd(),
+            dest: dest.as_raw_fd(),
+            source_start: { source }.stream_position().unwrap_or(0),
+            dest_start: { dest }.stream_position().unwrap_or(0),
+            dropped: 0,
+            copied: 0,
+        }
+    }
+
+    /// Records `n` more bytes copied, evicting once a window is complete.
+    pub(crate) fn advance(&mut self, n: u64) {
+        self.copied += n;
+        if self.copied - self.dropped >= WINDOW {
+            self.drop_copied();
+        }
+    }
+
+    fn drop_copied(&mut self) {
+        let offset = self.dropped as libc::off_t;
+        let len = (self.copied - self.dropped) as libc::off_t;
+        let dest_offset = self.dest_start as libc::off_t + offset;
+        // SAFETY: both descriptors outlive the dropper, which lives only for
+        // the duration of one copy.
+        unsafe {
+            // Dirty pages cannot be dropped, so write the range back first.
+            libc::sync_file_range(
+                self.dest,
+                dest_offset,
+                len,
+                libc::SYNC_FILE_RANGE_WAIT_BEFORE
+                    | libc::SYNC_FILE_RANGE_WRITE
+                    | libc::SYNC_FILE_RANGE_WAIT_AFTER,
+            );
+            libc::posix_fadvise(self.dest, dest_offset, len, libc::POSIX_FADV_DONTNEED);
+            libc::posix_fadvise(
+                self.source,
+                self.source_start as libc::off_t + offset,
+                len,
+                libc::POSIX_FADV_DONTNEED,
+            );
+        }
+        self.dropped = self.copied;
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for CacheDropper {
+    fn drop(&mut self) {
+        if self.copied > self.dropped {
+            self.drop_copied();
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub(crate) struct CacheDropper;
+
+#[cfg(target_os = "macos")]
+impl CacheDropper {
+    pub(crate) fn new(source: &File, dest: &File) -> Self {
+        use std::os::unix::io::AsRawFd;
+
+        for file in [source, dest] {
+            // SAFETY: F_NOCACHE only toggles caching on an open descriptor.
+            unsafe { libc::fcntl(file.as_raw_fd(), libc::F_NOCACHE, 1) };
+        }
+        Self
+    }
+
+    pub(crate) fn advance(&mut self, _n: u64) {}
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub(crate) struct CacheDropper;
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+impl CacheDropper {
+    pub(crate) fn new(_source: &File, _dest: &File) -> Self {
+        Self
+    }
+
+    pub(crate) fn advance(&mut self, _n: u64) {}
+}
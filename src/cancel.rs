@@ -0,0 +1,72 @@
+//! Stopping a running copy from another thread, for applications that
+//! embed cpv and need a way to cancel without killing the process.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// A flag shared between a copy and whoever may cancel it. Clones share
+/// the same flag.
+///
+/// The copy checks it before each file and after each chunk of data it
+/// writes, then returns `CopyError::Cancelled` with the statistics so far.
+/// The file being copied is removed, or with `resume` or `partial` left
+/// partly written under its `.part` name, where they pick it up on the
+/// next run.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Asks the copies using this token to stop.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Cancels this token when the process receives SIGINT, as on Ctrl-C,
+    /// or SIGTERM, instead of being killed by it; once cancelled, another
+    /// one kills it. Only the first token of the process is cancelled so.
+    #[cfg(unix)]
+    pub fn cancel_on_interrupt(&self) -> io::Result<()> {
+        crate::signal::cancel_on_interrupt(self)
+    }
+
+    /// Fails with the error of a cancelled copy if this token was
+    /// cancelled.
+    pub(crate) fn check(&self) -> io::Result<()> {
+        if self.is_cancelled() {
+            Err(io::Error::other(Cancelled))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// The error that stops the copy loops once the copy is cancelled, turned
+/// into `CopyError::Cancelled` at the top.
+#[derive(Debug)]
+pub(crate) struct Cancelled;
+
+impl Cancelled {
+    /// Whether `err` reports a cancelled copy.
+    pub(crate) fn is(err: &io::Error) -> bool {
+        err.get_ref().is_some_and(|inner| inner.is::<Self>())
+    }
+}
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("copy cancelled")
+    }
+}
+
+impl Error for Cancelled {}
@@ -0,0 +1,93 @@
+//! Detection of sources modified while they are being copied.
+
+use std::error::Error;
+use std::fmt;
+use std::fs::{File, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::SystemTime;
+
+/// What happens when a source file changes while it is copied, as an
+/// actively written log file does.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChangePolicy {
+    /// Keep the copy and report a warning.
+    #[default]
+    Warn,
+    /// Copy the file again, up to `retries` times (at least once), and fail
+    /// if it keeps changing.
+    Retry,
+    /// Fail the file.
+    Fail,
+}
+
+impl ChangePolicy {
+    /// The names of the policies, as `from_str` takes them and `Display`
+    /// writes them.
+    pub const NAMES: &[&str] = &["warn", "retry", "fail"];
+}
+
+impl FromStr for ChangePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "warn" => Ok(Self::Warn),
+            "retry" => Ok(Self::Retry),
+            "fail" => Ok(Self::Fail),
+            _ => Err(format!(
+                "invalid change policy '{}' (expected warn, retry or fail)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ChangePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Warn => "warn",
+            Self::Retry => "retry",
+            Self::Fail => "fail",
+        })
+    }
+}
+
+/// The error of a copy whose source changed underneath it.
+#[derive(Debug)]
+pub(crate) struct SourceChanged(PathBuf);
+
+impl SourceChanged {
+    pub(crate) fn error(source: &Path) -> io::Error {
+        io::Error::other(Self(source.to_path_buf()))
+    }
+
+    /// Whether `err` reports a changed source.
+    pub(crate) fn is(err: &io::Error) -> bool {
+        err.get_ref().is_some_and(|inner| inner.is::<Self>())
+    }
+}
+
+impl fmt::Display for SourceChanged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "'{}' changed while it was being copied",
+            self.0.display()
+        )
+    }
+}
+
+impl Error for SourceChanged {}
+
+/// Whether `file`, whose metadata was `before` the copy, has since changed
+/// size or modification time.
+pub(crate) fn changed(file: &File, before: &Metadata) -> io::Result<bool> {
+    let after = file.metadata()?;
+    Ok(after.len() != before.len() || modified(&after) != modified(before))
+}
+
+fn modified(metadata: &Metadata) -> Option<SystemTime> {
+    metadata.modified().ok()
+}
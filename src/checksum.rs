@@ -0,0 +1,124 @@
+//! Content checksums shared by verification and manifests.
+
+use std::fmt;
+use std::str::FromStr;
+
+/// The hash used to compare and record file contents.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum ChecksumAlgo {
+    /// BLAKE3: cryptographic and fast on every CPU.
+    #[default]
+    Blake3,
+    /// XXH64: much faster, but only guards against accidental corruption.
+    Xxhash64,
+    /// SHA-256, where compliance requires it.
+    Sha256,
+}
+
+impl ChecksumAlgo {
+    /// The names of the algorithms, as `from_str` takes them and `Display`
+    /// writes them.
+    pub const NAMES: &[&str] = &["blake3", "xxhash64", "sha256"];
+
+    /// A fresh hasher for this algorithm.
+    pub(crate) fn hasher(self) -> Box<dyn Hasher> {
+        match self {
+            Self::Blake3 => Box::new(blake3::Hasher::new()),
+            Self::Xxhash64 => Box::new(xxhash_rust::xxh64::Xxh64::new(0)),
+            Self::Sha256 => Box::<sha2::Sha256>::default(),
+        }
+    }
+}
+
+impl FromStr for ChecksumAlgo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "blake3" => Ok(Self::Blake3),
+            "xxhash64" | "xxh64" => Ok(Self::Xxhash64),
+            "sha256" => Ok(Self::Sha256),
+            _ => Err(format!(
+                "invalid checksum algorithm '{}' (expected blake3, xxhash64 or sha256)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ChecksumAlgo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Blake3 => "blake3",
+            Self::Xxhash64 => "xxhash64",
+            Self::Sha256 => "sha256",
+        })
+    }
+}
+
+/// An incremental hash over file contents.
+pub(crate) trait Hasher: Send {
+    fn update(&mut self, data: &[u8]);
+    fn finish(self: Box<Self>) -> Digest;
+}
+
+impl Hasher for blake3::Hasher {
+    fn update(&mut self, data: &[u8]) {
+        blake3::Hasher::update(self, data);
+    }
+
+    fn finish(self: Box<Self>) -> Digest {
+        Digest(self.finalize().as_bytes().to_vec())
+    }
+}
+
+impl Hasher for xxhash_rust::xxh64::Xxh64 {
+    fn update(&mut self, data: &[u8]) {
+        xxhash_rust::xxh64::Xxh64::update(self, data);
+    }
+
+    fn finish(self: Box<Self>) -> Digest {
+        Digest(self.digest().to_be_bytes().to_vec())
+    }
+}
+
+impl Hasher for sha2::Sha256 {
+    fn update(&mut self, data: &[u8]) {
+        sha2::Digest::update(self, data);
+    }
+
+    fn finish(self: Box<Self>) -> Digest {
+        Digest(sha2::Digest::finalize(*self).to_vec())
+    }
+}
+
+/// A finished checksum, displayed as lowercase hex.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Digest(Vec<u8>);
+
+impl Digest {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Parses the lowercase or uppercase hex form that `Display` produces.
+    pub(crate) fn from_hex(hex: &str) -> Option<Self> {
+        if hex.len() % 2 != 0 || !hex.is_ascii() {
+            return None;
+        }
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+            .collect::<Option<Vec<_>>>()
+            .map(Self)
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,81 @@
+//! Whether the output of cpv is colored, for `--color`.
+
+use std::env;
+use std::fmt;
+use std::io::{self, IsTerminal};
+use std::str::FromStr;
+
+/// When the progress bars and messages are colored.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// When stderr is a terminal, unless `NO_COLOR` is set or `CLICOLOR`
+    /// is `0`; always when `CLICOLOR_FORCE` is set to anything but `0`.
+    #[default]
+    Auto,
+    /// Always, even into a pipe or a file.
+    Always,
+    /// Never.
+    Never,
+}
+
+impl ColorChoice {
+    /// The names of the choices, as `from_str` takes them and `Display`
+    /// writes them.
+    pub const NAMES: &[&str] = &["auto", "always", "never"];
+
+    /// Whether output is colored with this choice.
+    pub fn enabled(self) -> bool {
+        match self {
+            Self::Always => true,
+            Self::Never => false,
+            Self::Auto => {
+                if env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty()) {
+                    false
+                } else if env::var_os("CLICOLOR_FORCE").is_some_and(|value| value != "0") {
+                    true
+                } else if env::var_os("CLICOLOR").is_some_and(|value| value == "0") {
+                    false
+                } else {
+                    io::stderr().is_terminal()
+                }
+            }
+        }
+    }
+
+    /// Turns colors on or off for everything cpv prints, including the
+    /// progress bars.
+    pub fn apply(self) {
+        #[cfg(feature = "progress-bar")]
+        {
+            let enabled = self.enabled();
+            console::set_colors_enabled(enabled);
+            console::set_colors_enabled_stderr(enabled);
+        }
+    }
+}
+
+impl FromStr for ColorChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            _ => Err(format!(
+                "invalid color choice '{}' (expected auto, always or never)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ColorChoice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Auto => "auto",
+            Self::Always => "always",
+            Self::Never => "never",
+        })
+    }
+}
@@ -0,0 +1,138 @@
+//! Comparison of a source with an existing copy for `--verify-only`.
+
+use crate::digests::hash;
+use crate::location::Location;
+use crate::progress;
+use crate::ui::{ProgressBar, ProgressStyle};
+use crate::verify;
+use crate::{CopyError, CopyOptions};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// The differences between a source and its copy, with paths relative to
+/// the compared roots.
+#[derive(Debug, Default)]
+pub struct Comparison {
+    /// Source files with no counterpart in the copy.
+    pub missing: Vec<PathBuf>,
+    /// Files in the copy with no counterpart in the source.
+    pub extra: Vec<PathBuf>,
+    /// Files whose size or checksum differs.
+    pub mismatched: Vec<PathBuf>,
+    /// Pairs of files whose checksums were compared.
+    pub files_compared: usize,
+    /// Bytes hashed on both sides, counting files whose checksum was cached.
+    pub bytes_compared: u64,
+}
+
+impl Comparison {
+    /// Whether the copy matches the source exactly.
+    pub fn is_identical(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.mismatched.is_empty()
+    }
+}
+
+/// Compares the file or tree at `source` with the copy at `dest`: presence
+/// and size first, then `options.checksum` hashes of every pair of equal
+/// size, with a progress bar over the bytes hashed. Unchanged source files
+/// found in `options.checksum_cache` are not read again; the copy is always
+/// read, as damage that keeps its size and modification time is what the
+/// comparison is for.
+///
+/// Names on the source side are mapped through `options.normalization`,
+/// and for FAT with `options.fat_compat`, before looking them up in the
+/// copy.
+pub fn compare_trees(
+    source: &Path,
+    dest: &Path,
+    options: &CopyOptions,
+) -> Result<Comparison, CopyError> {
+    let (source_files, dest_files) = if source.is_file() {
+        let dest = if dest.is_dir() {
+            dest.join(options.target_name(source.file_name().unwrap_or_default()))
+        } else {
+            dest.to_path_buf()
+        };
+        let name = PathBuf::from(dest.file_name().unwrap_or_default());
+        let mut dest_files = BTreeMap::new();
+        if let Ok(metadata) = fs::metadata(&dest) {
+            dest_files.insert(name.clone(), (dest, metadata.len()));
+        }
+        let source_files =
+            BTreeMap::from([(name, (source.to_path_buf(), fs::metadata(source)?.len()))]);
+        (source_files, dest_files)
+    } else {
+        let source_files = list_files(source, |relative| options.target_path(relative))?;
+        (source_files, list_files(dest, Path::to_path_buf)?)
+    };
+
+    let mut comparison = Comparison::default();
+    let mut pairs = Vec::new();
+    for (name, (path, len)) in &source_files {
+        match dest_files.get(name) {
+            None => comparison.missing.push(name.clone()),
+            Some((_, dest_len)) if dest_len != len => comparison.mismatched.push(name.clone()),
+            Some((dest_path, _)) => pairs.push((name, path, dest_path, *len)),
+        }
+    }
+    comparison.extra = dest_files
+        .keys()
+        .filter(|name| !source_files.contains_key(*name))
+        .cloned()
+        .collect();
+
+    let pb = ProgressBar::with_draw_target(
+        Some(pairs.iter().map(|&(.., len)| 2 * len).sum()),
+        options.progress.draw_target(),
+    );
+    let rate = progress::Rate::new();
+    pb.set_style(rate.keys(
+        ProgressStyle::default_bar()
+            .template(&options.theme.template(
+                "[{elapsed_precise}] [{bar:40.green/white}] {bytes}/{total_bytes} compared ({eta}, done ~{finish})",
+            ))
+            .expect("Progress bar template error")
+            .progress_chars(progress::bar_chars(options)?),
+    ));
+    let rate_samples = rate.track(&pb);
+    let status_lines = options.progress.status_lines(&pb, &rate);
+    for (name, source_path, dest_path, _) in pairs {
+        let (expected, read) = hash(&Location::new(source_path), options, Some(&pb))?;
+        let (actual, dest_read) =
+            verify::hash_file(&Location::new(dest_path), options.checksum, Some(&pb))?;
+        comparison.files_compared += 1;
+        comparison.bytes_compared += read + dest_read;
+        if expected != actual {
+            comparison.mismatched.push(name.clone());
+        }
+    }
+    drop(status_lines);
+    drop(rate_samples);
+    pb.finish_and_clear();
+    comparison.mismatched.sort();
+    Ok(comparison)
+}
+
+/// Maps the regular files under `root`, by relative name after `rename`,
+/// to their paths and sizes.
+fn list_files(
+    root: &Path,
+    rename: impl Fn(&Path) -> PathBuf,
+) -> Result<BTreeMap<PathBuf, (PathBuf, u64)>, CopyError> {
+    let mut files = BTreeMap::new();
+    for entry in WalkDir::new(root) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let relative = entry
+            .path()
+            .strip_prefix(root)
+            .map_err(|e| CopyError::Other(e.into()))?;
+        let len = entry.metadata()?.len();
+        files.insert(rename(relative), (entry.path().to_path_buf(), len));
+    }
+    Ok(files)
+}
@@ -0,0 +1,182 @@
+//! Compression of destination files on the fly for `--compress`: every
+//! file is written as `NAME.gz` or `NAME.zst`, compressed as it is copied.
+
+use crate::vfs::{FileInfo, Filesystem};
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// How `CopyOptions::compress` compresses destination files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// gzip, into `NAME.gz`.
+    Gzip,
+    /// Zstandard, into `NAME.zst`, with the `zstd` feature.
+    Zstd,
+}
+
+impl Compression {
+    /// The names of the compressions, as `from_str` takes them and `Display`
+    /// writes them.
+    pub const NAMES: &[&str] = &["gzip", "zstd"];
+
+    /// The extension added to the names of compressed files.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Gzip => "gz",
+            Self::Zstd => "zst",
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "gzip" | "gz" => Ok(Self::Gzip),
+            "zstd" | "zst" => Ok(Self::Zstd),
+            _ => Err(format!(
+                "invalid compression '{}' (expected gzip or zstd)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+        })
+    }
+}
+
+#[cfg(not(feature = "zstd"))]
+pub(crate) fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "cpv was built without the zstd feature",
+    )
+}
+
+/// The destination filesystem `inner` with its files compressed: `path`
+/// is written as `path.gz` or `path.zst`, and read back decompressed.
+#[derive(Debug)]
+pub(crate) struct Compressed {
+    inner: Arc<dyn Filesystem>,
+    compression: Compression,
+}
+
+impl Compressed {
+    pub(crate) fn new(inner: Arc<dyn Filesystem>, compression: Compression) -> Self {
+        Self { inner, compression }
+    }
+
+    fn name(&self, path: &Path) -> PathBuf {
+        let mut name = path.as_os_str().to_owned();
+        name.push(".");
+        name.push(self.compression.extension());
+        name.into()
+    }
+}
+
+impl Filesystem for Compressed {
+    fn metadata(&self, path: &Path) -> io::Result<FileInfo> {
+        match self.inner.metadata(path) {
+            Ok(info) if info.is_dir => Ok(info),
+            _ => self.inner.metadata(&self.name(path)),
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        self.inner.read_dir(path)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        let file = self.inner.open(&self.name(path))?;
+        match self.compression {
+            Compression::Gzip => Ok(Box::new(MultiGzDecoder::new(file))),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Ok(Box::new(zstd::Decoder::new(file)?)),
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd => Err(unsupported()),
+        }
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn Write + Send>> {
+        let encoder = match self.compression {
+            Compression::Gzip => Encoder::Gzip(GzEncoder::new(
+                self.inner.create(&self.name(path))?,
+                flate2::Compression::default(),
+            )),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => {
+                Encoder::Zstd(zstd::Encoder::new(self.inner.create(&self.name(path))?, 0)?)
+            }
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd => return Err(unsupported()),
+        };
+        Ok(Box::new(CompressedFile(Some(encoder))))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.inner.create_dir_all(path)
+    }
+
+    fn set_modified(&self, path: &Path, modified: SystemTime) -> io::Result<()> {
+        self.inner.set_modified(&self.name(path), modified)
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        self.inner.set_mode(&self.name(path), mode)
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        self.inner.finish()
+    }
+}
+
+type Output = Box<dyn Write + Send>;
+
+enum Encoder {
+    Gzip(GzEncoder<Output>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::Encoder<'static, Output>),
+}
+
+/// A file being compressed, complete on flush, or when dropped.
+struct CompressedFile(Option<Encoder>);
+
+impl Write for CompressedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.0 {
+            Some(Encoder::Gzip(encoder)) => encoder.write(buf),
+            #[cfg(feature = "zstd")]
+            Some(Encoder::Zstd(encoder)) => encoder.write(buf),
+            None => Err(io::Error::other("the compressed file is already complete")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut out = match self.0.take() {
+            Some(Encoder::Gzip(encoder)) => encoder.finish()?,
+            #[cfg(feature = "zstd")]
+            Some(Encoder::Zstd(encoder)) => encoder.finish()?,
+            None => return Ok(()),
+        };
+        out.flush()
+    }
+}
+
+impl Drop for CompressedFile {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
@@ -0,0 +1,104 @@
+//! Files read one after the other as one, for `CopyOptions::concat`:
+//! `--concat part1 part2 part3 whole.bin` streams the parts into a single
+//! destination, as `cat` would, with the progress of the whole.
+
+use crate::vfs::{FileInfo, Filesystem};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::vec;
+
+/// The source filesystem `inner` with `parts` read after the source file,
+/// as if it ended with their contents.
+#[derive(Debug)]
+pub(crate) struct Concatenated {
+    inner: Arc<dyn Filesystem>,
+    parts: Vec<PathBuf>,
+}
+
+impl Concatenated {
+    pub(crate) fn new(inner: Arc<dyn Filesystem>, parts: Vec<PathBuf>) -> Self {
+        Self { inner, parts }
+    }
+}
+
+/// The error for the directory `path`, which has no contents to append.
+fn is_dir(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("cannot concatenate '{}': is a directory", path.display()),
+    )
+}
+
+/// `err` of the part `path`, which the copy would only name by its first.
+fn of_part(path: &Path, err: io::Error) -> io::Error {
+    io::Error::new(err.kind(), format!("'{}': {}", path.display(), err))
+}
+
+impl Filesystem for Concatenated {
+    /// The source with its total size and the time the last of its parts
+    /// was modified.
+    fn metadata(&self, path: &Path) -> io::Result<FileInfo> {
+        let mut info = self.inner.metadata(path)?;
+        if info.is_dir {
+            return Err(is_dir(path));
+        }
+        for part in &self.parts {
+            let next = self
+                .inner
+                .metadata(part)
+                .map_err(|err| of_part(part, err))?;
+            if next.is_dir {
+                return Err(is_dir(part));
+            }
+            info.len += next.len;
+            info.modified = info.modified.max(next.modified);
+        }
+        Ok(info)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        self.inner.read_dir(path)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(Chained {
+            inner: Arc::clone(&self.inner),
+            parts: self.parts.clone().into_iter(),
+            current: self.inner.open(path)?,
+        }))
+    }
+
+    fn create(&self, _path: &Path) -> io::Result<Box<dyn Write + Send>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "concatenated files are only read",
+        ))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.inner.create_dir_all(path)
+    }
+}
+
+/// Reads a file, then each of the parts after it, opened as they come.
+struct Chained {
+    inner: Arc<dyn Filesystem>,
+    parts: vec::IntoIter<PathBuf>,
+    current: Box<dyn Read + Send>,
+}
+
+impl Read for Chained {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.current.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            let Some(part) = self.parts.next() else {
+                return Ok(0);
+            };
+            self.current = self.inner.open(&part).map_err(|err| of_part(&part, err))?;
+        }
+    }
+}
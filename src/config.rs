@@ -0,0 +1,249 @@
+//! The configuration file of cpv, with the `config` feature:
+//! `~/.config/cpv/config.toml`, or another given with `--config`, holds
+//! defaults for some of the options of the command line, which override
+//! them.
+//!
+//! ```toml
+//! preserve = ["attributes", "flags"]
+//! buffer-size = "4M"
+//! jobs = 4
+//! progress-template = "{bar:40} {percent}% {bytes_per_sec}"
+//! exclude = [".git", "*.tmp"]
+//! color = "never"
+//! ```
+
+use crate::{parse_buffer_size, ColorChoice, Parallelism};
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Settings of the configuration file. Those left out are `None` or empty,
+/// which leaves the option to the command line and its defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// What is preserved without `-p` or `--preserve-flags`.
+    pub preserve: Vec<Preserved>,
+    /// The I/O buffer size, as a number of bytes or a size such as `"4M"`.
+    #[serde(deserialize_with = "buffer_size")]
+    pub buffer_size: Option<usize>,
+    /// The number of files copied in parallel, as a number or in the
+    /// spelling of `--jobs`.
+    #[serde(deserialize_with = "jobs")]
+    pub jobs: Option<Parallelism>,
+    pub progress_template: Option<String>,
+    /// Patterns of names left out of copies, as with `--exclude`.
+    pub exclude: Vec<String>,
+    pub color: Option<ColorChoice>,
+}
+
+impl Config {
+    /// Where the configuration file is looked for without `--config`:
+    /// `$XDG_CONFIG_HOME/cpv/config.toml`, by default under
+    /// `~/.config`, or `%APPDATA%\cpv\config.toml` on Windows.
+    pub fn default_path() -> Option<PathBuf> {
+        let dir = |var| {
+            env::var_os(var)
+                .filter(|dir| !dir.is_empty())
+                .map(PathBuf::from)
+        };
+        let base = match cfg!(windows) {
+            true => dir("APPDATA")?,
+            false => dir("XDG_CONFIG_HOME").or_else(|| Some(dir("HOME")?.join(".config")))?,
+        };
+        Some(base.join("cpv").join("config.toml"))
+    }
+
+    /// Loads the configuration file `path`, or the one at `default_path()`
+    /// without one. That one may be missing, which leaves every setting
+    /// out.
+    pub fn load(path: Option<&Path>) -> io::Result<Self> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match Self::default_path() {
+                Some(path) => (path, false),
+                None => return Ok(Self::default()),
+            },
+        };
+        match fs::read_to_string(&path) {
+            Ok(text) => text.parse().map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("'{}': {}", path.display(), err),
+                )
+            }),
+            Err(err) if err.kind() == io::ErrorKind::NotFound && !required => Ok(Self::default()),
+            Err(err) => Err(io::Error::new(
+                err.kind(),
+                format!("'{}': {}", path.display(), err),
+            )),
+        }
+    }
+
+    /// These settings with those of `over`, such as the ones given on the
+    /// command line, on top: its values replace these, and its lists add
+    /// to them.
+    pub fn layer(mut self, over: Config) -> Config {
+        for preserved in over.preserve {
+            if !self.preserve.contains(&preserved) {
+                self.preserve.push(preserved);
+            }
+        }
+        self.exclude.extend(over.exclude);
+        Config {
+            preserve: self.preserve,
+            buffer_size: over.buffer_size.or(self.buffer_size),
+            jobs: over.jobs.or(self.jobs),
+            progress_template: over.progress_template.or(self.progress_template),
+            exclude: self.exclude,
+            color: over.color.or(self.color),
+        }
+    }
+
+    /// Whether `what` is preserved.
+    pub fn preserves(&self, what: Preserved) -> bool {
+        self.preserve.contains(&what)
+    }
+}
+
+impl FromStr for Config {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        toml::from_str(s).map_err(|err| err.message().to_string())
+    }
+}
+
+/// What a copy may preserve besides the data of files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preserved {
+    /// Modes, owners and times, as `-p` does.
+    Attributes,
+    /// Linux inode flags, as `--preserve-flags` does.
+    Flags,
+}
+
+impl FromStr for Preserved {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "attributes" => Ok(Self::Attributes),
+            "flags" => Ok(Self::Flags),
+            _ => Err(format!(
+                "invalid preserved attribute '{}' (expected attributes or flags)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Preserved {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Attributes => "attributes",
+            Self::Flags => "flags",
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Preserved {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// A setting written as a number or as text, both read as text.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Scalar {
+    Number(u64),
+    Text(String),
+}
+
+impl Scalar {
+    fn text(self) -> String {
+        match self {
+            Self::Number(n) => n.to_string(),
+            Self::Text(text) => text,
+        }
+    }
+}
+
+fn buffer_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<usize>, D::Error> {
+    let size = Scalar::deserialize(deserializer)?.text();
+    parse_buffer_size(&size)
+        .map(Some)
+        .map_err(de::Error::custom)
+}
+
+fn jobs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Parallelism>, D::Error> {
+    let jobs = Scalar::deserialize(deserializer)?.text();
+    jobs.parse().map(Some).map_err(de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_config() {
+        let config: Config = r#"
+            preserve = ["attributes"]
+            buffer-size = "4M"
+            jobs = 4
+            exclude = ["*.tmp"]
+            color = "never"
+        "#
+        .parse()
+        .unwrap();
+        assert!(config.preserves(Preserved::Attributes));
+        assert!(!config.preserves(Preserved::Flags));
+        assert_eq!(config.buffer_size, Some(4 << 20));
+        assert_eq!(config.jobs, Some(Parallelism::Exact(4)));
+        assert_eq!(config.progress_template, None);
+        assert_eq!(config.color, Some(ColorChoice::Never));
+        assert_eq!("".parse::<Config>().unwrap(), Config::default());
+        assert_eq!(
+            "jobs = \"max:2\"".parse::<Config>().unwrap().jobs,
+            Some(Parallelism::Max(2))
+        );
+
+        assert!("colour = \"never\"".parse::<Config>().is_err());
+        assert!("preserve = [\"acls\"]".parse::<Config>().is_err());
+        assert!("buffer-size = \"0\"".parse::<Config>().is_err());
+
+        // The command line replaces values and adds to lists.
+        let layered = config.layer(Config {
+            preserve: vec![Preserved::Flags],
+            jobs: Some(Parallelism::Exact(1)),
+            progress_template: Some("{bar}".to_string()),
+            exclude: vec![".git".to_string()],
+            ..Default::default()
+        });
+        assert_eq!(layered.preserve, [Preserved::Attributes, Preserved::Flags]);
+        assert_eq!(layered.buffer_size, Some(4 << 20));
+        assert_eq!(layered.jobs, Some(Parallelism::Exact(1)));
+        assert_eq!(layered.progress_template.as_deref(), Some("{bar}"));
+        assert_eq!(layered.exclude, ["*.tmp", ".git"]);
+        assert_eq!(layered.color, Some(ColorChoice::Never));
+
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("config.toml");
+        fs::write(&path, "jobs = 2\n").unwrap();
+        assert_eq!(
+            Config::load(Some(&path)).unwrap().jobs,
+            Some(Parallelism::Exact(2))
+        );
+        let missing = temp.path().join("missing.toml");
+        assert!(Config::load(Some(&missing)).is_err());
+    }
+}
@@ -0,0 +1,124 @@
+//! The file an I/O error happened on and what was being done to it, so
+//! that "Permission denied" out of a copy of millions of files says which
+//! file and why. The context travels inside the `io::Error`, keeping its
+//! kind, and becomes `CopyError::Fs` when the error leaves the library.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// What was being done to the path of a `CopyError::Fs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum IoOp {
+    /// Opening a source file for reading.
+    OpenSource,
+    /// Opening a source or destination directory.
+    OpenDir,
+    /// Listing a source directory.
+    ReadDir,
+    /// Reading the metadata of a source entry.
+    Stat,
+    /// Creating or truncating a destination file.
+    CreateDest,
+    /// Creating a destination directory.
+    CreateDir,
+    /// Applying `chmod` or `chown` rules.
+    SetPermissions,
+    /// Copying times, ownership and mode with `preserve_attrs`.
+    PreserveAttributes,
+    /// Removing a destination entry the source lacks, with `delete`.
+    Remove,
+}
+
+impl fmt::Display for IoOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::OpenSource => "open source",
+            Self::OpenDir => "open directory",
+            Self::ReadDir => "read directory",
+            Self::Stat => "stat",
+            Self::CreateDest => "create destination",
+            Self::CreateDir => "create directory",
+            Self::SetPermissions => "set permissions on",
+            Self::PreserveAttributes => "preserve attributes on",
+            Self::Remove => "remove",
+        })
+    }
+}
+
+/// An OS error with its operation and path, inside an `io::Error`.
+#[derive(Debug)]
+pub(crate) struct Context {
+    pub(crate) op: IoOp,
+    pub(crate) path: PathBuf,
+    pub(crate) source: io::Error,
+}
+
+impl fmt::Display for Context {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "cannot {} '{}': {}",
+            self.op,
+            self.path.display(),
+            self.source
+        )
+    }
+}
+
+impl Error for Context {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// `err` with `op` and `path` attached. Only OS errors get them: errors
+/// made by cpv already say what they are about, and some are markers
+/// looked for further up.
+pub(crate) fn context(err: io::Error, op: IoOp, path: &Path) -> io::Error {
+    if err.get_ref().is_some() {
+        return err;
+    }
+    io::Error::new(
+        err.kind(),
+        Context {
+            op,
+            path: path.to_path_buf(),
+            source: err,
+        },
+    )
+}
+
+/// Attaching context to the error of an I/O result.
+pub(crate) trait ResultExt<T> {
+    fn context(self, op: IoOp, path: &Path) -> io::Result<T>;
+}
+
+impl<T> ResultExt<T> for io::Result<T> {
+    fn context(self, op: IoOp, path: &Path) -> io::Result<T> {
+        self.map_err(|err| context(err, op, path))
+    }
+}
+
+/// The OS error under any context, for checks on `raw_os_error`.
+pub(crate) fn os_error(err: &io::Error) -> &io::Error {
+    match err
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<Context>())
+    {
+        Some(context) => &context.source,
+        None => err,
+    }
+}
+
+/// The context of `err`, or `err` itself if it has none.
+pub(crate) fn take(err: io::Error) -> Result<Context, io::Error> {
+    if !err.get_ref().is_some_and(|inner| inner.is::<Context>()) {
+        return Err(err);
+    }
+    let inner = err.into_inner().and_then(|inner| inner.downcast().ok());
+    Ok(*inner.expect("checked to hold a context"))
+}
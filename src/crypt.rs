@@ -0,0 +1,304 @@
+//! Encryption of files as they are copied, with the `age` feature:
+//! `EncryptFs` writes every file as an age file `NAME.age` for
+//! `--encrypt`, and `DecryptFs` reads age files back as their plaintext for
+//! `--decrypt`.
+
+use crate::vfs::{FileInfo, Filesystem};
+use age::x25519::{Identity, Recipient};
+use age::{Decryptor, Encryptor};
+use std::collections::HashSet;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// The first line of an age file.
+const MAGIC: &[u8] = b"age-encryption.org/v1\n";
+
+/// The plaintext of a chunk of the payload, which carries a tag after it.
+const CHUNK_SIZE: u64 = 64 * 1024;
+const TAG_SIZE: u64 = 16;
+
+/// The nonce the payload starts with.
+const NONCE_SIZE: u64 = 16;
+
+/// The most a header may take, past which a file is not taken for one.
+const MAX_HEADER: u64 = 1024 * 1024;
+
+/// `path` with `.age` added to its name.
+fn sealed(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".age");
+    name.into()
+}
+
+/// The destination filesystem `inner` with its files encrypted to age
+/// recipients: `path` is written as `path.age`, which only the holders of
+/// their identities can read.
+///
+/// Encrypted files cannot be read back, so a copy to them cannot be
+/// verified.
+pub struct EncryptFs {
+    inner: Arc<dyn Filesystem>,
+    recipients: Vec<Recipient>,
+}
+
+impl EncryptFs {
+    /// Encrypts the files written to `inner` to every one of `recipients`,
+    /// age public keys of the form `age1…`.
+    pub fn new(inner: Arc<dyn Filesystem>, recipients: &[String]) -> io::Result<Self> {
+        if recipients.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "encrypting needs at least one recipient",
+            ));
+        }
+        let recipients = recipients
+            .iter()
+            .map(|recipient| {
+                Recipient::from_str(recipient.trim()).map_err(|err| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("invalid age recipient '{}': {}", recipient, err),
+                    )
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Self { inner, recipients })
+    }
+}
+
+impl fmt::Debug for EncryptFs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptFs")
+            .field("inner", &self.inner)
+            .field("recipients", &self.recipients)
+            .finish()
+    }
+}
+
+impl Filesystem for EncryptFs {
+    fn metadata(&self, path: &Path) -> io::Result<FileInfo> {
+        match self.inner.metadata(path) {
+            Ok(info) if info.is_dir => Ok(info),
+            _ => self.inner.metadata(&sealed(path)),
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        self.inner.read_dir(path)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "'{}' is encrypted and cannot be read back",
+                sealed(path).display()
+            ),
+        ))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn Write + Send>> {
+        let recipients = self
+            .recipients
+            .iter()
+            .map(|recipient| recipient as &dyn age::Recipient);
+        let encryptor = Encryptor::with_recipients(recipients).map_err(io::Error::other)?;
+        let file = encryptor.wrap_output(self.inner.create(&sealed(path))?)?;
+        Ok(Box::new(EncryptedFile(Some(file))))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.inner.create_dir_all(path)
+    }
+
+    fn set_modified(&self, path: &Path, modified: SystemTime) -> io::Result<()> {
+        self.inner.set_modified(&sealed(path), modified)
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        self.inner.set_mode(&sealed(path), mode)
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        self.inner.finish()
+    }
+}
+
+type Output = Box<dyn Write + Send>;
+
+/// A file being encrypted, complete on flush. One dropped before that is
+/// left without its last chunk, so that it fails to decrypt rather than
+/// reading as a shorter file.
+struct EncryptedFile(Option<age::stream::StreamWriter<Output>>);
+
+impl Write for EncryptedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.0 {
+            Some(file) => file.write(buf),
+            None => Err(io::Error::other("the encrypted file is already complete")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.0.take() {
+            Some(file) => file.finish()?.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The source filesystem `inner` with its files decrypted with age
+/// identities. A file `NAME.age` is read as `NAME`, unless that is also
+/// in its directory, and every file must be an age file encrypted to one
+/// of the identities.
+pub struct DecryptFs {
+    inner: Arc<dyn Filesystem>,
+    identities: Vec<Identity>,
+}
+
+impl DecryptFs {
+    /// Decrypts the files of `inner` with the identities in `keys`, the
+    /// text of an age identity file: one `AGE-SECRET-KEY-1…` per line,
+    /// with blank lines and `#` comments.
+    pub fn new(inner: Arc<dyn Filesystem>, keys: &str) -> io::Result<Self> {
+        let identities: Vec<_> = keys
+            .lines()
+            .map(str::trim)
+            .enumerate()
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .map(|(i, line)| {
+                Identity::from_str(line).map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("line {} is not an age identity", i + 1),
+                    )
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        if identities.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "decrypting needs at least one age identity",
+            ));
+        }
+        Ok(Self { inner, identities })
+    }
+
+    /// The path of `path` on `inner`, itself or `path.age`, with its
+    /// metadata there.
+    fn locate(&self, path: &Path) -> io::Result<(PathBuf, FileInfo)> {
+        match self.inner.metadata(path) {
+            Ok(info) => Ok((path.to_path_buf(), info)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let sealed = sealed(path);
+                match self.inner.metadata(&sealed) {
+                    Ok(info) if !info.is_dir => Ok((sealed, info)),
+                    _ => Err(err),
+                }
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl fmt::Debug for DecryptFs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DecryptFs")
+            .field("inner", &self.inner)
+            .field("identities", &self.identities.len())
+            .finish()
+    }
+}
+
+fn not_age(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("'{}' is not an age file", path.display()),
+    )
+}
+
+/// The size of the plaintext of the age file `file`, `len` bytes long,
+/// from the size of its header.
+fn plaintext_len(file: impl Read, len: u64, path: &Path) -> io::Result<u64> {
+    let mut reader = BufReader::new(file).take(MAX_HEADER);
+    let mut line = Vec::new();
+    let mut header = 0;
+    loop {
+        line.clear();
+        let n = reader.read_until(b'\n', &mut line)?;
+        if !line.ends_with(b"\n") || (header == 0 && line != MAGIC) {
+            return Err(not_age(path));
+        }
+        header += n as u64;
+        if line.starts_with(b"--- ") {
+            break;
+        }
+    }
+    let payload = len
+        .checked_sub(header + NONCE_SIZE)
+        .filter(|&payload| payload >= TAG_SIZE)
+        .ok_or_else(|| not_age(path))?;
+    let chunks = payload.div_ceil(CHUNK_SIZE + TAG_SIZE);
+    Ok(payload - chunks * TAG_SIZE)
+}
+
+impl Filesystem for DecryptFs {
+    fn metadata(&self, path: &Path) -> io::Result<FileInfo> {
+        let (path, mut info) = self.locate(path)?;
+        if !info.is_dir {
+            info.len = plaintext_len(self.inner.open(&path)?, info.len, &path)?;
+        }
+        Ok(info)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let children = self.inner.read_dir(path)?;
+        let names: HashSet<_> = children.iter().cloned().collect();
+        children
+            .iter()
+            .map(|child| {
+                let plain = child
+                    .to_str()
+                    .and_then(|child| child.strip_suffix(".age"))
+                    .map(PathBuf::from)
+                    .filter(|plain| !names.contains(plain));
+                match plain {
+                    Some(plain) if !self.inner.metadata(child)?.is_dir => Ok(plain),
+                    _ => Ok(child.clone()),
+                }
+            })
+            .collect()
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        let (path, _) = self.locate(path)?;
+        let file = BufReader::new(self.inner.open(&path)?);
+        let identities = self
+            .identities
+            .iter()
+            .map(|identity| identity as &dyn age::Identity);
+        let decrypted = Decryptor::new_buffered(file).and_then(|file| file.decrypt(identities));
+        match decrypted {
+            Ok(file) => Ok(Box::new(file)),
+            Err(err) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("cannot decrypt '{}': {}", path.display(), err),
+            )),
+        }
+    }
+
+    fn create(&self, _path: &Path) -> io::Result<Box<dyn Write + Send>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "files are decrypted only as they are read",
+        ))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.inner.create_dir_all(path)
+    }
+}
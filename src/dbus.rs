@@ -0,0 +1,157 @@
+//! Progress on the session bus, for `CopyOptions::desktop_progress`, with
+//! the `dbus` feature: the desktop shows the copy in its progress UI,
+//! through the launcher entry of the application
+//! (`com.canonical.Unity.LauncherEntry`, which GNOME docks and the Plasma
+//! task manager follow) and through a job in the notifications of Plasma
+//! (`org.kde.JobViewServer`), when it has them.
+
+use crate::progress::{Rate, Ticker};
+use crate::ui::ProgressBar;
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsStr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use zbus::blocking::Connection;
+use zbus::zvariant::{OwnedObjectPath, Value};
+
+/// Interval between updates of the progress shown by the desktop.
+const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where the updates of the launcher entry come from, which docks do not
+/// look at.
+const LAUNCHER_PATH: &str = "/com/canonical/unity/launcherentry/cpv";
+
+/// The service of the Plasma notifications, which shows jobs.
+const JOB_SERVICE: &str = "org.kde.kuiserver";
+
+/// The URI of the application whose launcher entry shows the copy: the
+/// desktop file `launched` from, as GIO sets it in
+/// `GIO_LAUNCHED_DESKTOP_FILE`, or `cpv.desktop`.
+pub(crate) fn app_uri(launched: Option<&OsStr>) -> String {
+    let desktop_file = launched
+        .and_then(|path| Path::new(path).file_name())
+        .map_or("cpv.desktop".into(), |name| name.to_string_lossy());
+    format!("application://{}", desktop_file)
+}
+
+/// The properties of the launcher entry for the copy of `pb`, hiding the
+/// progress once it is over.
+pub(crate) fn launcher_properties(
+    pb: &ProgressBar,
+    over: bool,
+) -> HashMap<&'static str, Value<'static>> {
+    let position = pb.position();
+    let length = pb.length().unwrap_or(0).max(position);
+    let progress = match length {
+        0 => 1.0,
+        _ => position as f64 / length as f64,
+    };
+    HashMap::from([
+        ("progress", Value::from(progress)),
+        ("progress-visible", Value::from(!over)),
+    ])
+}
+
+/// The session bus, with the job of the copy if Plasma shows one.
+struct Desktop {
+    connection: Connection,
+    app_uri: String,
+    job: Option<OwnedObjectPath>,
+}
+
+impl Desktop {
+    /// Asks Plasma for a job showing the copy, with no button to cancel or
+    /// suspend it.
+    fn request_job(connection: &Connection) -> Option<OwnedObjectPath> {
+        let reply = connection
+            .call_method(
+                Some(JOB_SERVICE),
+                "/JobViewServer",
+                Some("org.kde.JobViewServer"),
+                "requestView",
+                &("cpv", "edit-copy", 0i32),
+            )
+            .ok()?;
+        reply.body().deserialize().ok()
+    }
+
+    /// Calls `method` of the job, which may have gone with Plasma.
+    fn job<B>(&self, method: &str, body: &B)
+    where
+        B: serde::Serialize + zbus::zvariant::DynamicType,
+    {
+        if let Some(job) = &self.job {
+            let _ = self.connection.call_method(
+                Some(JOB_SERVICE),
+                job,
+                Some("org.kde.JobViewV2"),
+                method,
+                body,
+            );
+        }
+    }
+
+    fn update(&self, pb: &ProgressBar, rate: &Rate, over: bool) {
+        let _ = self.connection.emit_signal(
+            None::<&str>,
+            LAUNCHER_PATH,
+            "com.canonical.Unity.LauncherEntry",
+            "Update",
+            &(self.app_uri.as_str(), launcher_properties(pb, over)),
+        );
+        let position = pb.position();
+        let length = pb.length().unwrap_or(0).max(position);
+        self.job("setTotalAmount", &(length, "bytes"));
+        self.job("setProcessedAmount", &(position, "bytes"));
+        self.job(
+            "setPercent",
+            &((position * 100).checked_div(length).unwrap_or(100) as u32),
+        );
+        self.job("setSpeed", &(rate.per_sec() as u64));
+    }
+}
+
+/// Shows the progress of `pb` on the desktop until dropped.
+pub(crate) struct DesktopProgress {
+    desktop: Arc<Desktop>,
+    pb: ProgressBar,
+    rate: Arc<Rate>,
+    ticker: Option<Ticker>,
+}
+
+impl DesktopProgress {
+    /// Starts showing the progress when there is a session bus, and not
+    /// otherwise.
+    pub(crate) fn show(pb: &ProgressBar, rate: &Arc<Rate>) -> Option<Self> {
+        let connection = Connection::session().ok()?;
+        let job = Desktop::request_job(&connection);
+        let desktop = Arc::new(Desktop {
+            connection,
+            app_uri: app_uri(env::var_os("GIO_LAUNCHED_DESKTOP_FILE").as_deref()),
+            job,
+        });
+        desktop.job("setInfoMessage", &("Copying",));
+        desktop.update(pb, rate, false);
+        let updated = Arc::clone(&desktop);
+        let ticker_rate = Arc::clone(rate);
+        let ticker = Ticker::start(pb, UPDATE_INTERVAL, move |pb| {
+            updated.update(pb, &ticker_rate, false);
+        });
+        Some(Self {
+            desktop,
+            pb: pb.clone(),
+            rate: Arc::clone(rate),
+            ticker: Some(ticker),
+        })
+    }
+}
+
+impl Drop for DesktopProgress {
+    fn drop(&mut self) {
+        drop(self.ticker.take());
+        self.desktop.update(&self.pb, &self.rate, true);
+        self.desktop.job("terminate", &("",));
+    }
+}
@@ -0,0 +1,163 @@
+//! Duplicate files, for `CopyOptions::dedupe`: before a recursive copy,
+//! its files are grouped by size, then by checksum, to find those with the
+//! same contents as another source file or as a file already in the
+//! destination, which are reported, left out or hard-linked.
+
+use crate::checksum::Digest;
+use crate::digests;
+use crate::location::Location;
+use crate::CopyOptions;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use walkdir::WalkDir;
+
+/// What a copy does with files whose contents it finds elsewhere.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Dedupe {
+    /// Copy them as usual, and report them in `CopyStats::duplicates`.
+    #[default]
+    Report,
+    /// Leave them out of the copy.
+    Skip,
+    /// Hard-link them to the copy of the first source with the same
+    /// contents, or to the file of the destination that has them.
+    Link,
+}
+
+impl Dedupe {
+    /// The names of the actions, as `from_str` takes them and `Display`
+    /// writes them.
+    pub const NAMES: &[&str] = &["report", "skip", "link"];
+}
+
+impl FromStr for Dedupe {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "report" => Ok(Self::Report),
+            "skip" => Ok(Self::Skip),
+            "link" => Ok(Self::Link),
+            _ => Err(format!(
+                "invalid dedupe action '{}' (expected report, skip or link)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Dedupe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Report => "report",
+            Self::Skip => "skip",
+            Self::Link => "link",
+        })
+    }
+}
+
+/// Files with the same contents, found by `CopyOptions::dedupe`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Duplicates {
+    /// The size of each of them.
+    pub bytes: u64,
+    /// Source files, in the order they are copied.
+    pub sources: Vec<PathBuf>,
+    /// Files already in the destination, other than those the copy
+    /// replaces.
+    pub existing: Vec<PathBuf>,
+}
+
+impl Duplicates {
+    /// The copies of these contents that are not needed: one per source
+    /// file, less the first unless the destination already has them.
+    pub fn redundant(&self) -> usize {
+        self.sources.len() - usize::from(self.existing.is_empty())
+    }
+}
+
+/// The duplicates among `files`, the sources and targets of a copy into
+/// `dest`, and, for each file, what its target can be linked to instead of
+/// copying it: the target of the first source with the same contents, or
+/// the file of the destination that has them. Files that cannot be read
+/// are left to the copy to report.
+pub(crate) fn find(
+    files: &[(Location, Location)],
+    dest: &Path,
+    options: &CopyOptions,
+) -> (Vec<Duplicates>, Vec<Option<PathBuf>>) {
+    // Sources and files of the destination by size, empty files aside.
+    let mut by_size: HashMap<u64, (Vec<usize>, Vec<PathBuf>)> = HashMap::new();
+    for (index, (source, _)) in files.iter().enumerate() {
+        match fs::metadata(source.path()) {
+            Ok(metadata) if metadata.len() > 0 => {
+                by_size.entry(metadata.len()).or_default().0.push(index)
+            }
+            _ => {}
+        }
+    }
+    let targets: HashSet<&Path> = files.iter().map(|(_, target)| target.path()).collect();
+    for entry in WalkDir::new(dest).into_iter().flatten() {
+        if !entry.file_type().is_file() || targets.contains(entry.path()) {
+            continue;
+        }
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if let Some((_, existing)) = by_size.get_mut(&metadata.len()) {
+            existing.push(entry.into_path());
+        }
+    }
+
+    // Only files that share their size with another are hashed.
+    let mut by_digest: HashMap<(u64, Digest), (Vec<usize>, Vec<PathBuf>)> = HashMap::new();
+    for (len, (sources, existing)) in by_size {
+        if sources.len() + existing.len() < 2 {
+            continue;
+        }
+        for index in sources {
+            if let Ok((digest, _)) = digests::hash(&files[index].0, options, None) {
+                by_digest.entry((len, digest)).or_default().0.push(index);
+            }
+        }
+        for path in existing {
+            let Ok((digest, _)) = digests::hash(&Location::new(&path), options, None) else {
+                continue;
+            };
+            if let Some((_, found)) = by_digest.get_mut(&(len, digest)) {
+                found.push(path);
+            }
+        }
+    }
+
+    let mut originals = vec![None; files.len()];
+    let mut groups = Vec::new();
+    for ((bytes, _), (mut sources, mut existing)) in by_digest {
+        if sources.len() + existing.len() < 2 {
+            continue;
+        }
+        sources.sort_unstable();
+        existing.sort();
+        let (original, linked) = match existing.first() {
+            Some(file) => (file.clone(), &sources[..]),
+            None => (files[sources[0]].1.path().to_path_buf(), &sources[1..]),
+        };
+        for &index in linked {
+            originals[index] = Some(original.clone());
+        }
+        groups.push(Duplicates {
+            bytes,
+            sources: sources
+                .iter()
+                .map(|&index| files[index].0.path().to_path_buf())
+                .collect(),
+            existing,
+        });
+    }
+    groups.sort_by(|a, b| a.sources.cmp(&b.sources));
+    (groups, originals)
+}
@@ -0,0 +1,69 @@
+//! In-place updates of existing destinations for `--delta`.
+//!
+//! Both files are local, so instead of exchanging rolling checksums as
+//! rsync does over a network, the destination is read block by block next
+//! to the source and only the blocks that differ are written. Repeated
+//! backups of VM images or database dumps, where most blocks stay put,
+//! then cost reads instead of writes on the destination.
+
+use crate::location::Location;
+use crate::progress::Progress;
+use crate::verify::read_chunk;
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+
+/// Bytes compared per step.
+const BLOCK_SIZE: usize = 128 * 1024;
+
+/// Rewrites the blocks of `dest` that differ from `source` and truncates
+/// it to the length of `source`. Returns the open destination, the bytes
+/// that already matched and the bytes written, or `None` if `dest` does not
+/// exist or is not a regular file.
+///
+/// A destination with other hard links, such as one linked from an earlier
+/// snapshot by `--link-dest`, is removed instead and `None` returned, so that
+/// the copy gets a file of its own rather than changing the other names.
+pub(crate) fn update(
+    source: &File,
+    dest: &Location,
+    progress: &mut Progress<'_>,
+) -> io::Result<Option<(File, u64, u64)>> {
+    let dst_file = match dest.open_rw() {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let metadata = dst_file.metadata()?;
+    if !metadata.is_file() {
+        return Ok(None);
+    }
+    #[cfg(unix)]
+    if std::os::unix::fs::MetadataExt::nlink(&metadata) > 1 {
+        drop(dst_file);
+        dest.remove()?;
+        return Ok(None);
+    }
+
+    let (mut reader, mut writer) = (source, &dst_file);
+    let mut src_block = vec![0; BLOCK_SIZE];
+    let mut dst_block = vec![0; BLOCK_SIZE];
+    let (mut unchanged, mut written) = (0, 0);
+    loop {
+        let n = read_chunk(&mut reader, &mut src_block)?;
+        if n == 0 {
+            break;
+        }
+        let offset = unchanged + written;
+        let existing = read_chunk(&mut writer, &mut dst_block[..n])?;
+        if existing == n && src_block[..n] == dst_block[..n] {
+            unchanged += n as u64;
+        } else {
+            writer.seek(SeekFrom::Start(offset))?;
+            writer.write_all(&src_block[..n])?;
+            written += n as u64;
+        }
+        progress.inc_data(&src_block[..n])?;
+    }
+    dst_file.set_len(unchanged + written)?;
+    Ok(Some((dst_file, unchanged, written)))
+}
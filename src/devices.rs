@@ -0,0 +1,102 @@
+//! Separate worker limits for each device a recursive copy reads or
+//! writes, for `CopyOptions::per_device`: a tree spanning several mounts,
+//! such as two disks merged into one, keeps each spinning disk to one file
+//! at a time, so that it does not seek between files, while solid-state
+//! and network storage take as many as they would on their own. Workers
+//! wait for a turn on both the device of the source and that of the
+//! destination, taking both at once, so that two never wait on each other.
+//!
+//! Devices are told apart by their device number on Unix; elsewhere every
+//! file counts as being on the same, unlimited device.
+
+use crate::storage::StorageKind;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Condvar, Mutex};
+
+/// The turns taken on each device seen so far.
+#[derive(Debug, Default)]
+pub(crate) struct Devices {
+    devices: Mutex<HashMap<u64, Device>>,
+    freed: Condvar,
+}
+
+#[derive(Debug)]
+struct Device {
+    /// Files it takes at once.
+    limit: usize,
+    /// Files being copied from or to it.
+    busy: usize,
+}
+
+impl Devices {
+    /// Waits for a turn on the devices of `source` and `dest`, which lasts
+    /// until the returned `Turn` is dropped.
+    pub(crate) fn turn(&self, source: &Path, dest: &Path) -> Turn<'_> {
+        let mut ids: Vec<u64> = Vec::with_capacity(2);
+        let mut paths = Vec::with_capacity(2);
+        for path in [source, dest] {
+            if let Some(id) = device(path).filter(|id| !ids.contains(id)) {
+                ids.push(id);
+                paths.push(path);
+            }
+        }
+        let mut devices = self.devices.lock().unwrap_or_else(|err| err.into_inner());
+        for (&id, path) in ids.iter().zip(paths) {
+            devices.entry(id).or_insert_with(|| Device {
+                limit: StorageKind::detect(path).jobs(),
+                busy: 0,
+            });
+        }
+        while ids.iter().any(|id| {
+            let device = &devices[id];
+            device.busy >= device.limit
+        }) {
+            devices = self
+                .freed
+                .wait(devices)
+                .unwrap_or_else(|err| err.into_inner());
+        }
+        for id in &ids {
+            devices.get_mut(id).expect("device seen").busy += 1;
+        }
+        Turn { devices: self, ids }
+    }
+}
+
+/// A turn on the devices of a file being copied.
+pub(crate) struct Turn<'a> {
+    devices: &'a Devices,
+    ids: Vec<u64>,
+}
+
+impl Drop for Turn<'_> {
+    fn drop(&mut self) {
+        let mut devices = self
+            .devices
+            .devices
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        for id in &self.ids {
+            if let Some(device) = devices.get_mut(id) {
+                device.busy -= 1;
+            }
+        }
+        self.devices.freed.notify_all();
+    }
+}
+
+/// The device holding `path`, or the nearest existing ancestor of it.
+#[cfg(unix)]
+fn device(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    path.ancestors()
+        .find_map(|path| std::fs::metadata(path).ok())
+        .map(|metadata| metadata.dev())
+}
+
+#[cfg(not(unix))]
+fn device(_path: &Path) -> Option<u64> {
+    None
+}
@@ -0,0 +1,182 @@
+//! The persistent checksum cache that spares repeated `--verify` and
+//! `--verify-only` runs from hashing files that have not changed.
+//!
+//! Entries are keyed by canonical path and algorithm and hold the size and
+//! modification time the file had when it was hashed; a file whose size or
+//! modification time differs is hashed again. The cache is a text file with
+//! a version line followed by one `algo len mtime digest path` line per
+//! entry, read whole when opened and rewritten by `save`.
+
+use crate::checksum::{ChecksumAlgo, Digest};
+use crate::journal::{escape, unescape, Stamp};
+use crate::location::Location;
+use crate::ui::ProgressBar;
+use crate::verify;
+use crate::CopyOptions;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// First line of a cache in the current format.
+const HEADER: &str = "cpv checksums 1";
+
+type Key = (PathBuf, ChecksumAlgo);
+
+/// Checksums of files by path, valid while their size and modification
+/// time stay the same.
+#[derive(Debug)]
+pub struct ChecksumCache {
+    path: PathBuf,
+    entries: Mutex<HashMap<Key, (Stamp, Digest)>>,
+}
+
+impl ChecksumCache {
+    /// `checksums` in the per-user cache directory: `$XDG_CACHE_HOME/cpv`
+    /// or `~/.cache/cpv`, `~/Library/Caches/cpv` on macOS and
+    /// `%LOCALAPPDATA%\cpv` on Windows.
+    pub fn default_path() -> Option<PathBuf> {
+        let dir = if cfg!(windows) {
+            PathBuf::from(env::var_os("LOCALAPPDATA")?)
+        } else if cfg!(target_os = "macos") {
+            PathBuf::from(env::var_os("HOME")?).join("Library/Caches")
+        } else if let Some(dir) = env::var_os("XDG_CACHE_HOME").filter(|dir| !dir.is_empty()) {
+            PathBuf::from(dir)
+        } else {
+            PathBuf::from(env::var_os("HOME")?).join(".cache")
+        };
+        Some(dir.join("cpv").join("checksums"))
+    }
+
+    /// Loads the cache stored at `path`, starting empty if there is none or
+    /// it is in another format.
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let entries = match fs::read_to_string(&path) {
+            Ok(text) => parse(&text),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err),
+        };
+        Ok(Self {
+            path,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    /// Writes the cache back to its file, replacing it atomically.
+    pub fn save(&self) -> io::Result<()> {
+        let mut text = format!("{}\n", HEADER);
+        for ((path, algo), (stamp, digest)) in self.entries.lock().unwrap().iter() {
+            if let Some(path) = path.to_str() {
+                text.push_str(&format!(
+                    "{} {} {} {} {}\n",
+                    algo,
+                    stamp.len,
+                    stamp.mtime,
+                    digest,
+                    escape(path)
+                ));
+            }
+        }
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        fs::create_dir_all(dir)?;
+        // A temporary file of its own, so that runs saving at the same time
+        // do not write into each other's.
+        let mut temp = tempfile::NamedTempFile::new_in(dir)?;
+        temp.write_all(text.as_bytes())?;
+        temp.persist(&self.path).map_err(|err| err.error)?;
+        Ok(())
+    }
+
+    /// The `algo` checksum of `file` and its size, from the cache if the
+    /// file has not changed since it was last hashed, advancing `pb` by the
+    /// size either way.
+    pub(crate) fn hash(
+        &self,
+        file: &Location,
+        algo: ChecksumAlgo,
+        pb: Option<&ProgressBar>,
+    ) -> io::Result<(Digest, u64)> {
+        let stamp = Stamp::of(&file.open()?.metadata()?);
+        let key = (fs::canonicalize(file.path())?, algo);
+        if let Some((cached, digest)) = self.entries.lock().unwrap().get(&key) {
+            if *cached == stamp {
+                if let Some(pb) = pb {
+                    pb.inc(stamp.len);
+                }
+                return Ok((digest.clone(), stamp.len));
+            }
+        }
+        let (digest, read) = verify::hash_file(file, algo, pb)?;
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(key, (stamp, digest.clone()));
+        Ok((digest, read))
+    }
+
+    /// The files whose `algo` checksums the cache holds, by checksum.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn files(&self, algo: ChecksumAlgo) -> HashMap<Digest, Vec<PathBuf>> {
+        let mut files: HashMap<Digest, Vec<PathBuf>> = HashMap::new();
+        for ((path, entry_algo), (_, digest)) in self.entries.lock().unwrap().iter() {
+            if *entry_algo == algo {
+                files.entry(digest.clone()).or_default().push(path.clone());
+            }
+        }
+        files
+    }
+
+    /// Stores `digest` as the `algo` checksum of the file at `path` as it
+    /// is now.
+    pub(crate) fn record(
+        &self,
+        path: &Path,
+        algo: ChecksumAlgo,
+        digest: &Digest,
+    ) -> io::Result<()> {
+        let stamp = Stamp::of(&fs::metadata(path)?);
+        self.entries
+            .lock()
+            .unwrap()
+            .insert((fs::canonicalize(path)?, algo), (stamp, digest.clone()));
+        Ok(())
+    }
+}
+
+/// Hashes `file` with `options.checksum`, through `options.checksum_cache`
+/// when there is one.
+pub(crate) fn hash(
+    file: &Location,
+    options: &CopyOptions,
+    pb: Option<&ProgressBar>,
+) -> io::Result<(Digest, u64)> {
+    match &options.checksum_cache {
+        Some(cache) => cache.hash(file, options.checksum, pb),
+        None => verify::hash_file(file, options.checksum, pb),
+    }
+}
+
+/// Reads the entries of a cache, ignoring malformed lines.
+fn parse(text: &str) -> HashMap<Key, (Stamp, Digest)> {
+    let mut lines = text.lines();
+    if lines.next() != Some(HEADER) {
+        return HashMap::new();
+    }
+    lines
+        .filter_map(|line| {
+            let mut fields = line.splitn(5, ' ');
+            let algo = fields.next()?.parse().ok()?;
+            let len = fields.next()?.parse().ok()?;
+            let mtime = fields.next()?.parse().ok()?;
+            let digest = Digest::from_hex(fields.next()?)?;
+            let path = PathBuf::from(unescape(fields.next()?)?);
+            Some(((path, algo), (Stamp { len, mtime }, digest)))
+        })
+        .collect()
+}
@@ -0,0 +1,78 @@
+//! Page-cache-bypassing copies for `--direct-io` on Linux.
+
+use crate::location::Location;
+use crate::prealloc;
+use crate::progress::Progress;
+use std::fs::File;
+use std::io::{self, Read, Write};
+
+/// Alignment of buffers, offsets and lengths; covers every common logical
+/// block size.
+const ALIGN: usize = 4096;
+
+/// Bytes transferred per read and write.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Copies `source` to `dest` with both opened `O_DIRECT`.
+///
+/// Returns `Ok(None)`, before any data is written, when either filesystem
+/// refuses `O_DIRECT` (tmpfs, some network filesystems). The final partial
+/// block is written padded with zeros and the file truncated back to the
+/// source length afterwards.
+pub(crate) fn copy(
+    source: &Location,
+    dest: &Location,
+    progress: &mut Progress<'_>,
+    preallocate: bool,
+) -> io::Result<Option<(File, u64)>> {
+    let Some(mut src_file) = refused_as_none(source.open_with(libc::O_RDONLY | libc::O_DIRECT))?
+    else {
+        return Ok(None);
+    };
+    let Some(mut dst_file) = refused_as_none(
+        dest.open_with(libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_DIRECT),
+    )?
+    else {
+        return Ok(None);
+    };
+
+    if preallocate {
+        prealloc::preallocate(&dst_file, src_file.metadata()?.len())?;
+    }
+
+    let mut storage = vec![0u8; CHUNK_SIZE + ALIGN];
+    let offset = storage.as_ptr().align_offset(ALIGN);
+    let buffer = &mut storage[offset..offset + CHUNK_SIZE];
+    let mut copied = 0u64;
+
+    loop {
+        let n = match src_file.read(buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        copied += n as u64;
+        progress.inc_data(&buffer[..n])?;
+
+        if n < CHUNK_SIZE {
+            // Short reads only happen at EOF; pad to a whole block.
+            let padded = n.next_multiple_of(ALIGN);
+            buffer[n..padded].fill(0);
+            dst_file.write_all(&buffer[..padded])?;
+            dst_file.set_len(copied)?;
+            break;
+        }
+        dst_file.write_all(buffer)?;
+    }
+
+    Ok(Some((dst_file, copied)))
+}
+
+fn refused_as_none(result: io::Result<File>) -> io::Result<Option<File>> {
+    match result {
+        Ok(file) => Ok(Some(file)),
+        Err(err) if err.raw_os_error() == Some(libc::EINVAL) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
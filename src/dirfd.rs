@@ -0,0 +1,510 @@
+//! Directory traversal relative to open directory descriptors (Unix).
+//!
+//! Entries are listed with `readdir` and stat'ed or opened with the `*at`
+//! calls relative to their already-open parent, so the kernel resolves one
+//! name per call instead of a full path, and no path component can be
+//! swapped for a symlink between two operations on the same directory.
+
+use crate::context::{IoOp, ResultExt};
+use crate::exclude;
+use crate::fdlimit::Budget;
+use crate::location::Location;
+use crate::{preserve, CopyError, CopyOptions};
+use std::ffi::{CStr, CString, OsStr, OsString};
+use std::fs::{self, File};
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::vec;
+
+/// The type of a directory entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+    Other,
+}
+
+impl EntryKind {
+    fn from_mode(mode: libc::mode_t) -> Self {
+        match mode & libc::S_IFMT {
+            libc::S_IFREG => Self::File,
+            libc::S_IFDIR => Self::Dir,
+            libc::S_IFLNK => Self::Symlink,
+            _ => Self::Other,
+        }
+    }
+}
+
+/// An open directory.
+#[derive(Debug)]
+pub(crate) struct Dir {
+    file: File,
+}
+
+impl Dir {
+    /// Opens the directory at `path`, following symlinks like any path.
+    pub(crate) fn open(path: &Path) -> io::Result<Self> {
+        open_file(path, DIR_FLAGS).map(|file| Self { file })
+    }
+
+    /// Opens the subdirectory `name`, refusing symlinks.
+    pub(crate) fn open_at(&self, name: &OsStr) -> io::Result<Self> {
+        self.open_file_at(name, DIR_FLAGS | libc::O_NOFOLLOW)
+            .map(|file| Self { file })
+    }
+
+    /// Creates the subdirectory `name` unless it already exists, and opens
+    /// it, refusing symlinks. Also returns whether it was created.
+    pub(crate) fn create_dir_at(&self, name: &OsStr) -> io::Result<(Self, bool)> {
+        let c_name = cstring(name)?;
+        let mut created = true;
+        // SAFETY: `c_name` is NUL-terminated.
+        if unsafe { libc::mkdirat(self.file.as_raw_fd(), c_name.as_ptr(), 0o777) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::AlreadyExists {
+                return Err(err);
+            }
+            created = false;
+        }
+        Ok((self.open_at(name)?, created))
+    }
+
+    /// Opens `name` with `open(2)` `flags`, creating it with mode 0666 (less
+    /// the umask) when `O_CREAT` is given.
+    pub(crate) fn open_file_at(&self, name: &OsStr, flags: libc::c_int) -> io::Result<File> {
+        open_at(self.file.as_raw_fd(), &cstring(name)?, flags)
+    }
+
+    /// Renames the entry `from` to `to`, both in this directory, replacing
+    /// any existing `to`.
+    pub(crate) fn rename_at(&self, from: &OsStr, to: &OsStr) -> io::Result<()> {
+        let (c_from, c_to) = (cstring(from)?, cstring(to)?);
+        let fd = self.file.as_raw_fd();
+        // SAFETY: both names are NUL-terminated and `fd` stays open.
+        if unsafe { libc::renameat(fd, c_from.as_ptr(), fd, c_to.as_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Creates the entry `name` as a hard link to `original`, a path
+    /// resolved from the working directory.
+    pub(crate) fn link_at(&self, original: &Path, name: &OsStr) -> io::Result<()> {
+        let (c_original, c_name) = (cstring(original.as_os_str())?, cstring(name)?);
+        // SAFETY: both paths are NUL-terminated and the descriptor stays
+        // open.
+        let rc = unsafe {
+            libc::linkat(
+                libc::AT_FDCWD,
+                c_original.as_ptr(),
+                self.file.as_raw_fd(),
+                c_name.as_ptr(),
+                0,
+            )
+        };
+        if rc != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Creates the entry `name` as a symbolic link to `target`.
+    pub(crate) fn symlink_at(&self, target: &Path, name: &OsStr) -> io::Result<()> {
+        let (c_target, c_name) = (cstring(target.as_os_str())?, cstring(name)?);
+        // SAFETY: both paths are NUL-terminated and the descriptor stays
+        // open.
+        let rc =
+            unsafe { libc::symlinkat(c_target.as_ptr(), self.file.as_raw_fd(), c_name.as_ptr()) };
+        if rc != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Removes the non-directory entry `name`.
+    pub(crate) fn remove_at(&self, name: &OsStr) -> io::Result<()> {
+        let c_name = cstring(name)?;
+        // SAFETY: `c_name` is NUL-terminated.
+        if unsafe { libc::unlinkat(self.file.as_raw_fd(), c_name.as_ptr(), 0) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// The directory itself, for `fsync`, `fchmod` and the like.
+    pub(crate) fn as_file(&self) -> &File {
+        &self.file
+    }
+
+    /// Lists the entries other than `.` and `..`, with their type when
+    /// `readdir` reports it.
+    pub(crate) fn entries(&self) -> io::Result<Vec<(OsString, Option<EntryKind>)>> {
+        // SAFETY: fdopendir takes ownership of the duplicate, which closedir
+        // releases below.
+        let stream = unsafe {
+            let dup = libc::fcntl(self.file.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 0);
+            if dup < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let stream = libc::fdopendir(dup);
+            if stream.is_null() {
+                let err = io::Error::last_os_error();
+                libc::close(dup);
+                return Err(err);
+            }
+            stream
+        };
+
+        let mut entries = Vec::new();
+        let result = loop {
+            // SAFETY: `stream` is open; errno is cleared to tell EOF from
+            // failure, and each entry is copied before the next call.
+            unsafe {
+                *errno() = 0;
+                let entry = libc::readdir(stream);
+                if entry.is_null() {
+                    break match *errno() {
+                        0 => Ok(()),
+                        code => Err(io::Error::from_raw_os_error(code)),
+                    };
+                }
+                let name = CStr::from_ptr((*entry).d_name.as_ptr()).to_bytes();
+                if name == b"." || name == b".." {
+                    continue;
+                }
+                let kind = match (*entry).d_type {
+                    libc::DT_REG => Some(EntryKind::File),
+                    libc::DT_DIR => Some(EntryKind::Dir),
+                    libc::DT_LNK => Some(EntryKind::Symlink),
+                    libc::DT_UNKNOWN => None,
+                    _ => Some(EntryKind::Other),
+                };
+                entries.push((OsString::from_vec(name.to_vec()), kind));
+            }
+        };
+        // SAFETY: `stream` came from fdopendir and is closed exactly once.
+        unsafe { libc::closedir(stream) };
+        result.map(|()| entries)
+    }
+
+    /// Returns the type and size of `name` without following symlinks.
+    pub(crate) fn stat_at(&self, name: &OsStr) -> io::Result<(EntryKind, u64)> {
+        let name = cstring(name)?;
+        #[cfg(all(target_os = "linux", any(target_env = "gnu", target_env = "musl")))]
+        match statx(self.file.as_raw_fd(), &name) {
+            Err(err) if err.raw_os_error() == Some(libc::ENOSYS) => {}
+            result => return result,
+        }
+        // SAFETY: `name` is NUL-terminated and `stat` is writable.
+        unsafe {
+            let mut stat: libc::stat = mem::zeroed();
+            if libc::fstatat(
+                self.file.as_raw_fd(),
+                name.as_ptr(),
+                &mut stat,
+                libc::AT_SYMLINK_NOFOLLOW,
+            ) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            Ok((EntryKind::from_mode(stat.st_mode), stat.st_size as u64))
+        }
+    }
+}
+
+/// Asks only for the type and size, without forcing network filesystems
+/// to revalidate cached attributes.
+#[cfg(all(target_os = "linux", any(target_env = "gnu", target_env = "musl")))]
+fn statx(dirfd: libc::c_int, name: &CStr) -> io::Result<(EntryKind, u64)> {
+    // SAFETY: `name` is NUL-terminated and `stat` is writable.
+    unsafe {
+        let mut stat: libc::statx = mem::zeroed();
+        if libc::statx(
+            dirfd,
+            name.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW | libc::AT_STATX_DONT_SYNC,
+            libc::STATX_TYPE | libc::STATX_SIZE,
+            &mut stat,
+        ) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((
+            EntryKind::from_mode(stat.stx_mode as libc::mode_t),
+            stat.stx_size,
+        ))
+    }
+}
+
+const DIR_FLAGS: libc::c_int = libc::O_RDONLY | libc::O_DIRECTORY;
+
+/// Opens `path`, relative to the working directory, with `open(2)` `flags`.
+pub(crate) fn open_file(path: &Path, flags: libc::c_int) -> io::Result<File> {
+    open_at(libc::AT_FDCWD, &cstring(path.as_os_str())?, flags)
+}
+
+fn open_at(dirfd: libc::c_int, name: &CStr, flags: libc::c_int) -> io::Result<File> {
+    let mode: libc::c_uint = 0o666;
+    // SAFETY: `name` is NUL-terminated; on success the descriptor is ours.
+    unsafe {
+        let fd = libc::openat(dirfd, name.as_ptr(), flags | libc::O_CLOEXEC, mode);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(File::from_raw_fd(fd))
+    }
+}
+
+fn cstring(name: &OsStr) -> io::Result<CString> {
+    CString::new(name.as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "name contains a NUL byte"))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+unsafe fn errno() -> *mut libc::c_int {
+    libc::__errno_location()
+}
+
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly"
+))]
+unsafe fn errno() -> *mut libc::c_int {
+    libc::__error()
+}
+
+#[cfg(any(target_os = "netbsd", target_os = "openbsd"))]
+unsafe fn errno() -> *mut libc::c_int {
+    libc::__errno()
+}
+
+/// Calls `on_file` with the size of every regular file under `root`, and
+/// the name of the directory at the top of `root` it is in, if any,
+/// stopping early once `stop` returns true. Entries that `left_out` returns
+/// true for, given their directory and name, are skipped with all they
+/// hold.
+///
+/// One descriptor is held per directory on the current path. Directories
+/// reported by `readdir` are opened without a stat; unreadable entries are
+/// skipped.
+pub(crate) fn for_each_file_size(
+    root: &Path,
+    stop: impl Fn() -> bool,
+    left_out: impl Fn(&Path, &OsStr) -> bool,
+    mut on_file: impl FnMut(Option<&OsStr>, u64),
+) {
+    let Ok(dir) = Dir::open(root) else {
+        return;
+    };
+    let Ok(entries) = dir.entries() else {
+        return;
+    };
+    let mut stack = vec![(dir, entries.into_iter(), root.to_path_buf())];
+    let mut top = OsString::new();
+
+    loop {
+        let depth = stack.len();
+        let Some((dir, entries, path)) = stack.last_mut() else {
+            return;
+        };
+        if stop() {
+            return;
+        }
+        let Some((name, kind)) = entries.next() else {
+            stack.pop();
+            continue;
+        };
+        if left_out(path, &name) {
+            continue;
+        }
+        let kind = match kind {
+            Some(EntryKind::File) | None => match dir.stat_at(&name) {
+                Ok((EntryKind::File, size)) => {
+                    on_file((depth > 1).then_some(top.as_os_str()), size);
+                    continue;
+                }
+                Ok((kind, _)) => kind,
+                Err(_) => continue,
+            },
+            Some(kind) => kind,
+        };
+        if kind != EntryKind::Dir {
+            continue;
+        }
+        if let Ok(child) = dir.open_at(&name) {
+            if let Ok(entries) = child.entries() {
+                let child_path = path.join(&name);
+                stack.push((child, entries.into_iter(), child_path));
+                if depth == 1 {
+                    top = name;
+                }
+            }
+        }
+    }
+}
+
+/// Walks a source tree depth-first for a recursive copy, creating each
+/// directory under the target as it is reached and yielding the files to
+/// copy, addressed relative to their open source and target parents.
+///
+/// Only the directories on the current path, and the parents of files
+/// still queued for copying, are held open. Below `open_levels` levels,
+/// the directories of the outer ones are closed and opened again by path
+/// once the walk is back in them, which keeps deep trees within the limit
+/// on open files at the cost of resolving those paths again.
+pub(crate) struct TreeWalk<'a> {
+    options: &'a CopyOptions,
+    stack: Vec<Frame>,
+    /// Directories created or reused so far, including the target root.
+    pub(crate) dirs_created: usize,
+    /// Levels of the tree whose directories are held open at most.
+    pub(crate) open_levels: usize,
+}
+
+struct Frame {
+    /// The source and target directories, unless closed to save
+    /// descriptors.
+    dirs: Option<(Arc<Dir>, Arc<Dir>)>,
+    source_path: PathBuf,
+    target_path: PathBuf,
+    entries: vec::IntoIter<(OsString, Option<EntryKind>)>,
+}
+
+impl<'a> TreeWalk<'a> {
+    /// Creates `target` and prepares to copy the contents of `source` into it.
+    pub(crate) fn new(source: &Path, target: &Path, options: &'a CopyOptions) -> io::Result<Self> {
+        let source_dir = Dir::open(source).context(IoOp::OpenDir, source)?;
+        fs::create_dir_all(target).context(IoOp::CreateDir, target)?;
+        let target_dir = Dir::open(target).context(IoOp::OpenDir, target)?;
+        let mut walk = Self {
+            options,
+            stack: Vec::new(),
+            dirs_created: 0,
+            open_levels: Budget::current().open_levels(),
+        };
+        walk.enter(source_dir, target_dir, source.into(), target.into())?;
+        Ok(walk)
+    }
+
+    fn enter(
+        &mut self,
+        source: Dir,
+        target: Dir,
+        source_path: PathBuf,
+        target_path: PathBuf,
+    ) -> io::Result<()> {
+        preserve::apply_overrides(
+            target.as_file(),
+            true,
+            self.options.chown.as_ref(),
+            self.options.chmod.as_ref(),
+        )
+        .context(IoOp::SetPermissions, &target_path)?;
+        self.dirs_created += 1;
+        let entries = source.entries().context(IoOp::ReadDir, &source_path)?;
+        self.stack.push(Frame {
+            dirs: Some((Arc::new(source), Arc::new(target))),
+            source_path,
+            target_path,
+            entries: entries.into_iter(),
+        });
+        let open = self.stack.len().min(self.open_levels);
+        if let Some(outer) = self.stack.iter_mut().rev().nth(open) {
+            outer.dirs = None;
+        }
+        Ok(())
+    }
+
+    /// The directories of the innermost level, opened again if they were
+    /// closed.
+    fn dirs(&mut self) -> io::Result<(Arc<Dir>, Arc<Dir>)> {
+        let frame = self
+            .stack
+            .last_mut()
+            .expect("opened a level without a frame");
+        if frame.dirs.is_none() {
+            let source =
+                Dir::open(&frame.source_path).context(IoOp::OpenDir, &frame.source_path)?;
+            let target =
+                Dir::open(&frame.target_path).context(IoOp::OpenDir, &frame.target_path)?;
+            frame.dirs = Some((Arc::new(source), Arc::new(target)));
+        }
+        Ok(frame
+            .dirs
+            .clone()
+            .expect("opened the directories of a level"))
+    }
+
+    /// Handles one entry of the innermost directory, returning the pair to
+    /// copy if it is a regular file.
+    fn visit(
+        &mut self,
+        name: OsString,
+        kind: Option<EntryKind>,
+    ) -> io::Result<Option<(Location, Location)>> {
+        let (source_dir, target_dir) = self.dirs()?;
+        let frame = self.stack.last().expect("visited an entry without a frame");
+        if exclude::left_out(self.options, &frame.source_path, &name) {
+            return Ok(None);
+        }
+        let source_path = frame.source_path.join(&name);
+        let kind = match kind {
+            Some(kind) => kind,
+            None => {
+                source_dir
+                    .stat_at(&name)
+                    .context(IoOp::Stat, &source_path)?
+                    .0
+            }
+        };
+        let target_name = self.options.target_name(&name);
+        let target_path = frame.target_path.join(&target_name);
+
+        match kind {
+            EntryKind::File => Ok(Some((
+                Location::in_dir(source_dir, source_path),
+                Location::in_dir(target_dir, target_path),
+            ))),
+            EntryKind::Dir => {
+                let source = source_dir
+                    .open_at(&name)
+                    .context(IoOp::OpenDir, &source_path)?;
+                let (target, created) = target_dir
+                    .create_dir_at(&target_name)
+                    .context(IoOp::CreateDir, &target_path)?;
+                if let (true, Some(undo)) = (created, &self.options.undo_log) {
+                    undo.dir(&target_path);
+                }
+                self.enter(source, target, source_path, target_path)?;
+                Ok(None)
+            }
+            EntryKind::Symlink | EntryKind::Other => Ok(None),
+        }
+    }
+}
+
+impl Iterator for TreeWalk<'_> {
+    type Item = Result<(Location, Location), CopyError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let frame = self.stack.last_mut()?;
+            let Some((name, kind)) = frame.entries.next() else {
+                self.stack.pop();
+                continue;
+            };
+            match self.visit(name, kind) {
+                Ok(Some(pair)) => return Some(Ok(pair)),
+                Ok(None) => {}
+                Err(err) => return Some(Err(err.into())),
+            }
+        }
+    }
+}
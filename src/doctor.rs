@@ -0,0 +1,446 @@
+//! The `cpv doctor` self-test of what this platform and the filesystems of
+//! given directories support, so that it is known before a long copy which
+//! of the options asking for them take effect. Each capability is tried on
+//! probe files in a hidden directory created in the directory tested and
+//! removed afterwards, as `preflight` does, rather than guessed from the
+//! type of filesystem.
+
+use crate::location::Location;
+use crate::reflink;
+use crate::storage::StorageKind;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Bytes written to the probe files.
+const PROBE_LEN: usize = 64 * 1024;
+
+/// Length of the path tried by `Capability::LongPaths`, past the 260
+/// characters Windows limits paths to unless long paths are enabled.
+const LONG_PATH_LEN: usize = 300;
+
+/// Something `doctor` tries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum Capability {
+    /// Copy-on-write clones, for `--reflink`.
+    Reflinks,
+    /// In-kernel copies with `copy_file_range(2)`, for the default and the
+    /// `copy-file-range` backends on Linux.
+    CopyFileRange,
+    /// Files with holes taking no space, for `--sparse`.
+    SparseFiles,
+    /// Extended attributes, kept by `-p`.
+    Xattrs,
+    /// Access control lists.
+    Acls,
+    /// Paths longer than 260 characters.
+    LongPaths,
+    /// Creating symbolic links, for `-s` and symlinks in trees, which needs
+    /// a privilege or developer mode on Windows.
+    Symlinks,
+}
+
+impl Capability {
+    /// Every capability, in the order `doctor` tries them.
+    pub const ALL: [Self; 7] = [
+        Self::Reflinks,
+        Self::CopyFileRange,
+        Self::SparseFiles,
+        Self::Xattrs,
+        Self::Acls,
+        Self::LongPaths,
+        Self::Symlinks,
+    ];
+
+    /// What the capability is for, as the options relying on it.
+    pub fn used_by(self) -> &'static str {
+        match self {
+            Self::Reflinks => "--reflink",
+            Self::CopyFileRange => "--backend auto|copy-file-range",
+            Self::SparseFiles => "--sparse",
+            Self::Xattrs => "-p",
+            Self::Acls => "-p",
+            Self::LongPaths => "deep trees",
+            Self::Symlinks => "-s, symlinks in trees",
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Reflinks => "reflinks",
+            Self::CopyFileRange => "copy_file_range",
+            Self::SparseFiles => "sparse files",
+            Self::Xattrs => "xattrs",
+            Self::Acls => "ACLs",
+            Self::LongPaths => "long paths",
+            Self::Symlinks => "symlinks",
+        })
+    }
+}
+
+/// Whether a capability worked, and if not, why.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Check {
+    pub capability: Capability,
+    pub result: Result<(), String>,
+}
+
+/// What `doctor` found about a directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Diagnosis {
+    pub path: PathBuf,
+    /// The type of its filesystem, such as `ext4`, if it could be told.
+    pub filesystem: Option<String>,
+    pub storage: StorageKind,
+    pub checks: Vec<Check>,
+}
+
+/// Tries every capability in the directory `dir`, leaving nothing behind.
+/// Fails only if no probe files can be created in it.
+pub fn doctor(dir: &Path) -> io::Result<Diagnosis> {
+    let probe = dir.join(format!(".cpv-doctor-{}", std::process::id()));
+    fs::create_dir(&probe)?;
+    let source = probe.join("source");
+    let checks = File::create(&source)
+        .and_then(|mut file| file.write_all(&[0x5a; PROBE_LEN]))
+        .map(|()| {
+            Capability::ALL
+                .into_iter()
+                .map(|capability| Check {
+                    capability,
+                    result: try_capability(capability, &probe, &source)
+                        .map_err(|err| err.to_string()),
+                })
+                .collect()
+        });
+    let _ = fs::remove_dir_all(&probe);
+    let checks = checks?;
+    Ok(Diagnosis {
+        path: dir.to_path_buf(),
+        filesystem: sys::filesystem(dir),
+        storage: StorageKind::detect(dir),
+        checks,
+    })
+}
+
+/// Tries `capability` with probe files in the directory `probe`, in which
+/// `source` holds `PROBE_LEN` bytes.
+fn try_capability(capability: Capability, probe: &Path, source: &Path) -> io::Result<()> {
+    let name = capability.to_string().replace([' ', '_'], "-");
+    let dest = probe.join(&name);
+    match capability {
+        Capability::Reflinks => {
+            reflink::reflink(&File::open(source)?, &Location::new(dest)).map(drop)
+        }
+        Capability::CopyFileRange => {
+            sys::copy_file_range(&File::open(source)?, &File::create(dest)?)
+        }
+        Capability::SparseFiles => {
+            let mut file = File::create(&dest)?;
+            file.seek(SeekFrom::Start(16 * PROBE_LEN as u64))?;
+            file.write_all(&[0x5a; PROBE_LEN])?;
+            file.sync_all()?;
+            sys::holes_kept(&file)
+        }
+        Capability::Xattrs => sys::set_xattr(&File::create(dest)?),
+        Capability::Acls => sys::acls(&File::create(dest)?),
+        Capability::LongPaths => {
+            let mut dir = dest;
+            while dir.as_os_str().len() < LONG_PATH_LEN {
+                dir.push("d".repeat(40));
+            }
+            fs::create_dir_all(&dir)?;
+            File::create(dir.join("file")).map(drop)
+        }
+        Capability::Symlinks => Location::new(dest).symlink(Path::new("source")),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unsupported(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{} not supported on this platform", what),
+    )
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::fs::{self, File};
+    use std::io;
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+    use std::path::{Path, PathBuf};
+    use std::ptr;
+
+    /// The type of the filesystem mounted closest above `path`, from the
+    /// mount table.
+    pub(super) fn filesystem(path: &Path) -> Option<String> {
+        let path = fs::canonicalize(path).ok()?;
+        let mounts = fs::read_to_string("/proc/self/mounts").ok()?;
+        mounts
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split(' ').skip(1);
+                let point = PathBuf::from(unescape(fields.next()?));
+                let fstype = fields.next()?;
+                path.starts_with(&point).then_some((point, fstype))
+            })
+            // Later mounts over the same point hide earlier ones.
+            .max_by_key(|(point, _)| point.as_os_str().len())
+            .map(|(_, fstype)| fstype.to_string())
+    }
+
+    /// A mount table field with its spaces, tabs, newlines and backslashes
+    /// escaped as octal.
+    fn unescape(field: &str) -> String {
+        let mut unescaped = String::with_capacity(field.len());
+        let mut rest = field;
+        while let Some(at) = rest.find('\\') {
+            unescaped.push_str(&rest[..at]);
+            let code = rest.get(at + 1..at + 4);
+            match code.and_then(|code| u8::from_str_radix(code, 8).ok()) {
+                Some(byte) => {
+                    unescaped.push(byte as char);
+                    rest = &rest[at + 4..];
+                }
+                None => {
+                    unescaped.push('\\');
+                    rest = &rest[at + 1..];
+                }
+            }
+        }
+        unescaped.push_str(rest);
+        unescaped
+    }
+
+    pub(super) fn copy_file_range(source: &File, dest: &File) -> io::Result<()> {
+        // SAFETY: both descriptors are open for the duration of the call and
+        // null offsets make the kernel use the file offsets.
+        let n = unsafe {
+            libc::copy_file_range(
+                source.as_raw_fd(),
+                ptr::null_mut(),
+                dest.as_raw_fd(),
+                ptr::null_mut(),
+                super::PROBE_LEN,
+                0,
+            )
+        };
+        match n {
+            n if n > 0 => Ok(()),
+            0 => Err(io::Error::other("copy_file_range copied nothing")),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    /// Whether `file`, written past a hole, takes less space than its
+    /// length.
+    pub(super) fn holes_kept(file: &File) -> io::Result<()> {
+        let metadata = file.metadata()?;
+        match metadata.blocks() * 512 < metadata.len() {
+            true => Ok(()),
+            false => Err(io::Error::other("holes take up space")),
+        }
+    }
+
+    pub(super) fn set_xattr(file: &File) -> io::Result<()> {
+        const NAME: &[u8] = b"user.cpv.doctor\0";
+
+        // SAFETY: the name is NUL-terminated and the value length is
+        // accurate.
+        let rc = unsafe {
+            libc::fsetxattr(
+                file.as_raw_fd(),
+                NAME.as_ptr().cast(),
+                b"1".as_ptr().cast(),
+                1,
+                0,
+            )
+        };
+        match rc {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    /// Whether the filesystem of `file` has POSIX ACLs, which it tells by
+    /// having none on it rather than not supporting them.
+    pub(super) fn acls(file: &File) -> io::Result<()> {
+        const NAME: &[u8] = b"system.posix_acl_access\0";
+
+        let mut value = [0u8; 256];
+        // SAFETY: the name is NUL-terminated and the buffer length is
+        // accurate.
+        let n = unsafe {
+            libc::fgetxattr(
+                file.as_raw_fd(),
+                NAME.as_ptr().cast(),
+                value.as_mut_ptr().cast(),
+                value.len(),
+            )
+        };
+        if n >= 0 {
+            return Ok(());
+        }
+        match io::Error::last_os_error() {
+            err if err.raw_os_error() == Some(libc::ENODATA) => Ok(()),
+            err => Err(err),
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod sys {
+    use std::ffi::{CStr, CString};
+    use std::fs::File;
+    use std::io;
+    use std::mem;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
+
+    pub(super) fn filesystem(path: &Path) -> Option<String> {
+        let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+        // SAFETY: `path` is NUL-terminated, `stat` is a writable statfs and
+        // f_fstypename is NUL-terminated by the kernel.
+        unsafe {
+            let mut stat: libc::statfs = mem::zeroed();
+            if libc::statfs(path.as_ptr(), &mut stat) != 0 {
+                return None;
+            }
+            Some(
+                CStr::from_ptr(stat.f_fstypename.as_ptr())
+                    .to_string_lossy()
+                    .into_owned(),
+            )
+        }
+    }
+
+    pub(super) fn copy_file_range(_source: &File, _dest: &File) -> io::Result<()> {
+        Err(super::unsupported("copy_file_range is"))
+    }
+
+    pub(super) fn holes_kept(file: &File) -> io::Result<()> {
+        let metadata = file.metadata()?;
+        match metadata.blocks() * 512 < metadata.len() {
+            true => Ok(()),
+            false => Err(io::Error::other("holes take up space")),
+        }
+    }
+
+    pub(super) fn set_xattr(file: &File) -> io::Result<()> {
+        const NAME: &[u8] = b"user.cpv.doctor\0";
+
+        // SAFETY: the name is NUL-terminated, the value length is accurate
+        // and the position is 0, as for anything but resource forks.
+        let rc = unsafe {
+            libc::fsetxattr(
+                file.as_raw_fd(),
+                NAME.as_ptr().cast(),
+                b"1".as_ptr().cast(),
+                1,
+                0,
+                0,
+            )
+        };
+        match rc {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    /// ACLs go with extended attributes on macOS, where `copyfile(3)`
+    /// copies both.
+    pub(super) fn acls(file: &File) -> io::Result<()> {
+        set_xattr(file)
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use std::fs::File;
+    use std::io;
+    use std::os::windows::ffi::OsStrExt;
+    use std::path::{Component, Path};
+    use std::ptr;
+    use windows_sys::Win32::Storage::FileSystem::GetVolumeInformationW;
+
+    pub(super) fn filesystem(path: &Path) -> Option<String> {
+        let path = std::fs::canonicalize(path).ok()?;
+        let Some(Component::Prefix(prefix)) = path.components().next() else {
+            return None;
+        };
+        let mut root = prefix.as_os_str().to_os_string();
+        root.push("\\");
+        let root: Vec<u16> = root.encode_wide().chain(Some(0)).collect();
+        let mut name = [0u16; 32];
+        // SAFETY: `root` is a NUL-terminated wide string and `name` a
+        // writable buffer of the length given.
+        let found = unsafe {
+            GetVolumeInformationW(
+                root.as_ptr(),
+                ptr::null_mut(),
+                0,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+                name.as_mut_ptr(),
+                name.len() as u32,
+            )
+        };
+        let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+        (found != 0).then(|| String::from_utf16_lossy(&name[..len]))
+    }
+
+    pub(super) fn copy_file_range(_source: &File, _dest: &File) -> io::Result<()> {
+        Err(super::unsupported("copy_file_range is"))
+    }
+
+    pub(super) fn holes_kept(_file: &File) -> io::Result<()> {
+        Err(super::unsupported("sparse copies are"))
+    }
+
+    pub(super) fn set_xattr(_file: &File) -> io::Result<()> {
+        Err(super::unsupported("extended attributes are"))
+    }
+
+    pub(super) fn acls(_file: &File) -> io::Result<()> {
+        Err(super::unsupported("keeping ACLs is"))
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+mod sys {
+    use std::fs::File;
+    use std::io;
+    use std::path::Path;
+
+    pub(super) fn filesystem(_path: &Path) -> Option<String> {
+        None
+    }
+
+    pub(super) fn copy_file_range(_source: &File, _dest: &File) -> io::Result<()> {
+        Err(super::unsupported("copy_file_range is"))
+    }
+
+    pub(super) fn holes_kept(_file: &File) -> io::Result<()> {
+        Err(super::unsupported("sparse copies are"))
+    }
+
+    pub(super) fn set_xattr(_file: &File) -> io::Result<()> {
+        Err(super::unsupported("extended attributes are"))
+    }
+
+    pub(super) fn acls(_file: &File) -> io::Result<()> {
+        Err(super::unsupported("keeping ACLs is"))
+    }
+}
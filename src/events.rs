@@ -0,0 +1,134 @@
+//! Newline-delimited JSON progress events for `--progress-json`, for GUIs
+//! and scripts that wrap cpv.
+//!
+//! Every line is an object whose `event` field is one of:
+//!
+//! - `scan-started` and `scan-done` (`files`, `bytes`) around the count of
+//!   a recursive source;
+//! - `file-started` (`source`, `dest`) when a file is opened;
+//! - `bytes-progress` (`bytes`, `total`) every second;
+//! - `file-done` (`source`, `dest`, `bytes`, `skipped`) when a file is
+//!   complete;
+//! - `error` (`source` and `dest` for a file, `message`) when a file or the
+//!   whole copy fails;
+//! - `summary` (`files_copied`, `bytes_copied`, `files_failed`,
+//!   `duration_secs`) at the end of a successful copy.
+//!
+//! The same lines can go to several outputs at once, such as stderr and the
+//! clients of `--progress-socket`.
+
+use crate::progress::Ticker;
+use crate::ui::ProgressBar;
+use crate::CopyStats;
+use serde_json::{json, Value};
+use std::fmt;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Interval between `bytes-progress` events.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A stream of progress events, written to one or more outputs.
+pub struct JsonEvents {
+    outs: Mutex<Vec<Box<dyn Write + Send>>>,
+}
+
+impl fmt::Debug for JsonEvents {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JsonEvents").finish_non_exhaustive()
+    }
+}
+
+impl JsonEvents {
+    /// Writes events to `out`, one line each, flushed as they happen.
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        Self {
+            outs: Mutex::new(vec![Box::new(out)]),
+        }
+    }
+
+    /// Writes the same events to `out` as well.
+    pub fn with_output(self, out: impl Write + Send + 'static) -> Self {
+        self.outs.lock().unwrap().push(Box::new(out));
+        self
+    }
+
+    /// Writes an `event` with `fields`, which must be a JSON object. A
+    /// reader that went away does not stop the copy, so write errors are
+    /// ignored.
+    pub(crate) fn emit(&self, event: &str, fields: Value) {
+        // The event name leads, ahead of the fields in key order.
+        let mut line = format!("{{\"event\":{}", Value::from(event));
+        if let Value::Object(fields) = fields {
+            for (key, value) in fields {
+                line.push_str(&format!(",{}:{}", Value::from(key), value));
+            }
+        }
+        line.push('}');
+        line.push('\n');
+        for out in self.outs.lock().unwrap().iter_mut() {
+            let _ = out.write_all(line.as_bytes());
+            let _ = out.flush();
+        }
+    }
+
+    pub(crate) fn file_started(&self, source: &Path, dest: &Path) {
+        self.emit(
+            "file-started",
+            json!({ "source": source.to_string_lossy(), "dest": dest.to_string_lossy() }),
+        );
+    }
+
+    pub(crate) fn file_done(&self, source: &Path, dest: &Path, bytes: u64, skipped: bool) {
+        self.emit(
+            "file-done",
+            json!({
+                "source": source.to_string_lossy(),
+                "dest": dest.to_string_lossy(),
+                "bytes": bytes,
+                "skipped": skipped,
+            }),
+        );
+    }
+
+    pub(crate) fn file_error(&self, source: &Path, dest: &Path, err: &dyn fmt::Display) {
+        self.emit(
+            "error",
+            json!({
+                "source": source.to_string_lossy(),
+                "dest": dest.to_string_lossy(),
+                "message": err.to_string(),
+            }),
+        );
+    }
+
+    /// Reports the failure of the whole copy.
+    pub(crate) fn error(&self, err: &dyn fmt::Display) {
+        self.emit("error", json!({ "message": err.to_string() }));
+    }
+
+    pub(crate) fn summary(&self, stats: &CopyStats) {
+        self.emit(
+            "summary",
+            json!({
+                "files_copied": stats.files_copied,
+                "bytes_copied": stats.bytes_copied,
+                "files_failed": stats.failures.len(),
+                "duration_secs": stats.time_taken.as_secs_f64(),
+            }),
+        );
+    }
+
+    /// Emits `bytes-progress` events for `pb` until the ticker is dropped.
+    pub(crate) fn ticker(self: &Arc<Self>, pb: &ProgressBar) -> Ticker {
+        let events = Arc::clone(self);
+        Ticker::start(pb, PROGRESS_INTERVAL, move |pb| {
+            events.emit(
+                "bytes-progress",
+                json!({ "bytes": pb.position(), "total": pb.length().unwrap_or(0) }),
+            );
+        })
+    }
+}
@@ -0,0 +1,131 @@
+//! Exclude patterns, for `CopyOptions::exclude`: the entries of source
+//! directories whose names match a shell pattern are left out of the copy,
+//! as if the source did not have them. So are those of
+//! `CopyOptions::deselected`, by path.
+
+use crate::vfs::{FileInfo, Filesystem};
+use crate::CopyOptions;
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use walkdir::{DirEntry, WalkDir};
+
+/// Whether `name` matches the shell pattern `pattern`, in which `*` stands
+/// for any run of characters and `?` for any one.
+pub(crate) fn matches(pattern: &str, name: &str) -> bool {
+    let (pattern, name): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), name.chars().collect());
+    let (mut p, mut n) = (0, 0);
+    // Where the last `*` was, and the character of `name` it stopped at.
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    // The `*` takes one character more.
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Whether the entry `name` is excluded by one of `patterns`.
+pub(crate) fn excluded(patterns: &[String], name: &OsStr) -> bool {
+    let name = name.to_string_lossy();
+    patterns.iter().any(|pattern| matches(pattern, &name))
+}
+
+/// Whether the entry `name` of the source directory `dir` is left out of
+/// the copy by `options.exclude` or `options.deselected`, for walks of
+/// local sources.
+pub(crate) fn left_out(options: &CopyOptions, dir: &Path, name: &OsStr) -> bool {
+    excluded(&options.exclude, name)
+        || (!options.deselected.is_empty() && options.deselected.contains(&dir.join(name)))
+}
+
+/// The entries of `walk`, a walk of a local source, without those that
+/// `left_out` leaves out or what they hold.
+pub(crate) fn walk(
+    walk: WalkDir,
+    options: &CopyOptions,
+) -> impl Iterator<Item = walkdir::Result<DirEntry>> + '_ {
+    walk.into_iter().filter_entry(move |entry| {
+        entry.depth() == 0
+            || !left_out(
+                options,
+                entry.path().parent().unwrap_or(Path::new("")),
+                entry.file_name(),
+            )
+    })
+}
+
+/// The source filesystem `inner` without the entries whose names match
+/// `patterns` or whose paths are among `paths`, which its directories do
+/// not list.
+#[derive(Debug)]
+pub(crate) struct Excluded {
+    inner: Arc<dyn Filesystem>,
+    patterns: Vec<String>,
+    paths: HashSet<PathBuf>,
+}
+
+impl Excluded {
+    pub(crate) fn new(
+        inner: Arc<dyn Filesystem>,
+        patterns: Vec<String>,
+        paths: &[PathBuf],
+    ) -> Self {
+        Self {
+            inner,
+            patterns,
+            paths: paths.iter().cloned().collect(),
+        }
+    }
+}
+
+impl Filesystem for Excluded {
+    fn metadata(&self, path: &Path) -> io::Result<FileInfo> {
+        self.inner.metadata(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut children = self.inner.read_dir(path)?;
+        children.retain(|child| {
+            !self.paths.contains(child)
+                && child
+                    .file_name()
+                    .map_or(true, |name| !excluded(&self.patterns, name))
+        });
+        Ok(children)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        self.inner.open(path)
+    }
+
+    fn create(&self, _path: &Path) -> io::Result<Box<dyn Write + Send>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "exclude patterns apply to sources only",
+        ))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.inner.create_dir_all(path)
+    }
+}
@@ -0,0 +1,150 @@
+//! Sharing the extents of identical files after a copy, for
+//! `CopyOptions::share_extents`: a copy that could not reflink, e.g. from
+//! another filesystem, writes the data of a file again even where the
+//! destination already holds it, so once the copy is done each file copied
+//! is deduplicated with `FIDEDUPERANGE` against an identical file of the
+//! destination filesystem, found through the checksum cache, or copied
+//! before it. The kernel compares the contents before sharing them, so an
+//! outdated cache entry costs a read and nothing else.
+
+use crate::{CopyOptions, CopyStats};
+
+/// Shares the extents of the files `stats` recorded the checksums of, and
+/// counts those that were shared in `stats`.
+#[cfg(target_os = "linux")]
+pub(crate) fn share(options: &CopyOptions, stats: &mut CopyStats) {
+    use std::fs;
+    use std::io;
+    use std::os::unix::fs::MetadataExt;
+
+    let mut known = options
+        .checksum_cache
+        .as_ref()
+        .map(|cache| cache.files(options.checksum))
+        .unwrap_or_default();
+    for (dest, digest) in &stats.checksums {
+        let Ok(copy) = fs::metadata(dest) else {
+            continue;
+        };
+        let Ok(canonical) = fs::canonicalize(dest) else {
+            continue;
+        };
+        if copy.len() == 0 {
+            continue;
+        }
+        let others = known.entry(digest.clone()).or_default();
+        for other in others.iter() {
+            let same_filesystem = fs::metadata(other).is_ok_and(|found| {
+                found.is_file()
+                    && found.len() == copy.len()
+                    && found.dev() == copy.dev()
+                    && found.ino() != copy.ino()
+            });
+            if !same_filesystem {
+                continue;
+            }
+            match dedupe(other, dest, copy.len()) {
+                Ok(true) => {
+                    stats.files_shared += 1;
+                    stats.bytes_shared += copy.len();
+                    break;
+                }
+                Ok(false) => {}
+                Err(err)
+                    if matches!(
+                        err.raw_os_error(),
+                        Some(libc::EOPNOTSUPP | libc::ENOTTY | libc::EINVAL)
+                    ) =>
+                {
+                    stats.warnings.push(format!(
+                        "the filesystem of '{}' cannot share extents",
+                        dest.display()
+                    ));
+                    return;
+                }
+                Err(err) if err.kind() == io::ErrorKind::PermissionDenied => break,
+                Err(_) => {}
+            }
+        }
+        others.push(canonical);
+        if let Some(cache) = &options.checksum_cache {
+            let _ = cache.record(dest, options.checksum, digest);
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn share(_options: &CopyOptions, stats: &mut CopyStats) {
+    stats
+        .warnings
+        .push("sharing extents needs Linux (FIDEDUPERANGE)".to_string());
+}
+
+/// Shares the extents of the `len` bytes of `dest` with those of `source`,
+/// which must hold the same data. Returns whether all of them were shared,
+/// false if the files differ.
+#[cfg(target_os = "linux")]
+fn dedupe(source: &std::path::Path, dest: &std::path::Path, len: u64) -> std::io::Result<bool> {
+    use std::fs::{File, OpenOptions};
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+
+    /// `_IOWR(0x94, 54, struct file_dedupe_range)`, which libc lacks.
+    const FIDEDUPERANGE: u32 = 0xc018_9436;
+    /// The most a call is asked to share, as Btrfs shares no more at once.
+    const CHUNK: u64 = 16 * 1024 * 1024;
+    /// `FILE_DEDUPE_RANGE_DIFFERS`.
+    const DIFFERS: i32 = 1;
+
+    #[repr(C)]
+    struct Range {
+        src_offset: u64,
+        src_length: u64,
+        dest_count: u16,
+        reserved1: u16,
+        reserved2: u32,
+        info: [RangeInfo; 1],
+    }
+
+    #[repr(C)]
+    struct RangeInfo {
+        dest_fd: i64,
+        dest_offset: u64,
+        bytes_deduped: u64,
+        status: i32,
+        reserved: u32,
+    }
+
+    let source = File::open(source)?;
+    let dest = OpenOptions::new().write(true).open(dest)?;
+    let mut offset = 0;
+    while offset < len {
+        let mut range = Range {
+            src_offset: offset,
+            src_length: (len - offset).min(CHUNK),
+            dest_count: 1,
+            reserved1: 0,
+            reserved2: 0,
+            info: [RangeInfo {
+                dest_fd: i64::from(dest.as_raw_fd()),
+                dest_offset: offset,
+                bytes_deduped: 0,
+                status: 0,
+                reserved: 0,
+            }],
+        };
+        // SAFETY: `range` is a file_dedupe_range with room for the one
+        // destination it names, which the kernel fills in.
+        if unsafe { libc::ioctl(source.as_raw_fd(), FIDEDUPERANGE as _, &mut range) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let info = &range.info[0];
+        match info.status {
+            DIFFERS => return Ok(false),
+            status if status < 0 => return Err(io::Error::from_raw_os_error(-status)),
+            _ if info.bytes_deduped == 0 => return Ok(false),
+            _ => offset += info.bytes_deduped,
+        }
+    }
+    Ok(true)
+}
@@ -0,0 +1,311 @@
+//! Files that could not be copied with `keep_going`, kept with what was
+//! being done to them and the class of their error, so that callers can
+//! report them without parsing error messages, and the `--on-error` rules
+//! that pick what a failure of each class does to the copy.
+
+use crate::context::{self, IoOp};
+use crate::{retry, verify, CopyError};
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// What was being done to a file when it failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum FileOp {
+    /// Reading a source directory.
+    Scan,
+    /// Copying a file, or creating a directory or link.
+    Copy,
+    /// Reading a copy back with `verify`, which found it different from
+    /// its source.
+    Verify,
+}
+
+impl fmt::Display for FileOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Scan => "scan",
+            Self::Copy => "copy",
+            Self::Verify => "verify",
+        })
+    }
+}
+
+/// The kind of failure an error is, which `ErrorRules` pick the handling
+/// of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum ErrorClass {
+    /// Access to a source or destination was denied.
+    Permission,
+    /// A source went missing during the copy.
+    NotFound,
+    /// The destination ran out of space or quota, or cannot hold a file
+    /// that large.
+    DiskFull,
+    /// Data could not be read or written intact: I/O errors, and copies
+    /// that did not match their source.
+    ReadError,
+    /// Timeouts, stale handles and lost connections, which a network
+    /// filesystem may not repeat.
+    Transient,
+    /// The destination cannot hold the attributes, ownership or
+    /// permissions being preserved.
+    UnsupportedMetadata,
+    /// Anything else.
+    Other,
+}
+
+impl ErrorClass {
+    /// The names of the classes, as `from_str` takes them and `Display`
+    /// writes them.
+    pub const NAMES: &[&str] = &[
+        "permission",
+        "not-found",
+        "disk-full",
+        "read-error",
+        "transient",
+        "unsupported-metadata",
+        "other",
+    ];
+
+    /// The class of `err`.
+    pub fn of(err: &CopyError) -> Self {
+        match err {
+            CopyError::Io(err) => Self::of_io(err),
+            CopyError::Fs { op, source, .. } => Self::classify(source, Some(*op)),
+            CopyError::Walk(err) => err.io_error().map_or(Self::Other, Self::of_io),
+            _ => Self::Other,
+        }
+    }
+
+    /// The class of an I/O error, from its context if it has one.
+    pub(crate) fn of_io(err: &io::Error) -> Self {
+        let op = err
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<context::Context>())
+            .map(|context| context.op);
+        Self::classify(context::os_error(err), op)
+    }
+
+    fn classify(err: &io::Error, op: Option<IoOp>) -> Self {
+        let metadata = matches!(op, Some(IoOp::SetPermissions | IoOp::PreserveAttributes));
+        if verify::is_mismatch(err) {
+            return Self::ReadError;
+        }
+        match err.kind() {
+            io::ErrorKind::PermissionDenied => return Self::Permission,
+            io::ErrorKind::NotFound => return Self::NotFound,
+            io::ErrorKind::Unsupported if metadata => return Self::UnsupportedMetadata,
+            io::ErrorKind::TimedOut
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::BrokenPipe => return Self::Transient,
+            _ => {}
+        }
+        #[cfg(unix)]
+        match err.raw_os_error() {
+            Some(libc::EPERM | libc::EACCES | libc::EROFS) => return Self::Permission,
+            Some(libc::ENOSPC | libc::EDQUOT | libc::EFBIG) => return Self::DiskFull,
+            Some(libc::EIO) => return Self::ReadError,
+            Some(libc::EOPNOTSUPP | libc::ENOSYS | libc::EINVAL) if metadata => {
+                return Self::UnsupportedMetadata;
+            }
+            Some(libc::ENETDOWN | libc::ENETUNREACH | libc::EHOSTUNREACH) => {
+                return Self::Transient;
+            }
+            _ => {}
+        }
+        #[cfg(windows)]
+        {
+            use windows_sys::Win32::Foundation::{
+                ERROR_CRC, ERROR_DISK_FULL, ERROR_HANDLE_DISK_FULL, ERROR_NOT_SUPPORTED,
+            };
+            match err.raw_os_error().map(|code| code as u32) {
+                Some(ERROR_DISK_FULL | ERROR_HANDLE_DISK_FULL) => return Self::DiskFull,
+                Some(ERROR_CRC) => return Self::ReadError,
+                Some(ERROR_NOT_SUPPORTED) if metadata => return Self::UnsupportedMetadata,
+                _ => {}
+            }
+        }
+        match retry::is_transient(err) {
+            true => Self::Transient,
+            false => Self::Other,
+        }
+    }
+}
+
+impl FromStr for ErrorClass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "permission" => Ok(Self::Permission),
+            "not-found" => Ok(Self::NotFound),
+            "disk-full" => Ok(Self::DiskFull),
+            "read-error" => Ok(Self::ReadError),
+            "transient" => Ok(Self::Transient),
+            "unsupported-metadata" => Ok(Self::UnsupportedMetadata),
+            "other" => Ok(Self::Other),
+            _ => Err(format!(
+                "invalid error class '{}' (expected one of {})",
+                s,
+                Self::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ErrorClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Permission => "permission",
+            Self::NotFound => "not-found",
+            Self::DiskFull => "disk-full",
+            Self::ReadError => "read-error",
+            Self::Transient => "transient",
+            Self::UnsupportedMetadata => "unsupported-metadata",
+            Self::Other => "other",
+        })
+    }
+}
+
+/// What a failure of a class does to the copy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorAction {
+    /// Stop the copy, even with `keep_going`.
+    Abort,
+    /// Record the file as failed and carry on, even without `keep_going`.
+    Skip,
+    /// Copy the file again, up to `retries` times (at least once), then
+    /// skip it or stop as `keep_going` says.
+    Retry,
+}
+
+impl ErrorAction {
+    /// The names of the actions, as `from_str` takes them and `Display`
+    /// writes them.
+    pub const NAMES: &[&str] = &["abort", "skip", "retry"];
+}
+
+impl FromStr for ErrorAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "abort" => Ok(Self::Abort),
+            "skip" => Ok(Self::Skip),
+            "retry" => Ok(Self::Retry),
+            _ => Err(format!(
+                "invalid error action '{}' (expected abort, skip or retry)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ErrorAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Abort => "abort",
+            Self::Skip => "skip",
+            Self::Retry => "retry",
+        })
+    }
+}
+
+/// Parsed `--on-error` rules such as `permission=skip,disk-full=abort`:
+/// the action for failures of each class named. Failures of other classes
+/// are retried if transient, with `retries`, and skipped or not as
+/// `keep_going` says. A later rule for a class replaces an earlier one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ErrorRules {
+    rules: Vec<(ErrorClass, ErrorAction)>,
+}
+
+impl ErrorRules {
+    /// The action of the rule for `class`, if there is one.
+    pub fn action(&self, class: ErrorClass) -> Option<ErrorAction> {
+        self.rules
+            .iter()
+            .rev()
+            .find(|(ruled, _)| *ruled == class)
+            .map(|&(_, action)| action)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+}
+
+impl FromStr for ErrorRules {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rules = s
+            .split(',')
+            .map(|rule| {
+                let (class, action) = rule.split_once('=').ok_or_else(|| {
+                    format!("invalid error rule '{}' (expected CLASS=ACTION)", rule)
+                })?;
+                Ok((class.trim().parse()?, action.trim().parse()?))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self { rules })
+    }
+}
+
+/// The rules as a spec that parses back to them.
+impl fmt::Display for ErrorRules {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (class, action)) in self.rules.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}={}", class, action)?;
+        }
+        Ok(())
+    }
+}
+
+/// A source path that failed and was left out, in `CopyStats::failures`.
+#[derive(Error, Debug)]
+#[error("cannot {op} '{}': {source}", path.display())]
+pub struct FileError {
+    pub path: PathBuf,
+    pub op: FileOp,
+    pub class: ErrorClass,
+    pub source: CopyError,
+}
+
+impl FileError {
+    /// A directory of the source tree that could not be read.
+    pub(crate) fn scan(path: PathBuf, source: CopyError) -> Self {
+        Self {
+            path,
+            op: FileOp::Scan,
+            class: ErrorClass::of(&source),
+            source,
+        }
+    }
+
+    /// A source that could not be copied, or whose copy did not match.
+    pub(crate) fn copy(path: PathBuf, source: CopyError) -> Self {
+        let op = match &source {
+            CopyError::Io(err) if verify::is_mismatch(err) => FileOp::Verify,
+            _ => FileOp::Copy,
+        };
+        Self {
+            path,
+            op,
+            class: ErrorClass::of(&source),
+            source,
+        }
+    }
+}
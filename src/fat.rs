@@ -0,0 +1,175 @@
+//! FAT and exFAT destinations, as on memory cards and USB sticks, for
+//! `CopyOptions::fat_compat`: names with characters these filesystems
+//! reject are written with `_` in their place, modification times are
+//! rounded up to the 2 seconds FAT keeps so that a later copy does not find
+//! every file changed, and permissions, which they have no room for, are
+//! left out.
+
+use crate::exclude;
+use crate::CopyOptions;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use walkdir::WalkDir;
+
+/// Characters FAT and exFAT do not allow in names, besides control ones.
+const RESERVED: [char; 9] = ['"', '*', '/', ':', '<', '>', '?', '\\', '|'];
+
+/// The resolution of the modification times FAT keeps.
+pub(crate) const TIME_RESOLUTION: Duration = Duration::from_secs(2);
+
+/// Whether `path`, or the nearest existing ancestor of it, is on a FAT or
+/// exFAT filesystem.
+pub(crate) fn is_fat(path: &Path) -> bool {
+    path.ancestors()
+        .map(|p| {
+            if p.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                p
+            }
+        })
+        .find(|p| p.exists())
+        .is_some_and(sys::is_fat)
+}
+
+/// `name` as FAT can hold it: reserved and control characters, and the
+/// dots and spaces it would drop from the end, replaced with `_`. Names
+/// that are not valid UTF-8 are converted lossily.
+pub(crate) fn sanitize_name(name: &OsStr) -> OsString {
+    let mut sanitized: String = name
+        .to_string_lossy()
+        .chars()
+        .map(|c| match c.is_control() || RESERVED.contains(&c) {
+            true => '_',
+            false => c,
+        })
+        .collect();
+    let kept = sanitized.trim_end_matches(['.', ' ']).len();
+    let trailing = sanitized.len() - kept;
+    sanitized.replace_range(kept.., &"_".repeat(trailing));
+    sanitized.into()
+}
+
+/// `time` rounded up to the resolution of FAT, which would otherwise round
+/// it down, leaving the copy older than its source.
+pub(crate) fn round_time(time: SystemTime) -> SystemTime {
+    let Ok(since) = time.duration_since(UNIX_EPOCH) else {
+        return time;
+    };
+    let secs = since.as_secs() + u64::from(since.subsec_nanos() > 0);
+    UNIX_EPOCH + Duration::from_secs(secs.next_multiple_of(TIME_RESOLUTION.as_secs()))
+}
+
+/// The entries of the tree `source`, itself included if it is copied under
+/// its name, whose names are changed for FAT, with the names they get.
+pub(crate) fn renamed(
+    source: &Path,
+    named: bool,
+    options: &CopyOptions,
+) -> Vec<(PathBuf, PathBuf)> {
+    exclude::walk(WalkDir::new(source).min_depth(usize::from(!named)), options)
+        .flatten()
+        .filter_map(|entry| {
+            let name = options.normalization.normalize_name(entry.file_name());
+            let sanitized = sanitize_name(&name);
+            (sanitized != name).then(|| (entry.into_path(), sanitized.into()))
+        })
+        .collect()
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::ffi::CString;
+    use std::mem;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    /// `statfs` magic numbers of FAT (vfat and msdos) and exFAT.
+    const FAT_MAGIC: [u64; 2] = [0x4d44, 0x2011_bab0];
+
+    pub(super) fn is_fat(path: &Path) -> bool {
+        let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+            return false;
+        };
+        // SAFETY: `path` is NUL-terminated and `stat` is a writable statfs.
+        unsafe {
+            let mut stat: libc::statfs = mem::zeroed();
+            libc::statfs(path.as_ptr(), &mut stat) == 0 && FAT_MAGIC.contains(&(stat.f_type as u64))
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod sys {
+    use std::ffi::{CStr, CString};
+    use std::mem;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    pub(super) fn is_fat(path: &Path) -> bool {
+        let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+            return false;
+        };
+        // SAFETY: `path` is NUL-terminated, `stat` is a writable statfs and
+        // f_fstypename is NUL-terminated by the kernel.
+        unsafe {
+            let mut stat: libc::statfs = mem::zeroed();
+            libc::statfs(path.as_ptr(), &mut stat) == 0
+                && matches!(
+                    CStr::from_ptr(stat.f_fstypename.as_ptr()).to_bytes(),
+                    b"msdos" | b"exfat"
+                )
+        }
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use std::os::windows::ffi::OsStrExt;
+    use std::path::{Component, Path};
+    use std::ptr;
+    use windows_sys::Win32::Storage::FileSystem::GetVolumeInformationW;
+
+    pub(super) fn is_fat(path: &Path) -> bool {
+        let Ok(path) = std::fs::canonicalize(path) else {
+            return false;
+        };
+        let Some(Component::Prefix(prefix)) = path.components().next() else {
+            return false;
+        };
+        let mut root = prefix.as_os_str().to_os_string();
+        root.push("\\");
+        let root: Vec<u16> = root.encode_wide().chain(Some(0)).collect();
+        let mut name = [0u16; 32];
+        // SAFETY: `root` is a NUL-terminated wide string and `name` a
+        // writable buffer of the length given.
+        let found = unsafe {
+            GetVolumeInformationW(
+                root.as_ptr(),
+                ptr::null_mut(),
+                0,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+                name.as_mut_ptr(),
+                name.len() as u32,
+            )
+        };
+        let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+        found != 0
+            && matches!(
+                String::from_utf16_lossy(&name[..len]).as_str(),
+                "FAT" | "FAT32" | "exFAT"
+            )
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+mod sys {
+    use std::path::Path;
+
+    pub(super) fn is_fat(_path: &Path) -> bool {
+        false
+    }
+}
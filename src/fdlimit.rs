@@ -0,0 +1,120 @@
+//! The file descriptors a copy may use. With parallel workers and deep
+//! trees, a recursive copy can hold many files and directories open at
+//! once, so the limit on open files (`RLIMIT_NOFILE` on Unix) is raised as
+//! far as allowed and what remains free is split between the workers, the
+//! files queued for them and the directories the walk holds open, instead
+//! of failing with `EMFILE` halfway through.
+
+/// Descriptors left for what a copy opens besides its files: standard
+/// streams, the journal, logs and sockets.
+const RESERVED: usize = 64;
+
+/// Descriptors a worker holds while copying a file: the source, the
+/// destination or its `.part` file, and a file read back to verify it.
+const PER_WORKER: usize = 4;
+
+/// Directories a queued file or a level of the walk keeps open: its source
+/// and target parents.
+const PER_LEVEL: usize = 2;
+
+/// The descriptors free for the files and directories of a copy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Budget {
+    free: usize,
+}
+
+impl Budget {
+    pub(crate) fn new(free: usize) -> Self {
+        Self { free }
+    }
+
+    /// The descriptors free now, after raising the limit on open files.
+    pub(crate) fn current() -> Self {
+        Self::new(free().saturating_sub(RESERVED))
+    }
+
+    /// How many of `wanted` workers fit in half of the budget, at least one.
+    pub(crate) fn workers(self, wanted: usize) -> usize {
+        (self.free / 2 / PER_WORKER).clamp(1, wanted.max(1))
+    }
+
+    /// How many files may wait for `workers`, up to `most`, in a quarter of
+    /// the budget, each holding its parent directories open.
+    pub(crate) fn queue(self, workers: usize, most: usize) -> usize {
+        (self.free / 4 / PER_LEVEL).clamp(workers.min(most), most)
+    }
+
+    /// How many levels of a tree the walk may hold open, in the last
+    /// quarter of the budget.
+    #[cfg(unix)]
+    pub(crate) fn open_levels(self) -> usize {
+        (self.free / 4 / PER_LEVEL).max(1)
+    }
+}
+
+/// The descriptors this process may still open.
+#[cfg(unix)]
+fn free() -> usize {
+    raise();
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: `limit` is a valid rlimit to fill in.
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0
+        || limit.rlim_cur == libc::RLIM_INFINITY
+    {
+        return usize::MAX;
+    }
+    let limit = usize::try_from(limit.rlim_cur).unwrap_or(usize::MAX);
+    limit.saturating_sub(open())
+}
+
+/// Where no limit applies to open files.
+#[cfg(not(unix))]
+fn free() -> usize {
+    usize::MAX
+}
+
+/// Raises the soft limit on open files to the hard one, once per process.
+/// macOS refuses more than `OPEN_MAX` whatever the hard limit, so that is
+/// tried next.
+#[cfg(unix)]
+fn raise() {
+    use std::sync::Once;
+
+    static RAISE: Once = Once::new();
+    RAISE.call_once(|| {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        // SAFETY: `limit` is a valid rlimit to fill in, then to set.
+        unsafe {
+            if libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) != 0
+                || limit.rlim_cur >= limit.rlim_max
+            {
+                return;
+            }
+            let soft = limit.rlim_cur;
+            limit.rlim_cur = limit.rlim_max;
+            if libc::setrlimit(libc::RLIMIT_NOFILE, &limit) != 0 {
+                limit.rlim_cur = limit.rlim_max.min(10240).max(soft);
+                libc::setrlimit(libc::RLIMIT_NOFILE, &limit);
+            }
+        }
+    });
+}
+
+/// The descriptors open in this process, or `RESERVED` where they cannot
+/// be listed.
+#[cfg(unix)]
+fn open() -> usize {
+    let dir = if cfg!(target_os = "linux") {
+        "/proc/self/fd"
+    } else {
+        "/dev/fd"
+    };
+    // Listing opens one more, which is not counted.
+    std::fs::read_dir(dir).map_or(RESERVED, |entries| entries.count().saturating_sub(1))
+}
@@ -0,0 +1,255 @@
+//! A C interface to the copy engine, for applications not written in Rust
+//! that would otherwise run cpv and scrape its output. Built as a shared
+//! library with the `ffi` feature; `include/cpv.h` declares it.
+//!
+//! Options are passed as the JSON form of `CopyOptions`, so that the C
+//! side does not have to follow every option added here.
+
+use crate::{
+    copy, CancellationToken, CopyOptions, CopyOutcome, CopyStats, ExitStatus, ProgressMode,
+    ProgressReporter,
+};
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Every file was copied.
+pub const CPV_OK: c_int = 0;
+/// The copy stopped at an error.
+pub const CPV_FAILED: c_int = 1;
+/// Some files could not be copied with `keep_going` and the rest were.
+pub const CPV_PARTIAL: c_int = 2;
+/// A copy did not match its source with `verify`.
+pub const CPV_VERIFY_FAILED: c_int = 3;
+/// The progress callback asked to stop.
+pub const CPV_INTERRUPTED: c_int = 4;
+/// A path was not valid, or the options could not be read.
+pub const CPV_INVALID_ARGUMENT: c_int = -1;
+
+/// Called with the bytes copied so far and the bytes to copy in total,
+/// from the threads doing the copy, and again as each file ends.
+/// Returning nonzero cancels the copy.
+pub type CpvProgressFn =
+    Option<unsafe extern "C" fn(done: u64, total: u64, user_data: *mut c_void) -> c_int>;
+
+/// The statistics of a copy, filled in by `cpv_copy`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CpvStats {
+    pub bytes_copied: u64,
+    pub files_copied: u64,
+    pub files_skipped: u64,
+    pub files_failed: u64,
+    pub dirs_created: u64,
+    pub elapsed_ms: u64,
+}
+
+impl CpvStats {
+    fn new(stats: &CopyStats, failed: usize) -> Self {
+        Self {
+            bytes_copied: stats.bytes_copied,
+            files_copied: stats.files_copied as u64,
+            files_skipped: stats.files_skipped as u64,
+            files_failed: failed as u64,
+            dirs_created: stats.dirs_created as u64,
+            elapsed_ms: stats.time_taken.as_millis() as u64,
+        }
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: impl ToString) {
+    // An interior NUL cannot be passed on; the message is cut there.
+    let mut message = message.to_string().into_bytes();
+    message.truncate(
+        message
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(message.len()),
+    );
+    let message = CString::new(message).expect("NULs removed");
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// Hands the byte progress to the C callback, as it comes and again as
+/// each file ends, and cancels the copy when the callback asks to.
+struct Callback {
+    progress: unsafe extern "C" fn(u64, u64, *mut c_void) -> c_int,
+    user_data: *mut c_void,
+    cancel: CancellationToken,
+    /// The last byte counts reported.
+    done: AtomicU64,
+    total: AtomicU64,
+}
+
+impl Callback {
+    fn call(&self) {
+        let done = self.done.load(Ordering::Relaxed);
+        let total = self.total.load(Ordering::Relaxed);
+        // SAFETY: see `cpv_copy`.
+        if unsafe { (self.progress)(done, total, self.user_data) } != 0 {
+            self.cancel.cancel();
+        }
+    }
+}
+
+// SAFETY: the caller of `cpv_copy` is told that the callback is called
+// from other threads, with the `user_data` it gave.
+unsafe impl Send for Callback {}
+unsafe impl Sync for Callback {}
+
+impl std::fmt::Debug for Callback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Callback").finish_non_exhaustive()
+    }
+}
+
+impl ProgressReporter for Callback {
+    fn on_bytes(&self, done: u64, total: u64) {
+        self.done.store(done, Ordering::Relaxed);
+        self.total.store(total, Ordering::Relaxed);
+        self.call();
+    }
+
+    fn on_file_done(&self, _source: &Path, _dest: &Path, _bytes: u64) {
+        self.call();
+    }
+}
+
+/// # Safety
+///
+/// `ptr` must be NULL or point to a NUL-terminated string.
+unsafe fn path(ptr: *const c_char, what: &str) -> Result<PathBuf, String> {
+    if ptr.is_null() {
+        return Err(format!("{} is NULL", what));
+    }
+    let bytes = CStr::from_ptr(ptr).to_bytes();
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        Ok(std::ffi::OsStr::from_bytes(bytes).into())
+    }
+    #[cfg(not(unix))]
+    std::str::from_utf8(bytes)
+        .map(PathBuf::from)
+        .map_err(|_| format!("{} is not valid UTF-8", what))
+}
+
+/// The options of the JSON object at `ptr`, or the defaults for NULL.
+/// Progress is not drawn unless `progress` says otherwise.
+///
+/// # Safety
+///
+/// `ptr` must be NULL or point to a NUL-terminated string.
+unsafe fn options(ptr: *const c_char) -> Result<CopyOptions, String> {
+    let mut json = serde_json::Value::Object(Default::default());
+    if !ptr.is_null() {
+        let text = CStr::from_ptr(ptr)
+            .to_str()
+            .map_err(|_| "options are not valid UTF-8".to_string())?;
+        json = serde_json::from_str(text).map_err(|err| format!("invalid options: {}", err))?;
+    }
+    let Some(fields) = json.as_object_mut() else {
+        return Err("invalid options: expected a JSON object".to_string());
+    };
+    fields
+        .entry("progress")
+        .or_insert_with(|| ProgressMode::Hidden.to_string().into());
+    serde_json::from_value(json).map_err(|err| format!("invalid options: {}", err))
+}
+
+/// Copies `source` to `dest` with the options of the JSON object
+/// `options_json`, like `cpv::copy`, and returns one of the `CPV_` codes.
+///
+/// `options_json` may be NULL for the defaults, `progress` NULL for no
+/// callback and `stats` NULL when the statistics are not wanted. On any
+/// code but `CPV_OK`, `cpv_last_error` says what went wrong.
+///
+/// # Safety
+///
+/// `source`, `dest` and `options_json` must be NULL or NUL-terminated
+/// strings, and `stats` NULL or valid for writes. `progress` is called
+/// from the threads doing the copy, with `user_data`, until `cpv_copy`
+/// returns.
+#[no_mangle]
+pub unsafe extern "C" fn cpv_copy(
+    source: *const c_char,
+    dest: *const c_char,
+    options_json: *const c_char,
+    progress: CpvProgressFn,
+    user_data: *mut c_void,
+    stats: *mut CpvStats,
+) -> c_int {
+    LAST_ERROR.with(|last| *last.borrow_mut() = None);
+    let args = path(source, "source")
+        .and_then(|source| Ok((source, path(dest, "dest")?, options(options_json)?)));
+    let (source, dest, mut options) = match args {
+        Ok(args) => args,
+        Err(message) => {
+            set_last_error(message);
+            return CPV_INVALID_ARGUMENT;
+        }
+    };
+    if let Some(progress) = progress {
+        let cancel = options.cancel.get_or_insert_with(CancellationToken::new);
+        options.reporter = Some(Arc::new(Callback {
+            progress,
+            user_data,
+            cancel: cancel.clone(),
+            done: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+        }));
+    }
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| copy(&source, &dest, &options)));
+    let outcome = match result {
+        Ok(Ok(outcome)) => outcome,
+        Ok(Err(err)) => {
+            set_last_error(&err);
+            return ExitStatus::of(&Err(err)).exit_code();
+        }
+        Err(_) => {
+            set_last_error("the copy panicked");
+            return CPV_FAILED;
+        }
+    };
+    if !stats.is_null() {
+        let failed = match &outcome {
+            CopyOutcome::Partial { errors, .. } => errors.len(),
+            _ => 0,
+        };
+        *stats = CpvStats::new(outcome.stats(), failed);
+    }
+    match &outcome {
+        CopyOutcome::Partial { errors, .. } => {
+            let more = errors.len() - 1;
+            match more {
+                0 => set_last_error(&errors[0]),
+                _ => set_last_error(format!("{} (and {} more)", errors[0], more)),
+            }
+        }
+        CopyOutcome::Cancelled { .. } => set_last_error("copy cancelled"),
+        CopyOutcome::Completed(_) => {}
+    }
+    outcome.exit_status().exit_code()
+}
+
+/// The message of the last error `cpv_copy` reported on this thread, or
+/// NULL. It stays valid until the next call to `cpv_copy` on the thread.
+#[no_mangle]
+pub extern "C" fn cpv_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |s| s.as_ptr()))
+}
+
+/// The version of cpv, as a static string.
+#[no_mangle]
+pub extern "C" fn cpv_version() -> *const c_char {
+    concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr().cast()
+}
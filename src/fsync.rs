@@ -0,0 +1,103 @@
+//! Durability of copied files for `--sync`.
+
+use crate::location::Location;
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+/// What is flushed to the device before a file counts as copied.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SyncMode {
+    /// Leave write-back to the operating system.
+    #[default]
+    None,
+    /// Flush each destination file's data and metadata.
+    File,
+    /// Flush each file and then its parent directory, so the new directory
+    /// entry survives a power loss too.
+    Dir,
+}
+
+impl SyncMode {
+    /// The names of the modes, as `from_str` takes them and `Display`
+    /// writes them.
+    pub const NAMES: &[&str] = &["none", "file", "dir"];
+}
+
+impl FromStr for SyncMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "file" => Ok(Self::File),
+            "dir" => Ok(Self::Dir),
+            _ => Err(format!(
+                "invalid sync mode '{}' (expected file, dir or none)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SyncMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::None => "none",
+            Self::File => "file",
+            Self::Dir => "dir",
+        })
+    }
+}
+
+/// Flushes `file`, written at `dest`, as `mode` requires.
+pub(crate) fn sync(mode: SyncMode, file: &File, dest: &Location) -> io::Result<()> {
+    if mode == SyncMode::None {
+        return Ok(());
+    }
+    file.sync_all()?;
+    if mode == SyncMode::Dir {
+        #[cfg(unix)]
+        if let Some(parent) = dest.parent() {
+            return parent.as_file().sync_all();
+        }
+        if let Some(parent) = dest.path().parent() {
+            sync_dir(if parent.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                parent
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Flushes the file written at `path`, if it is still there. Links are
+/// left as they are.
+pub(crate) fn sync_path(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_file() => {}
+        Ok(_) => return Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    }
+    // Windows flushes only files open for writing.
+    #[cfg(windows)]
+    let file = File::options().write(true).open(path)?;
+    #[cfg(not(windows))]
+    let file = File::open(path)?;
+    file.sync_all()
+}
+
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> io::Result<()> {
+    File::open(path)?.sync_all()
+}
+
+/// Directory entries are flushed with the file's metadata on Windows.
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,136 @@
+//! Load-adaptive throttling, for `CopyOptions::adaptive_throttle`: the
+//! I/O pressure of the system (PSI, in `/proc/pressure/io` on Linux) is
+//! sampled every second while copying. When tasks spend a noticeable share
+//! of their time stalled on I/O, as an interactive workload competing with
+//! the copy does, the copy halves its bandwidth; once the pressure is gone
+//! it ramps back up, until it runs unthrottled again.
+
+use crate::limit::{RateLimiter, TokenBucket};
+use std::fmt;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Where Linux reports the I/O pressure of the system.
+const PRESSURE_PATH: &str = "/proc/pressure/io";
+
+/// How often the pressure is sampled.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The share of time some task stalled on I/O above which the copy backs
+/// off, and below which it speeds up again.
+const HIGH_PRESSURE: f64 = 0.10;
+const LOW_PRESSURE: f64 = 0.02;
+
+/// The bandwidth the copy never backs off below, in bytes per second.
+const FLOOR: f64 = 1024.0 * 1024.0;
+
+/// How much the bandwidth grows each second without pressure.
+const RAMP_UP: f64 = 1.25;
+
+/// The microseconds some task has stalled on I/O since boot, from the
+/// `some` line of `/proc/pressure/io`.
+pub(crate) fn stalled(pressure: &str) -> Option<u64> {
+    pressure
+        .lines()
+        .find_map(|line| line.strip_prefix("some "))?
+        .split_whitespace()
+        .find_map(|field| field.strip_prefix("total="))?
+        .parse()
+        .ok()
+}
+
+fn read_stalled() -> Option<u64> {
+    stalled(&fs::read_to_string(PRESSURE_PATH).ok()?)
+}
+
+/// The bandwidth limit after a second in which tasks stalled for the share
+/// `stall` of the time and the copy moved `throughput` bytes per second
+/// under `limit`, `None` being no limit.
+pub(crate) fn next_limit(limit: Option<f64>, stall: f64, throughput: f64) -> Option<f64> {
+    if stall > HIGH_PRESSURE {
+        let current = limit.map_or(throughput, |limit| limit.min(throughput));
+        return Some((current / 2.0).max(FLOOR));
+    }
+    match limit {
+        // A limit the copy does not reach is lifted.
+        Some(limit) if stall < LOW_PRESSURE && limit > 2.0 * throughput.max(FLOOR) => None,
+        Some(limit) if stall < LOW_PRESSURE => Some(limit * RAMP_UP),
+        limit => limit,
+    }
+}
+
+/// Throttles with `inner`, if any, then to the bandwidth the pressure of
+/// the system allows.
+pub(crate) struct Governor {
+    inner: Option<Arc<dyn RateLimiter>>,
+    state: Mutex<GovernorState>,
+}
+
+struct GovernorState {
+    /// When the pressure was last sampled, and the stall time then.
+    sampled: Instant,
+    stalled: u64,
+    /// Bytes copied since.
+    bytes: u64,
+    limit: Option<f64>,
+    bucket: Option<Arc<TokenBucket>>,
+}
+
+impl Governor {
+    /// Starts governing where the pressure of the system can be read, and
+    /// not otherwise.
+    pub(crate) fn start(inner: Option<Arc<dyn RateLimiter>>) -> Option<Self> {
+        let stalled = read_stalled()?;
+        Some(Self {
+            inner,
+            state: Mutex::new(GovernorState {
+                sampled: Instant::now(),
+                stalled,
+                bytes: 0,
+                limit: None,
+                bucket: None,
+            }),
+        })
+    }
+}
+
+impl fmt::Debug for Governor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Governor")
+            .field("inner", &self.inner)
+            .field("limit", &self.state.lock().unwrap().limit)
+            .finish()
+    }
+}
+
+impl RateLimiter for Governor {
+    fn acquire(&self, bytes: u64) {
+        if let Some(inner) = &self.inner {
+            inner.acquire(bytes);
+        }
+        let bucket = {
+            let mut state = self.state.lock().unwrap();
+            state.bytes += bytes;
+            let elapsed = state.sampled.elapsed();
+            if elapsed >= SAMPLE_INTERVAL {
+                if let Some(stalled) = read_stalled() {
+                    let secs = elapsed.as_secs_f64();
+                    let stall = stalled.saturating_sub(state.stalled) as f64 / 1e6 / secs;
+                    let limit = next_limit(state.limit, stall, state.bytes as f64 / secs);
+                    if limit != state.limit {
+                        state.bucket = limit.map(|limit| Arc::new(TokenBucket::new(limit as u64)));
+                        state.limit = limit;
+                    }
+                    state.stalled = stalled;
+                }
+                state.sampled = Instant::now();
+                state.bytes = 0;
+            }
+            state.bucket.clone()
+        };
+        if let Some(bucket) = bucket {
+            bucket.acquire(bytes);
+        }
+    }
+}
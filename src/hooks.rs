@@ -0,0 +1,72 @@
+//! Callbacks that let applications embedding cpv decide about individual
+//! files and observe their outcome, without reimplementing the traversal.
+
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+/// What to do with a file, as decided by `CopyHooks`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FileDecision {
+    /// Copy it, doing with an existing destination what
+    /// `CopyOptions::overwrite` says.
+    #[default]
+    Copy,
+    /// Leave it out, counting it in `CopyStats::files_declined`.
+    Skip,
+    /// Fail it, which stops the copy unless `keep_going` is set.
+    Fail,
+}
+
+/// Callbacks for the files of a copy, set in `CopyOptions::hooks`.
+///
+/// Every method has a default that leaves the copy as it would be without
+/// hooks. They are called from the threads doing the copy, possibly
+/// several at once.
+pub trait CopyHooks: fmt::Debug + Send + Sync {
+    /// Called before `source` is copied to `dest`.
+    fn on_file_start(&self, source: &Path, dest: &Path) -> FileDecision {
+        let _ = (source, dest);
+        FileDecision::Copy
+    }
+
+    /// Called when `dest` already holds a file, after `on_file_start`
+    /// allowed the copy. `Skip` and `Fail` take precedence over
+    /// `CopyOptions::overwrite`; `Copy` leaves the file to it, and replaces
+    /// it under `OverwritePolicy::Prompt`.
+    fn on_conflict(&self, source: &Path, dest: &Path) -> FileDecision {
+        let _ = (source, dest);
+        FileDecision::Copy
+    }
+
+    /// Called once `source` is at `dest`, with its size and the time the
+    /// copy took, retries included.
+    fn on_file_complete(&self, source: &Path, dest: &Path, bytes: u64, duration: Duration) {
+        let _ = (source, dest, bytes, duration);
+    }
+
+    /// Called when `source` could not be copied, after any retries, but not
+    /// for files failed by the hooks themselves.
+    fn on_error(&self, source: &Path, dest: &Path, err: &io::Error) {
+        let _ = (source, dest, err);
+    }
+
+    /// Called with `CopyOptions::span` when the destination tree at `dest`
+    /// has filled volume `volume`, counting from 1, with no file open on
+    /// it. Returns `true` once the next volume is mounted in its place, to
+    /// continue the copy there, or `false` to fail the file that did not
+    /// fit, as without spanning.
+    fn on_volume_full(&self, dest: &Path, volume: u32) -> bool {
+        let _ = (dest, volume);
+        false
+    }
+}
+
+/// The error of a file failed by `on_file_start`.
+pub(crate) fn refused(source: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("'{}' was refused by a copy hook", source.display()),
+    )
+}
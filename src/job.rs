@@ -0,0 +1,90 @@
+//! `CopyJob`, which runs a copy on its own thread and hands its progress
+//! over as `CopyEvent`s pulled by the caller, for TUIs and GUIs that poll
+//! from their event loop instead of taking callbacks.
+
+use crate::reporter::EventReporter;
+use crate::{copy_with_progress, CancellationToken, CopyError, CopyEvent, CopyOptions, CopyStats};
+use std::panic;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// A copy running on a thread of its own.
+///
+/// Iterating over it blocks for each event and ends once the copy is
+/// over; `finish` then returns its statistics.
+#[derive(Debug)]
+pub struct CopyJob {
+    events: mpsc::Receiver<CopyEvent>,
+    copy: JoinHandle<Result<CopyStats, CopyError>>,
+    cancel: CancellationToken,
+}
+
+impl CopyJob {
+    /// Starts copying `source` to `dest` with `options`.
+    /// `options.reporter` is replaced by the events.
+    pub fn start(
+        source: impl Into<PathBuf>,
+        dest: impl Into<PathBuf>,
+        mut options: CopyOptions,
+    ) -> Self {
+        let (source, dest) = (source.into(), dest.into());
+        let (sender, events) = mpsc::channel();
+        options.reporter = Some(Arc::new(EventReporter(move |event| {
+            // The job may be finished without reading its events.
+            let _ = sender.send(event);
+        })));
+        let cancel = options
+            .cancel
+            .get_or_insert_with(CancellationToken::new)
+            .clone();
+        let copy = thread::spawn(move || copy_with_progress(&source, &dest, &options));
+        Self {
+            events,
+            copy,
+            cancel,
+        }
+    }
+
+    /// The next event if there is one, without waiting.
+    pub fn try_next(&self) -> Option<CopyEvent> {
+        self.events.try_recv().ok()
+    }
+
+    /// The next event, waiting at most `timeout` for it. `None` once the
+    /// timeout passes or the copy is over.
+    pub fn next_timeout(&self, timeout: Duration) -> Option<CopyEvent> {
+        self.events.recv_timeout(timeout).ok()
+    }
+
+    /// Asks the copy to stop, after which `finish` returns
+    /// `CopyError::Cancelled`.
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    /// Whether the copy is over. Events may remain to be read.
+    pub fn is_finished(&self) -> bool {
+        self.copy.is_finished()
+    }
+
+    /// Waits for the copy to end, discarding the events not read.
+    pub fn finish(self) -> Result<CopyStats, CopyError> {
+        drop(self.events);
+        self.copy
+            .join()
+            .unwrap_or_else(|panic| panic::resume_unwind(panic))
+    }
+}
+
+impl Iterator for CopyJob {
+    type Item = CopyEvent;
+
+    /// The next event, waiting for it, or `None` once the copy is over and
+    /// every event has been read.
+    fn next(&mut self) -> Option<CopyEvent> {
+        self.events.recv().ok()
+    }
+}
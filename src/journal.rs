@@ -0,0 +1,407 @@
+//! The journal of finished files that lets `--resume` skip them when an
+//! interrupted recursive copy is restarted.
+//!
+//! The journal lives in the per-user state directory while the copy runs,
+//! named after the destination root, and is removed once it succeeds. It
+//! is kept out of the tree copied, where a source file of the same name
+//! would be copied onto it and then removed with it. It starts with a version line and a
+//! `source path` line naming the tree being copied, followed by one
+//! `len mtime path` line per finished file, appended as each file is done,
+//! so a copy killed at any point leaves at worst a truncated last line.
+//!
+//! The journal is only written through to the disk when the copy stops
+//! short, unless `CopyOptions::checkpoint` asks for checkpoints: the files
+//! copied since the last one are flushed, then the journal, so that what
+//! it records, and the files and bytes `--resume` offers to skip, survive
+//! a crash of the machine.
+
+use crate::location::Location;
+use crate::stage::state_dir;
+use crate::{fsync, parse_duration, parse_size};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+/// The journal of a copy into `root`, in `journals` in the per-user state
+/// directory, named after a hash of the canonical path of `root`.
+pub(crate) fn path(root: &Path) -> Option<PathBuf> {
+    let root = fs::canonicalize(root).ok()?;
+    let hash = blake3::hash(root.as_os_str().as_encoded_bytes());
+    Some(state_dir()?.join("journals").join(&hash.to_hex()[..32]))
+}
+
+/// First line of a journal in the current format.
+const HEADER: &str = "cpv journal 1";
+
+/// Start of the line naming the source, which journals written before it
+/// was added lack.
+const SOURCE: &str = "source ";
+
+/// Size and modification time of a file, which tell whether it has
+/// changed since.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Stamp {
+    pub(crate) len: u64,
+    pub(crate) mtime: u128,
+}
+
+impl Stamp {
+    pub(crate) fn of(metadata: &fs::Metadata) -> Self {
+        Self {
+            len: metadata.len(),
+            mtime: metadata
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |since| since.as_nanos()),
+        }
+    }
+}
+
+/// How often a recursive copy checkpoints, whichever comes first: after
+/// this long, this many files or this many bytes since the last
+/// checkpoint. Checked as each file is finished.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct Checkpoint {
+    pub interval: Option<Duration>,
+    pub files: Option<u64>,
+    pub bytes: Option<u64>,
+}
+
+impl Checkpoint {
+    fn due(&self, pending: &Pending) -> bool {
+        self.interval
+            .is_some_and(|interval| pending.since.elapsed() >= interval)
+            || self.files.is_some_and(|files| pending.files >= files)
+            || self.bytes.is_some_and(|bytes| pending.bytes >= bytes)
+    }
+}
+
+/// Parses a comma-separated list of a duration such as `30s`, a number of
+/// files such as `1000files` and a size with a `B`, such as `10GB`.
+impl FromStr for Checkpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut checkpoint = Self::default();
+        for part in s.split(',').map(str::trim) {
+            if let Some(files) = part.strip_suffix("files") {
+                checkpoint.files = Some(files.trim().parse().map_err(|_| {
+                    format!(
+                        "invalid number of files '{}' (expected e.g. 1000files)",
+                        part
+                    )
+                })?);
+            } else if part.ends_with(['b', 'B']) {
+                checkpoint.bytes = Some(parse_size(part)?);
+            } else {
+                checkpoint.interval = Some(parse_duration(part)?);
+            }
+        }
+        if checkpoint.bytes == Some(0)
+            || checkpoint.files == Some(0)
+            || checkpoint.interval == Some(Duration::ZERO)
+        {
+            return Err(format!("invalid checkpoint '{}': zero", s));
+        }
+        Ok(checkpoint)
+    }
+}
+
+/// The checkpoint as a spec that parses back to it.
+impl fmt::Display for Checkpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts = [
+            self.interval
+                .map(|interval| format!("{}ms", interval.as_millis())),
+            self.files.map(|files| format!("{}files", files)),
+            self.bytes.map(|bytes| format!("{}B", bytes)),
+        ];
+        let parts: Vec<_> = parts.into_iter().flatten().collect();
+        f.write_str(&parts.join(","))
+    }
+}
+
+/// What was copied since the last checkpoint.
+struct Pending {
+    since: Instant,
+    files: u64,
+    bytes: u64,
+    /// The copies, to flush at the checkpoint.
+    written: Vec<PathBuf>,
+}
+
+impl Pending {
+    fn new() -> Self {
+        Self {
+            since: Instant::now(),
+            files: 0,
+            bytes: 0,
+            written: Vec::new(),
+        }
+    }
+}
+
+/// The journal of one recursive copy.
+pub(crate) struct Journal {
+    /// `None` without a state directory, when nothing is journaled.
+    path: Option<PathBuf>,
+    root: PathBuf,
+    /// Files recorded by the interrupted run being resumed.
+    done: HashMap<PathBuf, Stamp>,
+    /// The version and source lines a journal starts with.
+    header: String,
+    file: Mutex<Option<File>>,
+    checkpoint: Option<Checkpoint>,
+    pending: Mutex<Pending>,
+}
+
+impl Journal {
+    /// Starts the journal of a copy of `source` into `root`. With `resume`,
+    /// the entries of a journal left by an interrupted copy are kept; a
+    /// journal in another format is discarded. With `checkpoint`, it is
+    /// checkpointed as often as that asks. Without a state directory to
+    /// keep it in, nothing is journaled.
+    pub(crate) fn open(
+        root: &Path,
+        source: &Path,
+        resume: bool,
+        checkpoint: Option<Checkpoint>,
+    ) -> io::Result<Self> {
+        let path = path(root);
+        let done = match &path {
+            Some(path) if resume => match fs::read_to_string(path) {
+                Ok(text) => parse(&text).1,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+                Err(err) => return Err(err),
+            },
+            _ => HashMap::new(),
+        };
+
+        let mut header = format!("{}\n", HEADER);
+        if let Some(source) = fs::canonicalize(source)
+            .ok()
+            .and_then(|source| Some(escape(source.to_str()?)))
+        {
+            header.push_str(&format!("{}{}\n", SOURCE, source));
+        }
+        let file = match &path {
+            Some(path) => {
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                let mut file = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(path)?;
+                let mut text = header.clone();
+                for (name, stamp) in &done {
+                    text.push_str(&entry(name, *stamp).unwrap_or_default());
+                }
+                file.write_all(text.as_bytes())?;
+                Some(file)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            path,
+            root: root.to_path_buf(),
+            done,
+            header,
+            file: Mutex::new(file),
+            checkpoint,
+            pending: Mutex::new(Pending::new()),
+        })
+    }
+
+    /// The size of `source` if the interrupted run finished copying it to
+    /// `dest` and neither has changed since, going by their sizes and the
+    /// source's modification time.
+    pub(crate) fn finished(&self, source: &Location, dest: &Location) -> io::Result<Option<u64>> {
+        let Some(stamp) = self.done.get(self.relative(dest)) else {
+            return Ok(None);
+        };
+        if Stamp::of(&source.open()?.metadata()?) != *stamp {
+            return Ok(None);
+        }
+        match dest.open() {
+            Ok(file) if file.metadata()?.len() == stamp.len => Ok(Some(stamp.len)),
+            Ok(_) => Ok(None),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Records that `source` has been copied to `dest`. Names that are not
+    /// valid Unicode are not recorded and are copied again on resume.
+    pub(crate) fn record(&self, source: &fs::Metadata, dest: &Location) -> io::Result<()> {
+        if let Some(line) = entry(self.relative(dest), Stamp::of(source)) {
+            if let Some(file) = &mut *self.file.lock().unwrap() {
+                file.write_all(line.as_bytes())?;
+            }
+        }
+        let Some(checkpoint) = &self.checkpoint else {
+            return Ok(());
+        };
+        let written = {
+            let mut pending = self.pending.lock().unwrap();
+            pending.files += 1;
+            pending.bytes += source.len();
+            pending.written.push(dest.path().to_path_buf());
+            if !checkpoint.due(&pending) {
+                return Ok(());
+            }
+            mem::replace(&mut *pending, Pending::new()).written
+        };
+        // The copies first, so that the journal never records one that
+        // did not reach the disk.
+        for path in &written {
+            fsync::sync_path(path)?;
+        }
+        self.sync()
+    }
+
+    /// Writes the journal through to the disk, once the copy has stopped
+    /// short, so that what it finished survives for `--resume`.
+    pub(crate) fn sync(&self) -> io::Result<()> {
+        match &*self.file.lock().unwrap() {
+            Some(file) => file.sync_data(),
+            None => Ok(()),
+        }
+    }
+
+    /// Holds the journal while `swap` replaces the full volume the
+    /// destination is on, for `CopyOptions::span`, once the copies not
+    /// yet checkpointed are flushed, and starts it anew for the next
+    /// volume. What is on the full one is complete, so its entries are
+    /// dropped; it starts anew too if `swap` does not replace it. Returns
+    /// what `swap` did.
+    pub(crate) fn across_volumes(&self, swap: impl FnOnce() -> bool) -> io::Result<bool> {
+        let mut file = self.file.lock().unwrap();
+        let written = mem::replace(&mut *self.pending.lock().unwrap(), Pending::new()).written;
+        for path in &written {
+            fsync::sync_path(path)?;
+        }
+        let swapped = swap();
+        if let Some(file) = &mut *file {
+            file.set_len(0)?;
+            file.rewind()?;
+            file.write_all(self.header.as_bytes())?;
+        }
+        Ok(swapped)
+    }
+
+    /// The root of the destination tree.
+    pub(crate) fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Removes the journal once the copy has succeeded.
+    pub(crate) fn remove(self) -> io::Result<()> {
+        drop(self.file);
+        match &self.path {
+            Some(path) => fs::remove_file(path),
+            None => Ok(()),
+        }
+    }
+
+    fn relative<'a>(&self, dest: &'a Location) -> &'a Path {
+        dest.path().strip_prefix(&self.root).unwrap_or(dest.path())
+    }
+}
+
+/// The journal line for `name`.
+fn entry(name: &Path, stamp: Stamp) -> Option<String> {
+    Some(format!(
+        "{} {} {}\n",
+        stamp.len,
+        stamp.mtime,
+        escape(name.to_str()?)
+    ))
+}
+
+/// `name` with backslashes and newlines escaped, to fit on one line.
+pub(crate) fn escape(name: &str) -> String {
+    name.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+/// Reads the source and the entries of a journal, ignoring malformed
+/// lines and a last line cut short by an interruption.
+fn parse(text: &str) -> (Option<PathBuf>, HashMap<PathBuf, Stamp>) {
+    let complete = &text[..text.rfind('\n').map_or(0, |end| end + 1)];
+    let mut lines = complete.split_terminator('\n').peekable();
+    if lines.next() != Some(HEADER) {
+        return (None, HashMap::new());
+    }
+    let source = lines
+        .next_if(|line| line.starts_with(SOURCE))
+        .and_then(|line| unescape(&line[SOURCE.len()..]))
+        .map(PathBuf::from);
+    let done = lines
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, ' ');
+            let len = fields.next()?.parse().ok()?;
+            let mtime = fields.next()?.parse().ok()?;
+            let name = unescape(fields.next()?)?;
+            Some((PathBuf::from(name), Stamp { len, mtime }))
+        })
+        .collect();
+    (source, done)
+}
+
+/// A recursive copy that was interrupted, found by `interrupted_copy`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterruptedCopy {
+    /// The directory the copy was writing.
+    pub target: PathBuf,
+    /// The files it finished, and their bytes.
+    pub files: usize,
+    pub bytes: u64,
+}
+
+/// The copy of `source` into `target` that an earlier run left unfinished,
+/// if there is a journal of it and it names `source`, or names nothing, as
+/// journals of older versions do.
+pub(crate) fn interrupted(source: &Path, target: &Path) -> Option<InterruptedCopy> {
+    let text = fs::read_to_string(path(target)?).ok()?;
+    if !text.starts_with(HEADER) {
+        return None;
+    }
+    let (journaled, done) = parse(&text);
+    if journaled.is_some_and(|journaled| Some(journaled) != fs::canonicalize(source).ok()) {
+        return None;
+    }
+    Some(InterruptedCopy {
+        target: target.to_path_buf(),
+        files: done.len(),
+        bytes: done.values().map(|stamp| stamp.len).sum(),
+    })
+}
+
+/// Reverses `escape`, or returns `None` for an invalid escape.
+pub(crate) fn unescape(name: &str) -> Option<String> {
+    let mut out = String::with_capacity(name.len());
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next()? {
+                '\\' => out.push('\\'),
+                'n' => out.push('\n'),
+                _ => return None,
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    Some(out)
+}
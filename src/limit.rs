@@ -0,0 +1,65 @@
+//! Bandwidth limiting for `--bwlimit`.
+
+use std::fmt;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Throttles copied data. One limiter is shared by every copy worker, so
+/// the limit applies to the copy as a whole.
+pub trait RateLimiter: fmt::Debug + Send + Sync {
+    /// Called after `bytes` have been transferred; blocks for as long as
+    /// needed to keep the overall rate within the limit.
+    fn acquire(&self, bytes: u64);
+}
+
+/// A token-bucket limiter allowing `rate` bytes per second, with bursts of
+/// up to a tenth of a second's worth.
+///
+/// Transfers larger than the bucket are allowed and put it into debt, which
+/// the caller then sleeps off, so the long-run rate holds for any chunk size.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    /// A limiter for `rate` bytes per second; `rate` must be non-zero.
+    pub fn new(rate: u64) -> Self {
+        assert!(rate > 0, "rate limit must be non-zero");
+        let rate = rate as f64;
+        let capacity = rate / 10.0;
+        Self {
+            rate,
+            capacity,
+            state: Mutex::new(BucketState {
+                tokens: capacity,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+}
+
+impl RateLimiter for TokenBucket {
+    fn acquire(&self, bytes: u64) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(state.refilled).as_secs_f64();
+            state.tokens = (state.tokens + elapsed * self.rate).min(self.capacity) - bytes as f64;
+            state.refilled = now;
+            (state.tokens < 0.0).then(|| Duration::from_secs_f64(-state.tokens / self.rate))
+        };
+        if let Some(wait) = wait {
+            thread::sleep(wait);
+        }
+    }
+}
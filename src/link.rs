@@ -0,0 +1,134 @@
+//! Linking destination files to their sources instead of copying, for
+//! `-l` and `-s`.
+
+use crate::location::Location;
+use std::env;
+use std::fmt;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+/// What is created in the destination for each source file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LinkMode {
+    /// A copy of the file's data.
+    #[default]
+    Copy,
+    /// A hard link to the source, which must be on the same filesystem.
+    /// The link shares the source's data and attributes, so nothing is
+    /// preserved or overridden on it.
+    Hard,
+    /// A symbolic link to the absolute path of the source, or to its path
+    /// relative to the link with `relative_links`.
+    Symbolic,
+}
+
+impl FromStr for LinkMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "copy" => Ok(Self::Copy),
+            "hard" => Ok(Self::Hard),
+            "symbolic" => Ok(Self::Symbolic),
+            _ => Err(format!(
+                "invalid link mode '{}' (expected copy, hard or symbolic)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for LinkMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Copy => "copy",
+            Self::Hard => "hard",
+            Self::Symbolic => "symbolic",
+        })
+    }
+}
+
+/// Creates `dest` as a hard link to `original`, explaining the failure
+/// when the two are on different filesystems.
+pub(crate) fn hard_link(original: &Path, dest: &Location) -> io::Result<()> {
+    dest.hard_link(original).map_err(|err| {
+        if crosses_devices(&err) {
+            io::Error::new(
+                err.kind(),
+                format!(
+                    "cannot hard-link '{}' to '{}': they are on different filesystems",
+                    dest.path().display(),
+                    original.display()
+                ),
+            )
+        } else {
+            err
+        }
+    })
+}
+
+/// Creates `dest` as a symbolic link to `source`, by absolute path or, with
+/// `relative`, by a path relative to the directory of `dest`.
+pub(crate) fn symlink(source: &Path, dest: &Location, relative: bool) -> io::Result<()> {
+    let source = absolute(source)?;
+    let target = if relative {
+        let dir = absolute(dest.path())?;
+        relative_path(dir.parent().unwrap_or(&dir), &source)
+    } else {
+        source
+    };
+    dest.symlink(&target)
+}
+
+/// `path` made absolute against the working directory, with `.` and `..`
+/// components resolved lexically so that symlinks in it are kept.
+fn absolute(path: &Path) -> io::Result<PathBuf> {
+    let mut absolute = if path.is_absolute() {
+        PathBuf::new()
+    } else {
+        env::current_dir()?
+    };
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                absolute.pop();
+            }
+            other => absolute.push(other),
+        }
+    }
+    Ok(absolute)
+}
+
+/// The path leading from the directory `from` to `to`, both absolute.
+fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let mut from = from.components().peekable();
+    let mut to = to.components().peekable();
+    while let (Some(a), Some(b)) = (from.peek(), to.peek()) {
+        if a != b {
+            break;
+        }
+        from.next();
+        to.next();
+    }
+    from.map(|_| Component::ParentDir)
+        .chain(to)
+        .collect::<PathBuf>()
+}
+
+pub(crate) fn crosses_devices(err: &io::Error) -> bool {
+    #[cfg(unix)]
+    {
+        err.raw_os_error() == Some(libc::EXDEV)
+    }
+    #[cfg(windows)]
+    {
+        err.raw_os_error() == Some(windows_sys::Win32::Foundation::ERROR_NOT_SAME_DEVICE as i32)
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = err;
+        false
+    }
+}
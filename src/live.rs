@@ -0,0 +1,161 @@
+//! `LiveStats`, counters of a running copy that a host application can
+//! read from any thread whenever it likes, instead of following events.
+
+use crate::reporter::ProgressReporter;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// The state of a copy as it runs, set in `CopyOptions::live_stats`.
+///
+/// The byte counts and the rate move at the interval of
+/// `CopyOptions::progress_throttle`; the file counts as each file ends.
+#[derive(Debug, Default)]
+pub struct LiveStats {
+    bytes_done: AtomicU64,
+    bytes_total: AtomicU64,
+    files_done: AtomicU64,
+    files_failed: AtomicU64,
+    files_total: AtomicU64,
+    bytes_per_sec: AtomicU64,
+    finished: AtomicBool,
+    current_file: Mutex<Option<PathBuf>>,
+    /// When `bytes_done` was last sampled and its value then.
+    sampled: Mutex<Option<(Instant, u64)>>,
+}
+
+impl LiveStats {
+    pub fn new() -> Arc<Self> {
+        Arc::default()
+    }
+
+    /// Bytes copied so far.
+    pub fn bytes_done(&self) -> u64 {
+        self.bytes_done.load(Ordering::Relaxed)
+    }
+
+    /// Bytes to copy in total, which grows while a recursive source is
+    /// scanned.
+    pub fn bytes_total(&self) -> u64 {
+        self.bytes_total.load(Ordering::Relaxed)
+    }
+
+    /// Files copied, linked or found already copied.
+    pub fn files_done(&self) -> u64 {
+        self.files_done.load(Ordering::Relaxed)
+    }
+
+    /// Files that could not be copied.
+    pub fn files_failed(&self) -> u64 {
+        self.files_failed.load(Ordering::Relaxed)
+    }
+
+    /// Files found by the scan of a recursive source so far.
+    pub fn files_total(&self) -> u64 {
+        self.files_total.load(Ordering::Relaxed)
+    }
+
+    /// Throughput over the last interval, in bytes per second.
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.bytes_per_sec.load(Ordering::Relaxed)
+    }
+
+    /// The source of the file started last, while the copy runs.
+    pub fn current_file(&self) -> Option<PathBuf> {
+        self.current_file.lock().unwrap().clone()
+    }
+
+    /// Whether the copy is over, successfully or not.
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn finish(&self) {
+        *self.current_file.lock().unwrap() = None;
+        self.bytes_per_sec.store(0, Ordering::Relaxed);
+        self.finished.store(true, Ordering::Relaxed);
+    }
+}
+
+impl ProgressReporter for LiveStats {
+    fn on_scan(&self, files: u64, bytes: u64) {
+        self.files_total.store(files, Ordering::Relaxed);
+        self.bytes_total.fetch_max(bytes, Ordering::Relaxed);
+    }
+
+    fn on_file_start(&self, source: &Path, _dest: &Path) {
+        *self.current_file.lock().unwrap() = Some(source.to_path_buf());
+    }
+
+    fn on_bytes(&self, done: u64, total: u64) {
+        let now = Instant::now();
+        let mut sampled = self.sampled.lock().unwrap();
+        if let Some((then, before)) = *sampled {
+            let secs = now.saturating_duration_since(then).as_secs_f64();
+            if secs > 0.0 {
+                let rate = done.saturating_sub(before) as f64 / secs;
+                self.bytes_per_sec.store(rate as u64, Ordering::Relaxed);
+            }
+        }
+        *sampled = Some((now, done));
+        self.bytes_done.store(done, Ordering::Relaxed);
+        self.bytes_total.store(total, Ordering::Relaxed);
+    }
+
+    fn on_file_done(&self, _source: &Path, _dest: &Path, _bytes: u64) {
+        self.files_done.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_error(&self, source: Option<&Path>, _err: &dyn Error) {
+        if source.is_some() {
+            self.files_failed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Hands every call to `live` and then to `reporter`, so that live stats
+/// ride on the reporter calls the copy already makes.
+#[derive(Debug)]
+pub(crate) struct Tee {
+    pub(crate) live: Arc<LiveStats>,
+    pub(crate) reporter: Option<Arc<dyn ProgressReporter>>,
+}
+
+impl ProgressReporter for Tee {
+    fn on_scan(&self, files: u64, bytes: u64) {
+        self.live.on_scan(files, bytes);
+        if let Some(reporter) = &self.reporter {
+            reporter.on_scan(files, bytes);
+        }
+    }
+
+    fn on_file_start(&self, source: &Path, dest: &Path) {
+        self.live.on_file_start(source, dest);
+        if let Some(reporter) = &self.reporter {
+            reporter.on_file_start(source, dest);
+        }
+    }
+
+    fn on_bytes(&self, done: u64, total: u64) {
+        self.live.on_bytes(done, total);
+        if let Some(reporter) = &self.reporter {
+            reporter.on_bytes(done, total);
+        }
+    }
+
+    fn on_file_done(&self, source: &Path, dest: &Path, bytes: u64) {
+        self.live.on_file_done(source, dest, bytes);
+        if let Some(reporter) = &self.reporter {
+            reporter.on_file_done(source, dest, bytes);
+        }
+    }
+
+    fn on_error(&self, source: Option<&Path>, err: &dyn Error) {
+        self.live.on_error(source, err);
+        if let Some(reporter) = &self.reporter {
+            reporter.on_error(source, err);
+        }
+    }
+}
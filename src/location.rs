@@ -0,0 +1,203 @@
+//! Files of a copy, addressed by path or, during a recursive copy on Unix,
+//! by name relative to their already-open parent directory.
+
+use std::ffi::OsString;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[cfg(unix)]
+use crate::dirfd::Dir;
+#[cfg(unix)]
+use std::sync::Arc;
+
+/// A source or destination file.
+///
+/// The full path is always kept for messages and for the calls that only
+/// take paths. When a parent directory is attached, files are opened
+/// relative to it with `O_NOFOLLOW`, so no component of the path can be
+/// swapped for a symlink between the walk and the copy.
+#[derive(Debug, Clone)]
+pub(crate) struct Location {
+    path: PathBuf,
+    #[cfg(unix)]
+    parent: Option<Arc<Dir>>,
+}
+
+impl Location {
+    pub(crate) fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            #[cfg(unix)]
+            parent: None,
+        }
+    }
+
+    /// The entry named by the last component of `path` inside `parent`.
+    #[cfg(unix)]
+    pub(crate) fn in_dir(parent: Arc<Dir>, path: PathBuf) -> Self {
+        Self {
+            path,
+            parent: Some(parent),
+        }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Drops the parent directory handle, leaving only the path.
+    pub(crate) fn detach(self) -> Self {
+        Self::new(self.path)
+    }
+
+    /// The hidden `.NAME.part` file next to this one, which `--partial`
+    /// writes into until the copy is complete.
+    pub(crate) fn part(&self) -> Self {
+        let mut name = OsString::from(".");
+        name.push(self.path.file_name().unwrap_or_default());
+        name.push(".part");
+        self.sibling(name)
+    }
+
+    /// The entry `name` of the directory holding this file.
+    pub(crate) fn sibling(&self, name: impl Into<OsString>) -> Self {
+        Self {
+            path: self.path.with_file_name(name.into()),
+            #[cfg(unix)]
+            parent: self.parent.clone(),
+        }
+    }
+
+    /// Renames this file to `to`, an entry of the same directory, replacing
+    /// any existing `to`.
+    pub(crate) fn rename(&self, to: &Location) -> io::Result<()> {
+        #[cfg(unix)]
+        if let (Some(parent), Some(from), Some(to)) =
+            (&self.parent, self.path.file_name(), to.path.file_name())
+        {
+            return parent.rename_at(from, to);
+        }
+        std::fs::rename(&self.path, &to.path)
+    }
+
+    /// Creates this file as a hard link to `original`, replacing any
+    /// existing file.
+    pub(crate) fn hard_link(&self, original: &Path) -> io::Result<()> {
+        self.replace_with(|part| {
+            #[cfg(unix)]
+            if let (Some(parent), Some(name)) = (&part.parent, part.path.file_name()) {
+                return parent.link_at(original, name);
+            }
+            std::fs::hard_link(original, &part.path)
+        })
+    }
+
+    /// Creates this file as a symbolic link to `target`, replacing any
+    /// existing file.
+    pub(crate) fn symlink(&self, target: &Path) -> io::Result<()> {
+        self.replace_with(|part| {
+            #[cfg(unix)]
+            {
+                if let (Some(parent), Some(name)) = (&part.parent, part.path.file_name()) {
+                    return parent.symlink_at(target, name);
+                }
+                std::os::unix::fs::symlink(target, &part.path)
+            }
+            #[cfg(windows)]
+            {
+                std::os::windows::fs::symlink_file(target, &part.path)
+            }
+            #[cfg(target_os = "wasi")]
+            {
+                let _ = (target, part);
+                Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "symbolic links are not supported on WASI",
+                ))
+            }
+        })
+    }
+
+    /// Creates the file under its `.part` name with `create` and renames it
+    /// into place, so an existing file is replaced in one step.
+    fn replace_with(&self, create: impl FnOnce(&Self) -> io::Result<()>) -> io::Result<()> {
+        let part = self.part();
+        match part.remove() {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        create(&part)?;
+        part.rename(self)
+    }
+
+    /// Removes the file.
+    pub(crate) fn remove(&self) -> io::Result<()> {
+        #[cfg(unix)]
+        if let (Some(parent), Some(name)) = (&self.parent, self.path.file_name()) {
+            return parent.remove_at(name);
+        }
+        std::fs::remove_file(&self.path)
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn parent(&self) -> Option<&Dir> {
+        self.parent.as_deref()
+    }
+
+    /// Opens the file for reading.
+    pub(crate) fn open(&self) -> io::Result<File> {
+        #[cfg(unix)]
+        {
+            self.open_with(libc::O_RDONLY)
+        }
+        #[cfg(not(unix))]
+        {
+            File::open(&self.path)
+        }
+    }
+
+    /// Creates or truncates the file for writing.
+    pub(crate) fn create(&self) -> io::Result<File> {
+        #[cfg(unix)]
+        {
+            self.open_with(libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC)
+        }
+        #[cfg(not(unix))]
+        {
+            File::create(&self.path)
+        }
+    }
+
+    /// Opens an existing file for reading and writing without truncating
+    /// it.
+    pub(crate) fn open_rw(&self) -> io::Result<File> {
+        #[cfg(unix)]
+        {
+            self.open_with(libc::O_RDWR)
+        }
+        #[cfg(not(unix))]
+        {
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&self.path)
+        }
+    }
+
+    /// Opens the file with `open(2)` `flags`, creating it with mode 0666
+    /// (less the umask) when `O_CREAT` is given.
+    #[cfg(unix)]
+    pub(crate) fn open_with(&self, flags: libc::c_int) -> io::Result<File> {
+        match &self.parent {
+            Some(parent) => match self.path.file_name() {
+                Some(name) => parent.open_file_at(name.as_ref(), flags | libc::O_NOFOLLOW),
+                None => Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("'{}' has no file name", self.path.display()),
+                )),
+            },
+            None => crate::dirfd::open_file(&self.path, flags),
+        }
+    }
+}
@@ -0,0 +1,116 @@
+//! The plain-text record of a copy written by `--log-file`, for audited
+//! data migrations.
+//!
+//! Every line starts with the local time in RFC 3339 form, followed by what
+//! happened: the start of the copy, each file copied, linked, skipped,
+//! retried or failed, the warnings and the final summary or error. Lines
+//! are appended, so one file can hold several runs; rotating it is left to
+//! the user.
+
+use crate::{CopyError, CopyStats};
+use chrono::{Local, SecondsFormat};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// An open log file.
+pub struct LogFile {
+    file: Mutex<File>,
+}
+
+impl fmt::Debug for LogFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LogFile").finish_non_exhaustive()
+    }
+}
+
+impl LogFile {
+    /// Opens the log at `path` for appending, creating it if needed.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Appends a line with the current time and `message`. The log does
+    /// not stop the copy it records, so write errors are ignored.
+    pub(crate) fn record(&self, message: fmt::Arguments<'_>) {
+        let line = format!(
+            "{} {}\n",
+            Local::now().to_rfc3339_opts(SecondsFormat::Millis, false),
+            message
+        );
+        let _ = self.file.lock().unwrap().write_all(line.as_bytes());
+    }
+
+    pub(crate) fn started(&self, source: &Path, dest: &Path) {
+        self.record(format_args!(
+            "started copying '{}' to '{}'",
+            source.display(),
+            dest.display()
+        ));
+    }
+
+    /// Records a file that was copied or linked as `action`, with `notes`
+    /// such as what became of the destination it replaced.
+    pub(crate) fn file_done(
+        &self,
+        action: &str,
+        source: &Path,
+        dest: &Path,
+        bytes: u64,
+        notes: &[Option<&str>],
+    ) {
+        let mut line = format!(
+            "{} '{}' -> '{}' ({} bytes",
+            action,
+            source.display(),
+            dest.display(),
+            bytes
+        );
+        for note in notes.iter().flatten() {
+            line.push_str(", ");
+            line.push_str(note);
+        }
+        line.push(')');
+        self.record(format_args!("{}", line));
+    }
+
+    pub(crate) fn file_skipped(&self, source: &Path, dest: &Path) {
+        self.record(format_args!(
+            "skipped '{}' -> '{}' (already copied)",
+            source.display(),
+            dest.display()
+        ));
+    }
+
+    pub(crate) fn file_retried(&self, source: &Path, err: &io::Error) {
+        self.record(format_args!("retrying '{}': {}", source.display(), err));
+    }
+
+    pub(crate) fn file_failed(&self, source: &Path, dest: &Path, err: &io::Error) {
+        self.record(format_args!(
+            "failed '{}' -> '{}': {}",
+            source.display(),
+            dest.display(),
+            err
+        ));
+    }
+
+    /// Records the end of the copy: its warnings and summary, or the error
+    /// that stopped it.
+    pub(crate) fn finished(&self, result: &Result<CopyStats, CopyError>) {
+        match result {
+            Ok(stats) => {
+                for warning in &stats.warnings {
+                    self.record(format_args!("warning: {}", warning));
+                }
+                self.record(format_args!("finished: {}", stats.format_summary()));
+            }
+            Err(err) => self.record(format_args!("stopped: {}", err)),
+        }
+    }
+}
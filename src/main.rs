@@ -1,71 +1,2154 @@
-use clap::Parser;
-use cpv::{copy_with_progress, CopyError, CopyOptions};
-use std::path::PathBuf;
+use chrono::Local;
+use clap::builder::{BoolishValueParser, PossibleValue, TypedValueParser};
+use clap::parser::ValueSource;
+use clap::{Arg, ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use clap_complete::Shell;
+use console::style;
+use cpv::bench::{self, BenchConfig, Distribution};
+use cpv::report::Report;
+#[cfg(feature = "webhook")]
+use cpv::Webhook;
+use cpv::{
+    compare_trees, copy_with_progress, doctor, interrupted_copy, lower_priority, parse_buffer_size,
+    parse_cpv_url, parse_duration, parse_remote, parse_sample, parse_size, parse_start_time, pick,
+    plan, preflight, undo, watch, ActionKind, ArchiveFs, ByteRange, CancellationToken,
+    ChangePolicy, Checkpoint, ChecksumAlgo, ChecksumCache, ChmodRules, Chown, CollisionPolicy,
+    ColorChoice, Compression, Config, CopyBackend, CopyError, CopyHooks, CopyOptions, CpvFs,
+    Dedupe, ErrorRules, EventSocket, ExitStatus, FileDecision, FileError, FileOrder, Filesystem,
+    IdMap, JsonEvents, LinkMode, LogFile, NameNormalization, NetConfig, OverwritePolicy,
+    Parallelism, Porcelain, Preserved, ProgressMode, ProgressThrottle, RateLimiter, ReflinkMode,
+    Server, SftpFs, SparseMode, Staging, StatsFormat, Summary, SyncMode, TarFs, Theme,
+    ThroughputHistory, TimeWindow, TokenBucket, UndoLog, Volumes,
+};
+#[cfg(feature = "tls")]
+use cpv::{ClientTls, ServerTls};
+#[cfg(feature = "age")]
+use cpv::{DecryptFs, EncryptFs, LocalFs};
+#[cfg(unix)]
+use cpv::{JobState, QueueDaemon, QueueReply, QueueRequest};
+#[cfg(feature = "metrics")]
+use cpv::{LiveStats, MetricsServer};
+use humansize::{format_size, BINARY};
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, BufWriter, IsTerminal, Write};
+use std::marker::PhantomData;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use std::{env, fmt};
 
 /// Modern file copy utility with progress visualization
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Args {
-    /// Source file or directory
-    #[arg(name = "SOURCE")]
-    source: PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
 
-    /// Destination file or directory
-    #[arg(name = "DEST")]
-    destination: PathBuf,
+    /// Source file or directory, [user@]host:path on another machine,
+    /// cpv://host[:port]/path under a `cpv serve` or s3://bucket/key
+    ///
+    /// A host:path that exists locally is a local path; write ./a:b to keep
+    /// a local name with a colon local.
+    #[arg(name = "SOURCE", required = true)]
+    source: Option<PathBuf>,
+
+    /// Destination file or directory, [user@]host:path on another
+    /// machine, cpv://host[:port]/path under a `cpv serve`, s3://bucket/key,
+    /// or a NAME.tar archive to write (- for standard output)
+    ///
+    /// A host:path that exists locally is a local path; write ./a:b to keep
+    /// a local name with a colon local.
+    #[arg(name = "DEST", required = true)]
+    destination: Option<PathBuf>,
+
+    /// With --concat, more files to concatenate, the last one being the destination
+    #[arg(name = "MORE", requires = "concat")]
+    more: Vec<PathBuf>,
 
     /// Copy directories recursively
     #[arg(short = 'r', long = "recursive")]
     recursive: bool,
 
+    /// Leave out the entries of SOURCE directories whose names match PATTERN, with * and ?; may be repeated
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+
+    /// Choose the entries of SOURCE to copy in a tree browser on the terminal first, recursively
+    #[arg(long, conflicts_with_all = ["watch", "verify_only", "dry_run"])]
+    pick: bool,
+
+    /// Copy only the files that are new or changed since an earlier copy to DEST, recursively
+    #[arg(long)]
+    mirror: bool,
+
+    /// With --mirror, remove from DEST what SOURCE no longer has (preview it with --dry-run)
+    #[arg(long, requires = "mirror", conflicts_with_all = ["compress", "encrypt", "split"])]
+    delete: bool,
+
+    /// Copy SOURCE, a directory, then keep copying what is created or changed in it until interrupted
+    #[arg(long, conflicts_with_all = ["extract", "verify_only", "dry_run", "join"])]
+    watch: bool,
+
+    /// Extract SOURCE, a tar, gzipped tar or zip archive, into DEST
+    #[arg(short = 'x', long)]
+    extract: bool,
+
+    /// Compress each destination file into NAME.gz or NAME.zst (gzip, zstd)
+    #[arg(long, value_name = "ALGO")]
+    compress: Option<Compression>,
+
+    /// Encrypt each destination file into NAME.age, to the --recipient keys
+    #[arg(long, requires = "recipient", conflicts_with = "decrypt")]
+    encrypt: bool,
+
+    /// An age public key (age1...) to encrypt to; may be repeated
+    #[arg(long, value_name = "KEY", requires = "encrypt")]
+    recipient: Vec<String>,
+
+    /// Decrypt SOURCE, whose files are age files, with the --identity keys
+    #[arg(long, requires = "identity")]
+    decrypt: bool,
+
+    /// An age identity file to decrypt with; may be repeated
+    #[arg(long, value_name = "FILE", requires = "decrypt")]
+    identity: Vec<PathBuf>,
+
+    /// Write each destination file as parts NAME.000, NAME.001, ... of at most SIZE bytes
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    split: Option<u64>,
+
+    /// Read files split into parts NAME.000, NAME.001, ... in SOURCE as the whole files
+    #[arg(long)]
+    join: bool,
+
+    /// Write SOURCE and the files after it, one after the other, into the last path as one file
+    #[arg(long, conflicts_with_all = ["recursive", "watch"])]
+    concat: bool,
+
+    /// Copy SOURCE from byte OFFSET on, as with --length
+    #[arg(long, value_name = "OFFSET", value_parser = parse_size, conflicts_with_all = ["recursive", "watch"])]
+    offset: Option<u64>,
+
+    /// Copy only LENGTH bytes of SOURCE, from --offset or its start
+    #[arg(long, value_name = "LENGTH", value_parser = parse_size, conflicts_with_all = ["recursive", "watch"])]
+    length: Option<u64>,
+
+    /// Write the bytes copied into DEST at OFFSET, keeping the rest of it, instead of replacing it
+    #[arg(long, value_name = "OFFSET", value_parser = parse_size, conflicts_with_all = ["recursive", "watch"])]
+    dest_offset: Option<u64>,
+
     /// Preserve attributes
     #[arg(short = 'p', long)]
     preserve: bool,
 
-    /// Force overwrite
-    #[arg(short = 'f', long)]
+    /// Replace existing destination files (the default; same as --overwrite overwrite)
+    #[arg(short = 'f', long, conflicts_with = "interactive")]
     force: bool,
 
-    /// Verbose output
+    /// Ask before replacing each existing destination file (same as --overwrite prompt)
+    #[arg(short = 'i', long)]
+    interactive: bool,
+
+    /// What to do with existing destination files: overwrite, skip, error, prompt, trash or rename
+    #[arg(long, value_name = "POLICY", conflicts_with_all = ["force", "interactive", "trash_overwrites", "backup_dir"])]
+    overwrite: Option<OverwritePolicy>,
+
+    /// List each file as it is copied, then the transfer statistics
     #[arg(short = 'v', long)]
     verbose: bool,
+
+    /// Print a timeline of the throughput with its lowest, average and highest rates at the end
+    #[arg(long)]
+    timeline: bool,
+
+    /// Show the percentage and rate of the copy in the terminal title
+    #[arg(long)]
+    title: bool,
+
+    /// Show the progress of the copy in the launcher and notifications of the desktop, over D-Bus
+    #[cfg(feature = "dbus")]
+    #[arg(long)]
+    desktop_progress: bool,
+
+    /// Print the final statistics as json or csv
+    #[arg(long, value_name = "FORMAT")]
+    stats_format: Option<StatsFormat>,
+
+    /// Write the statistics of --stats-format to FILE instead of stdout
+    #[arg(long, value_name = "FILE", requires = "stats_format")]
+    stats_file: Option<PathBuf>,
+
+    /// Append a timestamped record of every file copied, skipped or failed to PATH
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// Add to the record of the copy before for `cpv undo`, rather than replacing it
+    #[arg(long, hide = true)]
+    undo_append: bool,
+
+    /// Print a tab-separated record of every file on stdout in a stable format for scripts
+    #[arg(long, conflicts_with = "verbose")]
+    porcelain: bool,
+
+    /// Print nothing but errors
+    #[arg(short = 'q', long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Do not show progress bars
+    #[arg(long, env = "CPV_NO_PROGRESS", value_parser = BoolishValueParser::new())]
+    no_progress: bool,
+
+    /// Show no progress bars for copies smaller than SIZE in total
+    #[arg(long, value_name = "SIZE", default_value = "1M", value_parser = parse_size)]
+    progress_threshold: u64,
+
+    /// Least time between progress updates, e.g. 200ms
+    #[arg(long, value_name = "DURATION", default_value = "50ms", value_parser = parse_duration)]
+    progress_interval: Duration,
+
+    /// Write progress events as JSON lines to STREAM: stderr (the default, replacing the bars), stdout or a file descriptor number
+    #[arg(long, value_name = "STREAM", num_args = 0..=1, default_missing_value = "stderr", require_equals = true)]
+    progress_json: Option<String>,
+
+    /// Serve the same progress events to clients of a Unix socket at PATH (a named pipe on Windows)
+    #[arg(long, value_name = "PATH")]
+    progress_socket: Option<PathBuf>,
+
+    /// Serve the progress as Prometheus metrics at http://ADDR/metrics while copying
+    #[cfg(feature = "metrics")]
+    #[arg(long, value_name = "ADDR")]
+    metrics_listen: Option<String>,
+
+    /// POST the outcome of the copy, its statistics and failures as JSON, to URL when it ends
+    #[cfg(feature = "webhook")]
+    #[arg(long, value_name = "URL")]
+    notify_url: Option<String>,
+
+    /// Template of the progress bar, e.g. "{bar:40} {percent}% {bytes_per_sec}"
+    #[arg(long, value_name = "TEMPLATE", env = "CPV_PROGRESS_TEMPLATE")]
+    progress_template: Option<String>,
+
+    /// Characters drawing the done, current and remaining parts of the bars (default from the theme)
+    #[arg(long, value_name = "CHARS")]
+    progress_chars: Option<String>,
+
+    /// Look of the progress bars (classic, blocks or plain)
+    #[arg(long, value_name = "THEME", default_value_t = Theme::Classic, env = "CPV_THEME")]
+    theme: Theme,
+
+    /// When to color the output (auto, always or never); auto honors NO_COLOR and CLICOLOR
+    #[arg(long, value_name = "WHEN", num_args = 0..=1, default_value_t = ColorChoice::Auto, default_missing_value = "always", require_equals = true, env = "CPV_COLOR")]
+    color: ColorChoice,
+
+    /// Read defaults from this configuration file instead of ~/.config/cpv/config.toml
+    #[arg(long, value_name = "PATH", env = "CPV_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Normalize destination names to a Unicode form (nfc, nfd or none)
+    #[arg(long, value_name = "FORM", default_value_t = NameNormalization::None)]
+    normalize: NameNormalization,
+
+    /// Names differing only by case on a case-insensitive destination (error, warn or ignore)
+    #[arg(long, value_name = "POLICY", default_value_t = CollisionPolicy::Error)]
+    case_collisions: CollisionPolicy,
+
+    /// Set modes on everything written, rsync-style (e.g. D755,F644)
+    #[arg(long, value_name = "RULES")]
+    chmod: Option<ChmodRules>,
+
+    /// Set owner and/or group on everything written (USER:GROUP)
+    #[arg(long, value_name = "USER:GROUP")]
+    chown: Option<Chown>,
+
+    /// Translate preserved owners, rsync-style (e.g. 1000:1001,*:nobody)
+    #[arg(long, value_name = "FROM:TO", value_parser = IdMap::parse_users)]
+    usermap: Option<IdMap>,
+
+    /// Translate preserved groups, rsync-style (e.g. 100:1000)
+    #[arg(long, value_name = "FROM:TO", value_parser = IdMap::parse_groups)]
+    groupmap: Option<IdMap>,
+
+    /// Control creation of sparse files (auto, always or never)
+    #[arg(long, value_name = "WHEN", default_value_t = SparseMode::Auto)]
+    sparse: SparseMode,
+
+    /// Preserve Linux inode flags such as immutable and append-only
+    #[arg(long)]
+    preserve_flags: bool,
+
+    /// Copy from a Volume Shadow Copy snapshot so locked files can be read (Windows)
+    #[arg(long)]
+    vss: bool,
+
+    /// Clone file data with copy-on-write reflinks (auto, always or never)
+    #[arg(long, value_name = "WHEN", default_value_t = ReflinkMode::Auto, default_missing_value = "always", num_args = 0..=1, require_equals = true)]
+    reflink: ReflinkMode,
+
+    /// After the copy, share the extents of copied files with identical files already on DEST's filesystem, found through the checksum cache (Btrfs, XFS; Linux)
+    #[arg(long, conflicts_with_all = ["dry_run", "verify_only"])]
+    share_extents: bool,
+
+    /// Number of files to copy in parallel: auto (from the CPUs and storage), N or max:N
+    #[arg(short = 'j', long, value_name = "N", default_value_t = Parallelism::Auto, env = "CPV_JOBS")]
+    jobs: Parallelism,
+
+    /// Limit the parallel copies on each disk separately, as --jobs auto would: one per spinning disk, more for SSDs, for trees spanning several disks
+    #[arg(long)]
+    per_device: bool,
+
+    /// Copy ranges of a single large file on N threads
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    chunked_threads: usize,
+
+    /// Copy engine: auto, buffered, copy-file-range, sendfile, io-uring, mmap or pipelined
+    #[arg(long, value_name = "BACKEND", default_value_t = CopyBackend::Auto)]
+    backend: CopyBackend,
+
+    /// Bypass the page cache with O_DIRECT (Linux)
+    #[arg(long)]
+    direct_io: bool,
+
+    /// Do not reserve space for destination files before writing them
+    #[arg(long)]
+    no_preallocate: bool,
+
+    /// Evict copied data from the page cache as the copy progresses
+    #[arg(long)]
+    drop_cache: bool,
+
+    /// Fixed I/O buffer size, e.g. 4M (adaptive by default)
+    #[arg(long, value_name = "SIZE", value_parser = parse_buffer_size, env = "CPV_BUFFER_SIZE")]
+    buffer_size: Option<usize>,
+
+    /// Cap the memory of the copy buffers of all workers together, e.g. 256M; workers wait for room
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_memory: Option<u64>,
+
+    /// Order files are copied in: as-found, smallest-first or largest-first
+    #[arg(long, value_name = "ORDER", default_value_t = FileOrder::AsFound)]
+    order: FileOrder,
+
+    /// Limit bandwidth to RATE bytes per second, e.g. 50M (0 for no limit)
+    #[arg(long, value_name = "RATE", value_parser = parse_size, env = "CPV_BWLIMIT")]
+    bwlimit: Option<u64>,
+
+    /// Wait until TIME before copying: HH:MM for its next occurrence, or a date and time such as 2024-05-06 22:00
+    #[arg(long, value_name = "TIME", value_parser = parse_start_time)]
+    start_at: Option<SystemTime>,
+
+    /// Stop copying during this window of every day, e.g. 08:00-18:00, and go on once it ends
+    #[arg(long, value_name = "WINDOW")]
+    pause_between: Option<TimeWindow>,
+
+    /// Slow down while other processes wait on I/O and speed up again once the system is idle (Linux)
+    #[arg(long)]
+    adaptive_throttle: bool,
+
+    /// Run at the lowest CPU and I/O priority
+    #[arg(long)]
+    low_priority: bool,
+
+    /// Flush each copied file (file) and its directory (dir) to the device
+    #[arg(long, value_name = "WHAT", default_value_t = SyncMode::None)]
+    sync: SyncMode,
+
+    /// Re-read each copied file and fail if its hash differs from the source
+    #[arg(long)]
+    verify: bool,
+
+    /// Once copied, re-read this share of the files picked at random, e.g. 5%, and the largest and retried ones, instead of all of them as --verify does
+    #[arg(long, value_name = "PERCENT", value_parser = parse_sample, conflicts_with = "verify")]
+    verify_sample: Option<f64>,
+
+    /// Hash used to compare files: blake3, xxhash64 or sha256
+    #[arg(long, value_name = "ALGO", default_value_t = ChecksumAlgo::Blake3, env = "CPV_CHECKSUM_ALGO")]
+    checksum_algo: ChecksumAlgo,
+
+    /// Print a checksum of each copied file, computed from the data as it is copied
+    #[arg(long)]
+    checksum_only: bool,
+
+    /// Write a checksum manifest of the copied files (sha256sum/b3sum format)
+    #[arg(long, value_name = "FILE")]
+    manifest: Option<PathBuf>,
+
+    /// Compare SOURCE with an existing copy at DEST instead of copying
+    #[arg(long)]
+    verify_only: bool,
+
+    /// List the files that would be copied, and those it would overwrite or delete, without copying
+    #[arg(short = 'n', long)]
+    dry_run: bool,
+
+    /// Before copying, check that every source entry can be read and DEST written, owners and extended attributes kept with -p, and list all the problems found instead of copying
+    #[arg(long, conflicts_with = "verify_only")]
+    preflight: bool,
+
+    /// Hash every file instead of reusing checksums cached from earlier runs
+    #[arg(long)]
+    no_cache: bool,
+
+    /// Write a JSON report of every file's outcome, checksum and duration to FILE
+    #[arg(long, value_name = "FILE")]
+    report: Option<PathBuf>,
+
+    /// Continue partial files and skip files finished by an interrupted recursive copy
+    #[arg(long)]
+    resume: bool,
+
+    /// Start over without asking when an interrupted recursive copy to DEST is found
+    #[arg(long, conflicts_with = "resume")]
+    no_resume: bool,
+
+    /// Write into hidden .NAME.part files, renamed into place when complete and continued after an interruption
+    #[arg(long)]
+    partial: bool,
+
+    /// When DEST fills up, pause and show how much more space is needed until enough is freed, rather than fail
+    #[arg(long)]
+    wait_for_space: bool,
+
+    /// Wait while another cpv started with --take-turns copies from or to the same disk, rather than compete with it
+    #[arg(long)]
+    take_turns: bool,
+
+    /// When DEST fills up, ask for the next disk to be mounted in its place and continue there, listing the files on each disk in MANIFEST (kept off the disks)
+    #[arg(long, value_name = "MANIFEST", conflicts_with = "wait_for_space")]
+    span: Option<PathBuf>,
+
+    /// Copy a file failing with a transient I/O error again up to N times
+    #[arg(long, value_name = "N", default_value_t = 0, env = "CPV_RETRIES")]
+    retries: u32,
+
+    /// Wait before the first retry, doubled for each further one (e.g. 500ms, 2s)
+    #[arg(long, value_name = "DELAY", default_value = "1s", value_parser = parse_duration)]
+    retry_delay: Duration,
+
+    /// Flush the files copied and the resume journal every INTERVAL, N files or SIZE, such as 30s, 1000files or 10GB, or a comma-separated mix, whichever comes first
+    #[arg(long, value_name = "WHEN")]
+    checkpoint: Option<Checkpoint>,
+
+    /// Copy a file that --verify finds different from its source again, up to N times, before failing it
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    repairs: u32,
+
+    /// Keep copying after a file fails, list the failures at the end and exit with status 2
+    #[arg(long)]
+    keep_going: bool,
+
+    /// What failures of each class do, overriding --keep-going and --retries: CLASS=ACTION,... with classes permission, not-found, disk-full, read-error, transient, unsupported-metadata and other, and actions abort, skip and retry
+    #[arg(long, value_name = "RULES")]
+    on_error: Option<ErrorRules>,
+
+    /// When a source changes while it is copied: warn, retry or fail
+    #[arg(long, value_name = "POLICY", default_value_t = ChangePolicy::Warn)]
+    source_changes: ChangePolicy,
+
+    /// Keep copying what is appended to SOURCE, a file still being written, until it has not grown for QUIET, then verify the copy
+    #[arg(long, value_name = "QUIET", num_args = 0..=1, default_missing_value = "5s", require_equals = true, value_parser = parse_duration, conflicts_with = "recursive")]
+    follow: Option<Duration>,
+
+    /// Update existing destination files in place, writing only changed blocks
+    #[arg(long)]
+    delta: bool,
+
+    /// Hard-link files unchanged since a previous copy in DIR instead of copying them
+    #[arg(long, value_name = "DIR")]
+    link_dest: Option<PathBuf>,
+
+    /// Before a recursive copy, report the files whose contents another source file or a file of DEST already has
+    #[arg(long, requires = "recursive")]
+    dedupe_report: bool,
+
+    /// Report duplicates as --dedupe-report does, and skip them or hard-link them to the file with their contents (report, skip or link)
+    #[arg(long, value_name = "ACTION", requires = "recursive")]
+    dedupe: Option<Dedupe>,
+
+    /// Hard-link files to their sources instead of copying them (same filesystem only)
+    #[arg(short = 'l', long, conflicts_with = "symbolic_link")]
+    link: bool,
+
+    /// Create symbolic links to the sources instead of copying them
+    #[arg(short = 's', long)]
+    symbolic_link: bool,
+
+    /// Make the links of -s relative to their location rather than absolute
+    #[arg(long, requires = "symbolic_link")]
+    relative_links: bool,
+
+    /// Move destination files that would be replaced to the system trash
+    #[arg(long, conflicts_with_all = ["delta", "resume", "force", "interactive"])]
+    trash_overwrites: bool,
+
+    /// Move destination files that would be replaced into DIR, keeping their relative paths
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["delta", "resume", "trash_overwrites", "force", "interactive"])]
+    backup_dir: Option<PathBuf>,
+
+    /// Compress file data sent to and from cpv:// servers with zstd, except data compressed already
+    #[arg(long)]
+    compress_transit: bool,
+
+    /// Connect to cpv:// servers over TLS, trusting the certificates in FILE (PEM)
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "FILE")]
+    tls_ca: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Compare copy strategies on this machine with a generated payload
+    Bench {
+        /// Directory to generate the payload and copies in
+        #[arg(name = "TARGET", default_value = ".")]
+        target: PathBuf,
+
+        /// Total payload size
+        #[arg(long, value_name = "SIZE", default_value = "256M", value_parser = parse_size)]
+        size: u64,
+
+        /// Number of files in the payload
+        #[arg(long, value_name = "N", default_value_t = 1)]
+        files: usize,
+
+        /// How the size is spread over the files (uniform or mixed)
+        #[arg(long, value_name = "DIST", default_value_t = Distribution::Uniform)]
+        distribution: Distribution,
+    },
+    /// Serve a directory to cpv://host/path sources and destinations on other machines.
+    /// Clients must present the token in CPV_TOKEN when it is set.
+    Serve {
+        /// Address to listen on, such as 0.0.0.0:7373 for every network; other machines need a token in CPV_TOKEN
+        #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:7373")]
+        listen: String,
+
+        /// Directory to serve
+        #[arg(long, value_name = "DIR")]
+        root: PathBuf,
+
+        /// Refuse to write anything
+        #[arg(long)]
+        read_only: bool,
+
+        /// Let clients on other machines write without a token
+        #[arg(long)]
+        no_auth: bool,
+
+        /// Serve over TLS with the certificate chain in FILE (PEM)
+        #[cfg(feature = "tls")]
+        #[arg(long, value_name = "FILE", requires = "tls_key")]
+        tls_cert: Option<PathBuf>,
+
+        /// Private key of --tls-cert (PEM)
+        #[cfg(feature = "tls")]
+        #[arg(long, value_name = "FILE", requires = "tls_cert")]
+        tls_key: Option<PathBuf>,
+    },
+    /// Run the queue that `cpv enqueue` adds copies to, until killed (Unix)
+    Daemon {
+        /// Copies to run at once
+        #[arg(long, value_name = "N", default_value_t = 1)]
+        jobs: usize,
+
+        /// Socket to listen on [default: $XDG_RUNTIME_DIR/cpv.sock]
+        #[arg(long, value_name = "PATH")]
+        socket: Option<PathBuf>,
+    },
+    /// Add a copy to the queue of `cpv daemon`
+    Enqueue {
+        /// Socket of the daemon [default: $XDG_RUNTIME_DIR/cpv.sock]
+        #[arg(long, value_name = "PATH")]
+        socket: Option<PathBuf>,
+
+        /// The options, SOURCE and DEST of the copy, as for `cpv`
+        #[arg(value_name = "ARGS", required = true, num_args = 2.., trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+    /// List the jobs of `cpv daemon`, or pause, resume, cancel or move one
+    Jobs {
+        /// Socket of the daemon [default: $XDG_RUNTIME_DIR/cpv.sock]
+        #[arg(long, value_name = "PATH")]
+        socket: Option<PathBuf>,
+
+        #[command(subcommand)]
+        action: Option<JobAction>,
+    },
+    /// Show the progress of the jobs of `cpv daemon`
+    Status {
+        /// Socket of the daemon [default: $XDG_RUNTIME_DIR/cpv.sock]
+        #[arg(long, value_name = "PATH")]
+        socket: Option<PathBuf>,
+
+        /// Only this job
+        #[arg(value_name = "ID")]
+        id: Option<u64>,
+    },
+    /// Stage PATHs for `cpv paste` to copy, from any shell or directory, or list those staged
+    Stage {
+        /// Unstage everything first
+        #[arg(long)]
+        clear: bool,
+
+        /// Files and directories to stage
+        #[arg(value_name = "PATH")]
+        paths: Vec<PathBuf>,
+    },
+    /// Copy what `cpv stage` staged into DEST, then unstage it
+    Paste {
+        /// Leave what was pasted staged, to paste it again elsewhere
+        #[arg(long)]
+        keep: bool,
+
+        /// Directory to paste into
+        #[arg(value_name = "DEST", default_value = ".")]
+        dest: PathBuf,
+
+        /// Options of the copies, as for `cpv`, after --
+        #[arg(value_name = "OPTIONS", last = true)]
+        options: Vec<String>,
+    },
+    /// Remove the files the last copy created and move back those it backed up or moved to the trash
+    Undo,
+    /// Try which copy features this platform and the filesystems of DIRs support
+    Doctor {
+        /// Directories to try, such as the source and destination of a copy
+        #[arg(value_name = "DIR", default_value = ".")]
+        dirs: Vec<PathBuf>,
+    },
+    /// Print shell completions or the man page of cpv, for packages
+    Generate {
+        #[command(subcommand)]
+        what: Generate,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum Generate {
+    /// Print the completion script of SHELL (bash, elvish, fish, powershell or zsh)
+    Completions { shell: Shell },
+    /// Print the man page, in roff
+    Man,
+}
+
+#[derive(Subcommand, Debug)]
+enum JobAction {
+    /// Hold a queued job, or stop a running one where it is
+    Pause { id: u64 },
+    /// Let a paused job run again
+    Resume { id: u64 },
+    /// Drop a queued job, or stop a running one
+    Cancel { id: u64 },
+    /// Move a job to POSITION in the queue, counted from 1
+    Move { id: u64, position: usize },
 }
 
 fn main() {
-    let args = Args::parse();
+    let matches = command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    if let Some(Command::Generate { what }) = &args.command {
+        run_generate(what);
+    }
+    configure(&mut args, &matches);
+    args.color.apply();
+
+    if let Some(Command::Bench {
+        target,
+        size,
+        files,
+        distribution,
+    }) = &args.command
+    {
+        let config = BenchConfig {
+            size: *size,
+            files: *files,
+            distribution: *distribution,
+        };
+        run_bench(target, &config);
+        return;
+    }
+    if let Some(Command::Serve { .. }) = &args.command {
+        run_server(args.command.unwrap());
+    }
+    if let Some(Command::Stage { .. } | Command::Paste { .. }) = &args.command {
+        run_staging(args.command.unwrap());
+    }
+    if let Some(Command::Undo) = &args.command {
+        run_undo();
+    }
+    if let Some(Command::Doctor { dirs }) = &args.command {
+        run_doctor(dirs);
+    }
+    if let Some(command) = args.command {
+        run_queue(command);
+    }
+    QUIET.store(args.quiet, Ordering::Relaxed);
+    let mut net = NetConfig::default();
+    net.token = env::var("CPV_TOKEN").ok();
+    net.compress = args.compress_transit;
+    net.checksum = Some(args.checksum_algo);
+    #[cfg(feature = "tls")]
+    if let Some(ca) = &args.tls_ca {
+        net.tls = Some(ClientTls::from_pem_file(ca).unwrap_or_else(|err| fail(err)));
+    }
+    let source = args.source.expect("SOURCE is required");
+    let destination = args.destination.expect("DEST is required");
+    // With --concat, the last path is the destination, and the ones
+    // before it the files to concatenate.
+    let (source, destination, concat) = match args.concat {
+        true => {
+            let mut paths: Vec<PathBuf> =
+                [source, destination].into_iter().chain(args.more).collect();
+            let destination = paths.pop().expect("DEST is required");
+            let source = paths.remove(0);
+            (source, destination, paths)
+        }
+        false => (source, destination, Vec::new()),
+    };
+    let archiving = writes_tar(&source, &destination);
+    let to_stdout = destination == Path::new("-");
+    let (source, source_fs) = match args.extract {
+        true => archive(&source),
+        false => remote(source, &net),
+    };
+    let (destination, dest_fs) = match archiving {
+        true => tar(&destination),
+        false => remote(destination, &net),
+    };
+    let transformed = args.compress.is_some() || args.encrypt || args.decrypt;
+    let range = (args.offset.is_some() || args.length.is_some() || args.dest_offset.is_some())
+        .then(|| ByteRange {
+            offset: args.offset.unwrap_or(0),
+            length: args.length,
+            dest_offset: args.dest_offset,
+        });
+    let parted = args.split.is_some() || args.join || args.concat || range.is_some();
+    if (source_fs.is_some() || dest_fs.is_some() || transformed || parted)
+        && (args.verify_only || args.dry_run)
+    {
+        fail("--verify-only and --dry-run need local paths, without --compress, --encrypt, --decrypt, --split, --join, --concat or a byte range");
+    }
+    if args.concat && (source_fs.is_some() || args.extract) {
+        fail("--concat needs local files to concatenate");
+    }
+    if args.split == Some(0) {
+        fail("--split needs a size of at least one byte");
+    }
+    if args.pick && (source_fs.is_some() || args.concat || !source.is_dir()) {
+        fail("--pick needs a local directory to pick from");
+    }
+    if args.pick && !(io::stdin().is_terminal() && io::stderr().is_terminal()) {
+        fail("--pick needs a terminal");
+    }
+    if args.preflight && (source_fs.is_some() || dest_fs.is_some()) {
+        fail("--preflight needs a local source and destination");
+    }
+    if args.watch && (source_fs.is_some() || archiving) {
+        fail("--watch needs a local source and cannot write a tar archive");
+    }
+    if archiving && args.delete {
+        fail("--delete cannot remove files from a tar archive");
+    }
+    if archiving && args.verify {
+        fail("--verify cannot read back a tar archive");
+    }
+    if archiving && (transformed || parted) {
+        fail("--compress, --encrypt, --decrypt, --split, --join and --concat do not apply to tar archive destinations");
+    }
+    if args.encrypt && args.verify {
+        fail("--verify cannot read back encrypted files");
+    }
+    if (source_fs.is_some() || dest_fs.is_some() || archiving || transformed || parted)
+        && args.verify_sample.is_some()
+    {
+        fail("--verify-sample needs local paths, without --compress, --encrypt, --decrypt, --split, --join or --concat");
+    }
+    let (destination, source_fs, dest_fs) = match args.encrypt || args.decrypt {
+        true => crypt(
+            (&args.recipient, &args.identity),
+            &source,
+            destination,
+            source_fs,
+            dest_fs,
+        ),
+        false => (destination, source_fs, dest_fs),
+    };
+    // The parts of one file, given by the first, are copied as that file.
+    let source = match args.join && source.extension().is_some_and(|ext| ext == "000") {
+        true => source.with_extension(""),
+        false => source,
+    };
+    #[cfg(not(feature = "zstd"))]
+    if args.compress == Some(Compression::Zstd) {
+        fail("cannot compress with zstd: cpv was built without the zstd feature");
+    }
+    let prints = args.verbose
+        || args.porcelain
+        || args.checksum_only
+        || (args.stats_format.is_some() && args.stats_file.is_none());
+    if to_stdout && prints {
+        fail("--verbose, --porcelain, --checksum-only and --stats-format print to standard output, where the archive goes");
+    }
+
+    if args.low_priority {
+        if let Err(err) = lower_priority() {
+            warn(format_args!("cannot lower priority: {}", err));
+        }
+    }
 
-    let options = CopyOptions {
-        preserve_attrs: args.preserve,
-        force: args.force,
-        verbose: args.verbose,
-        recursive: args.recursive,
+    let progress_socket =
+        args.progress_socket
+            .as_deref()
+            .map(|path| match EventSocket::bind(path) {
+                Ok(socket) => socket,
+                Err(err) => fail(format_args!(
+                    "cannot listen on progress socket '{}': {}",
+                    path.display(),
+                    err
+                )),
+            });
+    let progress_json = args
+        .progress_json
+        .as_deref()
+        .map(|stream| match event_stream(stream) {
+            Ok(out) => JsonEvents::new(out),
+            Err(err) => fail(format_args!(
+                "cannot write progress events to '{}': {}",
+                stream, err
+            )),
+        });
+    let progress_json = match (progress_json, progress_socket) {
+        (Some(events), Some(socket)) => Some(Arc::new(events.with_output(socket))),
+        (Some(events), None) => Some(Arc::new(events)),
+        (None, Some(socket)) => Some(Arc::new(JsonEvents::new(socket))),
+        (None, None) => None,
+    };
+    #[cfg(feature = "metrics")]
+    let live_stats = args.metrics_listen.as_deref().map(|addr| {
+        let live = LiveStats::new();
+        if let Err(err) = MetricsServer::bind(addr, Arc::clone(&live)) {
+            fail(format_args!("cannot serve metrics at '{}': {}", addr, err));
+        }
+        live
+    });
+    #[cfg(not(feature = "metrics"))]
+    let live_stats = None;
+    #[cfg(feature = "webhook")]
+    let webhook = args
+        .notify_url
+        .as_deref()
+        .map(|url| Webhook::new(url).unwrap_or_else(|err| fail(err)));
+    #[cfg(feature = "dbus")]
+    let desktop_progress = args.desktop_progress;
+    #[cfg(not(feature = "dbus"))]
+    let desktop_progress = false;
+    let log_file = args
+        .log_file
+        .as_deref()
+        .map(|path| match LogFile::open(path) {
+            Ok(log) => Arc::new(log),
+            Err(err) => fail(format_args!(
+                "cannot open log file '{}': {}",
+                path.display(),
+                err
+            )),
+        });
+    let hashes = args.verify
+        || args.verify_sample.is_some()
+        || args.verify_only
+        || args.checksum_only
+        || args.share_extents
+        || args.manifest.is_some();
+    let checksum_cache = if hashes && !args.no_cache {
+        open_cache()
+    } else {
+        None
     };
+    let overwrite = match (&args.backup_dir, args.overwrite.clone()) {
+        (Some(dir), _) => OverwritePolicy::Backup(dir.clone()),
+        _ if args.trash_overwrites => OverwritePolicy::Trash,
+        _ if args.interactive => OverwritePolicy::Prompt,
+        (None, policy) => policy.unwrap_or_default(),
+    };
+    let prompting = overwrite == OverwritePolicy::Prompt;
+    let cancel = CancellationToken::new();
+    let mut options = CopyOptions::builder()
+        .preserve_attrs(args.preserve)
+        .verbose(args.verbose)
+        .recursive(args.recursive || args.extract || args.watch || args.mirror || args.pick)
+        .normalization(args.normalize)
+        .case_collisions(args.case_collisions)
+        .chmod(args.chmod)
+        .chown(args.chown)
+        .usermap(args.usermap.unwrap_or_default())
+        .groupmap(args.groupmap.unwrap_or_default())
+        .sparse(args.sparse)
+        .preserve_flags(args.preserve_flags)
+        .vss(args.vss)
+        .reflink(args.reflink)
+        .share_extents(args.share_extents)
+        .parallelism(args.jobs)
+        .per_device(args.per_device)
+        .chunked_threads(args.chunked_threads)
+        .backend(args.backend)
+        .direct_io(args.direct_io)
+        .no_preallocate(args.no_preallocate)
+        .drop_cache(args.drop_cache)
+        .buffer_size(args.buffer_size)
+        .max_memory(args.max_memory)
+        .order(args.order)
+        .auto_tune(true)
+        .rate_limiter(
+            args.bwlimit
+                .filter(|&rate| rate > 0)
+                .map(|rate| Arc::new(TokenBucket::new(rate)) as Arc<dyn RateLimiter>),
+        )
+        .start_at(args.start_at)
+        .pause_between(args.pause_between)
+        .adaptive_throttle(args.adaptive_throttle)
+        .sync(args.sync)
+        .verify(args.verify || args.follow.is_some())
+        .verify_sample(args.verify_sample)
+        .checksum(args.checksum_algo)
+        .record_checksums(args.checksum_only)
+        .manifest(args.manifest)
+        .resume(args.resume)
+        .partial(args.partial)
+        .checkpoint(args.checkpoint)
+        .retries(args.retries)
+        .repairs(args.repairs)
+        .retry_delay(args.retry_delay)
+        .wait_for_space(args.wait_for_space)
+        .take_turns(args.take_turns)
+        .keep_going(args.keep_going)
+        .on_error(args.on_error.unwrap_or_default())
+        .source_changes(args.source_changes)
+        .follow(args.follow)
+        .delta(args.delta)
+        .link_dest(args.link_dest)
+        .dedupe(args.dedupe.or(args.dedupe_report.then_some(Dedupe::Report)))
+        .link(if args.link {
+            LinkMode::Hard
+        } else if args.symbolic_link {
+            LinkMode::Symbolic
+        } else {
+            LinkMode::Copy
+        })
+        .relative_links(args.relative_links)
+        .overwrite(overwrite.clone())
+        .mirror(args.mirror)
+        .delete(args.delete)
+        .hooks((prompting || args.span.is_some()).then(|| {
+            Arc::new(Prompt {
+                conflicts: prompting,
+                ..Prompt::default()
+            }) as Arc<dyn CopyHooks>
+        }))
+        .source_fs(source_fs)
+        .dest_fs(dest_fs)
+        .compress(args.compress)
+        .exclude(args.exclude)
+        .split(args.split)
+        .join(args.join)
+        .concat(concat)
+        .range(range)
+        .checksum_cache(checksum_cache)
+        .cancel(cancel.clone())
+        .record_files(args.report.is_some())
+        .progress_json(progress_json)
+        .progress_throttle(ProgressThrottle {
+            interval: args.progress_interval,
+            ..Default::default()
+        })
+        .progress_template(args.progress_template)
+        .progress_chars(args.progress_chars)
+        .theme(args.theme)
+        .status_signals(true)
+        .terminal_title(args.title)
+        .systemd_notify(true)
+        .desktop_progress(desktop_progress)
+        .progress_threshold(args.progress_threshold)
+        .log_file(log_file)
+        .live_stats(live_stats)
+        .porcelain(
+            args.porcelain
+                .then(|| Arc::new(Porcelain::new(io::stdout()))),
+        )
+        .progress(
+            if args.quiet
+                || args.no_progress
+                || prompting
+                || args.span.is_some()
+                || args.progress_json.as_deref() == Some("stderr")
+            {
+                ProgressMode::Hidden
+            } else {
+                ProgressMode::Auto
+            },
+        )
+        .build();
+
+    if args.pick {
+        match pick(&source) {
+            Ok(Some(deselected)) => options.deselected = deselected,
+            Ok(None) => fail_with(ExitStatus::Interrupted, "nothing picked, nothing copied"),
+            Err(err) => fail(format_args!(
+                "cannot pick from '{}': {}",
+                source.display(),
+                err
+            )),
+        }
+    }
+
+    if args.preflight {
+        run_preflight(&source, &destination, &options);
+    }
 
-    match copy_with_progress(&args.source, &args.destination, &options) {
+    if !options.resume && !args.no_resume && !args.verify_only && !args.dry_run && !args.watch {
+        options.resume = offer_resume(&source, &destination, &options);
+    }
+
+    if args.verify_only {
+        run_verify_only(&source, &destination, &options);
+        return;
+    }
+    if args.dry_run {
+        run_dry_run(&source, &destination, &options);
+        return;
+    }
+
+    // Recorded only for copies made on this machine.
+    if let (Some(path), None) = (UndoLog::default_path(), &options.dest_fs) {
+        let log = match args.undo_append {
+            true => UndoLog::append(&path),
+            false => UndoLog::create(&path),
+        };
+        match log {
+            Ok(log) => options.undo_log = Some(Arc::new(log)),
+            Err(err) => warn(format_args!("cannot record the copy for undo: {}", err)),
+        }
+    }
+    if options.dest_fs.is_none() {
+        options.throughput_history = open_history();
+    }
+    if let Some(path) = &args.span {
+        match Volumes::create(path) {
+            Ok(volumes) => options.span = Some(Arc::new(volumes)),
+            Err(err) => fail(format_args!(
+                "cannot create volume manifest '{}': {}",
+                path.display(),
+                err
+            )),
+        }
+    }
+
+    // Ctrl-C stops the copy cleanly rather than killing it, once past the
+    // questions asked on the terminal.
+    #[cfg(unix)]
+    if let Err(err) = cancel.cancel_on_interrupt() {
+        warn(format_args!("cannot handle interrupts: {}", err));
+    }
+    let result = match args.watch {
+        true => watch(&source, &destination, &options),
+        false => copy_with_progress(&source, &destination, &options),
+    };
+    save_cache(&options);
+    save_history(&options);
+    save_undo(&options);
+    #[cfg(feature = "webhook")]
+    if let Some(webhook) = &webhook {
+        if let Err(err) = webhook.send(&source, &destination, &result) {
+            warn(format_args!("cannot notify: {}", err));
+        }
+    }
+    let outcome = ExitStatus::of(&result);
+    match result {
         Ok(stats) => {
+            for warning in &stats.warnings {
+                warn(warning);
+            }
+            if options.record_checksums {
+                for (path, digest) in &stats.checksums {
+                    println!("{}  {}", digest, path.display());
+                }
+            }
             if options.verbose {
-                println!("{}", stats.format_summary());
+                if let Some(profile) = &stats.storage {
+                    println!("Storage: {}", profile);
+                }
+                println!(
+                    "{}, done at {}",
+                    stats.format_summary(),
+                    Local::now().format("%H:%M")
+                );
+                if let Some(top) = stats.format_top_files() {
+                    println!("{}", top);
+                }
+            }
+            if let Some(duplicates) = stats.format_duplicates() {
+                eprintln!("{}", duplicates);
+            }
+            if let Some(renamed) = stats.format_renamed() {
+                eprintln!("{}", renamed);
+            }
+            if args.timeline {
+                if let Some(timeline) = stats.format_throughput() {
+                    eprintln!("{}", timeline);
+                }
+            }
+            if let Some(format) = args.stats_format {
+                let summary = Summary::new(&stats);
+                let written = match &args.stats_file {
+                    Some(path) => File::create(path).and_then(|file| summary.write(format, file)),
+                    None => summary.write(format, io::stdout().lock()),
+                };
+                if let Err(err) = written {
+                    fail(format_args!("cannot write statistics: {}", err));
+                }
+            }
+            if let Some(path) = &args.report {
+                if let Err(err) = Report::new(&source, &destination, &options, &stats).write(path) {
+                    fail(format_args!(
+                        "cannot write report '{}': {}",
+                        path.display(),
+                        err
+                    ));
+                }
+            }
+            if !stats.failures.is_empty() {
+                print_failures(&stats.failures);
+            }
+            if outcome != ExitStatus::Completed {
+                process::exit(outcome.exit_code());
             }
         }
+        Err(CopyError::Cancelled(stats)) => {
+            for warning in &stats.warnings {
+                warn(warning);
+            }
+            if !stats.failures.is_empty() {
+                print_failures(&stats.failures);
+            }
+            fail_with(
+                outcome,
+                format_args!("interrupted: {}", stats.format_summary()),
+            );
+        }
         Err(CopyError::NotADirectory(path)) => {
-            eprintln!("cpv: {}: Not a directory", path.display());
-            process::exit(1);
+            fail(format_args!("{}: Not a directory", path.display()));
         }
         Err(CopyError::IsADirectory(path)) => {
-            eprintln!(
-                "cpv: {}: Is a directory (not copied, try using -r)",
+            fail(format_args!(
+                "{}: Is a directory (not copied, try using -r)",
                 path.display()
+            ));
+        }
+        Err(err) => fail_with(outcome, err),
+    }
+}
+
+/// Layers the command line, and the `CPV_*` environment variables under
+/// it, over the configuration file: the settings of the file stand for
+/// the options both leave out.
+fn configure(args: &mut Args, matches: &ArgMatches) {
+    let given = |id| {
+        matches!(
+            matches.value_source(id),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        )
+    };
+    let command_line = Config {
+        preserve: [
+            args.preserve.then_some(Preserved::Attributes),
+            args.preserve_flags.then_some(Preserved::Flags),
+        ]
+        .into_iter()
+        .flatten()
+        .collect(),
+        buffer_size: args.buffer_size,
+        jobs: given("jobs").then_some(args.jobs),
+        progress_template: args.progress_template.take(),
+        exclude: std::mem::take(&mut args.exclude),
+        color: given("color").then_some(args.color),
+    };
+    let config = match Config::load(args.config.as_deref()) {
+        Ok(config) => config.layer(command_line),
+        Err(err) => fail(format_args!("cannot load configuration {}", err)),
+    };
+    args.preserve = config.preserves(Preserved::Attributes);
+    args.preserve_flags = config.preserves(Preserved::Flags);
+    args.buffer_size = config.buffer_size;
+    args.jobs = config.jobs.unwrap_or(args.jobs);
+    args.progress_template = config.progress_template;
+    args.exclude = config.exclude;
+    args.color = config.color.unwrap_or(args.color);
+}
+
+/// Prints the files that could not be copied with `--keep-going` as a
+/// table on stderr.
+fn print_failures(failures: &[FileError]) {
+    let width = failures
+        .iter()
+        .map(|failure| failure.path.display().to_string().chars().count())
+        .max()
+        .unwrap_or(0)
+        .clamp(4, 60);
+    eprintln!(
+        "{} {} file{} could not be copied:",
+        style("cpv:").red().bold().for_stderr(),
+        failures.len(),
+        if failures.len() == 1 { "" } else { "s" }
+    );
+    eprintln!(
+        "{}",
+        style(format!(
+            "{:<width$}  OP      CLASS                 ERROR",
+            "PATH",
+            width = width
+        ))
+        .bold()
+        .for_stderr()
+    );
+    let mut sorted: Vec<_> = failures.iter().collect();
+    sorted.sort_by(|a, b| a.path.cmp(&b.path));
+    for failure in sorted {
+        eprintln!(
+            "{:<width$}  {:<6}  {:<20}  {}",
+            failure.path.display().to_string(),
+            failure.op.to_string(),
+            failure.class.to_string(),
+            failure.source,
+            width = width
+        );
+    }
+}
+
+/// Asks on the terminal before each existing file is replaced, for
+/// `--interactive`, and for the next disk once one is full, for `--span`.
+#[derive(Debug, Default)]
+struct Prompt {
+    /// Whether to ask about existing files.
+    conflicts: bool,
+    /// Held while asking, so that parallel copies ask one at a time.
+    asking: Mutex<()>,
+}
+
+impl CopyHooks for Prompt {
+    fn on_conflict(&self, _source: &Path, dest: &Path) -> FileDecision {
+        if !self.conflicts {
+            return FileDecision::Copy;
+        }
+        let _asking = self.asking.lock().unwrap_or_else(|err| err.into_inner());
+        eprint!("cpv: overwrite '{}'? [y/N] ", dest.display());
+        let mut answer = String::new();
+        match io::stdin().read_line(&mut answer) {
+            Ok(_) if matches!(answer.trim(), "y" | "Y" | "yes") => FileDecision::Copy,
+            _ => FileDecision::Skip,
+        }
+    }
+
+    fn on_volume_full(&self, dest: &Path, volume: u32) -> bool {
+        if !io::stdin().is_terminal() {
+            return false;
+        }
+        let _asking = self.asking.lock().unwrap_or_else(|err| err.into_inner());
+        eprint!(
+            "cpv: disk {} is full. Mount the next one in its place, for '{}', and press Enter, or type q to stop: ",
+            volume,
+            dest.display()
+        );
+        let mut answer = String::new();
+        match io::stdin().read_line(&mut answer) {
+            Ok(_) => !matches!(answer.trim(), "q" | "Q" | "quit"),
+            Err(_) => false,
+        }
+    }
+}
+
+/// Offers to continue the copy of `source` to `dest` that an earlier run
+/// left unfinished, if there is one: asks on a terminal, and only tells
+/// how to otherwise.
+fn offer_resume(source: &Path, dest: &Path, options: &CopyOptions) -> bool {
+    let Some(interrupted) = interrupted_copy(source, dest, options) else {
+        return false;
+    };
+    if interrupted.files == 0 {
+        return false;
+    }
+    let found = format!(
+        "an interrupted copy to '{}' finished {} file{} ({})",
+        interrupted.target.display(),
+        interrupted.files,
+        if interrupted.files == 1 { "" } else { "s" },
+        format_size(interrupted.bytes, BINARY)
+    );
+    if QUIET.load(Ordering::Relaxed) || !io::stdin().is_terminal() {
+        warn(format_args!(
+            "{}; starting over (continue it with --resume)",
+            found
+        ));
+        return false;
+    }
+    eprint!("cpv: {}. Resume it? [Y/n] ", found);
+    let mut answer = String::new();
+    match io::stdin().read_line(&mut answer) {
+        Ok(_) => !matches!(answer.trim(), "n" | "N" | "no"),
+        Err(_) => false,
+    }
+}
+
+/// Set by `--quiet` to silence everything but errors.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Prints a warning unless `--quiet` is given.
+fn warn(message: impl fmt::Display) {
+    if !QUIET.load(Ordering::Relaxed) {
+        eprintln!(
+            "cpv: {} {}",
+            style("warning:").yellow().bold().for_stderr(),
+            message
+        );
+    }
+}
+
+/// Prints an error and exits with status 1.
+fn fail(message: impl fmt::Display) -> ! {
+    fail_with(ExitStatus::Failed, message)
+}
+
+/// The path of `arg` and, for a `[user@]host:path`, `cpv://host/path` or
+/// `s3://bucket/key` argument, a connection to the host or bucket.
+fn remote(arg: PathBuf, net: &NetConfig) -> (PathBuf, Option<Arc<dyn Filesystem>>) {
+    if let Some(url) = arg.to_str().filter(|arg| arg.starts_with("s3://")) {
+        return s3(url);
+    }
+    if let Some((authority, path)) = arg.to_str().and_then(parse_cpv_url) {
+        return match CpvFs::connect(authority, net.clone()) {
+            Ok(fs) => (PathBuf::from(path), Some(Arc::new(fs))),
+            Err(err) => fail(err),
+        };
+    }
+    let Some((host, path)) = arg.to_str().and_then(parse_remote) else {
+        return (arg, None);
+    };
+    match SftpFs::connect(host) {
+        Ok(fs) => (PathBuf::from(path), Some(Arc::new(fs))),
+        Err(err) => fail(err),
+    }
+}
+
+/// A filesystem other than the local one, if any.
+type MaybeFs = Option<Arc<dyn Filesystem>>;
+
+/// The filesystems for `--encrypt` to `recipients`, or `--decrypt` with
+/// the `identities` files, with DEST for decrypting a single `NAME.age`
+/// into a directory, where it is `NAME`.
+#[cfg(feature = "age")]
+fn crypt(
+    (recipients, identities): (&[String], &[PathBuf]),
+    source: &Path,
+    destination: PathBuf,
+    source_fs: MaybeFs,
+    dest_fs: MaybeFs,
+) -> (PathBuf, MaybeFs, MaybeFs) {
+    let local = || Arc::new(LocalFs) as Arc<dyn Filesystem>;
+    if !recipients.is_empty() {
+        let dest_fs = dest_fs.unwrap_or_else(local);
+        return match EncryptFs::new(dest_fs, recipients) {
+            Ok(fs) => (destination, source_fs, Some(Arc::new(fs))),
+            Err(err) => fail(err),
+        };
+    }
+    let mut keys = String::new();
+    for identity in identities {
+        match std::fs::read_to_string(identity) {
+            Ok(text) => {
+                keys.push_str(&text);
+                keys.push('\n');
+            }
+            Err(err) => fail(format_args!(
+                "cannot read identity file '{}': {}",
+                identity.display(),
+                err
+            )),
+        }
+    }
+    let source_fs = source_fs.unwrap_or_else(local);
+    let sealed_file = source.extension().is_some_and(|ext| ext == "age")
+        && source_fs.metadata(source).is_ok_and(|info| !info.is_dir);
+    let into_dir = dest_fs
+        .as_ref()
+        .map_or_else(local, Arc::clone)
+        .metadata(&destination)
+        .is_ok_and(|info| info.is_dir);
+    let destination = match source.file_stem() {
+        Some(name) if sealed_file && into_dir => destination.join(name),
+        _ => destination,
+    };
+    match DecryptFs::new(source_fs, &keys) {
+        Ok(fs) => (destination, Some(Arc::new(fs)), dest_fs),
+        Err(err) => fail(format_args!("cannot decrypt: {}", err)),
+    }
+}
+
+#[cfg(not(feature = "age"))]
+fn crypt(
+    _keys: (&[String], &[PathBuf]),
+    _source: &Path,
+    _destination: PathBuf,
+    _source_fs: MaybeFs,
+    _dest_fs: MaybeFs,
+) -> (PathBuf, MaybeFs, MaybeFs) {
+    fail("cannot encrypt or decrypt: cpv was built without the age feature")
+}
+
+/// The archive `source` as the source filesystem, with its root as the
+/// source path, for `--extract`.
+fn archive(source: &Path) -> (PathBuf, Option<Arc<dyn Filesystem>>) {
+    match ArchiveFs::open(source) {
+        Ok(fs) => (PathBuf::new(), Some(Arc::new(fs))),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => fail(format_args!(
+            "cannot open archive '{}': {}",
+            source.display(),
+            err
+        )),
+        Err(err) => fail(err),
+    }
+}
+
+/// Whether DEST is a tar archive to write: `-`, or a name ending in
+/// `.tar` that is not a directory, unless SOURCE is an archive too.
+fn writes_tar(source: &Path, dest: &Path) -> bool {
+    let is_tar = |path: &Path| {
+        path.extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("tar"))
+    };
+    dest == Path::new("-") || (is_tar(dest) && !dest.is_dir() && !is_tar(source))
+}
+
+/// A tar archive written to `dest`, or to standard output for `-`, as
+/// the destination filesystem, with its root as the destination path.
+fn tar(dest: &Path) -> (PathBuf, Option<Arc<dyn Filesystem>>) {
+    let out: Box<dyn Write + Send> = if dest == Path::new("-") {
+        if io::stdout().is_terminal() {
+            fail("refusing to write a tar archive to a terminal");
+        }
+        Box::new(BufWriter::new(io::stdout()))
+    } else {
+        match File::create(dest) {
+            Ok(file) => Box::new(BufWriter::new(file)),
+            Err(err) => fail(format_args!("cannot create '{}': {}", dest.display(), err)),
+        }
+    };
+    (PathBuf::new(), Some(Arc::new(TarFs::new(out))))
+}
+
+#[cfg(feature = "s3")]
+fn s3(url: &str) -> (PathBuf, Option<Arc<dyn Filesystem>>) {
+    let Some((bucket, key)) = cpv::parse_s3(url) else {
+        fail(format_args!("'{}' names no bucket", url));
+    };
+    match cpv::S3Config::from_env() {
+        Ok(config) => (
+            PathBuf::from(key),
+            Some(Arc::new(cpv::S3Fs::new(bucket, config))),
+        ),
+        Err(err) => fail(err),
+    }
+}
+
+#[cfg(not(feature = "s3"))]
+fn s3(url: &str) -> (PathBuf, Option<Arc<dyn Filesystem>>) {
+    fail(format_args!(
+        "cannot copy '{}': cpv was built without the s3 feature",
+        url
+    ))
+}
+
+/// The command line of cpv, with the names of the values of its options
+/// known to completions, which offer them, and to `--help`, which lists
+/// them in the descriptions already.
+fn command() -> clap::Command {
+    fn named<T>(names: &'static [&'static str]) -> impl FnOnce(Arg) -> Arg
+    where
+        T: FromStr<Err = String> + Clone + Send + Sync + 'static,
+    {
+        move |arg| {
+            arg.value_parser(Named::<T> {
+                names,
+                parsed: PhantomData,
+            })
+            .hide_possible_values(true)
+        }
+    }
+
+    Args::command()
+        .mut_arg("compress", named::<Compression>(Compression::NAMES))
+        .mut_arg(
+            "overwrite",
+            named::<OverwritePolicy>(OverwritePolicy::NAMES),
+        )
+        .mut_arg("stats_format", named::<StatsFormat>(StatsFormat::NAMES))
+        .mut_arg("theme", named::<Theme>(Theme::NAMES))
+        .mut_arg("color", named::<ColorChoice>(ColorChoice::NAMES))
+        .mut_arg(
+            "normalize",
+            named::<NameNormalization>(NameNormalization::NAMES),
+        )
+        .mut_arg(
+            "case_collisions",
+            named::<CollisionPolicy>(CollisionPolicy::NAMES),
+        )
+        .mut_arg("sparse", named::<SparseMode>(SparseMode::NAMES))
+        .mut_arg("reflink", named::<ReflinkMode>(ReflinkMode::NAMES))
+        .mut_arg("backend", named::<CopyBackend>(CopyBackend::NAMES))
+        .mut_arg("order", named::<FileOrder>(FileOrder::NAMES))
+        .mut_arg("sync", named::<SyncMode>(SyncMode::NAMES))
+        .mut_arg("checksum_algo", named::<ChecksumAlgo>(ChecksumAlgo::NAMES))
+        .mut_arg("source_changes", named::<ChangePolicy>(ChangePolicy::NAMES))
+        .mut_arg("dedupe", named::<Dedupe>(Dedupe::NAMES))
+        .mut_subcommand("bench", |bench| {
+            bench.mut_arg("distribution", named::<Distribution>(Distribution::NAMES))
+        })
+}
+
+/// Parses a value with `FromStr`, as the derived parser does, but knows
+/// the names of the values.
+#[derive(Clone)]
+struct Named<T> {
+    names: &'static [&'static str],
+    parsed: PhantomData<fn() -> T>,
+}
+
+impl<T> TypedValueParser for Named<T>
+where
+    T: FromStr<Err = String> + Clone + Send + Sync + 'static,
+{
+    type Value = T;
+
+    fn parse_ref(
+        &self,
+        command: &clap::Command,
+        arg: Option<&Arg>,
+        value: &OsStr,
+    ) -> Result<T, clap::Error> {
+        T::from_str.parse_ref(command, arg, value)
+    }
+
+    fn possible_values(&self) -> Option<Box<dyn Iterator<Item = PossibleValue> + '_>> {
+        Some(Box::new(self.names.iter().copied().map(PossibleValue::new)))
+    }
+}
+
+/// Prints what `cpv generate` asks for on stdout.
+fn run_generate(what: &Generate) -> ! {
+    let mut stdout = io::stdout().lock();
+    match what {
+        Generate::Completions { shell } => {
+            clap_complete::generate(*shell, &mut command(), "cpv", &mut stdout)
+        }
+        Generate::Man => match clap_mangen::Man::new(command()).render(&mut stdout) {
+            Err(err) if err.kind() != io::ErrorKind::BrokenPipe => {
+                fail(format_args!("cannot write the man page: {}", err))
+            }
+            _ => {}
+        },
+    }
+    process::exit(0)
+}
+
+/// Runs `cpv serve` until it fails.
+fn run_server(command: Command) -> ! {
+    let Command::Serve {
+        listen,
+        root,
+        read_only,
+        no_auth,
+        #[cfg(feature = "tls")]
+        tls_cert,
+        #[cfg(feature = "tls")]
+        tls_key,
+    } = command
+    else {
+        unreachable!("called for `cpv serve`");
+    };
+    if !root.is_dir() {
+        fail(format_args!("'{}' is not a directory", root.display()));
+    }
+    let mut server = Server::new(&root).read_only(read_only);
+    let token = env::var("CPV_TOKEN").ok().filter(|token| !token.is_empty());
+    if let Some(token) = &token {
+        server = server.token(token);
+    }
+    #[cfg(feature = "tls")]
+    if let (Some(cert), Some(key)) = (tls_cert, tls_key) {
+        match ServerTls::from_pem_files(&cert, &key) {
+            Ok(tls) => server = server.tls(tls),
+            Err(err) => fail(err),
+        }
+    }
+    let listener = match TcpListener::bind(&listen) {
+        Ok(listener) => listener,
+        Err(err) => fail(format_args!("cannot listen on {}: {}", listen, err)),
+    };
+    let loopback = listener
+        .local_addr()
+        .is_ok_and(|addr| addr.ip().is_loopback());
+    if !loopback && token.is_none() && !read_only && !no_auth {
+        fail(format_args!(
+            "refusing to let anyone reaching {} write to '{}': set a token in CPV_TOKEN, \
+             or pass --read-only or --no-auth",
+            listen,
+            root.display()
+        ));
+    }
+    eprintln!("cpv: serving '{}' on {}", root.display(), listen);
+    if let Err(err) = server.run(listener) {
+        fail(err);
+    }
+    process::exit(0);
+}
+
+/// Runs `cpv stage` or `cpv paste`.
+fn run_staging(command: Command) -> ! {
+    let Some(path) = Staging::default_path() else {
+        fail("cannot find the home directory to keep the staged paths in");
+    };
+    let staging = Staging::new(path);
+    match command {
+        Command::Stage { clear, paths } => {
+            if clear {
+                staging.clear().unwrap_or_else(|err| fail(err));
+            }
+            if paths.is_empty() {
+                for path in staging.list().unwrap_or_else(|err| fail(err)) {
+                    println!("{}", path.display());
+                }
+            } else {
+                let staged = staging.add(&paths).unwrap_or_else(|err| fail(err));
+                eprintln!(
+                    "cpv: {} staged, pasted with `cpv paste [DEST]`",
+                    match staged.len() {
+                        1 => "1 path".to_string(),
+                        n => format!("{} paths", n),
+                    }
+                );
+            }
+        }
+        Command::Paste {
+            keep,
+            dest,
+            options,
+        } => {
+            let staged = staging.list().unwrap_or_else(|err| fail(err));
+            if staged.is_empty() {
+                fail("nothing is staged; stage paths with `cpv stage PATH...`");
+            }
+            if !dest.is_dir() {
+                fail(format_args!(
+                    "cannot paste into '{}': not a directory",
+                    dest.display()
+                ));
+            }
+            // Checked once here, rather than failing every copy.
+            let copy = Args::try_parse_from(
+                ["cpv".to_string()]
+                    .into_iter()
+                    .chain(options.iter().cloned())
+                    .chain(["SOURCE".to_string(), "DEST".to_string()]),
+            )
+            .unwrap_or_else(|err| err.exit());
+            let program = env::current_exe().unwrap_or_else(|err| fail(err));
+            let mut pasted = Vec::new();
+            let mut status = 0;
+            for (i, source) in staged.iter().enumerate() {
+                let mut command = process::Command::new(&program);
+                command.args(&options);
+                // Undone together.
+                if i > 0 {
+                    command.arg("--undo-append");
+                }
+                if source.is_dir() && !copy.recursive {
+                    command.arg("--recursive");
+                }
+                let code = match command.arg(source).arg(&dest).status() {
+                    Ok(exit) => exit.code().unwrap_or(ExitStatus::Interrupted.exit_code()),
+                    Err(err) => fail(format_args!("cannot run '{}': {}", program.display(), err)),
+                };
+                match code {
+                    0 => pasted.push(source.clone()),
+                    code if code == ExitStatus::Interrupted.exit_code() => {
+                        status = code;
+                        break;
+                    }
+                    code if status == 0 => status = code,
+                    _ => {}
+                }
+            }
+            // What failed stays staged, to be pasted again.
+            if !keep {
+                if let Err(err) = staging.remove(&pasted) {
+                    warn(format_args!("cannot unstage what was pasted: {}", err));
+                }
+            }
+            process::exit(status);
+        }
+        _ => unreachable!("not a staging command"),
+    }
+    process::exit(0);
+}
+
+/// Runs `cpv undo`.
+fn run_undo() -> ! {
+    let Some(path) = UndoLog::default_path() else {
+        fail("cannot find the home directory the record of the last copy is kept in");
+    };
+    let undone = undo(&path).unwrap_or_else(|err| fail(err));
+    for (path, reason) in &undone.kept {
+        warn(format_args!("kept '{}': {}", path.display(), reason));
+    }
+    eprintln!(
+        "cpv: undone: {} files removed, {} restored, {} directories removed",
+        undone.files_removed, undone.files_restored, undone.dirs_removed
+    );
+    process::exit(0);
+}
+
+/// Runs `cpv doctor`, exiting with status 1 if a directory could not be
+/// tried at all.
+fn run_doctor(dirs: &[PathBuf]) -> ! {
+    let mut failed = false;
+    for (i, dir) in dirs.iter().enumerate() {
+        let diagnosis = match doctor(dir) {
+            Ok(diagnosis) => diagnosis,
+            Err(err) => {
+                warn(format_args!("cannot try '{}': {}", dir.display(), err));
+                failed = true;
+                continue;
+            }
+        };
+        if i > 0 {
+            println!();
+        }
+        println!(
+            "{}: {}, {}",
+            style(diagnosis.path.display()).bold(),
+            diagnosis
+                .filesystem
+                .as_deref()
+                .unwrap_or("unknown filesystem"),
+            diagnosis.storage
+        );
+        for check in &diagnosis.checks {
+            let (status, reason) = match &check.result {
+                Ok(()) => (style("yes").green(), String::new()),
+                Err(err) => (style("no ").red(), format!(" ({})", err)),
+            };
+            println!(
+                "  {:<16} {}  {}{}",
+                check.capability.to_string(),
+                status,
+                check.capability.used_by(),
+                reason
             );
-            process::exit(1);
         }
-        Err(CopyError::Io(err)) => {
-            eprintln!("cpv: {}", err);
-            process::exit(1);
+    }
+    process::exit(i32::from(failed));
+}
+
+/// The socket of the queue when `--socket` is not given.
+#[cfg(unix)]
+fn default_socket() -> PathBuf {
+    QueueDaemon::default_socket().unwrap_or_else(|| {
+        fail("nowhere to put the queue socket: set XDG_RUNTIME_DIR or HOME, or pass --socket")
+    })
+}
+
+/// Runs `cpv daemon`, `cpv enqueue`, `cpv jobs` or `cpv status`.
+#[cfg(unix)]
+fn run_queue(command: Command) -> ! {
+    let request = |socket: Option<PathBuf>, request: QueueRequest| {
+        let socket = socket.unwrap_or_else(default_socket);
+        match request.send(&socket) {
+            Ok(QueueReply::Error(err)) => fail(err),
+            Ok(reply) => reply,
+            Err(err) => fail(err),
+        }
+    };
+    match command {
+        Command::Daemon { jobs, socket } => {
+            let socket = socket.unwrap_or_else(default_socket);
+            let program = env::current_exe().unwrap_or_else(|err| fail(err));
+            let listener = QueueDaemon::bind(&socket).unwrap_or_else(|err| fail(err));
+            eprintln!(
+                "cpv: running up to {} copies at once, queued at '{}'",
+                jobs.max(1),
+                socket.display()
+            );
+            if let Err(err) = QueueDaemon::new(program, jobs).run(listener) {
+                fail(err);
+            }
+        }
+        Command::Enqueue { socket, args } => {
+            // Checked here, so that mistakes are not left for the daemon
+            // to find.
+            let mut argv: Vec<String> = ["--quiet", "--no-progress", "--progress-json=stdout"]
+                .map(String::from)
+                .into();
+            argv.extend(args);
+            let copy = Args::try_parse_from(std::iter::once("cpv".to_string()).chain(argv.clone()))
+                .unwrap_or_else(|err| err.exit());
+            if copy.interactive || copy.overwrite == Some(OverwritePolicy::Prompt) {
+                fail("queued copies cannot ask before overwriting");
+            }
+            let dir = env::current_dir().unwrap_or_else(|err| fail(err));
+            let enqueue = QueueRequest::Enqueue {
+                args: argv,
+                dir,
+                source: copy.source.expect("SOURCE is required"),
+                dest: copy.destination.expect("DEST is required"),
+            };
+            if let QueueReply::Enqueued(id) = request(socket, enqueue) {
+                println!("{}", id);
+            }
+        }
+        Command::Jobs {
+            socket,
+            action: Some(action),
+        } => {
+            request(
+                socket,
+                match action {
+                    JobAction::Pause { id } => QueueRequest::Pause(id),
+                    JobAction::Resume { id } => QueueRequest::Resume(id),
+                    JobAction::Cancel { id } => QueueRequest::Cancel(id),
+                    JobAction::Move { id, position } => QueueRequest::Move { id, position },
+                },
+            );
+        }
+        Command::Jobs {
+            socket,
+            action: None,
+        } => {
+            let QueueReply::Jobs(jobs) = request(socket, QueueRequest::List) else {
+                fail("unexpected reply from the daemon");
+            };
+            println!("{:>4}  {:<9}  {:>4}  JOB", "ID", "STATE", "DONE");
+            for job in jobs {
+                let done = match job.total {
+                    0 => "-".to_string(),
+                    total => format!("{}%", job.bytes * 100 / total),
+                };
+                println!(
+                    "{:>4}  {:<9}  {:>4}  '{}' -> '{}'",
+                    job.id,
+                    job.state,
+                    done,
+                    job.source.display(),
+                    job.dest.display()
+                );
+                if let Some(error) = job.error {
+                    println!("{:23}{}", "", error);
+                }
+            }
+        }
+        Command::Status { socket, id } => {
+            let QueueReply::Jobs(jobs) = request(socket, QueueRequest::List) else {
+                fail("unexpected reply from the daemon");
+            };
+            if id.is_some_and(|id| jobs.iter().all(|job| job.id != id)) {
+                fail(format_args!("there is no job {}", id.unwrap()));
+            }
+            let count = |state| jobs.iter().filter(|job| job.state == state).count();
+            if id.is_none() {
+                println!(
+                    "{} queued, {} running, {} paused, {} done, {} failed, {} cancelled",
+                    count(JobState::Queued),
+                    count(JobState::Running),
+                    count(JobState::Paused),
+                    count(JobState::Done),
+                    count(JobState::Failed),
+                    count(JobState::Cancelled)
+                );
+            }
+            let shown = jobs.iter().filter(|job| match id {
+                Some(id) => job.id == id,
+                None => matches!(job.state, JobState::Running | JobState::Paused),
+            });
+            for job in shown {
+                println!(
+                    "job {} {}: {} of {} '{}' -> '{}'",
+                    job.id,
+                    job.state,
+                    format_size(job.bytes, BINARY),
+                    format_size(job.total, BINARY),
+                    job.source.display(),
+                    job.dest.display()
+                );
+                if let Some(error) = &job.error {
+                    println!("  {}", error);
+                }
+            }
+        }
+        Command::Bench { .. }
+        | Command::Serve { .. }
+        | Command::Stage { .. }
+        | Command::Paste { .. }
+        | Command::Undo
+        | Command::Doctor { .. }
+        | Command::Generate { .. } => {
+            unreachable!("handled in main")
+        }
+    }
+    process::exit(0);
+}
+
+/// The job queue needs Unix domain sockets.
+#[cfg(not(unix))]
+fn run_queue(_command: Command) -> ! {
+    fail("the job queue of cpv daemon is only available on Unix")
+}
+
+/// Prints an error and exits with the status of `outcome`.
+fn fail_with(outcome: ExitStatus, message: impl fmt::Display) -> ! {
+    eprintln!("{} {}", style("cpv:").red().bold().for_stderr(), message);
+    process::exit(outcome.exit_code());
+}
+
+/// The stream named by `--progress-json`.
+fn event_stream(stream: &str) -> io::Result<Box<dyn Write + Send>> {
+    match stream {
+        "stderr" => Ok(Box::new(io::stderr())),
+        "stdout" => Ok(Box::new(io::stdout())),
+        #[cfg(unix)]
+        fd => {
+            use std::os::unix::io::FromRawFd;
+
+            let fd: i32 = fd.parse().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "expected stderr, stdout or a file descriptor number",
+                )
+            })?;
+            // SAFETY: fcntl only queries the descriptor, which must be open
+            // for it to be taken over.
+            if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: the descriptor is open and was handed to cpv for the
+            // events, so nothing else in the process owns it.
+            Ok(Box::new(unsafe { std::fs::File::from_raw_fd(fd) }))
         }
+        #[cfg(not(unix))]
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "expected stderr or stdout",
+        )),
+    }
+}
+
+/// The checksum cache in the user's cache directory, or `None` with a
+/// warning if it cannot be read.
+fn open_cache() -> Option<Arc<ChecksumCache>> {
+    let path = ChecksumCache::default_path()?;
+    match ChecksumCache::open(&path) {
+        Ok(cache) => Some(Arc::new(cache)),
+        Err(err) => {
+            warn(format_args!(
+                "cannot read checksum cache '{}': {}",
+                path.display(),
+                err
+            ));
+            None
+        }
+    }
+}
+
+fn save_cache(options: &CopyOptions) {
+    if let Some(cache) = &options.checksum_cache {
+        if let Err(err) = cache.save() {
+            warn(format_args!("cannot write checksum cache: {}", err));
+        }
+    }
+}
+
+fn open_history() -> Option<Arc<ThroughputHistory>> {
+    let path = ThroughputHistory::default_path()?;
+    match ThroughputHistory::open(&path) {
+        Ok(history) => Some(Arc::new(history)),
         Err(err) => {
-            eprintln!("cpv: {}", err);
-            process::exit(1);
+            warn(format_args!(
+                "cannot read throughput history '{}': {}",
+                path.display(),
+                err
+            ));
+            None
+        }
+    }
+}
+
+fn save_history(options: &CopyOptions) {
+    if let Some(history) = &options.throughput_history {
+        if let Err(err) = history.save() {
+            warn(format_args!("cannot write throughput history: {}", err));
+        }
+    }
+}
+
+fn save_undo(options: &CopyOptions) {
+    if let Some(log) = &options.undo_log {
+        if let Err(err) = log.save() {
+            warn(format_args!("cannot record the copy for undo: {}", err));
+        }
+    }
+}
+
+fn run_verify_only(source: &Path, dest: &Path, options: &CopyOptions) {
+    let result = compare_trees(source, dest, options);
+    save_cache(options);
+    let comparison = match result {
+        Ok(comparison) => comparison,
+        Err(err) => fail(err),
+    };
+
+    for path in &comparison.missing {
+        println!("{} {}", style("missing:").yellow(), path.display());
+    }
+    for path in &comparison.extra {
+        println!("{} {}", style("extra:").yellow(), path.display());
+    }
+    for path in &comparison.mismatched {
+        println!("{} {}", style("differs:").red(), path.display());
+    }
+    let differences =
+        comparison.missing.len() + comparison.extra.len() + comparison.mismatched.len();
+    if !QUIET.load(Ordering::Relaxed) {
+        println!(
+            "{} files compared ({}, {}), {} difference{}",
+            comparison.files_compared,
+            format_size(comparison.bytes_compared, BINARY),
+            options.checksum,
+            differences,
+            if differences == 1 { "" } else { "s" }
+        );
+    }
+    if !comparison.is_identical() {
+        process::exit(ExitStatus::VerifyFailed.exit_code());
+    }
+}
+
+/// Checks the copy with `--preflight`, and lists what stands in its way
+/// and exits if anything does.
+fn run_preflight(source: &Path, dest: &Path, options: &CopyOptions) {
+    let problems = preflight(source, dest, options);
+    if problems.is_empty() {
+        if options.verbose {
+            println!("Preflight: no problems found");
+        }
+        return;
+    }
+    let width = problems
+        .iter()
+        .map(|problem| problem.path.display().to_string().chars().count())
+        .max()
+        .unwrap_or(0)
+        .clamp(4, 60);
+    eprintln!(
+        "{} preflight found {} problem{}, nothing copied:",
+        style("cpv:").red().bold().for_stderr(),
+        problems.len(),
+        if problems.len() == 1 { "" } else { "s" }
+    );
+    eprintln!(
+        "{}",
+        style(format!(
+            "{:<width$}  PROBLEM       ERROR",
+            "PATH",
+            width = width
+        ))
+        .bold()
+        .for_stderr()
+    );
+    for problem in &problems {
+        eprintln!(
+            "{:<width$}  {:<12}  {}",
+            problem.path.display().to_string(),
+            problem.kind.to_string(),
+            problem.error,
+            width = width
+        );
+    }
+    process::exit(ExitStatus::Failed.exit_code());
+}
+
+fn run_dry_run(source: &Path, dest: &Path, options: &CopyOptions) {
+    let plan = match plan(source, dest, options) {
+        Ok(plan) => plan,
+        Err(err) => fail(err),
+    };
+    let mut deletions = 0;
+    for action in &plan.actions {
+        match action.kind {
+            ActionKind::CopyFile => println!(
+                "'{}' -> '{}'{}",
+                action.source.display(),
+                action.dest.display(),
+                if action.conflict { " (overwrite)" } else { "" }
+            ),
+            ActionKind::Delete => {
+                deletions += 1;
+                println!("deleting '{}'", action.dest.display());
+            }
+            ActionKind::CreateDir => {}
+        }
+    }
+    if !QUIET.load(Ordering::Relaxed) {
+        let conflicts = plan.conflicts().count();
+        print!(
+            "would copy {} files ({}), overwriting {}",
+            plan.file_count(),
+            format_size(plan.total_bytes(), BINARY),
+            conflicts
+        );
+        match options.delete {
+            true => println!(", and delete {}", deletions),
+            false => println!(),
+        }
+    }
+}
+
+fn run_bench(target: &Path, config: &BenchConfig) {
+    let results = match bench::run(target, config) {
+        Ok(results) => results,
+        Err(err) => fail(err),
+    };
+
+    println!(
+        "{} in {} file(s), {} distribution",
+        format_size(config.size, BINARY),
+        config.files,
+        config.distribution
+    );
+    println!("{:<16} {:>10} {:>14}", "STRATEGY", "TIME", "THROUGHPUT");
+    for result in results {
+        match result.outcome {
+            Ok(time) => println!(
+                "{:<16} {:>9.3}s {:>12.2} MB/s",
+                result.name,
+                time.as_secs_f64(),
+                config.size as f64 / 1_000_000.0 / time.as_secs_f64()
+            ),
+            Err(err) => println!("{:<16} {:>10} ({})", result.name, "-", err),
         }
     }
 }
@@ -0,0 +1,76 @@
+//! Checksum manifests for `--manifest`.
+
+use crate::checksum::{ChecksumAlgo, Digest};
+use crate::ui::{format_size, BINARY};
+use std::io::{self, BufWriter, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// Writes `checksums` to `path` as `hash  path` lines, the format read by
+/// `sha256sum -c` and `b3sum --check`, with paths relative to `root` and
+/// `#` comment lines summarizing the copy first.
+///
+/// The manifest is written to a temporary file next to `path` and renamed
+/// over it, so readers never see a partial manifest.
+pub(crate) fn write(
+    path: &Path,
+    root: &Path,
+    source: &Path,
+    algo: ChecksumAlgo,
+    bytes: u64,
+    checksums: &[(PathBuf, Digest)],
+) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let mut lines: Vec<(String, &Digest)> = checksums
+        .iter()
+        .map(|(file, digest)| (relative_name(file, root), digest))
+        .collect();
+    lines.sort_by(|a, b| a.0.cmp(&b.0));
+
+    // Temporary files are private by default; a manifest is meant to be
+    // shared, so create it like any other file, subject to the umask.
+    #[cfg(unix)]
+    let temp = tempfile::Builder::new()
+        .permissions(std::os::unix::fs::PermissionsExt::from_mode(0o666))
+        .tempfile_in(dir)?;
+    #[cfg(not(unix))]
+    let temp = tempfile::NamedTempFile::new_in(dir)?;
+    let mut out = BufWriter::new(temp.as_file());
+    writeln!(
+        out,
+        "# {} checksums of {} files ({}) copied from {}",
+        algo,
+        lines.len(),
+        format_size(bytes, BINARY),
+        source.display()
+    )?;
+    for (name, digest) in &lines {
+        // GNU escaping: names with a backslash or newline start with '\'.
+        if name.contains(['\\', '\n']) {
+            let escaped = name.replace('\\', "\\\\").replace('\n', "\\n");
+            writeln!(out, "\\{}  {}", digest, escaped)?;
+        } else {
+            writeln!(out, "{}  {}", digest, name)?;
+        }
+    }
+    out.flush()?;
+    drop(out);
+    temp.as_file().sync_all()?;
+    temp.persist(path).map_err(|err| err.error)?;
+    Ok(())
+}
+
+/// `file` relative to `root`, with `/` separators on every platform.
+fn relative_name(file: &Path, root: &Path) -> String {
+    let relative = file.strip_prefix(root).unwrap_or(file);
+    relative
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
@@ -0,0 +1,157 @@
+//! A Prometheus endpoint for `--metrics-listen`, with the `metrics`
+//! feature: a small HTTP listener that serves the `LiveStats` of the copy
+//! at `/metrics`, so that long migrations can be followed from the
+//! monitoring of a fleet.
+
+use crate::LiveStats;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// How long a scraper may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The media type of the OpenMetrics text format, which scrapers that ask
+/// for it get instead of the Prometheus one.
+const OPENMETRICS: &str = "application/openmetrics-text";
+
+/// A listener serving the metrics of a copy in the background, until the
+/// process ends.
+#[derive(Debug)]
+pub struct MetricsServer {
+    addr: SocketAddr,
+}
+
+impl MetricsServer {
+    /// Listens at `addr` and serves the counters of `live` at `/metrics`.
+    pub fn bind(addr: impl ToSocketAddrs, live: Arc<LiveStats>) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        thread::spawn(move || {
+            // One scrape at a time, which is all a copy sees.
+            for stream in listener.incoming().flatten() {
+                let _ = respond(stream, &live);
+            }
+        });
+        Ok(Self { addr })
+    }
+
+    /// The address listened at, with the port the system chose for port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+/// Answers the request on `stream`.
+fn respond(mut stream: TcpStream, live: &LiveStats) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    let mut openmetrics = false;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            openmetrics |= name.eq_ignore_ascii_case("accept") && value.contains(OPENMETRICS);
+        }
+    }
+
+    let mut words = request.split_whitespace();
+    let (method, path) = (words.next().unwrap_or(""), words.next().unwrap_or(""));
+    let (status, content_type, body) = match (method, path.split('?').next()) {
+        ("GET" | "HEAD", Some("/metrics")) => {
+            let content_type = match openmetrics {
+                true => "application/openmetrics-text; version=1.0.0; charset=utf-8",
+                false => "text/plain; version=0.0.4; charset=utf-8",
+            };
+            ("200 OK", content_type, render(live, openmetrics))
+        }
+        ("GET" | "HEAD", _) => ("404 Not Found", "text/plain", "not found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "method not allowed\n".to_string(),
+        ),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    if method != "HEAD" {
+        stream.write_all(body.as_bytes())?;
+    }
+    stream.flush()
+}
+
+/// The counters of `live` in the Prometheus text format, or the OpenMetrics
+/// one, which names counters without their `_total` in its metadata and
+/// ends with `# EOF`.
+pub(crate) fn render(live: &LiveStats, openmetrics: bool) -> String {
+    let metrics: [(&str, &str, &str, u64); 7] = [
+        (
+            "cpv_bytes_copied_total",
+            "counter",
+            "Bytes copied so far.",
+            live.bytes_done(),
+        ),
+        (
+            "cpv_bytes_expected",
+            "gauge",
+            "Bytes to copy in total, growing while the source is scanned.",
+            live.bytes_total(),
+        ),
+        (
+            "cpv_files_copied_total",
+            "counter",
+            "Files copied, linked or found already copied.",
+            live.files_done(),
+        ),
+        (
+            "cpv_files_expected",
+            "gauge",
+            "Files found by the scan of the source so far.",
+            live.files_total(),
+        ),
+        (
+            "cpv_files_failed_total",
+            "counter",
+            "Files that could not be copied.",
+            live.files_failed(),
+        ),
+        (
+            "cpv_rate_bytes_per_second",
+            "gauge",
+            "Throughput over the last progress interval.",
+            live.bytes_per_sec(),
+        ),
+        (
+            "cpv_finished",
+            "gauge",
+            "1 once the copy is over, successfully or not.",
+            live.is_finished() as u64,
+        ),
+    ];
+    let mut text = String::new();
+    for (name, kind, help, value) in metrics {
+        let family = match (openmetrics, kind) {
+            (true, "counter") => name.trim_end_matches("_total"),
+            _ => name,
+        };
+        let _ = writeln!(text, "# HELP {} {}", family, help);
+        let _ = writeln!(text, "# TYPE {} {}", family, kind);
+        let _ = writeln!(text, "{} {}", name, value);
+    }
+    if openmetrics {
+        text.push_str("# EOF\n");
+    }
+    text
+}
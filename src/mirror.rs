@@ -0,0 +1,135 @@
+//! Mirroring, for `CopyOptions::mirror` and `delete`: copies that are up
+//! to date with their sources are left alone, and what the source no
+//! longer has is removed from the destination, so that a copy repeated
+//! over the same directories only does what changed.
+
+use crate::context::{IoOp, ResultExt};
+use crate::exclude;
+use crate::fat;
+use crate::progress::Bars;
+use crate::vfs::{FileInfo, Filesystem};
+use crate::{CopyError, CopyOptions, CopyStats};
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Whether `copy` is up to date with the file described by `source`: the
+/// same size and modified no earlier, or at most the 2 seconds FAT rounds
+/// times to earlier with `options.fat_compat`. Without modification times
+/// on both sides, no copy is.
+pub(crate) fn up_to_date(source: &FileInfo, copy: &FileInfo, options: &CopyOptions) -> bool {
+    let slack = match options.fat_compat {
+        true => fat::TIME_RESOLUTION,
+        false => Duration::ZERO,
+    };
+    !copy.is_dir
+        && source.len == copy.len
+        && source
+            .modified
+            .zip(copy.modified)
+            .is_some_and(|(source, copy)| source <= copy + slack)
+}
+
+/// The entries under the directory `target`, the copy of the directory
+/// `source`, that `source` has no counterpart of, in order. Those in an
+/// entry that is a directory are not listed apart, and neither are those
+/// that `options.exclude` or `options.deselected` leaves out of the copy.
+pub(crate) fn extraneous(
+    (source_fs, dest_fs): (&dyn Filesystem, &dyn Filesystem),
+    source: &Path,
+    target: &Path,
+    options: &CopyOptions,
+) -> io::Result<Vec<(PathBuf, FileInfo)>> {
+    let mut found = Vec::new();
+    walk((source_fs, dest_fs), source, target, options, &mut found)?;
+    Ok(found)
+}
+
+fn walk(
+    (source_fs, dest_fs): (&dyn Filesystem, &dyn Filesystem),
+    source: &Path,
+    target: &Path,
+    options: &CopyOptions,
+    found: &mut Vec<(PathBuf, FileInfo)>,
+) -> io::Result<()> {
+    let names: HashSet<_> = source_fs
+        .read_dir(source)
+        .context(IoOp::ReadDir, source)?
+        .iter()
+        .filter_map(|entry| entry.file_name())
+        .map(|name| options.target_name(name))
+        .collect();
+    let mut entries = match dest_fs.read_dir(target) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).context(IoOp::ReadDir, target),
+    };
+    entries.sort();
+    for entry in entries {
+        let Some(name) = entry.file_name() else {
+            continue;
+        };
+        let info = dest_fs.metadata(&entry).context(IoOp::Stat, &entry)?;
+        if !names.contains(name)
+            && !exclude::excluded(&options.exclude, name)
+            && !options.deselected.contains(&source.join(name))
+        {
+            found.push((entry, info));
+        } else if info.is_dir && names.contains(name) && is_dir(source_fs, &source.join(name))? {
+            walk(
+                (source_fs, dest_fs),
+                &source.join(name),
+                &entry,
+                options,
+                found,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether `path` is a directory, where a path that has gone since it was
+/// listed is not.
+fn is_dir(fs: &dyn Filesystem, path: &Path) -> io::Result<bool> {
+    match fs.metadata(path) {
+        Ok(info) => Ok(info.is_dir),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err).context(IoOp::Stat, path),
+    }
+}
+
+/// Removes from the directory `target`, the copy of the directory
+/// `source`, what `source` has no counterpart of, counting it in `stats`.
+/// Nothing is removed after a copy with failures, which may have left out
+/// files that are still in the source.
+pub(crate) fn delete(
+    (source_fs, dest_fs): (&dyn Filesystem, &dyn Filesystem),
+    source: &Path,
+    target: &Path,
+    bars: &Bars,
+    options: &CopyOptions,
+    stats: &mut CopyStats,
+) -> Result<(), CopyError> {
+    if !stats.failures.is_empty() {
+        stats.warnings.push(format!(
+            "nothing was deleted from '{}' as some files failed",
+            target.display()
+        ));
+        return Ok(());
+    }
+    for (path, info) in extraneous((source_fs, dest_fs), source, target, options)? {
+        if let Some(token) = &options.cancel {
+            token.check()?;
+        }
+        dest_fs.remove(&path).context(IoOp::Remove, &path)?;
+        match info.is_dir {
+            true => stats.dirs_deleted += 1,
+            false => stats.files_deleted += 1,
+        }
+        if options.verbose {
+            bars.list(format!("removed '{}'", path.display()));
+        }
+    }
+    Ok(())
+}
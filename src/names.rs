@@ -0,0 +1,200 @@
+//! Destination file naming: Unicode normalization and collision detection.
+
+use crate::exclude;
+use crate::{CopyError, CopyOptions};
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use tempfile::Builder;
+use unicode_normalization::UnicodeNormalization;
+use walkdir::WalkDir;
+
+/// Unicode normalization form applied to destination file names.
+///
+/// HFS+ and APFS hand out decomposed (NFD) names while Linux and Windows
+/// tooling almost always produce composed (NFC) ones, so trees moved between
+/// them can end up with visually identical but byte-wise different names.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NameNormalization {
+    /// Keep names byte-for-byte as they are in the source.
+    #[default]
+    None,
+    /// Canonical composition (NFC).
+    Nfc,
+    /// Canonical decomposition (NFD).
+    Nfd,
+}
+
+impl NameNormalization {
+    /// The names of the normalizations, as `from_str` takes them and `Display`
+    /// writes them.
+    pub const NAMES: &[&str] = &["none", "nfc", "nfd"];
+
+    /// Normalizes a single file name. Names that are not valid UTF-8 are
+    /// returned unchanged.
+    pub fn normalize_name(self, name: &OsStr) -> OsString {
+        match (self, name.to_str()) {
+            (Self::Nfc, Some(name)) => name.nfc().collect::<String>().into(),
+            (Self::Nfd, Some(name)) => name.nfd().collect::<String>().into(),
+            _ => name.to_os_string(),
+        }
+    }
+
+    /// Normalizes every regular component of `path`.
+    pub fn normalize_path(self, path: &Path) -> PathBuf {
+        if self == Self::None {
+            return path.to_path_buf();
+        }
+        path.components()
+            .map(|component| match component {
+                Component::Normal(name) => self.normalize_name(name),
+                other => other.as_os_str().to_os_string(),
+            })
+            .collect()
+    }
+}
+
+impl FromStr for NameNormalization {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "nfc" => Ok(Self::Nfc),
+            "nfd" => Ok(Self::Nfd),
+            _ => Err(format!(
+                "invalid normalization '{}' (expected nfc, nfd or none)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for NameNormalization {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::None => "none",
+            Self::Nfc => "nfc",
+            Self::Nfd => "nfd",
+        })
+    }
+}
+
+/// Walks `source` and fails if two entries would be written to the same
+/// destination name once `options` have mapped their names.
+pub(crate) fn check_collisions(source: &Path, options: &CopyOptions) -> Result<(), CopyError> {
+    let mut seen: HashMap<PathBuf, PathBuf> = HashMap::new();
+
+    for entry in exclude::walk(WalkDir::new(source).min_depth(1), options) {
+        let entry = entry?;
+        let relative = entry
+            .path()
+            .strip_prefix(source)
+            .map_err(|e| CopyError::Other(e.into()))?;
+        let key = options.target_path(relative);
+
+        if let Some(previous) = seen.insert(key, relative.to_path_buf()) {
+            return Err(CopyError::NameCollision(
+                source.join(previous),
+                entry.path().to_path_buf(),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// What to do when two source entries differ only by case and the
+/// destination filesystem is case-insensitive.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CollisionPolicy {
+    /// Abort before anything is written.
+    #[default]
+    Error,
+    /// Report both paths and copy anyway; the later entry wins.
+    Warn,
+    /// Skip the check entirely.
+    Ignore,
+}
+
+impl CollisionPolicy {
+    /// The names of the policies, as `from_str` takes them and `Display`
+    /// writes them.
+    pub const NAMES: &[&str] = &["error", "warn", "ignore"];
+}
+
+impl FromStr for CollisionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(Self::Error),
+            "warn" => Ok(Self::Warn),
+            "ignore" => Ok(Self::Ignore),
+            _ => Err(format!(
+                "invalid collision policy '{}' (expected error, warn or ignore)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for CollisionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Error => "error",
+            Self::Warn => "warn",
+            Self::Ignore => "ignore",
+        })
+    }
+}
+
+/// Probes whether the filesystem `dest` will be written to treats names
+/// case-insensitively, by creating a mixed-case temporary file in the
+/// nearest existing ancestor directory and looking it up in lowercase.
+pub(crate) fn is_case_insensitive(dest: &Path) -> io::Result<bool> {
+    let dir = dest
+        .ancestors()
+        .find(|dir| dir.is_dir())
+        .unwrap_or_else(|| Path::new("."));
+    let probe = Builder::new().prefix(".cpvCaseProbe").tempfile_in(dir)?;
+    let name = probe.path().file_name().unwrap().to_ascii_lowercase();
+    Ok(dir.join(name).exists())
+}
+
+/// Walks `source` and returns every pair of entries whose destination
+/// names, as `options` map them, differ only by case.
+pub(crate) fn find_case_collisions(
+    source: &Path,
+    options: &CopyOptions,
+) -> Result<Vec<(PathBuf, PathBuf)>, CopyError> {
+    let mut seen: HashMap<PathBuf, PathBuf> = HashMap::new();
+    let mut collisions = Vec::new();
+
+    for entry in exclude::walk(WalkDir::new(source).min_depth(1), options) {
+        let entry = entry?;
+        let relative = entry
+            .path()
+            .strip_prefix(source)
+            .map_err(|e| CopyError::Other(e.into()))?;
+        let key = fold_case(&options.target_path(relative));
+
+        if let Some(previous) = seen.get(&key) {
+            collisions.push((source.join(previous), entry.path().to_path_buf()));
+        } else {
+            seen.insert(key, relative.to_path_buf());
+        }
+    }
+
+    Ok(collisions)
+}
+
+fn fold_case(path: &Path) -> PathBuf {
+    match path.to_str() {
+        Some(path) => path.to_lowercase().into(),
+        None => path.as_os_str().to_ascii_lowercase().into(),
+    }
+}
@@ -0,0 +1,1026 @@
+//! Copies between machines running cpv, without SSH or a network
+//! filesystem: `cpv serve` runs a `Server` for a directory, and other
+//! machines copy to and from it with `cpv://host[:port]/path` arguments,
+//! which are `CpvFs`.
+//!
+//! The protocol is a few request types framed as in `wire`, over TCP or,
+//! with the `tls` feature, TLS. Files are streamed in chunks without
+//! waiting for acknowledgements and end with a checksum of their data,
+//! which the other side compares with its own. Files received are written
+//! as `.NAME.part` next to their destination and renamed into place once
+//! complete and checked.
+
+use crate::checksum::{ChecksumAlgo, Hasher};
+use crate::location::Location;
+use crate::transit::{self, Packer};
+use crate::vfs::{FileInfo, Filesystem};
+use crate::wire::{self, Fields, Packet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The port of `cpv://` URLs without one.
+pub const DEFAULT_PORT: u16 = 7373;
+
+/// Sent first on every connection.
+const MAGIC: &[u8] = b"cpv";
+
+/// The protocol version spoken.
+const VERSION: u32 = 1;
+
+/// `HELLO` flags: compression of file data in transit.
+const COMPRESS: u8 = 1;
+
+/// Bytes of file data per packet.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The longest packet accepted: a chunk and its length.
+const MAX_PACKET: usize = CHUNK_SIZE + 1024;
+
+/// How long a server waits for the `HELLO` of a new connection.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connections a server answers at once, unless `Server::max_connections`
+/// says otherwise.
+const MAX_CONNECTIONS: usize = 64;
+
+/// Packet types.
+mod msg {
+    /// The magic, the version, the token of the client and the flags it
+    /// asks for, answered with `OK` and the flags agreed.
+    pub const HELLO: u8 = 1;
+    pub const STAT: u8 = 2;
+    pub const LIST: u8 = 3;
+    /// A path and the checksum to end the data with, or an empty string.
+    pub const READ: u8 = 4;
+    /// A path and the checksum the data will end with, or an empty string.
+    pub const WRITE: u8 = 5;
+    pub const MKDIR: u8 = 6;
+    /// A path and a modification time.
+    pub const MTIME: u8 = 7;
+    /// A chunk of a file, sent by either side.
+    pub const DATA: u8 = 16;
+    /// The end of a file, with its checksum.
+    pub const END: u8 = 17;
+    /// A chunk of a file compressed with zstd, once both sides agreed to.
+    pub const ZDATA: u8 = 18;
+    pub const OK: u8 = 32;
+    /// The kind of an `io::Error` and its message.
+    pub const ERROR: u8 = 33;
+    /// Whether a path is a directory, its size and its modification time.
+    pub const INFO: u8 = 34;
+    /// The names in a directory.
+    pub const NAMES: u8 = 35;
+}
+
+/// Splits a `cpv://host[:port]/path` argument into the address of the
+/// server and the path under the directory it serves, which is empty for
+/// the directory itself.
+pub fn parse_cpv_url(arg: &str) -> Option<(&str, &str)> {
+    let rest = arg.strip_prefix("cpv://")?;
+    let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+    (!authority.is_empty()).then_some((authority, path.trim_end_matches('/')))
+}
+
+/// `host[:port]` with `DEFAULT_PORT` when it has none.
+fn address(authority: &str) -> String {
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(rest) => match rest.split_once(']') {
+            Some((host, port)) => (host, port.strip_prefix(':')),
+            None => (rest, None),
+        },
+        None => match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+    let port = port.map_or(DEFAULT_PORT.to_string(), str::to_string);
+    match host.contains(':') {
+        true => format!("[{}]:{}", host, port),
+        false => format!("{}:{}", host, port),
+    }
+}
+
+/// A TLS certificate and key for a `Server`, with the `tls` feature.
+#[cfg(feature = "tls")]
+#[derive(Debug, Clone)]
+pub struct ServerTls(Arc<rustls::ServerConfig>);
+
+#[cfg(feature = "tls")]
+impl ServerTls {
+    /// Reads a certificate chain and its private key from PEM files.
+    pub fn from_pem_files(cert_chain: &Path, key: &Path) -> io::Result<Self> {
+        use rustls::pki_types::pem::PemObject;
+        use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+        let certs = CertificateDer::pem_file_iter(cert_chain)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|err| pem_error(cert_chain, err))?;
+        let key = PrivateKeyDer::from_pem_file(key).map_err(|err| pem_error(key, err))?;
+        let config = rustls::ServerConfig::builder_with_provider(tls_provider())
+            .with_safe_default_protocol_versions()
+            .and_then(|config| config.with_no_client_auth().with_single_cert(certs, key))
+            .map_err(tls_error)?;
+        Ok(Self(Arc::new(config)))
+    }
+}
+
+/// The certificate authorities a `CpvFs` trusts, with the `tls` feature.
+#[cfg(feature = "tls")]
+#[derive(Debug, Clone)]
+pub struct ClientTls(Arc<rustls::ClientConfig>);
+
+#[cfg(feature = "tls")]
+impl ClientTls {
+    /// Trusts the certificates of a PEM file: the authority that signed
+    /// the server's certificate, or a self-signed certificate itself.
+    pub fn from_pem_file(path: &Path) -> io::Result<Self> {
+        use rustls::pki_types::pem::PemObject;
+        use rustls::pki_types::CertificateDer;
+
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(path).map_err(|err| pem_error(path, err))? {
+            roots
+                .add(cert.map_err(|err| pem_error(path, err))?)
+                .map_err(tls_error)?;
+        }
+        let config = rustls::ClientConfig::builder_with_provider(tls_provider())
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(Self(Arc::new(config)))
+    }
+}
+
+#[cfg(feature = "tls")]
+fn tls_provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+#[cfg(feature = "tls")]
+fn pem_error(path: &Path, err: rustls::pki_types::pem::Error) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("cannot read '{}': {}", path.display(), err),
+    )
+}
+
+#[cfg(feature = "tls")]
+fn tls_error(err: rustls::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+/// A connection, over TCP or TLS.
+trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+/// One end of a connection. Packets are written whole and flushed, so
+/// that they leave without waiting for more.
+struct Connection {
+    stream: BufReader<Box<dyn Stream>>,
+    /// Whether file data may go compressed.
+    compress: bool,
+}
+
+impl Connection {
+    fn new(stream: Box<dyn Stream>) -> Self {
+        Self {
+            stream: BufReader::new(stream),
+            compress: false,
+        }
+    }
+
+    fn send(&mut self, kind: u8, body: &[u8]) -> io::Result<()> {
+        let stream = self.stream.get_mut();
+        wire::write_packet(stream, kind, body)?;
+        stream.flush()
+    }
+
+    fn send_error(&mut self, err: &io::Error) -> io::Result<()> {
+        let mut body = Packet::new();
+        body.u8(error_code(err.kind()))
+            .string(err.to_string().as_bytes());
+        self.send(msg::ERROR, &body.0)
+    }
+
+    /// Sends a chunk of a file, compressed if `packer` finds that worth it.
+    fn send_chunk(&mut self, packer: &mut Packer, chunk: &[u8]) -> io::Result<()> {
+        let mut body = Packet::new();
+        match packer.pack(chunk) {
+            Some(packed) => {
+                body.string(&packed);
+                self.send(msg::ZDATA, &body.0)
+            }
+            None => {
+                body.string(chunk);
+                self.send(msg::DATA, &body.0)
+            }
+        }
+    }
+
+    fn receive(&mut self) -> io::Result<(u8, Vec<u8>)> {
+        wire::read_packet(&mut self.stream, MAX_PACKET)
+    }
+
+    /// Receives a packet, failing with the error it holds if it is one.
+    fn response(&mut self) -> io::Result<(u8, Vec<u8>)> {
+        match self.receive()? {
+            (msg::ERROR, payload) => Err(remote_error(&payload)),
+            response => Ok(response),
+        }
+    }
+}
+
+/// The data of a `DATA` or `ZDATA` packet.
+fn chunk(kind: u8, payload: &[u8]) -> io::Result<Vec<u8>> {
+    let data = Fields(payload).string()?;
+    match kind {
+        msg::ZDATA => transit::unpack(data, CHUNK_SIZE),
+        _ => Ok(data.to_vec()),
+    }
+}
+
+/// Error kinds as sent, in order; others are sent as `Other`.
+const ERROR_KINDS: [io::ErrorKind; 7] = [
+    io::ErrorKind::Other,
+    io::ErrorKind::NotFound,
+    io::ErrorKind::PermissionDenied,
+    io::ErrorKind::AlreadyExists,
+    io::ErrorKind::InvalidInput,
+    io::ErrorKind::InvalidData,
+    io::ErrorKind::Unsupported,
+];
+
+fn error_code(kind: io::ErrorKind) -> u8 {
+    ERROR_KINDS.iter().position(|&k| k == kind).unwrap_or(0) as u8
+}
+
+/// The error of an `ERROR` packet.
+fn remote_error(payload: &[u8]) -> io::Error {
+    let mut fields = Fields(payload);
+    let (Ok(code), Ok(message)) = (fields.u8(), fields.string()) else {
+        return bad_message("a malformed error");
+    };
+    let kind = ERROR_KINDS.get(code as usize).copied();
+    io::Error::new(
+        kind.unwrap_or(io::ErrorKind::Other),
+        String::from_utf8_lossy(message).into_owned(),
+    )
+}
+
+fn bad_message(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("the cpv server sent {}", what),
+    )
+}
+
+fn unexpected(kind: u8) -> io::Error {
+    bad_message(&format!("an unexpected packet of type {}", kind))
+}
+
+/// `path` as sent: its components joined with `/`. The server refuses
+/// `..`.
+fn wire_path(path: &Path) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for name in path.components().filter_map(|component| match component {
+        Component::Normal(name) => Some(name),
+        Component::ParentDir => Some("..".as_ref()),
+        _ => None,
+    }) {
+        if !bytes.is_empty() {
+            bytes.push(b'/');
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            bytes.extend_from_slice(name.as_bytes());
+        }
+        #[cfg(not(unix))]
+        bytes.extend_from_slice(name.to_string_lossy().as_bytes());
+    }
+    bytes
+}
+
+fn local_name(name: &[u8]) -> PathBuf {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        std::ffi::OsStr::from_bytes(name).into()
+    }
+    #[cfg(not(unix))]
+    {
+        String::from_utf8_lossy(name).into_owned().into()
+    }
+}
+
+/// The name of `checksum` as sent, empty for none.
+fn checksum_name(checksum: Option<ChecksumAlgo>) -> String {
+    checksum.map(|algo| algo.to_string()).unwrap_or_default()
+}
+
+fn checksum_of(name: &[u8]) -> io::Result<Option<ChecksumAlgo>> {
+    if name.is_empty() {
+        return Ok(None);
+    }
+    let name = String::from_utf8_lossy(name);
+    name.parse()
+        .map(Some)
+        .map_err(|err: String| io::Error::new(io::ErrorKind::Unsupported, err))
+}
+
+fn mismatch(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "'{}' was damaged on the way: its checksum does not match",
+            path.display()
+        ),
+    )
+}
+
+/// Serves a directory to `CpvFs` clients, for `cpv serve`.
+///
+/// Clients see the directory as their root and cannot name anything
+/// outside it with `..`, but symbolic links inside it are followed.
+pub struct Server {
+    root: PathBuf,
+    read_only: bool,
+    token: Option<String>,
+    max_connections: usize,
+    #[cfg(feature = "tls")]
+    tls: Option<ServerTls>,
+}
+
+impl fmt::Debug for Server {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Server")
+            .field("root", &self.root)
+            .field("read_only", &self.read_only)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Server {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            read_only: false,
+            token: None,
+            max_connections: MAX_CONNECTIONS,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    /// Refuses to write anything.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Only answers clients that present `token`.
+    pub fn token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Answers at most `max` connections at once, closing those beyond
+    /// as soon as they are accepted.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = max;
+        self
+    }
+
+    /// Speaks TLS instead of plain TCP.
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, tls: ServerTls) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Answers the connections of `listener`, each on its own thread,
+    /// until accepting them fails for good.
+    pub fn run(self, listener: TcpListener) -> io::Result<()> {
+        let server = Arc::new(self);
+        let open = Arc::new(AtomicUsize::new(0));
+        let mut failures = 0;
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                // Out of descriptors, or a client gone before accepted.
+                Err(err) => {
+                    failures += 1;
+                    if failures == 100 {
+                        return Err(err);
+                    }
+                    thread::sleep(Duration::from_millis(100));
+                    continue;
+                }
+            };
+            failures = 0;
+            // Dropped, and so closed, when there are too many already.
+            if open.load(Ordering::Acquire) >= server.max_connections {
+                continue;
+            }
+            open.fetch_add(1, Ordering::AcqRel);
+            let server = server.clone();
+            let open = open.clone();
+            thread::spawn(move || {
+                let _ = server.handle(stream);
+                open.fetch_sub(1, Ordering::AcqRel);
+            });
+        }
+        Ok(())
+    }
+
+    fn handle(&self, tcp: TcpStream) -> io::Result<()> {
+        tcp.set_nodelay(true)?;
+        // Clients that connect and say nothing do not hold a thread for
+        // long; once in, they may wait as long as they like.
+        tcp.set_read_timeout(Some(HELLO_TIMEOUT))?;
+        let socket = tcp.try_clone()?;
+        #[cfg(feature = "tls")]
+        let stream: Box<dyn Stream> = match &self.tls {
+            Some(tls) => {
+                let connection = rustls::ServerConnection::new(tls.0.clone()).map_err(tls_error)?;
+                Box::new(rustls::StreamOwned::new(connection, tcp))
+            }
+            None => Box::new(tcp),
+        };
+        #[cfg(not(feature = "tls"))]
+        let stream: Box<dyn Stream> = Box::new(tcp);
+        let mut conn = Connection::new(stream);
+        let (kind, payload) = conn.receive()?;
+        let mut hello = Fields(&payload);
+        if kind != msg::HELLO || hello.string()? != MAGIC || hello.u32()? != VERSION {
+            let err = io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("the server speaks version {} of the protocol", VERSION),
+            );
+            return conn.send_error(&err);
+        }
+        let token = hello.string()?;
+        // Hashes compare in constant time, so that the time taken tells
+        // nothing of how much of the token was right.
+        if self
+            .token
+            .as_ref()
+            .is_some_and(|expected| blake3::hash(expected.as_bytes()) != blake3::hash(token))
+        {
+            let err = io::Error::new(io::ErrorKind::PermissionDenied, "wrong token");
+            return conn.send_error(&err);
+        }
+        // Clients from before flags send none.
+        let flags = hello.u8().unwrap_or(0);
+        conn.compress = flags & COMPRESS != 0 && transit::supported();
+        let agreed = if conn.compress { COMPRESS } else { 0 };
+        conn.send(msg::OK, &[agreed])?;
+        socket.set_read_timeout(None)?;
+        loop {
+            let (kind, payload) = match conn.receive() {
+                Ok(request) => request,
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(err) => return Err(err),
+            };
+            self.answer(&mut conn, kind, &payload)?;
+        }
+    }
+
+    fn answer(&self, conn: &mut Connection, kind: u8, payload: &[u8]) -> io::Result<()> {
+        let mut fields = Fields(payload);
+        let path = self.resolve(fields.string()?);
+        let response = match kind {
+            msg::READ => return self.send_file(conn, path, checksum_of(fields.string()?)),
+            msg::WRITE => return self.receive_file(conn, path, checksum_of(fields.string()?)),
+            msg::STAT => path.and_then(fs::metadata).map(|metadata| {
+                let mut body = Packet::new();
+                let modified = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok());
+                body.u8(metadata.is_dir() as u8)
+                    .u64(if metadata.is_dir() { 0 } else { metadata.len() })
+                    .u8(modified.is_some() as u8)
+                    .u64(modified.unwrap_or_default().as_secs())
+                    .u32(modified.unwrap_or_default().subsec_nanos());
+                (msg::INFO, body)
+            }),
+            msg::LIST => path.and_then(fs::read_dir).and_then(|entries| {
+                let names = entries
+                    .map(|entry| Ok(wire_path(Path::new(&entry?.file_name()))))
+                    .collect::<io::Result<Vec<_>>>()?;
+                let mut body = Packet::new();
+                body.u32(names.len() as u32);
+                for name in names {
+                    body.string(&name);
+                }
+                Ok((msg::NAMES, body))
+            }),
+            msg::MKDIR => path
+                .and_then(|path| self.writable(path))
+                .and_then(fs::create_dir_all)
+                .map(|()| (msg::OK, Packet::new())),
+            msg::MTIME => {
+                let modified = UNIX_EPOCH
+                    + Duration::from_secs(fields.u64()?)
+                    + Duration::from_nanos(fields.u32()?.into());
+                path.and_then(|path| self.writable(path))
+                    .and_then(|path| File::options().write(true).open(path))
+                    .and_then(|file| file.set_modified(modified))
+                    .map(|()| (msg::OK, Packet::new()))
+            }
+            kind => return Err(bad_message(&format!("a request of type {}", kind))),
+        };
+        match response {
+            Ok((kind, body)) => conn.send(kind, &body.0),
+            Err(err) => conn.send_error(&err),
+        }
+    }
+
+    /// The local path of the path of a request.
+    fn resolve(&self, path: &[u8]) -> io::Result<PathBuf> {
+        let mut resolved = self.root.clone();
+        for name in path.split(|&b| b == b'/') {
+            match name {
+                b"" | b"." => {}
+                b".." => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "paths may not leave the served directory",
+                    ))
+                }
+                name => {
+                    let name = local_name(name);
+                    if !matches!(name.components().next(), Some(Component::Normal(_)))
+                        || name.components().count() != 1
+                    {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("invalid name '{}'", name.display()),
+                        ));
+                    }
+                    resolved.push(name);
+                }
+            }
+        }
+        Ok(resolved)
+    }
+
+    /// `path`, unless the server is read-only.
+    fn writable(&self, path: PathBuf) -> io::Result<PathBuf> {
+        match self.read_only {
+            true => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "the server is read-only",
+            )),
+            false => Ok(path),
+        }
+    }
+
+    /// Answers a `READ`: `OK`, the data and `END`, or an error at any point.
+    fn send_file(
+        &self,
+        conn: &mut Connection,
+        path: io::Result<PathBuf>,
+        checksum: io::Result<Option<ChecksumAlgo>>,
+    ) -> io::Result<()> {
+        let opened = checksum.and_then(|checksum| {
+            let path = path?;
+            Ok((File::open(&path)?, path, checksum))
+        });
+        let (mut file, path, checksum) = match opened {
+            Ok(opened) => opened,
+            Err(err) => return conn.send_error(&err),
+        };
+        conn.send(msg::OK, &[])?;
+        let mut packer = Packer::new(conn.compress, &path);
+        let mut hasher = checksum.map(ChecksumAlgo::hasher);
+        let mut buf = vec![0; CHUNK_SIZE];
+        loop {
+            let n = match file.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return conn.send_error(&err),
+            };
+            if let Some(hasher) = &mut hasher {
+                hasher.update(&buf[..n]);
+            }
+            conn.send_chunk(&mut packer, &buf[..n])?;
+        }
+        let mut body = Packet::new();
+        body.string(&digest(hasher));
+        conn.send(msg::END, &body.0)
+    }
+
+    /// Answers a `WRITE`: `OK` or an error, then, once the data and its
+    /// `END` are in, `OK` or an error again.
+    fn receive_file(
+        &self,
+        conn: &mut Connection,
+        path: io::Result<PathBuf>,
+        checksum: io::Result<Option<ChecksumAlgo>>,
+    ) -> io::Result<()> {
+        let created = checksum.and_then(|checksum| {
+            let path = self.writable(path?)?;
+            let part = Part {
+                path: Location::new(&path).part().path().to_path_buf(),
+                kept: false,
+            };
+            let file = File::create(&part.path)?;
+            Ok((path, part, file, checksum))
+        });
+        let (path, part, mut file, checksum) = match created {
+            Ok(created) => created,
+            Err(err) => return conn.send_error(&err),
+        };
+        conn.send(msg::OK, &[])?;
+        let mut hasher = checksum.map(ChecksumAlgo::hasher);
+        // A failed write is reported once the client is done sending.
+        let mut failed = None;
+        let expected = loop {
+            match conn.receive()? {
+                (kind @ (msg::DATA | msg::ZDATA), payload) => {
+                    let data = chunk(kind, &payload)?;
+                    if let Some(hasher) = &mut hasher {
+                        hasher.update(&data);
+                    }
+                    if failed.is_none() {
+                        failed = file.write_all(&data).err();
+                    }
+                }
+                (msg::END, payload) => break Fields(&payload).string()?.to_vec(),
+                (kind, _) => return Err(bad_message(&format!("a packet of type {}", kind))),
+            }
+        };
+        drop(file);
+        let result = match failed {
+            Some(err) => Err(err),
+            None if digest(hasher) != expected => Err(mismatch(&path)),
+            None => part.keep(&path),
+        };
+        match result {
+            Ok(()) => conn.send(msg::OK, &[]),
+            Err(err) => conn.send_error(&err),
+        }
+    }
+}
+
+/// The digest of `hasher`, or nothing without one.
+fn digest(hasher: Option<Box<dyn Hasher>>) -> Vec<u8> {
+    hasher.map_or_else(Vec::new, |hasher| hasher.finish().as_bytes().to_vec())
+}
+
+/// A file being received, removed unless kept.
+struct Part {
+    path: PathBuf,
+    kept: bool,
+}
+
+impl Part {
+    fn keep(mut self, path: &Path) -> io::Result<()> {
+        fs::rename(&self.path, path)?;
+        self.kept = true;
+        Ok(())
+    }
+}
+
+impl Drop for Part {
+    fn drop(&mut self) {
+        if !self.kept {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// How a `CpvFs` connects to its server.
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct NetConfig {
+    /// The token the server asks for, if any.
+    pub token: Option<String>,
+    /// The checksum files are sent and received with, which the other
+    /// side compares with its own; none if not set.
+    pub checksum: Option<ChecksumAlgo>,
+    /// Compresses file data in transit with zstd, where both sides are
+    /// built with the `zstd` feature, except for data compressed already.
+    pub compress: bool,
+    /// Speaks TLS, trusting these certificates, instead of plain TCP.
+    #[cfg(feature = "tls")]
+    pub tls: Option<ClientTls>,
+}
+
+/// The directory a `Server` serves, as a `Filesystem`, for
+/// `CopyOptions::source_fs` or `CopyOptions::dest_fs`.
+///
+/// Connections are opened as needed and kept for the next request. Files
+/// written appear at their path once `flush` is called on them, and not
+/// at all if the copy fails; modification times are kept with
+/// `preserve_attrs`.
+pub struct CpvFs {
+    shared: Arc<Shared>,
+}
+
+impl fmt::Debug for CpvFs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CpvFs")
+            .field("addr", &self.shared.addr)
+            .finish_non_exhaustive()
+    }
+}
+
+/// What a `CpvFs` and its open files share.
+struct Shared {
+    addr: String,
+    config: NetConfig,
+    idle: Mutex<Vec<Connection>>,
+}
+
+impl CpvFs {
+    /// Connects to the server at `host[:port]`, as in `cpv://` URLs.
+    pub fn connect(authority: &str, config: NetConfig) -> io::Result<Self> {
+        let shared = Shared {
+            addr: address(authority),
+            config,
+            idle: Mutex::new(Vec::new()),
+        };
+        // Fails early on a server that is not there or refuses us.
+        let conn = shared.connect()?;
+        shared.release(conn);
+        Ok(Self {
+            shared: Arc::new(shared),
+        })
+    }
+}
+
+impl Shared {
+    fn connect(&self) -> io::Result<Connection> {
+        let context = |err: io::Error| {
+            io::Error::new(
+                err.kind(),
+                format!("cannot connect to cpv://{}: {}", self.addr, err),
+            )
+        };
+        let tcp = TcpStream::connect(&self.addr).map_err(context)?;
+        tcp.set_nodelay(true)?;
+        #[cfg(feature = "tls")]
+        let stream: Box<dyn Stream> = match &self.config.tls {
+            Some(tls) => {
+                // The certificate must be for the host, without the port.
+                let (host, _) = self.addr.rsplit_once(':').expect("the port is added");
+                let host = host.trim_start_matches('[').trim_end_matches(']');
+                let name = rustls::pki_types::ServerName::try_from(host.to_string())
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+                let connection =
+                    rustls::ClientConnection::new(tls.0.clone(), name).map_err(tls_error)?;
+                Box::new(rustls::StreamOwned::new(connection, tcp))
+            }
+            None => Box::new(tcp),
+        };
+        #[cfg(not(feature = "tls"))]
+        let stream: Box<dyn Stream> = Box::new(tcp);
+        let mut conn = Connection::new(stream);
+        let mut hello = Packet::new();
+        hello
+            .string(MAGIC)
+            .u32(VERSION)
+            .string(self.config.token.as_deref().unwrap_or_default().as_bytes())
+            .u8(if self.config.compress { COMPRESS } else { 0 });
+        conn.send(msg::HELLO, &hello.0).map_err(context)?;
+        match conn.response().map_err(context)? {
+            (msg::OK, agreed) => {
+                // Servers from before flags agree to none.
+                conn.compress = Fields(&agreed).u8().unwrap_or(0) & COMPRESS != 0;
+                Ok(conn)
+            }
+            (kind, _) => Err(unexpected(kind)),
+        }
+    }
+
+    /// An idle connection, or a new one.
+    fn connection(&self) -> io::Result<Connection> {
+        match self.idle.lock().unwrap().pop() {
+            Some(conn) => Ok(conn),
+            None => self.connect(),
+        }
+    }
+
+    /// Keeps `conn`, which has no request under way, for the next one.
+    fn release(&self, conn: Connection) {
+        self.idle.lock().unwrap().push(conn);
+    }
+
+    /// Sends a request and returns the response, failing with the error
+    /// the server sent.
+    fn request(&self, kind: u8, body: &[u8]) -> io::Result<(u8, Vec<u8>)> {
+        let mut conn = self.connection()?;
+        conn.send(kind, body)?;
+        let response = conn.receive()?;
+        self.release(conn);
+        match response {
+            (msg::ERROR, payload) => Err(remote_error(&payload)),
+            response => Ok(response),
+        }
+    }
+
+    fn expect_ok(&self, kind: u8, body: &[u8]) -> io::Result<()> {
+        match self.request(kind, body)? {
+            (msg::OK, _) => Ok(()),
+            (kind, _) => Err(unexpected(kind)),
+        }
+    }
+
+    /// Starts streaming a file with a `READ` or `WRITE` request on a
+    /// connection of its own.
+    fn start(&self, kind: u8, path: &Path) -> io::Result<Connection> {
+        let mut body = Packet::new();
+        body.string(&wire_path(path))
+            .string(checksum_name(self.config.checksum).as_bytes());
+        let mut conn = self.connection()?;
+        conn.send(kind, &body.0)?;
+        match conn.receive()? {
+            (msg::OK, _) => Ok(conn),
+            (msg::ERROR, payload) => {
+                let err = remote_error(&payload);
+                self.release(conn);
+                Err(err)
+            }
+            (kind, _) => Err(unexpected(kind)),
+        }
+    }
+}
+
+impl Filesystem for CpvFs {
+    fn metadata(&self, path: &Path) -> io::Result<FileInfo> {
+        let mut body = Packet::new();
+        body.string(&wire_path(path));
+        let payload = match self.shared.request(msg::STAT, &body.0)? {
+            (msg::INFO, payload) => payload,
+            (kind, _) => return Err(unexpected(kind)),
+        };
+        let mut fields = Fields(&payload);
+        let is_dir = fields.u8()? != 0;
+        let len = fields.u64()?;
+        let has_modified = fields.u8()? != 0;
+        let modified =
+            Duration::from_secs(fields.u64()?) + Duration::from_nanos(fields.u32()?.into());
+        Ok(FileInfo {
+            is_dir,
+            len,
+            modified: has_modified.then(|| UNIX_EPOCH + modified),
+            mode: None,
+        })
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut body = Packet::new();
+        body.string(&wire_path(path));
+        let payload = match self.shared.request(msg::LIST, &body.0)? {
+            (msg::NAMES, payload) => payload,
+            (kind, _) => return Err(unexpected(kind)),
+        };
+        let mut fields = Fields(&payload);
+        (0..fields.u32()?)
+            .map(|_| Ok(path.join(local_name(fields.string()?))))
+            .collect()
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(NetReader {
+            conn: Some(self.shared.start(msg::READ, path)?),
+            shared: self.shared.clone(),
+            path: path.to_path_buf(),
+            hasher: self.shared.config.checksum.map(ChecksumAlgo::hasher),
+            buffer: Vec::new(),
+            read: 0,
+        }))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn Write + Send>> {
+        let conn = self.shared.start(msg::WRITE, path)?;
+        Ok(Box::new(NetWriter {
+            packer: Packer::new(conn.compress, path),
+            conn: Some(conn),
+            shared: self.shared.clone(),
+            path: path.to_path_buf(),
+            hasher: self.shared.config.checksum.map(ChecksumAlgo::hasher),
+        }))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut body = Packet::new();
+        body.string(&wire_path(path));
+        self.shared.expect_ok(msg::MKDIR, &body.0)
+    }
+
+    fn set_modified(&self, path: &Path, modified: SystemTime) -> io::Result<()> {
+        let since_epoch = modified
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| io::Error::from(io::ErrorKind::Unsupported))?;
+        let mut body = Packet::new();
+        body.string(&wire_path(path))
+            .u64(since_epoch.as_secs())
+            .u32(since_epoch.subsec_nanos());
+        self.shared.expect_ok(msg::MTIME, &body.0)
+    }
+}
+
+/// A file being received. Its connection goes back to the pool once the
+/// whole file is in; one dropped before is closed.
+struct NetReader {
+    shared: Arc<Shared>,
+    conn: Option<Connection>,
+    path: PathBuf,
+    hasher: Option<Box<dyn Hasher>>,
+    buffer: Vec<u8>,
+    /// How much of `buffer` has been read.
+    read: usize,
+}
+
+impl Read for NetReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.read == self.buffer.len() {
+            let Some(conn) = &mut self.conn else {
+                return Ok(0);
+            };
+            let (kind, payload) = conn.receive()?;
+            match kind {
+                msg::DATA | msg::ZDATA => {
+                    self.buffer = chunk(kind, &payload)?;
+                    self.read = 0;
+                    if let Some(hasher) = &mut self.hasher {
+                        hasher.update(&self.buffer);
+                    }
+                }
+                msg::END | msg::ERROR => {
+                    let conn = self.conn.take().expect("checked above");
+                    self.shared.release(conn);
+                    if kind == msg::ERROR {
+                        return Err(remote_error(&payload));
+                    }
+                    if digest(self.hasher.take()) != Fields(&payload).string()? {
+                        return Err(mismatch(&self.path));
+                    }
+                }
+                kind => return Err(unexpected(kind)),
+            }
+        }
+        let n = buf.len().min(self.buffer.len() - self.read);
+        buf[..n].copy_from_slice(&self.buffer[self.read..self.read + n]);
+        self.read += n;
+        Ok(n)
+    }
+}
+
+/// A file being sent, which the server puts in place on `flush`; nothing
+/// can be written after. One dropped before is discarded.
+struct NetWriter {
+    shared: Arc<Shared>,
+    conn: Option<Connection>,
+    path: PathBuf,
+    hasher: Option<Box<dyn Hasher>>,
+    packer: Packer,
+}
+
+impl Write for NetWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(conn) = &mut self.conn else {
+            return Err(io::Error::other(format!(
+                "'{}' is already sent",
+                self.path.display()
+            )));
+        };
+        for chunk in buf.chunks(CHUNK_SIZE) {
+            conn.send_chunk(&mut self.packer, chunk)?;
+        }
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(buf);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let Some(mut conn) = self.conn.take() else {
+            return Ok(());
+        };
+        let mut body = Packet::new();
+        body.string(&digest(self.hasher.take()));
+        conn.send(msg::END, &body.0)?;
+        let result = match conn.receive()? {
+            (msg::OK, _) => Ok(()),
+            (msg::ERROR, payload) => Err(remote_error(&payload)),
+            (kind, _) => return Err(unexpected(kind)),
+        };
+        self.shared.release(conn);
+        result
+    }
+}
@@ -0,0 +1,123 @@
+//! How a copy ended: `CopyOutcome` with what was done, for callers of
+//! `copy`, and `ExitStatus`, the status cpv reports it with, so that
+//! scripts can tell a partial copy from one that failed outright.
+
+use crate::{copy_with_progress, verify, CopyError, CopyOptions, CopyStats, FileError, FileOp};
+use std::path::Path;
+
+/// How a copy that ran to an end ended. Copies that stopped at an error
+/// are the `Err` of `copy`.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "outcome", rename_all = "snake_case"))]
+pub enum CopyOutcome {
+    /// Every file was copied.
+    Completed(CopyStats),
+    /// Some files could not be copied with `keep_going` and the rest were.
+    /// `stats.failures` is left empty, the failures being in `errors`.
+    Partial {
+        stats: CopyStats,
+        errors: Vec<FileError>,
+    },
+    /// The copy was stopped through `CopyOptions::cancel`; `stats` cover
+    /// what was done until then.
+    Cancelled { stats: CopyStats },
+}
+
+/// Copies `source` to `dest` like `copy_with_progress`, telling complete,
+/// partial and cancelled copies apart.
+pub fn copy(source: &Path, dest: &Path, options: &CopyOptions) -> Result<CopyOutcome, CopyError> {
+    CopyOutcome::from_result(copy_with_progress(source, dest, options))
+}
+
+impl CopyOutcome {
+    /// The outcome of a copy that returned `result`, or its error if it
+    /// stopped at one.
+    pub fn from_result(result: Result<CopyStats, CopyError>) -> Result<Self, CopyError> {
+        match result {
+            Err(CopyError::Cancelled(stats)) => Ok(Self::Cancelled { stats: *stats }),
+            Err(err) => Err(err),
+            Ok(mut stats) if !stats.failures.is_empty() => {
+                let errors = std::mem::take(&mut stats.failures);
+                Ok(Self::Partial { stats, errors })
+            }
+            Ok(stats) => Ok(Self::Completed(stats)),
+        }
+    }
+
+    pub fn stats(&self) -> &CopyStats {
+        match self {
+            Self::Completed(stats) | Self::Partial { stats, .. } | Self::Cancelled { stats } => {
+                stats
+            }
+        }
+    }
+
+    pub fn is_completed(&self) -> bool {
+        matches!(self, Self::Completed(_))
+    }
+
+    /// The exit status of cpv for this outcome.
+    pub fn exit_status(&self) -> ExitStatus {
+        match self {
+            Self::Completed(_) => ExitStatus::Completed,
+            Self::Partial { errors, .. } if errors.iter().any(|f| f.op == FileOp::Verify) => {
+                ExitStatus::VerifyFailed
+            }
+            Self::Partial { .. } => ExitStatus::Partial,
+            Self::Cancelled { .. } => ExitStatus::Interrupted,
+        }
+    }
+}
+
+/// How a copy ended, as cpv reports it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum ExitStatus {
+    /// Every file was copied. Exit status 0.
+    Completed,
+    /// Nothing was copied, or the copy stopped at an error. Exit status 1.
+    Failed,
+    /// Some files could not be copied with `keep_going` and the rest were.
+    /// Exit status 2.
+    Partial,
+    /// A copy read back with `verify`, or a tree compared with
+    /// `compare_trees`, did not match its source. Exit status 3.
+    VerifyFailed,
+    /// The copy was stopped before it finished, e.g. through
+    /// `CopyOptions::cancel`. Exit status 4.
+    Interrupted,
+}
+
+impl ExitStatus {
+    /// The status of a copy that returned `result`. A verification
+    /// mismatch outweighs other failures.
+    pub fn of(result: &Result<CopyStats, CopyError>) -> Self {
+        match result {
+            Err(CopyError::Cancelled(_)) => Self::Interrupted,
+            Err(err) if is_mismatch(err) => Self::VerifyFailed,
+            Err(_) => Self::Failed,
+            Ok(stats) if stats.failures.iter().any(|f| f.op == FileOp::Verify) => {
+                Self::VerifyFailed
+            }
+            Ok(stats) if !stats.failures.is_empty() => Self::Partial,
+            Ok(_) => Self::Completed,
+        }
+    }
+
+    /// The exit status of cpv for this outcome.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Self::Completed => 0,
+            Self::Failed => 1,
+            Self::Partial => 2,
+            Self::VerifyFailed => 3,
+            Self::Interrupted => 4,
+        }
+    }
+}
+
+fn is_mismatch(err: &CopyError) -> bool {
+    matches!(err, CopyError::Io(err) if verify::is_mismatch(err))
+}
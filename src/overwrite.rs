@@ -0,0 +1,177 @@
+//! What happens to destination files that a copy would replace, and
+//! keeping them when they are replaced.
+
+use crate::link;
+use crate::location::Location;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// What a copy does with a destination that already holds a file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum OverwritePolicy {
+    /// Replace it.
+    #[default]
+    Overwrite,
+    /// Keep it and leave the source out, counting it in
+    /// `CopyStats::files_kept`.
+    Skip,
+    /// Fail the file, which stops the copy unless `keep_going` is set.
+    Error,
+    /// Let `CopyHooks::on_conflict` decide, failing the file when no hooks
+    /// are set.
+    Prompt,
+    /// Move it into this directory, under its path relative to the
+    /// destination, then replace it.
+    Backup(PathBuf),
+    /// Move it to the system trash, then replace it.
+    Trash,
+    /// Keep it and write the copy next to it as `NAME (1).EXT`, or the
+    /// first such number that is free.
+    #[cfg_attr(feature = "serde", serde(rename = "rename"))]
+    RenameNew,
+}
+
+impl OverwritePolicy {
+    /// The names of the policies that take no directory, as `from_str`
+    /// takes them and `Display` writes them.
+    pub const NAMES: &[&str] = &["overwrite", "skip", "error", "prompt", "trash", "rename"];
+}
+
+impl FromStr for OverwritePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "overwrite" => Ok(Self::Overwrite),
+            "skip" => Ok(Self::Skip),
+            "error" => Ok(Self::Error),
+            "prompt" => Ok(Self::Prompt),
+            "trash" => Ok(Self::Trash),
+            "rename" => Ok(Self::RenameNew),
+            _ => Err(format!(
+                "invalid overwrite policy '{}' (expected overwrite, skip, error, prompt, trash or rename)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for OverwritePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Overwrite => "overwrite",
+            Self::Skip => "skip",
+            Self::Error => "error",
+            Self::Prompt => "prompt",
+            Self::Backup(_) => "backup",
+            Self::Trash => "trash",
+            Self::RenameNew => "rename",
+        })
+    }
+}
+
+/// The error of a file whose destination exists and may not be replaced.
+pub(crate) fn exists(dest: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("'{}' already exists", dest.display()),
+    )
+}
+
+/// The first of `NAME (1).EXT`, `NAME (2).EXT`, ... next to `dest` for
+/// which `taken` is false.
+pub(crate) fn free_name(
+    dest: &Path,
+    mut taken: impl FnMut(&Path) -> io::Result<bool>,
+) -> io::Result<PathBuf> {
+    let stem = dest.file_stem().unwrap_or_default();
+    for n in 1.. {
+        let mut name = OsString::from(stem);
+        name.push(format!(" ({})", n));
+        if let Some(extension) = dest.extension() {
+            name.push(".");
+            name.push(extension);
+        }
+        let candidate = dest.with_file_name(name);
+        if !taken(&candidate)? {
+            return Ok(candidate);
+        }
+    }
+    unreachable!("a name is free before the counter overflows")
+}
+
+/// Moves `dest` to the system trash (the Freedesktop trash, the macOS
+/// Trash or the Windows Recycle Bin) if it exists and is not a directory.
+/// Returns whether there was a file to move.
+pub(crate) fn trash(dest: &Location) -> io::Result<bool> {
+    if !replaced(dest)? {
+        return Ok(false);
+    }
+    #[cfg(not(target_os = "wasi"))]
+    let moved = trash::delete(dest.path()).map_err(|err| err.to_string());
+    // A sandbox has no trash to move files to.
+    #[cfg(target_os = "wasi")]
+    let moved = Err("no trash on WASI");
+    moved.map_err(|err| {
+        io::Error::other(format!(
+            "cannot move '{}' to the trash: {}",
+            dest.path().display(),
+            err
+        ))
+    })?;
+    Ok(true)
+}
+
+/// Moves `dest` into `backup_dir` under its path relative to `root` if it
+/// exists and is not a directory, replacing an earlier backup of it.
+/// Returns where it was moved, if there was a file to move.
+pub(crate) fn back_up(
+    dest: &Location,
+    root: &Path,
+    backup_dir: &Path,
+) -> io::Result<Option<PathBuf>> {
+    if !replaced(dest)? {
+        return Ok(None);
+    }
+    let backup = backup_dir.join(dest.path().strip_prefix(root).unwrap_or(dest.path()));
+    if let Some(parent) = backup.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    move_file(dest, &backup)?;
+    Ok(Some(backup))
+}
+
+/// Moves the file, or symbolic link, `from` to `to`, replacing it.
+pub(crate) fn move_file(from: &Location, to: &Path) -> io::Result<()> {
+    match fs::rename(from.path(), to) {
+        Err(err) if link::crosses_devices(&err) => {
+            // `to` is on another filesystem, so the file is copied there,
+            // with its modification time, and removed.
+            let metadata = fs::symlink_metadata(from.path())?;
+            if metadata.file_type().is_symlink() {
+                Location::new(to).symlink(&fs::read_link(from.path())?)?;
+            } else {
+                fs::copy(from.path(), to)?;
+                let file = fs::OpenOptions::new().write(true).open(to)?;
+                file.set_modified(metadata.modified()?)?;
+            }
+            from.remove()
+        }
+        result => result,
+    }
+}
+
+/// Whether `dest` holds a file that writing it would replace.
+pub(crate) fn replaced(dest: &Location) -> io::Result<bool> {
+    match fs::symlink_metadata(dest.path()) {
+        Ok(metadata) => Ok(!metadata.is_dir()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err),
+    }
+}
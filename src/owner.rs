@@ -0,0 +1,203 @@
+//! Destination ownership: `--chown` and `--usermap`/`--groupmap`.
+
+use std::fmt;
+use std::str::FromStr;
+
+/// An explicit `user:group` owner for everything written.
+///
+/// Either half may be omitted (`user`, `user:`, `:group`) and each accepts a
+/// name or a numeric ID. Names are resolved when the spec is parsed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Chown {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl FromStr for Chown {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (user, group) = s.split_once(':').unwrap_or((s, ""));
+        let chown = Self {
+            uid: (!user.is_empty()).then(|| resolve_user(user)).transpose()?,
+            gid: (!group.is_empty())
+                .then(|| resolve_group(group))
+                .transpose()?,
+        };
+        if chown.uid.is_none() && chown.gid.is_none() {
+            return Err(format!("invalid owner '{}'", s));
+        }
+        Ok(chown)
+    }
+}
+
+/// The owner as numeric IDs, e.g. `1000:100` or `:100`.
+impl fmt::Display for Chown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(uid) = self.uid {
+            write!(f, "{}", uid)?;
+        }
+        if let Some(gid) = self.gid {
+            write!(f, ":{}", gid)?;
+        }
+        Ok(())
+    }
+}
+
+/// An rsync-style ID translation table applied when ownership is preserved,
+/// e.g. `1000:1001,alice:bob,500-599:nobody,*:0`.
+///
+/// Each comma-separated `FROM:TO` entry matches a single ID or name, an
+/// inclusive numeric range, or `*` for anything; the first match wins and
+/// unmatched IDs pass through unchanged.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IdMap {
+    entries: Vec<(IdMatch, u32)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IdMatch {
+    Any,
+    Range(u32, u32),
+}
+
+impl IdMap {
+    /// Parses a `--usermap` value, resolving names as users.
+    pub fn parse_users(s: &str) -> Result<Self, String> {
+        Self::parse(s, resolve_user)
+    }
+
+    /// Parses a `--groupmap` value, resolving names as groups.
+    pub fn parse_groups(s: &str) -> Result<Self, String> {
+        Self::parse(s, resolve_group)
+    }
+
+    fn parse(s: &str, resolve: fn(&str) -> Result<u32, String>) -> Result<Self, String> {
+        let entries = s
+            .split(',')
+            .map(|entry| {
+                let (from, to) = entry
+                    .split_once(':')
+                    .ok_or_else(|| format!("invalid mapping '{}' (expected FROM:TO)", entry))?;
+                let range = from
+                    .split_once('-')
+                    .and_then(|(low, high)| Some((low.parse().ok()?, high.parse().ok()?)));
+                let from = match range {
+                    _ if from == "*" => IdMatch::Any,
+                    Some((low, high)) => IdMatch::Range(low, high),
+                    None => {
+                        let id = resolve(from)?;
+                        IdMatch::Range(id, id)
+                    }
+                };
+                Ok((from, resolve(to)?))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self { entries })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Translates `id`, returning it unchanged when nothing matches.
+    pub fn map(&self, id: u32) -> u32 {
+        self.entries
+            .iter()
+            .find(|(from, _)| match *from {
+                IdMatch::Any => true,
+                IdMatch::Range(low, high) => (low..=high).contains(&id),
+            })
+            .map_or(id, |&(_, to)| to)
+    }
+}
+
+/// The table with numeric IDs, e.g. `1000:1001,500-599:65534,*:0`.
+impl fmt::Display for IdMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (from, to)) in self.entries.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            match *from {
+                IdMatch::Any => f.write_str("*")?,
+                IdMatch::Range(low, high) if low == high => write!(f, "{}", low)?,
+                IdMatch::Range(low, high) => write!(f, "{}-{}", low, high)?,
+            }
+            write!(f, ":{}", to)?;
+        }
+        Ok(())
+    }
+}
+
+/// Resolves a user name or numeric UID.
+pub(crate) fn resolve_user(user: &str) -> Result<u32, String> {
+    user.parse()
+        .ok()
+        .or_else(|| sys::uid_by_name(user))
+        .ok_or_else(|| format!("unknown user '{}'", user))
+}
+
+/// Resolves a group name or numeric GID.
+pub(crate) fn resolve_group(group: &str) -> Result<u32, String> {
+    group
+        .parse()
+        .ok()
+        .or_else(|| sys::gid_by_name(group))
+        .ok_or_else(|| format!("unknown group '{}'", group))
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::ffi::CString;
+    use std::{mem, ptr};
+
+    const BUF_SIZE: usize = 16 * 1024;
+
+    pub fn uid_by_name(name: &str) -> Option<u32> {
+        let name = CString::new(name).ok()?;
+        let mut buf = vec![0; BUF_SIZE];
+        // SAFETY: all pointers reference live, correctly sized buffers.
+        unsafe {
+            let mut pwd: libc::passwd = mem::zeroed();
+            let mut result = ptr::null_mut();
+            let rc = libc::getpwnam_r(
+                name.as_ptr(),
+                &mut pwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            );
+            (rc == 0 && !result.is_null()).then_some(pwd.pw_uid)
+        }
+    }
+
+    pub fn gid_by_name(name: &str) -> Option<u32> {
+        let name = CString::new(name).ok()?;
+        let mut buf = vec![0; BUF_SIZE];
+        // SAFETY: all pointers reference live, correctly sized buffers.
+        unsafe {
+            let mut grp: libc::group = mem::zeroed();
+            let mut result = ptr::null_mut();
+            let rc = libc::getgrnam_r(
+                name.as_ptr(),
+                &mut grp,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            );
+            (rc == 0 && !result.is_null()).then_some(grp.gr_gid)
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    pub fn uid_by_name(_name: &str) -> Option<u32> {
+        None
+    }
+
+    pub fn gid_by_name(_name: &str) -> Option<u32> {
+        None
+    }
+}
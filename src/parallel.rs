@@ -0,0 +1,396 @@
+//! Parallel copying: the `-j/--jobs` worker pool across files and
+//! `--chunked-threads` range copies within a single file.
+
+use crate::devices::Devices;
+use crate::fdlimit::Budget;
+use crate::journal::Journal;
+use crate::location::Location;
+use crate::progress::Bars;
+use crate::storage::StorageProfile;
+use crate::{
+    copy_journaled, is_cancelled, CopyError, CopyOptions, CopyStats, ErrorClass, FileError,
+};
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+
+// The range copies of `--chunked-threads` need positional I/O, which WASI
+// does not offer on stable Rust.
+#[cfg(any(unix, windows))]
+use crate::progress::Progress;
+#[cfg(any(unix, windows))]
+use std::fs::File;
+#[cfg(any(unix, windows))]
+use std::io;
+#[cfg(any(unix, windows))]
+use std::sync::atomic::AtomicU64;
+
+/// Size of the ranges handed out to `--chunked-threads` workers.
+#[cfg(any(unix, windows))]
+pub(crate) const RANGE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Per-worker buffer for range copies.
+#[cfg(any(unix, windows))]
+const RANGE_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// The order in which files of a recursive copy are handed to workers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FileOrder {
+    /// Directory walk order.
+    #[default]
+    AsFound,
+    /// Small files first, so most of the tree is usable sooner.
+    SmallestFirst,
+    /// Large files first, so no big file is left running alone at the end.
+    LargestFirst,
+}
+
+impl FileOrder {
+    /// The names of the orders, as `from_str` takes them and `Display`
+    /// writes them.
+    pub const NAMES: &[&str] = &["as-found", "smallest-first", "largest-first"];
+}
+
+impl FromStr for FileOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "as-found" => Ok(Self::AsFound),
+            "smallest-first" => Ok(Self::SmallestFirst),
+            "largest-first" => Ok(Self::LargestFirst),
+            _ => Err(format!(
+                "invalid order '{}' (expected as-found, smallest-first or largest-first)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for FileOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::AsFound => "as-found",
+            Self::SmallestFirst => "smallest-first",
+            Self::LargestFirst => "largest-first",
+        })
+    }
+}
+
+/// How many files of a recursive copy are copied concurrently.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Parallelism {
+    /// One worker per CPU on solid-state storage, a few for network
+    /// filesystems and one for spinning or unidentified disks.
+    #[default]
+    Auto,
+    /// Exactly this many workers; 0 is treated as 1.
+    Exact(usize),
+    /// Chosen like `Auto`, but never more than this many workers.
+    Max(usize),
+    /// Copy one file at a time.
+    Disabled,
+}
+
+impl Parallelism {
+    /// Whether the worker count depends on the storage being copied.
+    pub(crate) fn needs_profile(self) -> bool {
+        matches!(self, Self::Auto | Self::Max(_))
+    }
+
+    /// The number of workers for a copy between the storage in `profile`.
+    pub fn workers(self, profile: &StorageProfile) -> usize {
+        match self {
+            Self::Auto => profile.jobs(),
+            Self::Exact(n) => n.max(1),
+            Self::Max(n) => profile.jobs().min(n).max(1),
+            Self::Disabled => 1,
+        }
+    }
+}
+
+impl FromStr for Parallelism {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.to_ascii_lowercase();
+        let count = |n: &str| n.parse::<usize>().ok().filter(|&n| n > 0);
+        let parsed = if lower == "auto" {
+            Some(Self::Auto)
+        } else if let Some(n) = lower.strip_prefix("max:") {
+            count(n).map(Self::Max)
+        } else {
+            count(&lower).map(Self::Exact)
+        };
+        parsed.ok_or_else(|| {
+            format!(
+                "invalid job count '{}' (expected auto, N or max:N with N at least 1)",
+                s
+            )
+        })
+    }
+}
+
+impl fmt::Display for Parallelism {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Auto => f.write_str("auto"),
+            Self::Exact(n) => write!(f, "{}", n),
+            Self::Max(n) => write!(f, "max:{}", n),
+            Self::Disabled => f.write_str("1"),
+        }
+    }
+}
+
+/// Reorders `files` by source size according to `order`; ties keep walk
+/// order.
+pub(crate) fn schedule(files: &mut [(Location, Location)], order: FileOrder) {
+    let size = |(source, _): &(Location, Location)| {
+        fs::symlink_metadata(source.path()).map_or(0, |m| m.len())
+    };
+    match order {
+        FileOrder::AsFound => {}
+        FileOrder::SmallestFirst => files.sort_by_cached_key(size),
+        FileOrder::LargestFirst => files.sort_by_cached_key(|file| std::cmp::Reverse(size(file))),
+    }
+}
+
+/// Files queued per worker while the walk runs ahead of the copies.
+const QUEUE_PER_WORKER: usize = 64;
+
+/// Copies each `(source, target)` pair yielded by `files`, on up to
+/// `options.parallelism` threads, as soon as the pair is produced.
+///
+/// Target directories must already exist when a pair is yielded. The
+/// calling thread pulls pairs from `files` and hands them to workers
+/// through a bounded queue; workers report into the shared progress bar and
+/// keep their own `CopyStats`, which are merged into `stats` at the end.
+/// The first failure, from the iterator or a worker, stops any further
+/// files from being copied.
+pub(crate) fn copy_files<I>(
+    files: I,
+    bars: &Bars,
+    journal: &Journal,
+    options: &CopyOptions,
+    stats: &mut CopyStats,
+) -> Result<(), CopyError>
+where
+    I: Iterator<Item = Result<(Location, Location), CopyError>>,
+{
+    // `Auto` and `Max` are resolved against the storage before copying.
+    let jobs = match options.parallelism {
+        Parallelism::Exact(n) => n.max(1),
+        _ => 1,
+    };
+    // WASI has no threads for workers.
+    #[cfg(target_os = "wasi")]
+    let jobs = jobs.min(1);
+    if jobs == 1 {
+        for file in files {
+            let (source, target) = file?;
+            if let Err(err) = copy_journaled(&source, &target, bars, journal, options, stats) {
+                if !options.skips(ErrorClass::of(&err)) || is_cancelled(&err) {
+                    return Err(err);
+                }
+                stats
+                    .failures
+                    .push(FileError::copy(source.path().to_path_buf(), err));
+            }
+        }
+        return Ok(());
+    }
+
+    // Queued files hold their parent directories open.
+    let queue = Budget::current().queue(jobs, jobs * QUEUE_PER_WORKER);
+    let (sender, receiver) = mpsc::sync_channel::<(Location, Location)>(queue);
+    let receiver = Mutex::new(receiver);
+    let failed = AtomicBool::new(false);
+    let devices = options.per_device.then(Devices::default);
+
+    let (produced, results) = thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut local = CopyStats::new();
+                    let mut result = Ok(());
+                    // Keep draining after a failure so the producer never
+                    // blocks on a full queue.
+                    while let Ok((source, target)) = receiver.lock().unwrap().recv() {
+                        if failed.load(Ordering::Relaxed) {
+                            continue;
+                        }
+                        let _turn = devices
+                            .as_ref()
+                            .map(|devices| devices.turn(source.path(), target.path()));
+                        if let Err(err) =
+                            copy_journaled(&source, &target, bars, journal, options, &mut local)
+                        {
+                            if options.skips(ErrorClass::of(&err)) && !is_cancelled(&err) {
+                                local
+                                    .failures
+                                    .push(FileError::copy(source.path().to_path_buf(), err));
+                            } else {
+                                failed.store(true, Ordering::Relaxed);
+                                result = Err(err);
+                            }
+                        }
+                    }
+                    (local, result)
+                })
+            })
+            .collect();
+
+        let mut produced = Ok(());
+        for file in files {
+            if failed.load(Ordering::Relaxed) {
+                break;
+            }
+            match file {
+                Ok(pair) => sender.send(pair).expect("copy workers exited early"),
+                Err(err) => {
+                    failed.store(true, Ordering::Relaxed);
+                    produced = Err(err);
+                    break;
+                }
+            }
+        }
+        drop(sender);
+
+        let results: Vec<(CopyStats, Result<(), CopyError>)> = workers
+            .into_iter()
+            .map(|worker| worker.join().expect("copy worker panicked"))
+            .collect();
+        (produced, results)
+    });
+
+    // The work of every worker counts, even if one of them failed.
+    let mut failed = Ok(());
+    for (local, result) in results {
+        stats.merge(local);
+        if failed.is_ok() {
+            failed = result;
+        }
+    }
+    failed.and(produced)
+}
+
+#[cfg(any(unix, windows))]
+/// Copies `len` bytes of `source` into `dest` as fixed-size ranges spread
+/// over `threads` workers using positional reads and writes, each with a
+/// buffer of `buffer_size` bytes (1 MiB by default), reporting to the bars
+/// and limiter of `progress`.
+///
+/// The destination is sized up front so that every worker writes into
+/// already-existing file space. Returns the number of bytes copied.
+pub(crate) fn copy_chunked(
+    source: &File,
+    dest: &File,
+    len: u64,
+    progress: &Progress<'_>,
+    threads: usize,
+    buffer_size: Option<usize>,
+) -> io::Result<u64> {
+    dest.set_len(len)?;
+
+    let ranges = len.div_ceil(RANGE_SIZE);
+    let next = &AtomicU64::new(0);
+    let failed = &AtomicBool::new(false);
+
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads.min(ranges as usize))
+            .map(|_| {
+                let mut progress = progress.worker();
+                scope.spawn(move || {
+                    let mut buffer =
+                        progress.buffer(0, Some(buffer_size.unwrap_or(RANGE_BUFFER_SIZE)));
+                    while !failed.load(Ordering::Relaxed) {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        if index >= ranges {
+                            break;
+                        }
+                        let start = index * RANGE_SIZE;
+                        let end = (start + RANGE_SIZE).min(len);
+                        if let Err(err) =
+                            copy_range(source, dest, start..end, &mut buffer, &mut progress)
+                        {
+                            failed.store(true, Ordering::Relaxed);
+                            return Err(err);
+                        }
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+
+        workers
+            .into_iter()
+            .try_for_each(|worker| worker.join().expect("copy worker panicked"))
+    })?;
+
+    Ok(len)
+}
+
+#[cfg(any(unix, windows))]
+fn copy_range(
+    source: &File,
+    dest: &File,
+    range: std::ops::Range<u64>,
+    buffer: &mut [u8],
+    progress: &mut Progress<'_>,
+) -> io::Result<()> {
+    let mut pos = range.start;
+    while pos < range.end {
+        let want = buffer.len().min((range.end - pos) as usize);
+        let n = read_at(source, &mut buffer[..want], pos)?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "source file shrank while being copied",
+            ));
+        }
+        write_all_at(dest, &buffer[..n], pos)?;
+        pos += n as u64;
+        progress.inc(n as u64)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::unix::fs::FileExt;
+    loop {
+        match file.read_at(buf, offset) {
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            result => return result,
+        }
+    }
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.write_all_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::windows::fs::FileExt;
+    file.seek_read(buf, offset)
+}
+
+#[cfg(windows)]
+fn write_all_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        let n = file.seek_write(buf, offset)?;
+        if n == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        buf = &buf[n..];
+        offset += n as u64;
+    }
+    Ok(())
+}
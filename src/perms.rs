@@ -0,0 +1,182 @@
+//! Explicit destination modes for `--chmod`.
+
+use std::fmt;
+use std::str::FromStr;
+
+/// A parsed rsync-style `--chmod` specification such as `D755,F644` or
+/// `Dg+s,ug+w,Fo-w,+X`.
+///
+/// Each comma-separated clause may be prefixed with `D` (directories only)
+/// or `F` (files only) and is either an octal mode or a symbolic
+/// `[ugoa]*([-+=][rwxXst]*)+` expression. Clauses are applied in order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChmodRules {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Scope {
+    All,
+    Dirs,
+    Files,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rule {
+    scope: Scope,
+    kind: RuleKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum RuleKind {
+    Octal(u32),
+    Symbolic(u32, Vec<(char, Perms)>),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Perms {
+    bits: u32,
+    conditional_exec: bool,
+}
+
+impl ChmodRules {
+    /// Applies the rules to `mode` for a directory or a file.
+    pub fn apply(&self, mut mode: u32, is_dir: bool) -> u32 {
+        for rule in &self.rules {
+            match (rule.scope, is_dir) {
+                (Scope::Dirs, false) | (Scope::Files, true) => continue,
+                _ => {}
+            }
+
+            match &rule.kind {
+                RuleKind::Octal(bits) => mode = (mode & !0o7777) | bits,
+                RuleKind::Symbolic(who, ops) => {
+                    for (op, perms) in ops {
+                        let mut bits = perms.bits;
+                        if perms.conditional_exec && (is_dir || mode & 0o111 != 0) {
+                            bits |= 0o111;
+                        }
+                        let bits = bits & who;
+                        mode = match op {
+                            '+' => mode | bits,
+                            '-' => mode & !bits,
+                            _ => (mode & !who) | bits,
+                        };
+                    }
+                }
+            }
+        }
+        mode
+    }
+}
+
+impl FromStr for ChmodRules {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rules = s
+            .split(',')
+            .map(|clause| {
+                parse_clause(clause).ok_or_else(|| format!("invalid chmod rule '{}'", clause))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { rules })
+    }
+}
+
+/// The rules as a spec that parses back to them, e.g. `D755,Fu=rw,go=r`.
+impl fmt::Display for ChmodRules {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, rule) in self.rules.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            match rule.scope {
+                Scope::All => {}
+                Scope::Dirs => f.write_str("D")?,
+                Scope::Files => f.write_str("F")?,
+            }
+            match &rule.kind {
+                RuleKind::Octal(bits) => write!(f, "{:o}", bits)?,
+                RuleKind::Symbolic(who, ops) => {
+                    for (c, bits) in [('u', 0o4700), ('g', 0o2070), ('o', 0o1007)] {
+                        if *who != 0o7777 && who & bits == bits {
+                            write!(f, "{}", c)?;
+                        }
+                    }
+                    for (op, perms) in ops {
+                        write!(f, "{}", op)?;
+                        for (c, bits) in [('r', 0o444), ('w', 0o222), ('x', 0o111)] {
+                            if perms.bits & bits == bits {
+                                write!(f, "{}", c)?;
+                            }
+                        }
+                        if perms.conditional_exec {
+                            f.write_str("X")?;
+                        }
+                        for (c, bits) in [('s', 0o6000), ('t', 0o1000)] {
+                            if perms.bits & bits == bits {
+                                write!(f, "{}", c)?;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_clause(clause: &str) -> Option<Rule> {
+    let (scope, rest) = match clause.chars().next()? {
+        'D' => (Scope::Dirs, &clause[1..]),
+        'F' => (Scope::Files, &clause[1..]),
+        _ => (Scope::All, clause),
+    };
+
+    if !rest.is_empty() && rest.chars().all(|c| c.is_digit(8)) {
+        let bits = u32::from_str_radix(rest, 8).ok()?;
+        return (bits <= 0o7777).then_some(Rule {
+            scope,
+            kind: RuleKind::Octal(bits),
+        });
+    }
+
+    let who_len = rest.find(|c| !"ugoa".contains(c)).unwrap_or(rest.len());
+    let mut who = 0;
+    for c in rest[..who_len].chars() {
+        who |= match c {
+            'u' => 0o4700,
+            'g' => 0o2070,
+            'o' => 0o1007,
+            _ => 0o7777,
+        };
+    }
+    if who == 0 {
+        who = 0o7777;
+    }
+
+    let mut ops = Vec::new();
+    for c in rest[who_len..].chars() {
+        match c {
+            '+' | '-' | '=' => ops.push((c, Perms::default())),
+            _ => {
+                let (_, perms) = ops.last_mut()?;
+                match c {
+                    'r' => perms.bits |= 0o444,
+                    'w' => perms.bits |= 0o222,
+                    'x' => perms.bits |= 0o111,
+                    'X' => perms.conditional_exec = true,
+                    's' => perms.bits |= 0o6000,
+                    't' => perms.bits |= 0o1000,
+                    _ => return None,
+                }
+            }
+        }
+    }
+
+    (!ops.is_empty()).then_some(Rule {
+        scope,
+        kind: RuleKind::Symbolic(who, ops),
+    })
+}
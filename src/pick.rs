@@ -0,0 +1,294 @@
+//! Interactive selection of what to copy, for `--pick`: the tree of the
+//! source is browsed on the terminal with the size of every entry, entries
+//! are checked or unchecked, and the copy then leaves out the unchecked
+//! ones as `CopyOptions::deselected`.
+
+use crate::ui::{format_size, BINARY};
+use console::{style, Key, Term};
+use std::io;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Lines of the screen that are not rows of the tree: the help above it
+/// and the selection below.
+const CHROME: usize = 3;
+
+/// An entry of the source tree.
+struct Node {
+    path: PathBuf,
+    name: String,
+    /// Bytes of the file, or of all the files under the directory.
+    bytes: u64,
+    is_dir: bool,
+    depth: usize,
+    children: Vec<usize>,
+    /// Whether it is copied. An unchecked directory has no checked entries.
+    checked: bool,
+    open: bool,
+}
+
+/// Whether a directory is copied whole, in part or not at all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Check {
+    All,
+    Part,
+    Nothing,
+}
+
+/// The tree of a source, all of it checked and its directories closed at
+/// first.
+pub(crate) struct Tree {
+    nodes: Vec<Node>,
+    /// The entries at the top of the source.
+    top: Vec<usize>,
+}
+
+impl Tree {
+    /// Reads the tree under `source`, which unreadable entries are left
+    /// out of.
+    pub(crate) fn scan(source: &Path) -> Self {
+        let mut tree = Self {
+            nodes: Vec::new(),
+            top: Vec::new(),
+        };
+        // The directories from the top down to the entry last read.
+        let mut parents: Vec<usize> = Vec::new();
+        for entry in WalkDir::new(source)
+            .min_depth(1)
+            .sort_by_file_name()
+            .into_iter()
+            .flatten()
+        {
+            parents.truncate(entry.depth() - 1);
+            let is_dir = entry.file_type().is_dir();
+            let bytes = match is_dir {
+                true => 0,
+                false => entry.metadata().map_or(0, |metadata| metadata.len()),
+            };
+            let index = tree.nodes.len();
+            tree.nodes.push(Node {
+                name: entry.file_name().to_string_lossy().into_owned(),
+                path: entry.into_path(),
+                bytes,
+                is_dir,
+                depth: parents.len(),
+                children: Vec::new(),
+                checked: true,
+                open: false,
+            });
+            match parents.last() {
+                Some(&parent) => tree.nodes[parent].children.push(index),
+                None => tree.top.push(index),
+            }
+            for &parent in &parents {
+                tree.nodes[parent].bytes += bytes;
+            }
+            if is_dir {
+                parents.push(index);
+            }
+        }
+        tree
+    }
+
+    /// The entries shown, in order: those at the top and those in open
+    /// directories.
+    pub(crate) fn rows(&self) -> Vec<usize> {
+        let mut rows = Vec::new();
+        let mut stack: Vec<usize> = self.top.iter().rev().copied().collect();
+        while let Some(index) = stack.pop() {
+            rows.push(index);
+            let node = &self.nodes[index];
+            if node.open {
+                stack.extend(node.children.iter().rev());
+            }
+        }
+        rows
+    }
+
+    pub(crate) fn check(&self, index: usize) -> Check {
+        let node = &self.nodes[index];
+        if !node.checked {
+            Check::Nothing
+        } else if node
+            .children
+            .iter()
+            .all(|&child| self.check(child) == Check::All)
+        {
+            Check::All
+        } else {
+            Check::Part
+        }
+    }
+
+    /// Unchecks the entry `index` if it is wholly checked, and checks it
+    /// whole otherwise, along with the directories it is in.
+    pub(crate) fn toggle(&mut self, index: usize) {
+        let checked = self.check(index) != Check::All;
+        let mut stack = vec![index];
+        while let Some(index) = stack.pop() {
+            self.nodes[index].checked = checked;
+            stack.extend(&self.nodes[index].children);
+        }
+        if checked {
+            for parent in self.parents(index) {
+                self.nodes[parent].checked = true;
+            }
+        }
+    }
+
+    /// The directories the entry `index` is in, from its own up.
+    fn parents(&self, index: usize) -> Vec<usize> {
+        let mut parents = Vec::new();
+        let mut depth = self.nodes[index].depth;
+        // Parents come before their entries, at one level less.
+        for candidate in (0..index).rev() {
+            if depth == 0 {
+                break;
+            }
+            if self.nodes[candidate].depth == depth - 1 {
+                parents.push(candidate);
+                depth -= 1;
+            }
+        }
+        parents
+    }
+
+    /// Opens or closes the directory `index`.
+    pub(crate) fn set_open(&mut self, index: usize, open: bool) {
+        if self.nodes[index].is_dir {
+            self.nodes[index].open = open;
+        }
+    }
+
+    /// The files checked and their bytes.
+    pub(crate) fn selected(&self) -> (u64, u64) {
+        self.nodes
+            .iter()
+            .filter(|node| node.checked && !node.is_dir)
+            .fold((0, 0), |(files, bytes), node| {
+                (files + 1, bytes + node.bytes)
+            })
+    }
+
+    /// The unchecked entries whose directory is checked, which are all the
+    /// copy needs to leave out.
+    pub(crate) fn deselected(&self) -> Vec<PathBuf> {
+        let mut deselected = Vec::new();
+        let mut stack = self.top.clone();
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            match node.checked {
+                true => stack.extend(&node.children),
+                false => deselected.push(node.path.clone()),
+            }
+        }
+        deselected.sort();
+        deselected
+    }
+
+    /// The row of the entry `index`, `width` characters at most.
+    fn line(&self, index: usize, width: usize) -> String {
+        let node = &self.nodes[index];
+        let mark = match self.check(index) {
+            Check::All => "[x]",
+            Check::Part => "[-]",
+            Check::Nothing => "[ ]",
+        };
+        let (name, fold) = match (node.is_dir, node.open) {
+            (true, true) => (format!("{}/", node.name), "▾ "),
+            (true, false) => (format!("{}/", node.name), "▸ "),
+            (false, _) => (node.name.clone(), "  "),
+        };
+        let size = format_size(node.bytes, BINARY);
+        let head = format!("{}{} {}{}", "  ".repeat(node.depth), mark, fold, name);
+        let room = width.saturating_sub(size.len() + 1);
+        let head = console::truncate_str(&head, room, "…");
+        let pad = room.saturating_sub(console::measure_text_width(&head));
+        format!("{}{} {}", head, " ".repeat(pad), size)
+    }
+}
+
+/// Lets the tree under `source` be browsed on the terminal and its entries
+/// checked or unchecked, and returns those unchecked, or `None` if the
+/// copy was called off.
+pub fn pick(source: &Path) -> io::Result<Option<Vec<PathBuf>>> {
+    let term = Term::stderr();
+    let mut tree = Tree::scan(source);
+    if tree.top.is_empty() {
+        return Ok(Some(Vec::new()));
+    }
+    term.hide_cursor()?;
+    let picked = browse(&term, source, &mut tree);
+    term.clear_screen()?;
+    term.show_cursor()?;
+    Ok(picked?.then(|| tree.deselected()))
+}
+
+/// Draws `tree` and follows the keys until the selection is copied, which
+/// returns true, or called off.
+fn browse(term: &Term, source: &Path, tree: &mut Tree) -> io::Result<bool> {
+    let mut cursor = 0;
+    let mut scroll = 0;
+    loop {
+        let rows = tree.rows();
+        cursor = cursor.min(rows.len() - 1);
+        let (height, width) = term.size();
+        let height = usize::from(height).saturating_sub(CHROME).max(1);
+        let width = usize::from(width);
+        scroll = scroll.clamp(cursor.saturating_sub(height - 1), cursor);
+
+        term.clear_screen()?;
+        term.write_line(&console::truncate_str(
+            &format!("Pick what to copy from {}", source.display()),
+            width,
+            "…",
+        ))?;
+        term.write_line(
+            &style(console::truncate_str(
+                "space: check or uncheck  →/←: open or close  enter: copy  q: quit",
+                width,
+                "…",
+            ))
+            .dim()
+            .to_string(),
+        )?;
+        for (row, &index) in rows.iter().enumerate().skip(scroll).take(height) {
+            let line = tree.line(index, width);
+            match row == cursor {
+                true => term.write_line(&style(line).reverse().to_string())?,
+                false => term.write_line(&line)?,
+            }
+        }
+        let (files, bytes) = tree.selected();
+        term.write_str(&format!(
+            "{} file{} ({}) selected",
+            files,
+            if files == 1 { "" } else { "s" },
+            format_size(bytes, BINARY)
+        ))?;
+
+        let index = rows[cursor];
+        match term.read_key()? {
+            Key::ArrowUp | Key::Char('k') => cursor = cursor.saturating_sub(1),
+            Key::ArrowDown | Key::Char('j') => cursor += 1,
+            Key::PageUp => cursor = cursor.saturating_sub(height),
+            Key::PageDown => cursor += height,
+            Key::Home => cursor = 0,
+            Key::End => cursor = rows.len() - 1,
+            Key::Char(' ') => tree.toggle(index),
+            Key::ArrowRight | Key::Char('l') => tree.set_open(index, true),
+            Key::ArrowLeft | Key::Char('h') => match tree.nodes[index].open {
+                true => tree.set_open(index, false),
+                // To the directory the entry is in.
+                false => {
+                    if let Some(&parent) = tree.parents(index).first() {
+                        cursor = rows.iter().position(|&row| row == parent).unwrap_or(0);
+                    }
+                }
+            },
+            Key::Enter => return Ok(true),
+            Key::Escape | Key::CtrlC | Key::Char('q') => return Ok(false),
+            _ => {}
+        }
+    }
+}
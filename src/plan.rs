@@ -0,0 +1,250 @@
+//! Working out what a copy will do before doing it, for dry runs, for
+//! reviewing conflicts and for exact totals up front.
+
+use crate::hooks::{CopyHooks, FileDecision};
+use crate::mirror;
+use crate::vfs::{FileInfo, Filesystem};
+use crate::{copy_with_progress, CopyError, CopyOptions, CopyStats, ProgressReporter};
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// What a `PlannedAction` does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum ActionKind {
+    CreateDir,
+    CopyFile,
+    /// A destination entry the source lacks, removed with
+    /// `CopyOptions::delete`, with what it holds if a directory.
+    Delete,
+}
+
+/// A directory to create, a file to copy or an entry to delete.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PlannedAction {
+    pub kind: ActionKind,
+    /// For `ActionKind::Delete`, where the entry would be in the source.
+    pub source: PathBuf,
+    pub dest: PathBuf,
+    /// Size of the file; 0 for directories.
+    pub bytes: u64,
+    /// Whether `dest` already holds a file that the copy replaces.
+    pub conflict: bool,
+}
+
+/// The actions of a copy, in the order of a walk of the source with the
+/// entries of each directory sorted by name.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CopyPlan {
+    pub source: PathBuf,
+    pub dest: PathBuf,
+    /// Removing the action of a file leaves the file out when the plan is
+    /// executed. Directories are created as the copy needs them, and
+    /// entries deleted as `CopyOptions::delete` finds them then. Files
+    /// that `CopyOptions::mirror` finds up to date have no action.
+    pub actions: Vec<PlannedAction>,
+    options: CopyOptions,
+}
+
+/// Plans copying `source` to `dest` with `options`, reading both through
+/// `options.source_fs` and `options.dest_fs` but changing nothing.
+pub fn plan(source: &Path, dest: &Path, options: &CopyOptions) -> Result<CopyPlan, CopyError> {
+    let (source_fs, dest_fs) = options.layered_filesystems();
+    let info = source_fs.metadata(source)?;
+    let into_dir = dest_fs.metadata(dest).is_ok_and(|dest| dest.is_dir);
+    let target = match source.file_name() {
+        Some(name) if into_dir => dest.join(options.target_name(name)),
+        _ => dest.to_path_buf(),
+    };
+    let mut actions = Vec::new();
+    if !info.is_dir {
+        if !(options.mirror && is_up_to_date(dest_fs.as_ref(), &target, &info, options)) {
+            actions.push(PlannedAction {
+                kind: ActionKind::CopyFile,
+                source: source.to_path_buf(),
+                conflict: is_file(dest_fs.as_ref(), &target),
+                dest: target,
+                bytes: info.len,
+            });
+        }
+    } else if !options.recursive {
+        return Err(CopyError::IsADirectory(source.to_path_buf()));
+    } else {
+        let planner = Planner {
+            source_fs: source_fs.as_ref(),
+            dest_fs: dest_fs.as_ref(),
+            options,
+        };
+        planner.dir(source, &target, &mut actions)?;
+        if options.delete {
+            let fs = (source_fs.as_ref(), dest_fs.as_ref());
+            for (path, info) in mirror::extraneous(fs, source, &target, options)? {
+                actions.push(PlannedAction {
+                    kind: ActionKind::Delete,
+                    source: source.join(path.strip_prefix(&target).unwrap_or(&path)),
+                    dest: path,
+                    bytes: info.len,
+                    conflict: false,
+                });
+            }
+        }
+    }
+    Ok(CopyPlan {
+        source: source.to_path_buf(),
+        dest: dest.to_path_buf(),
+        actions,
+        options: options.clone(),
+    })
+}
+
+fn is_file(fs: &dyn Filesystem, path: &Path) -> bool {
+    fs.metadata(path).is_ok_and(|info| !info.is_dir)
+}
+
+fn is_up_to_date(
+    fs: &dyn Filesystem,
+    path: &Path,
+    source: &FileInfo,
+    options: &CopyOptions,
+) -> bool {
+    fs.metadata(path)
+        .is_ok_and(|copy| mirror::up_to_date(source, &copy, options))
+}
+
+struct Planner<'a> {
+    source_fs: &'a dyn Filesystem,
+    dest_fs: &'a dyn Filesystem,
+    options: &'a CopyOptions,
+}
+
+impl Planner<'_> {
+    /// Adds the actions for the directory `source` copied to `target`.
+    fn dir(
+        &self,
+        source: &Path,
+        target: &Path,
+        actions: &mut Vec<PlannedAction>,
+    ) -> io::Result<()> {
+        if !self.dest_fs.metadata(target).is_ok_and(|info| info.is_dir) {
+            actions.push(PlannedAction {
+                kind: ActionKind::CreateDir,
+                source: source.to_path_buf(),
+                dest: target.to_path_buf(),
+                bytes: 0,
+                conflict: is_file(self.dest_fs, target),
+            });
+        }
+        let mut entries = self.source_fs.read_dir(source)?;
+        entries.sort();
+        for entry in entries {
+            let Some(name) = entry.file_name() else {
+                continue;
+            };
+            let entry_target = target.join(self.options.target_name(name));
+            let info = self.source_fs.metadata(&entry)?;
+            if info.is_dir {
+                self.dir(&entry, &entry_target, actions)?;
+            } else if self.options.mirror
+                && is_up_to_date(self.dest_fs, &entry_target, &info, self.options)
+            {
+                continue;
+            } else {
+                actions.push(PlannedAction {
+                    kind: ActionKind::CopyFile,
+                    source: entry,
+                    conflict: is_file(self.dest_fs, &entry_target),
+                    dest: entry_target,
+                    bytes: info.len,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl CopyPlan {
+    fn files(&self) -> impl Iterator<Item = &PlannedAction> {
+        self.actions
+            .iter()
+            .filter(|action| action.kind == ActionKind::CopyFile)
+    }
+
+    /// Number of files to copy.
+    pub fn file_count(&self) -> usize {
+        self.files().count()
+    }
+
+    /// Bytes to copy.
+    pub fn total_bytes(&self) -> u64 {
+        self.files().map(|action| action.bytes).sum()
+    }
+
+    /// The actions that replace an existing file.
+    pub fn conflicts(&self) -> impl Iterator<Item = &PlannedAction> {
+        self.actions.iter().filter(|action| action.conflict)
+    }
+
+    /// Copies the files left in the plan with the options it was made
+    /// with, reporting to `reporter` if given instead of
+    /// `CopyOptions::reporter`. Files whose action was removed count as
+    /// `CopyStats::files_declined`.
+    ///
+    /// The source is walked again, so files added since planning are left
+    /// out and files that disappeared fail.
+    pub fn execute(
+        &self,
+        reporter: Option<Arc<dyn ProgressReporter>>,
+    ) -> Result<CopyStats, CopyError> {
+        let mut options = self.options.clone();
+        if reporter.is_some() {
+            options.reporter = reporter;
+        }
+        options.hooks = Some(Arc::new(Planned {
+            files: self.files().map(|action| action.source.clone()).collect(),
+            hooks: options.hooks.take(),
+        }));
+        copy_with_progress(&self.source, &self.dest, &options)
+    }
+}
+
+/// Hooks that leave out the files not in a plan and hand the rest to the
+/// hooks set in the options.
+#[derive(Debug)]
+struct Planned {
+    files: HashSet<PathBuf>,
+    hooks: Option<Arc<dyn CopyHooks>>,
+}
+
+impl CopyHooks for Planned {
+    fn on_file_start(&self, source: &Path, dest: &Path) -> FileDecision {
+        match &self.hooks {
+            _ if !self.files.contains(source) => FileDecision::Skip,
+            Some(hooks) => hooks.on_file_start(source, dest),
+            None => FileDecision::Copy,
+        }
+    }
+
+    fn on_conflict(&self, source: &Path, dest: &Path) -> FileDecision {
+        self.hooks
+            .as_ref()
+            .map_or(FileDecision::Copy, |hooks| hooks.on_conflict(source, dest))
+    }
+
+    fn on_file_complete(&self, source: &Path, dest: &Path, bytes: u64, duration: Duration) {
+        if let Some(hooks) = &self.hooks {
+            hooks.on_file_complete(source, dest, bytes, duration);
+        }
+    }
+
+    fn on_error(&self, source: &Path, dest: &Path, err: &io::Error) {
+        if let Some(hooks) = &self.hooks {
+            hooks.on_error(source, dest, err);
+        }
+    }
+}
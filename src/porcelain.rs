@@ -0,0 +1,102 @@
+//! The per-file output of `--porcelain`, a stable format for scripts, as
+//! git separates its porcelain output from the human one.
+//!
+//! The output starts with the line `# cpv porcelain 1`, where the number is
+//! `PORCELAIN_VERSION`, followed by one tab-separated record per line:
+//!
+//! ```text
+//! file  STATUS  BYTES  SOURCE  DEST  [ERROR]
+//! done  FILES_COPIED  BYTES_COPIED  FILES_FAILED
+//! error  MESSAGE
+//! ```
+//!
+//! A `file` record is written as each file is done, with a status of
+//! `copied`, `linked`, `skipped` or `failed` and, for a failed file, the
+//! error. The last record is `done` for a copy that finished, or `error`
+//! for one that stopped. In paths and messages, backslashes, tabs and
+//! newlines are written as `\\`, `\t` and `\n`.
+//!
+//! Records and fields may be added within a version, the latter at the end
+//! of a record; any other change increments it.
+
+use crate::report::FileStatus;
+use crate::{CopyError, CopyStats};
+use std::fmt;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Version of the porcelain format written by this release.
+pub const PORCELAIN_VERSION: u32 = 1;
+
+/// A stream of porcelain records.
+pub struct Porcelain {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl fmt::Debug for Porcelain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Porcelain").finish_non_exhaustive()
+    }
+}
+
+impl Porcelain {
+    /// Writes the header to `out`, then records as they happen. A reader
+    /// that went away does not stop the copy, so write errors are ignored.
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        let porcelain = Self {
+            out: Mutex::new(Box::new(out)),
+        };
+        porcelain.write(&format!("# cpv porcelain {}", PORCELAIN_VERSION));
+        porcelain
+    }
+
+    fn write(&self, line: &str) {
+        let mut out = self.out.lock().unwrap();
+        let _ = writeln!(out, "{}", line);
+        let _ = out.flush();
+    }
+
+    pub(crate) fn file(
+        &self,
+        status: FileStatus,
+        bytes: u64,
+        source: &Path,
+        dest: &Path,
+        error: Option<&dyn fmt::Display>,
+    ) {
+        let mut line = format!(
+            "file\t{}\t{}\t{}\t{}",
+            status,
+            bytes,
+            escape(&source.to_string_lossy()),
+            escape(&dest.to_string_lossy())
+        );
+        if let Some(error) = error {
+            line.push('\t');
+            line.push_str(&escape(&error.to_string()));
+        }
+        self.write(&line);
+    }
+
+    /// Writes the last record, for the outcome of the copy.
+    pub(crate) fn finished(&self, result: &Result<CopyStats, CopyError>) {
+        match result {
+            Ok(stats) => self.write(&format!(
+                "done\t{}\t{}\t{}",
+                stats.files_copied,
+                stats.bytes_copied,
+                stats.files_failed()
+            )),
+            Err(err) => self.write(&format!("error\t{}", escape(&err.to_string()))),
+        }
+    }
+}
+
+/// `field` with the characters that would break a record escaped.
+fn escape(field: &str) -> String {
+    field
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,100 @@
+//! Reserving destination space before data is written.
+//!
+//! Allocating the whole file up front lets the filesystem lay it out
+//! contiguously and turns a full disk into an immediate error instead of a
+//! failure partway through a long copy. Filesystems that cannot preallocate
+//! are silently skipped.
+
+use std::fs::File;
+use std::io;
+
+/// Reserves `len` bytes for `file` without changing its visible length, so
+/// an interrupted copy never looks complete.
+#[cfg(target_os = "linux")]
+pub(crate) fn preallocate(file: &File, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    if len == 0 {
+        return Ok(());
+    }
+    // SAFETY: fallocate only operates on the descriptor, which stays open.
+    let rc = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_KEEP_SIZE,
+            0,
+            len as libc::off_t,
+        )
+    };
+    if rc == 0 {
+        return Ok(());
+    }
+    match io::Error::last_os_error() {
+        err if matches!(err.raw_os_error(), Some(libc::EOPNOTSUPP | libc::ENOSYS)) => Ok(()),
+        err => Err(err),
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub(crate) fn preallocate(file: &File, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    if len == 0 {
+        return Ok(());
+    }
+    // Ask for one contiguous extent first, then settle for any layout.
+    for flags in [
+        libc::F_ALLOCATECONTIG | libc::F_ALLOCATEALL,
+        libc::F_ALLOCATEALL,
+    ] {
+        let mut store = libc::fstore_t {
+            fst_flags: flags,
+            fst_posmode: libc::F_PEOFPOSMODE,
+            fst_offset: 0,
+            fst_length: len as libc::off_t,
+            fst_bytesalloc: 0,
+        };
+        // SAFETY: `store` is a valid fstore_t for the duration of the call.
+        if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_PREALLOCATE, &mut store) } == 0 {
+            return Ok(());
+        }
+    }
+    match io::Error::last_os_error() {
+        err if err.raw_os_error() == Some(libc::ENOTSUP) => Ok(()),
+        err => Err(err),
+    }
+}
+
+#[cfg(windows)]
+pub(crate) fn preallocate(file: &File, len: u64) -> io::Result<()> {
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::Storage::FileSystem::{
+        FileAllocationInfo, SetFileInformationByHandle, FILE_ALLOCATION_INFO,
+    };
+
+    if len == 0 {
+        return Ok(());
+    }
+    let info = FILE_ALLOCATION_INFO {
+        AllocationSize: len as i64,
+    };
+    // SAFETY: the handle stays open and `info` is the structure the
+    // information class expects.
+    let ok = unsafe {
+        SetFileInformationByHandle(
+            file.as_raw_handle(),
+            FileAllocationInfo,
+            (&info as *const FILE_ALLOCATION_INFO).cast(),
+            std::mem::size_of::<FILE_ALLOCATION_INFO>() as u32,
+        )
+    };
+    if ok == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+pub(crate) fn preallocate(_file: &File, _len: u64) -> io::Result<()> {
+    Ok(())
+}
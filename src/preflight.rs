@@ -0,0 +1,311 @@
+//! Checking that a copy can be done before starting it, for `--preflight`:
+//! every source entry is opened or listed, the destination directories
+//! that will be written to are checked for write access, and so, when
+//! attributes are preserved, is whether owners and extended attributes can
+//! be kept. All the problems are found at once, rather than one at a time
+//! hours into the copy.
+
+use crate::{exclude, fat, tree_target, CopyOptions};
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// What a `Problem` is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum ProblemKind {
+    /// A source file that cannot be opened, or a directory that cannot be
+    /// listed.
+    Unreadable,
+    /// A destination directory or file the copy cannot write.
+    NotWritable,
+    /// Owners that cannot be kept or set without root.
+    Ownership,
+    /// Extended attributes the destination cannot hold.
+    Xattrs,
+}
+
+impl fmt::Display for ProblemKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Unreadable => "unreadable",
+            Self::NotWritable => "not writable",
+            Self::Ownership => "ownership",
+            Self::Xattrs => "xattrs",
+        })
+    }
+}
+
+/// Something `preflight` found that would make the copy fail, or fall
+/// short of what it was asked for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Problem {
+    pub kind: ProblemKind,
+    pub path: PathBuf,
+    pub error: String,
+}
+
+impl Problem {
+    fn new(kind: ProblemKind, path: &Path, error: impl fmt::Display) -> Self {
+        Self {
+            kind,
+            path: path.to_path_buf(),
+            error: error.to_string(),
+        }
+    }
+}
+
+/// Checks, without changing anything but a probe file created and removed
+/// in the destination, that copying the local `source` to `dest` with
+/// `options` can read and write everything it needs to, and returns the
+/// problems found, none if it can.
+pub fn preflight(source: &Path, dest: &Path, options: &CopyOptions) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let target = tree_target(source, dest, options);
+    // FAT keeps neither owners nor extended attributes.
+    let preserved = options.preserve_attrs && !options.fat_compat && !fat::is_fat(&target);
+    // Entries owned by someone else, whose owner the copy cannot keep.
+    let mut foreign = 0;
+    // File capabilities of a source, which the destination must take.
+    let mut capabilities = None;
+    let walk = WalkDir::new(source)
+        .max_depth(if options.recursive { usize::MAX } else { 0 })
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| {
+            entry.depth() == 0
+                || !(exclude::excluded(&options.exclude, entry.file_name())
+                    || options.deselected.iter().any(|path| path == entry.path()))
+        });
+    for entry in walk {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                let path = err.path().unwrap_or(source);
+                let error = match err.io_error() {
+                    Some(io) => io.to_string(),
+                    None => err.to_string(),
+                };
+                problems.push(Problem::new(ProblemKind::Unreadable, path, error));
+                continue;
+            }
+        };
+        let file_type = entry.file_type();
+        // Opening anything else, such as a FIFO, may block.
+        if file_type.is_file() {
+            match File::open(entry.path()) {
+                Ok(file) if preserved && capabilities.is_none() => {
+                    capabilities = sys::capabilities(&file);
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    problems.push(Problem::new(ProblemKind::Unreadable, entry.path(), err));
+                }
+            }
+        }
+        if preserved && !file_type.is_symlink() && !owned(&entry, options) {
+            foreign += 1;
+        }
+        if !(file_type.is_file() || file_type.is_dir()) {
+            continue;
+        }
+        let relative = entry.path().strip_prefix(source).unwrap_or(entry.path());
+        let existing = target.join(options.target_path(relative));
+        if existing.exists() {
+            if let Err(err) = writable(&existing) {
+                problems.push(Problem::new(ProblemKind::NotWritable, &existing, err));
+            }
+        }
+    }
+    if foreign > 0 {
+        problems.push(Problem::new(
+            ProblemKind::Ownership,
+            source,
+            format!(
+                "{} entries are owned by other users, whose owners only root can keep",
+                foreign
+            ),
+        ));
+    }
+    #[cfg(unix)]
+    if let Some(uid) = options.chown.as_ref().and_then(|chown| chown.uid) {
+        // SAFETY: geteuid has no preconditions.
+        let euid = unsafe { libc::geteuid() };
+        if euid != 0 && uid != euid {
+            problems.push(Problem::new(
+                ProblemKind::Ownership,
+                dest,
+                format!("only root can give files to user {} with --chown", uid),
+            ));
+        }
+    }
+    let mut found = Vec::new();
+    check_dest(&target, preserved, capabilities.as_deref(), &mut found);
+    found.extend(problems);
+    found
+}
+
+/// Checks that the directory `target` is to be created in, or replaced in,
+/// can be written to, and that it can hold what is preserved: extended
+/// attributes, and on Linux the file `capabilities` of a source.
+fn check_dest(
+    target: &Path,
+    preserved: bool,
+    capabilities: Option<&[u8]>,
+    problems: &mut Vec<Problem>,
+) {
+    // The nearest directory that exists, which the copy creates the rest in.
+    let Some(dir) = target
+        .ancestors()
+        .skip(usize::from(!target.is_dir()))
+        .map(|dir| match dir.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => dir,
+        })
+        .find(|dir| dir.exists())
+    else {
+        return;
+    };
+    if !dir.is_dir() {
+        problems.push(Problem::new(
+            ProblemKind::NotWritable,
+            dir,
+            "not a directory",
+        ));
+        return;
+    }
+    let probe = dir.join(format!(".cpv-preflight-{}", std::process::id()));
+    let file = match File::options().write(true).create_new(true).open(&probe) {
+        Ok(file) => file,
+        Err(err) => {
+            problems.push(Problem::new(ProblemKind::NotWritable, dir, err));
+            return;
+        }
+    };
+    if preserved {
+        if let Err(err) = sys::set_xattr(&file, capabilities) {
+            problems.push(Problem::new(ProblemKind::Xattrs, dir, err));
+        }
+    }
+    drop(file);
+    let _ = fs::remove_file(&probe);
+}
+
+/// Whether the copy can keep the owner of `entry`: it is root, or the
+/// owner, as `usermap` has it, is itself.
+#[cfg(unix)]
+fn owned(entry: &walkdir::DirEntry, options: &CopyOptions) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    // SAFETY: geteuid has no preconditions.
+    let euid = unsafe { libc::geteuid() };
+    euid == 0
+        || entry
+            .metadata()
+            .is_ok_and(|metadata| options.usermap.map(metadata.uid()) == euid)
+}
+
+#[cfg(not(unix))]
+fn owned(_entry: &walkdir::DirEntry, _options: &CopyOptions) -> bool {
+    true
+}
+
+/// Whether the existing destination `path` can be written, or in the case
+/// of a directory, have entries created in it.
+#[cfg(unix)]
+fn writable(path: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: `path` is NUL-terminated.
+    match unsafe { libc::access(path.as_ptr(), libc::W_OK) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(unix))]
+fn writable(path: &Path) -> io::Result<()> {
+    let metadata = fs::metadata(path)?;
+    match metadata.is_file() && metadata.permissions().readonly() {
+        true => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "the file is read-only",
+        )),
+        false => Ok(()),
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use crate::preserve;
+    use std::fs::File;
+    use std::io;
+
+    /// The file capabilities of the source `file`, if it has any.
+    pub(super) fn capabilities(file: &File) -> Option<Vec<u8>> {
+        preserve::read_capabilities(file).ok().flatten()
+    }
+
+    /// Sets the file `capabilities` of a source on `file`, to find whether
+    /// the copies can take them: only those are preserved on Linux.
+    pub(super) fn set_xattr(file: &File, capabilities: Option<&[u8]>) -> io::Result<()> {
+        match capabilities {
+            Some(capabilities) => preserve::write_capabilities(file, capabilities),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod sys {
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+
+    const NAME: &[u8] = b"user.cpv.preflight\0";
+
+    pub(super) fn capabilities(_file: &File) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Sets an extended attribute on `file`, to find whether its
+    /// filesystem holds them.
+    pub(super) fn set_xattr(file: &File, _capabilities: Option<&[u8]>) -> io::Result<()> {
+        // SAFETY: the name is NUL-terminated, the value length is accurate
+        // and the position is 0, as for anything but resource forks.
+        let rc = unsafe {
+            libc::fsetxattr(
+                file.as_raw_fd(),
+                NAME.as_ptr().cast(),
+                b"1".as_ptr().cast(),
+                1,
+                0,
+                0,
+            )
+        };
+        match rc {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+mod sys {
+    use std::fs::File;
+    use std::io;
+
+    pub(super) fn capabilities(_file: &File) -> Option<Vec<u8>> {
+        None
+    }
+
+    pub(super) fn set_xattr(_file: &File, _capabilities: Option<&[u8]>) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,242 @@
+//! Attribute preservation for `--preserve`.
+
+use crate::fat;
+use crate::owner::Chown;
+use crate::perms::ChmodRules;
+use crate::CopyOptions;
+use std::fs::{File, FileTimes, Metadata};
+use std::io;
+
+/// Copies permissions, ownership and timestamps from the source `metadata`
+/// onto the freshly written destination `file`.
+///
+/// Access and modification times are preserved everywhere. The creation
+/// (birth) time is carried over as well on Windows and macOS, where backup
+/// verification tools compare it. FAT, with `fat_compat`, only gets the
+/// times, rounded up to what it keeps.
+pub(crate) fn preserve_attributes(
+    metadata: &Metadata,
+    file: &File,
+    options: &CopyOptions,
+) -> io::Result<()> {
+    // Times first: a read-only mode would otherwise get in the way on Windows.
+    file.set_times(file_times(metadata, options)?)?;
+    if options.fat_compat {
+        return Ok(());
+    }
+    // Ownership before the mode, since chown clears set-ID bits.
+    #[cfg(unix)]
+    preserve_owner(metadata, file, options)?;
+    #[cfg(not(unix))]
+    let _ = options;
+    file.set_permissions(metadata.permissions())
+}
+
+/// Preserves owner and group, translated through `--usermap`/`--groupmap`.
+///
+/// Like `cp -p`, an unprivileged caller that cannot give files away keeps
+/// whatever it is allowed to (usually the group), unless an explicit mapping
+/// was requested.
+#[cfg(unix)]
+fn preserve_owner(metadata: &Metadata, file: &File, options: &CopyOptions) -> io::Result<()> {
+    use std::os::unix::fs::{fchown, MetadataExt};
+
+    let uid = options.usermap.map(metadata.uid());
+    let gid = options.groupmap.map(metadata.gid());
+    match fchown(file, Some(uid), Some(gid)) {
+        Err(err)
+            if err.kind() == io::ErrorKind::PermissionDenied
+                && options.usermap.is_empty()
+                && options.groupmap.is_empty() =>
+        {
+            let _ = fchown(file, None, Some(gid));
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+fn file_times(metadata: &Metadata, options: &CopyOptions) -> io::Result<FileTimes> {
+    let modified = match options.fat_compat {
+        true => fat::round_time(metadata.modified()?),
+        false => metadata.modified()?,
+    };
+    let times = FileTimes::new()
+        .set_accessed(metadata.accessed()?)
+        .set_modified(modified);
+
+    #[cfg(windows)]
+    let times = {
+        use std::os::windows::fs::FileTimesExt;
+        times.set_created(metadata.created()?)
+    };
+
+    #[cfg(target_os = "macos")]
+    let times = {
+        use std::os::macos::fs::FileTimesExt;
+        times.set_created(metadata.created()?)
+    };
+
+    Ok(times)
+}
+
+/// Applies `--chown` and `--chmod` overrides to a written file or directory,
+/// through its open handle.
+///
+/// Ownership is changed first because doing so clears set-ID bits that the
+/// mode rules may want to set.
+#[cfg(unix)]
+pub(crate) fn apply_overrides(
+    file: &File,
+    is_dir: bool,
+    chown: Option<&Chown>,
+    chmod: Option<&ChmodRules>,
+) -> io::Result<()> {
+    use std::fs::Permissions;
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Some(chown) = chown {
+        std::os::unix::fs::fchown(file, chown.uid, chown.gid)?;
+    }
+    if let Some(chmod) = chmod {
+        let mode = file.metadata()?.permissions().mode();
+        file.set_permissions(Permissions::from_mode(chmod.apply(mode, is_dir)))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn apply_overrides(
+    _file: &File,
+    _is_dir: bool,
+    chown: Option<&Chown>,
+    chmod: Option<&ChmodRules>,
+) -> io::Result<()> {
+    if chown.is_some() || chmod.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "--chmod and --chown are only supported on Unix",
+        ));
+    }
+    Ok(())
+}
+
+/// `chattr` flags carried over by `--preserve-flags`: sync, immutable,
+/// append-only, no-dump, no-atime and dirsync. Filesystem-specific flags
+/// (compression, no-COW, ...) are left to the destination's defaults.
+#[cfg(target_os = "linux")]
+const PRESERVED_FLAGS: libc::c_int = 0x8 | 0x10 | 0x20 | 0x40 | 0x80 | 0x10000;
+
+/// Reapplies the source's supported inode flags to `dest`.
+///
+/// This has to run after everything else has been written, since an
+/// immutable or append-only file rejects further changes. Sources on
+/// filesystems without flag support simply have nothing to preserve.
+#[cfg(target_os = "linux")]
+pub(crate) fn preserve_flags(source: &File, dest: &File) -> io::Result<()> {
+    let flags = match get_flags(source) {
+        Ok(flags) => flags & PRESERVED_FLAGS,
+        Err(_) => return Ok(()),
+    };
+    if flags == 0 {
+        return Ok(());
+    }
+    set_flags(dest, get_flags(dest)? | flags)
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn get_flags(file: &File) -> io::Result<libc::c_int> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut flags: libc::c_int = 0;
+    // SAFETY: FS_IOC_GETFLAGS writes a single int through the pointer.
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(flags)
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn set_flags(file: &File, flags: libc::c_int) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: FS_IOC_SETFLAGS reads a single int through the pointer.
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_SETFLAGS, &flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Name of the xattr holding file capabilities (`setcap`).
+#[cfg(target_os = "linux")]
+const CAPABILITY_XATTR: &[u8] = b"security.capability\0";
+
+/// Reads the file capabilities of `file`, if it has any.
+#[cfg(target_os = "linux")]
+pub(crate) fn read_capabilities(file: &File) -> io::Result<Option<Vec<u8>>> {
+    use std::os::unix::io::AsRawFd;
+
+    // vfs_cap_data is at most 24 bytes.
+    let mut buf = vec![0u8; 64];
+    // SAFETY: the name is NUL-terminated and the buffer length is accurate.
+    let n = unsafe {
+        libc::fgetxattr(
+            file.as_raw_fd(),
+            CAPABILITY_XATTR.as_ptr().cast(),
+            buf.as_mut_ptr().cast(),
+            buf.len(),
+        )
+    };
+    if n < 0 {
+        let err = io::Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(libc::ENODATA) | Some(libc::EOPNOTSUPP) => Ok(None),
+            _ => Err(err),
+        };
+    }
+    buf.truncate(n as usize);
+    Ok(Some(buf))
+}
+
+/// Sets file capabilities on `file`. This needs `CAP_SETFCAP` and must run
+/// after any ownership change or write, both of which clear capabilities.
+#[cfg(target_os = "linux")]
+pub(crate) fn write_capabilities(file: &File, caps: &[u8]) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: the name is NUL-terminated and the value length is accurate.
+    let rc = unsafe {
+        libc::fsetxattr(
+            file.as_raw_fd(),
+            CAPABILITY_XATTR.as_ptr().cast(),
+            caps.as_ptr().cast(),
+            caps.len(),
+            0,
+        )
+    };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Copies ACLs and extended attributes with `fcopyfile(3)`.
+#[cfg(target_os = "macos")]
+pub(crate) fn preserve_extended(source: &File, dest: &File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    use std::ptr;
+
+    // SAFETY: both descriptors are open and a null state is allowed.
+    let rc = unsafe {
+        libc::fcopyfile(
+            source.as_raw_fd(),
+            dest.as_raw_fd(),
+            ptr::null_mut(),
+            libc::COPYFILE_ACL | libc::COPYFILE_XATTR,
+        )
+    };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
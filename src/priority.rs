@@ -0,0 +1,103 @@
+//! Lowering the priority of the whole process for `--low-priority`.
+
+use std::io;
+
+/// Niceness applied to the process; the lowest CPU priority.
+#[cfg(unix)]
+const NICENESS: libc::c_int = 19;
+
+/// Lowers the CPU and I/O priority of the current process so a long copy
+/// yields to interactive work. Threads started afterwards inherit it, so
+/// call this before copying.
+///
+/// On Linux the I/O scheduling class becomes idle, on macOS the process is
+/// moved to the background band and on Windows to background processing
+/// mode.
+pub fn lower_priority() -> io::Result<()> {
+    sys::lower_priority()
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use super::NICENESS;
+    use std::io;
+
+    const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_long = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_long = 13;
+
+    pub(super) fn lower_priority() -> io::Result<()> {
+        // SAFETY: plain syscalls on the calling process with no pointers.
+        unsafe {
+            if libc::setpriority(libc::PRIO_PROCESS, 0, NICENESS) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let ioprio = IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT;
+            if libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod sys {
+    use super::NICENESS;
+    use std::io;
+
+    pub(super) fn lower_priority() -> io::Result<()> {
+        // SAFETY: plain calls on the calling process with no pointers.
+        unsafe {
+            if libc::setpriority(libc::PRIO_PROCESS, 0, NICENESS) != 0
+                || libc::setpriority(libc::PRIO_DARWIN_PROCESS, 0, libc::PRIO_DARWIN_BG) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "macos"))))]
+mod sys {
+    use super::NICENESS;
+    use std::io;
+
+    pub(super) fn lower_priority() -> io::Result<()> {
+        // SAFETY: a plain call on the calling process with no pointers.
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, NICENESS) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use std::io;
+    use windows_sys::Win32::System::Threading::{
+        GetCurrentProcess, SetPriorityClass, PROCESS_MODE_BACKGROUND_BEGIN,
+    };
+
+    pub(super) fn lower_priority() -> io::Result<()> {
+        // SAFETY: the pseudo-handle of the current process is always valid.
+        if unsafe { SetPriorityClass(GetCurrentProcess(), PROCESS_MODE_BACKGROUND_BEGIN) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// A sandboxed process has no priority of its own to lower.
+#[cfg(target_os = "wasi")]
+mod sys {
+    use std::io;
+
+    pub(super) fn lower_priority() -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "lowering the priority is not supported on WASI",
+        ))
+    }
+}
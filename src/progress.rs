@@ -0,0 +1,1312 @@
+//! Per-file progress accounting shared by the copy loops.
+
+use crate::buffer::{IoBuffer, Memory};
+use crate::cache::CacheDropper;
+use crate::cancel::CancellationToken;
+use crate::checksum::{ChecksumAlgo, Digest, Hasher};
+use crate::limit::RateLimiter;
+use crate::schedule::TimeWindow;
+use crate::space;
+use crate::ui::{format_size, BINARY};
+use crate::ui::{
+    HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressState,
+    ProgressStyle,
+};
+use crate::{CopyError, CopyOptions};
+use chrono::{DateTime, Datelike, Local};
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, IsTerminal};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The template of the copy bar.
+pub const DEFAULT_PROGRESS_TEMPLATE: &str =
+    "[{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta}, done ~{finish}) {wide_msg}";
+
+/// The characters of the done, current and remaining parts of every bar
+/// in the classic theme.
+pub const DEFAULT_PROGRESS_CHARS: &str = "#>-";
+
+/// The most directories at the top of a tree that get a bar each; a tree
+/// with more is shown as a whole only.
+const MAX_GROUPS: usize = 8;
+
+/// The bar characters of `options`, which indicatif needs at least two of.
+pub(crate) fn bar_chars(options: &CopyOptions) -> Result<&str, CopyError> {
+    let chars = options
+        .progress_chars
+        .as_deref()
+        .unwrap_or(options.theme.chars());
+    if chars.chars().count() < 2 {
+        return Err(CopyError::Other(anyhow::anyhow!(
+            "invalid progress characters '{}' (expected at least two)",
+            chars
+        )));
+    }
+    Ok(chars)
+}
+
+/// How often the bytes copied reach the bars and the reporter. Copy
+/// loops report every buffer, which on fast disks is far more often than
+/// anything can draw, so updates are held back until both limits are met.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct ProgressThrottle {
+    /// Least time between updates; 50 ms by default.
+    pub interval: Duration,
+    /// Least bytes per update; none by default.
+    pub min_bytes: u64,
+}
+
+impl Default for ProgressThrottle {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(50),
+            min_bytes: 0,
+        }
+    }
+}
+
+impl ProgressThrottle {
+    /// Updates on every report, as the copy loops make them.
+    pub const NONE: Self = Self {
+        interval: Duration::ZERO,
+        min_bytes: 0,
+    };
+}
+
+/// How the progress of a copy is displayed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ProgressMode {
+    /// `Bars` when stderr is a terminal, `Lines` otherwise.
+    #[default]
+    Auto,
+    /// Progress bars on stderr.
+    Bars,
+    /// A plain status line on stderr every few seconds, for logs.
+    Lines,
+    /// Nothing, for scripts and cron jobs.
+    Hidden,
+}
+
+impl ProgressMode {
+    /// The mode `Auto` stands for here.
+    fn resolve(self) -> Self {
+        match self {
+            Self::Auto if io::stderr().is_terminal() => Self::Bars,
+            Self::Auto => Self::Lines,
+            mode => mode,
+        }
+    }
+
+    pub(crate) fn draw_target(self) -> ProgressDrawTarget {
+        match self.resolve() {
+            Self::Bars => ProgressDrawTarget::stderr(),
+            _ => ProgressDrawTarget::hidden(),
+        }
+    }
+
+    /// Starts printing status lines for `pb`, whose throughput `rate`
+    /// tracks, if this mode calls for them.
+    pub(crate) fn status_lines(self, pb: &ProgressBar, rate: &Arc<Rate>) -> Option<Ticker> {
+        let rate = Arc::clone(rate);
+        (self.resolve() == Self::Lines).then(|| {
+            Ticker::start(pb, LINE_INTERVAL, move |pb| {
+                eprintln!("{}", status_line(pb, &rate))
+            })
+        })
+    }
+}
+
+impl FromStr for ProgressMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "bars" => Ok(Self::Bars),
+            "lines" => Ok(Self::Lines),
+            "none" => Ok(Self::Hidden),
+            _ => Err(format!(
+                "invalid progress mode '{}' (expected auto, bars, lines or none)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ProgressMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Auto => "auto",
+            Self::Bars => "bars",
+            Self::Lines => "lines",
+            Self::Hidden => "none",
+        })
+    }
+}
+
+/// The look of the progress bars.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Theme {
+    /// Colored bars drawn with `#>-` between brackets.
+    #[default]
+    Classic,
+    /// Colored bars of solid blocks.
+    Blocks,
+    /// Bars drawn with `=> ` and no colors, for terminals that render
+    /// neither well.
+    Plain,
+}
+
+impl Theme {
+    /// The names of the themes, as `from_str` takes them and `Display`
+    /// writes them.
+    pub const NAMES: &[&str] = &["classic", "blocks", "plain"];
+
+    fn chars(self) -> &'static str {
+        match self {
+            Self::Classic => DEFAULT_PROGRESS_CHARS,
+            Self::Blocks => "█▓░",
+            Self::Plain => "=> ",
+        }
+    }
+
+    /// A built-in bar template, written for the classic theme with a
+    /// `[{bar:...}]` placeholder, adapted to this theme.
+    pub(crate) fn template(self, classic: &str) -> String {
+        let Some(start) = classic.find("[{bar:") else {
+            return classic.to_string();
+        };
+        let Some(end) = classic[start..].find("}]").map(|end| start + end + 2) else {
+            return classic.to_string();
+        };
+        let spec = &classic[start + "[{bar:".len()..end - "}]".len()];
+        let bar = match self {
+            Self::Classic => return classic.to_string(),
+            Self::Blocks => format!("{{bar:{}}}", spec),
+            Self::Plain => format!("[{{bar:{}}}]", spec.split('.').next().unwrap_or(spec)),
+        };
+        format!("{}{}{}", &classic[..start], bar, &classic[end..])
+    }
+}
+
+impl FromStr for Theme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "classic" => Ok(Self::Classic),
+            "blocks" => Ok(Self::Blocks),
+            "plain" => Ok(Self::Plain),
+            _ => Err(format!(
+                "invalid theme '{}' (expected classic, blocks or plain)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Theme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Classic => "classic",
+            Self::Blocks => "blocks",
+            Self::Plain => "plain",
+        })
+    }
+}
+
+/// Interval between status lines in `ProgressMode::Lines`.
+const LINE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Calls a function with a bar at a fixed interval on its own thread
+/// until dropped.
+pub(crate) struct Ticker {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Ticker {
+    pub(crate) fn start(
+        pb: &ProgressBar,
+        interval: Duration,
+        tick: impl Fn(&ProgressBar) + Send + 'static,
+    ) -> Self {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let pb = pb.clone();
+        // Where no thread can be started, as on WASI, nothing ticks.
+        let thread = thread::Builder::new()
+            .spawn(move || {
+                while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    tick(&pb);
+                }
+            })
+            .ok();
+        Self {
+            stop: Some(stop),
+            thread,
+        }
+    }
+}
+
+impl Drop for Ticker {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Interval between samples of the position of a bar by `Rate`.
+const RATE_SAMPLE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Length of the intervals of the throughput timeline.
+pub(crate) const TIMELINE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time constant of the moving average of `Rate`: samples older than this
+/// weigh less than 37% of their original weight.
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Throughput of a bar as an exponentially weighted moving average, which
+/// the rate and ETA shown are based on. Unlike indicatif's own estimate it
+/// barely moves when a run of tiny files alternates with huge ones.
+pub(crate) struct Rate {
+    state: Mutex<RateState>,
+    /// The daily window the copy is paused in, which the ETA skips.
+    pauses: Option<TimeWindow>,
+}
+
+#[derive(Default)]
+struct RateState {
+    /// When the last sample was taken and the position then.
+    last: Option<(Instant, u64)>,
+    /// The average so far, in bytes per second.
+    per_sec: Option<f64>,
+    /// When the current second of the timeline started and the position
+    /// then, and the throughput of each second before it.
+    second: Option<(Instant, u64)>,
+    timeline: Vec<u64>,
+    /// Set while the copy waits, which the average leaves out.
+    held: bool,
+}
+
+impl Rate {
+    pub(crate) fn new() -> Arc<Self> {
+        Self::with_pauses(None)
+    }
+
+    /// The rate of a copy paused during `pauses` every day.
+    pub(crate) fn with_pauses(pauses: Option<TimeWindow>) -> Arc<Self> {
+        Arc::new(Self {
+            state: Mutex::new(RateState::default()),
+            pauses,
+        })
+    }
+
+    /// Starts the average at `per_sec`, as expected from past copies,
+    /// rather than at the rate of the first samples.
+    pub(crate) fn seed(&self, per_sec: f64) {
+        let mut state = self.state.lock().unwrap();
+        if state.per_sec.is_none() {
+            state.per_sec = Some(per_sec);
+        }
+    }
+
+    /// Leaves the samples taken while `held` out of the average, so that a
+    /// copy waiting for its start or a pause to end keeps the rate it had.
+    pub(crate) fn hold(&self, held: bool) {
+        self.state.lock().unwrap().held = held;
+    }
+
+    /// Samples the position of `pb` until the ticker is dropped.
+    pub(crate) fn track(self: &Arc<Self>, pb: &ProgressBar) -> Ticker {
+        let rate = Arc::clone(self);
+        rate.sample(pb.position(), Instant::now());
+        Ticker::start(pb, RATE_SAMPLE_INTERVAL, move |pb| {
+            rate.sample(pb.position(), Instant::now())
+        })
+    }
+
+    pub(crate) fn sample(&self, position: u64, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        match state.second {
+            Some((then, before)) if now.saturating_duration_since(then) >= TIMELINE_INTERVAL => {
+                let elapsed = now.saturating_duration_since(then).as_secs_f64();
+                let per_sec = position.saturating_sub(before) as f64 / elapsed;
+                state.timeline.push(per_sec as u64);
+                state.second = Some((now, position));
+            }
+            Some(_) => {}
+            None => state.second = Some((now, position)),
+        }
+        if let Some((then, before)) = state.last.filter(|_| !state.held) {
+            let elapsed = now.saturating_duration_since(then).as_secs_f64();
+            if elapsed <= 0.0 {
+                return;
+            }
+            let current = position.saturating_sub(before) as f64 / elapsed;
+            let weight = 1.0 - (-elapsed / RATE_WINDOW.as_secs_f64()).exp();
+            state.per_sec = Some(
+                state
+                    .per_sec
+                    .map_or(current, |average| average + weight * (current - average)),
+            );
+        }
+        state.last = Some((now, position));
+    }
+
+    /// The throughput of each second up to `position`. A last partial
+    /// second is included unless it is too short to tell a rate.
+    pub(crate) fn timeline(&self, position: u64) -> Vec<u64> {
+        let state = self.state.lock().unwrap();
+        let mut timeline = state.timeline.clone();
+        if let Some((then, before)) = state.second {
+            let elapsed = then.elapsed();
+            if elapsed >= TIMELINE_INTERVAL / 4 || (timeline.is_empty() && !elapsed.is_zero()) {
+                let per_sec = position.saturating_sub(before) as f64 / elapsed.as_secs_f64();
+                timeline.push(per_sec as u64);
+            }
+        }
+        timeline
+    }
+
+    /// Bytes per second, or 0 before two samples have been taken.
+    pub(crate) fn per_sec(&self) -> f64 {
+        self.state.lock().unwrap().per_sec.unwrap_or(0.0)
+    }
+
+    /// The time spent copying to cover `remaining` bytes at the current
+    /// rate, or `None` before the rate is known.
+    fn active(&self, remaining: u64) -> Option<Duration> {
+        let per_sec = self.per_sec();
+        if per_sec < 1.0 {
+            return None;
+        }
+        Some(Duration::try_from_secs_f64(remaining as f64 / per_sec).unwrap_or(Duration::MAX))
+    }
+
+    /// The time left to cover `remaining` bytes at the current rate, the
+    /// pauses to come included.
+    pub(crate) fn eta(&self, remaining: u64) -> Duration {
+        let Some(active) = self.active(remaining) else {
+            return Duration::ZERO;
+        };
+        if self.pauses.is_none() {
+            return active;
+        }
+        let now = Local::now();
+        self.finish_from(now, remaining)
+            .and_then(|at| (at - now).to_std().ok())
+            .unwrap_or(Duration::MAX)
+    }
+
+    /// The time at which `remaining` bytes will have been covered at the
+    /// current rate, or `None` before the rate is known.
+    pub(crate) fn finish(&self, remaining: u64) -> Option<DateTime<Local>> {
+        self.finish_from(Local::now(), remaining)
+    }
+
+    fn finish_from(&self, now: DateTime<Local>, remaining: u64) -> Option<DateTime<Local>> {
+        let active = self.active(remaining)?;
+        match self.pauses {
+            Some(window) => window.finish(now, active),
+            None => now.checked_add_signed(chrono::Duration::from_std(active).ok()?),
+        }
+    }
+
+    /// `style` with the `{eta}` and `{bytes_per_sec}` keys based on this
+    /// rate instead of indicatif's estimate, and a `{finish}` key with the
+    /// time of day the copy should be done at.
+    pub(crate) fn keys(self: &Arc<Self>, style: ProgressStyle) -> ProgressStyle {
+        let eta = Arc::clone(self);
+        let per_sec = Arc::clone(self);
+        let finish = Arc::clone(self);
+        style
+            .with_key(
+                "eta",
+                move |state: &ProgressState, w: &mut dyn fmt::Write| {
+                    let remaining = state.len().unwrap_or(0).saturating_sub(state.pos());
+                    let _ = write!(w, "{:#}", HumanDuration(eta.eta(remaining)));
+                },
+            )
+            .with_key(
+                "bytes_per_sec",
+                move |_: &ProgressState, w: &mut dyn fmt::Write| {
+                    let _ = write!(w, "{}/s", HumanBytes(per_sec.per_sec() as u64));
+                },
+            )
+            .with_key(
+                "finish",
+                move |state: &ProgressState, w: &mut dyn fmt::Write| {
+                    let remaining = state.len().unwrap_or(0).saturating_sub(state.pos());
+                    let _ = match finish.finish(remaining) {
+                        Some(at) => w.write_str(&clock(Local::now(), at)),
+                        None => w.write_str("--:--"),
+                    };
+                },
+            )
+    }
+}
+
+/// Interval between updates of the terminal title.
+const TITLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Shows the percentage and rate of `pb` in the title of the terminal on
+/// stderr, if it is one, until dropped, then restores the previous title.
+pub(crate) struct TerminalTitle {
+    ticker: Option<Ticker>,
+}
+
+impl TerminalTitle {
+    pub(crate) fn show(pb: &ProgressBar, rate: &Arc<Rate>) -> Option<Self> {
+        if !io::stderr().is_terminal() {
+            return None;
+        }
+        // Saves the current title on the terminal's title stack.
+        eprint!("\x1b[22;0t");
+        let rate = Arc::clone(rate);
+        let ticker = Ticker::start(pb, TITLE_INTERVAL, move |pb| {
+            eprint!("\x1b]0;{}\x07", title(pb, &rate));
+        });
+        Some(Self {
+            ticker: Some(ticker),
+        })
+    }
+}
+
+impl Drop for TerminalTitle {
+    fn drop(&mut self) {
+        drop(self.ticker.take());
+        eprint!("\x1b[23;0t");
+    }
+}
+
+/// E.g. `cpv 30% 85.3 MiB/s`.
+pub(crate) fn title(pb: &ProgressBar, rate: &Rate) -> String {
+    let position = pb.position();
+    let length = pb.length().unwrap_or(0).max(position);
+    format!(
+        "cpv {}% {}/s",
+        (position * 100).checked_div(length).unwrap_or(100),
+        format_size(rate.per_sec() as u64, BINARY)
+    )
+}
+
+/// The time of day of `at`, with the day of the week if it is not today
+/// and the full date if it is a week or more after `now`.
+pub(crate) fn clock(now: DateTime<Local>, at: DateTime<Local>) -> String {
+    let format = if at.date_naive() == now.date_naive() {
+        "%H:%M"
+    } else if at.date_naive().num_days_from_ce() - now.date_naive().num_days_from_ce() < 7 {
+        "%a %H:%M"
+    } else {
+        "%Y-%m-%d %H:%M"
+    };
+    at.format(format).to_string()
+}
+
+/// `samples` as a line of block characters at most `width` wide, each the
+/// average of consecutive samples, scaled to the highest.
+pub(crate) fn sparkline(samples: &[u64], width: usize) -> String {
+    const BLOCKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let per_char = samples.len().div_ceil(width.max(1)).max(1);
+    let averages: Vec<u64> = samples
+        .chunks(per_char)
+        .map(|chunk| chunk.iter().sum::<u64>() / chunk.len() as u64)
+        .collect();
+    let max = averages.iter().copied().max().unwrap_or(0).max(1);
+    averages
+        .iter()
+        .map(|&average| BLOCKS[(average * 7 / max) as usize])
+        .collect()
+}
+
+/// E.g. `cpv: 1.20 GiB/4.00 GiB (30%), 85.3 MiB/s, done ~14:32, file 12/40
+/// src/a.iso`.
+pub(crate) fn status_line(pb: &ProgressBar, rate: &Rate) -> String {
+    let position = pb.position();
+    let length = pb.length().unwrap_or(0).max(position);
+    let percent = (position * 100).checked_div(length).unwrap_or(100);
+    let mut line = format!(
+        "cpv: {}/{} ({}%), {}/s",
+        format_size(position, BINARY),
+        format_size(length, BINARY),
+        percent,
+        format_size(rate.per_sec() as u64, BINARY)
+    );
+    if let Some(at) = rate.finish(length - position) {
+        line.push_str(&format!(", done ~{}", clock(Local::now(), at)));
+    }
+    let message = pb.message();
+    if !message.is_empty() {
+        line.push_str(", ");
+        line.push_str(&message);
+    }
+    line
+}
+
+/// Tracks bytes written by a copy loop, advancing the progress bar and the
+/// per-copy work that follows the data: dropping cached pages, throttling
+/// to the bandwidth limit, hashing the source and stopping once the copy
+/// is cancelled.
+pub(crate) struct Progress<'a> {
+    pb: &'a ProgressBar,
+    /// The bar of the file being copied, if it has one.
+    file: Option<&'a ProgressBar>,
+    /// The bar of the top-level directory the file is in, if it has one.
+    group: Option<Group<'a>>,
+    limiter: Option<&'a dyn RateLimiter>,
+    cancel: Option<&'a CancellationToken>,
+    /// The cap on the memory of copy buffers, if there is one.
+    memory: Option<&'a Arc<Memory>>,
+    cache: Option<CacheDropper>,
+    hash: Option<SourceHash>,
+    /// Bytes the bar has been advanced by, pending ones included.
+    advanced: u64,
+    throttle: ProgressThrottle,
+    /// Bytes held back from the bars by the throttle.
+    pending: u64,
+    flushed: Instant,
+    /// For a file of unknown size, the bytes the totals counted for it,
+    /// and what they were grown by past that, or shrunk by to what it held.
+    counted: Option<u64>,
+    resized: i64,
+}
+
+/// A checksum of the source built from the data passing through the copy.
+///
+/// It is only usable while every reported byte has also been hashed, in
+/// order; loops that never see the data (in-kernel copies, clones, ranges
+/// copied out of order) leave it incomplete.
+struct SourceHash {
+    hasher: Box<dyn Hasher>,
+    hashed: u64,
+    reported: u64,
+}
+
+impl SourceHash {
+    fn in_sync(&self) -> bool {
+        self.hashed == self.reported
+    }
+}
+
+impl<'a> Progress<'a> {
+    pub(crate) fn new(pb: &'a ProgressBar, limiter: Option<&'a dyn RateLimiter>) -> Self {
+        Self {
+            pb,
+            file: None,
+            group: None,
+            limiter,
+            cancel: None,
+            memory: None,
+            cache: None,
+            hash: None,
+            advanced: 0,
+            throttle: ProgressThrottle::NONE,
+            pending: 0,
+            flushed: Instant::now(),
+            counted: None,
+            resized: 0,
+        }
+    }
+
+    /// Holds back updates of the bars as `throttle` says.
+    pub(crate) fn throttled(mut self, throttle: ProgressThrottle) -> Self {
+        self.throttle = throttle;
+        self
+    }
+
+    /// Also advances `bar`, the bar of the file being copied.
+    pub(crate) fn file_bar(mut self, bar: Option<&'a ProgressBar>) -> Self {
+        self.file = bar;
+        self
+    }
+
+    /// Also advances `group`, the bar of the directory the file is in.
+    pub(crate) fn group(mut self, group: Option<Group<'a>>) -> Self {
+        self.group = group;
+        self
+    }
+
+    /// A progress for another thread copying part of the same file, which
+    /// advances the same bars under the same limiter but neither hashes the
+    /// data nor drops it from the cache.
+    #[cfg(any(unix, windows))]
+    pub(crate) fn worker(&self) -> Progress<'a> {
+        Self {
+            pb: self.pb,
+            file: self.file,
+            group: self.group,
+            limiter: self.limiter,
+            cancel: self.cancel,
+            memory: self.memory,
+            cache: None,
+            hash: None,
+            advanced: 0,
+            throttle: self.throttle,
+            pending: 0,
+            flushed: Instant::now(),
+            counted: None,
+            resized: 0,
+        }
+    }
+
+    /// Fails the loop reporting data once `token` is cancelled.
+    pub(crate) fn cancellable(mut self, token: Option<&'a CancellationToken>) -> Self {
+        self.cancel = token;
+        self
+    }
+
+    /// Has the buffers of the copy lease their memory from `memory`.
+    pub(crate) fn memory(mut self, memory: Option<&'a Arc<Memory>>) -> Self {
+        self.memory = memory;
+        self
+    }
+
+    /// A buffer for the copy, of `size` bytes if given, else sized for a
+    /// file of `len` bytes.
+    pub(crate) fn buffer(&self, len: u64, size: Option<usize>) -> IoBuffer {
+        IoBuffer::new(len, size, self.memory)
+    }
+
+    /// Starts the file's bar over for a copy of `len` bytes.
+    pub(crate) fn start_file(&self, len: u64) {
+        if let Some(file) = self.file {
+            file.set_length(len);
+            file.set_position(0);
+        }
+    }
+
+    /// Starts the bars of a file whose size is not known, for which the
+    /// totals counted `counted` bytes, which they are grown past as data
+    /// is reported.
+    pub(crate) fn start_unknown_size(&mut self, counted: u64) {
+        self.start_file(counted);
+        self.counted = Some(counted);
+    }
+
+    /// Shrinks the totals to the bytes reported of a file of unknown size,
+    /// once it is copied, if it held less than they counted.
+    pub(crate) fn settle(&mut self) {
+        self.flush();
+        if let Some(counted) = self.counted {
+            if self.advanced < counted {
+                self.resize(self.advanced as i64 - counted as i64);
+            }
+        }
+    }
+
+    /// Grows, or shrinks, the totals of the bars by `by` bytes.
+    fn resize(&mut self, by: i64) {
+        let resize = |bar: &ProgressBar| {
+            let length = bar.length().unwrap_or(0);
+            bar.set_length(length.saturating_add_signed(by));
+        };
+        resize(self.pb);
+        if let Some(file) = self.file {
+            resize(file);
+        }
+        if let Some(group) = self.group {
+            resize(group.bar());
+        }
+        self.resized += by;
+    }
+
+    /// Hashes the source with `algo` as data is reported through
+    /// [`inc_data`](Self::inc_data).
+    pub(crate) fn hashing(mut self, algo: ChecksumAlgo) -> Self {
+        self.hash = Some(SourceHash {
+            hasher: algo.hasher(),
+            hashed: 0,
+            reported: 0,
+        });
+        self
+    }
+
+    #[cfg(windows)]
+    pub(crate) fn bar(&self) -> &'a ProgressBar {
+        self.pb
+    }
+
+    /// Whether a loop that can see the data should be preferred.
+    pub(crate) fn wants_data(&self) -> bool {
+        self.hash.is_some()
+    }
+
+    /// Evicts the data copied from `source` to `dest` from the page cache as
+    /// it is reported.
+    pub(crate) fn drop_cache(&mut self, source: &File, dest: &File) {
+        self.cache = Some(CacheDropper::new(source, dest));
+    }
+
+    /// The checksum of the source if all of its `len` bytes went through
+    /// [`inc_data`](Self::inc_data) and [`skip`](Self::skip) in order.
+    pub(crate) fn source_digest(&mut self, len: u64) -> Option<Digest> {
+        let hash = self.hash.take()?;
+        (hash.in_sync() && hash.hashed == len).then(|| hash.hasher.finish())
+    }
+
+    /// Records `data`, the next bytes of the source, as written to the
+    /// destination. Fails if the copy has been cancelled.
+    pub(crate) fn inc_data(&mut self, data: &[u8]) -> io::Result<()> {
+        if let Some(hash) = &mut self.hash {
+            if hash.in_sync() {
+                hash.hasher.update(data);
+                hash.hashed += data.len() as u64;
+            }
+        }
+        self.inc(data.len() as u64)
+    }
+
+    /// Records `n` more bytes written to the destination. Fails if the
+    /// copy has been cancelled.
+    pub(crate) fn inc(&mut self, n: u64) -> io::Result<()> {
+        if let Some(hash) = &mut self.hash {
+            hash.reported += n;
+        }
+        self.advance(n);
+        if let Some(cache) = &mut self.cache {
+            cache.advance(n);
+        }
+        if let Some(limiter) = self.limiter {
+            limiter.acquire(n);
+        }
+        self.check_cancelled()
+    }
+
+    /// Advances the progress bar over `n` zero bytes that needed no
+    /// transfer, such as holes in a sparse file. Fails if the copy has
+    /// been cancelled.
+    pub(crate) fn skip(&mut self, n: u64) -> io::Result<()> {
+        self.advance(n);
+        if let Some(hash) = &mut self.hash {
+            if hash.in_sync() {
+                let zeros = [0; 8192];
+                let mut left = n;
+                while left > 0 {
+                    let step = left.min(zeros.len() as u64);
+                    hash.hasher.update(&zeros[..step as usize]);
+                    left -= step;
+                }
+                hash.hashed += n;
+            }
+            hash.reported += n;
+        }
+        self.check_cancelled()
+    }
+
+    /// Fails if the copy has been cancelled.
+    pub(crate) fn check_cancelled(&self) -> io::Result<()> {
+        match self.cancel {
+            Some(token) => token.check(),
+            None => Ok(()),
+        }
+    }
+
+    /// Bytes reported so far, pending ones included.
+    pub(crate) fn advanced(&self) -> u64 {
+        self.advanced
+    }
+
+    /// Advances the progress bar over `n` bytes of the source that are
+    /// already in place without being read, such as a cloned file or a
+    /// prefix kept by `--resume`. They leave the source hash incomplete.
+    pub(crate) fn present(&mut self, n: u64) {
+        self.advance(n);
+        if let Some(hash) = &mut self.hash {
+            hash.reported += n;
+        }
+    }
+
+    fn advance(&mut self, n: u64) {
+        self.advanced += n;
+        self.pending += n;
+        if self.pending >= self.throttle.min_bytes
+            && (self.throttle.interval.is_zero()
+                || self.flushed.elapsed() >= self.throttle.interval)
+        {
+            self.flush();
+        }
+    }
+
+    /// Advances the bars by the bytes the throttle held back.
+    pub(crate) fn flush(&mut self) {
+        if self.pending == 0 {
+            return;
+        }
+        if let Some(counted) = self.counted {
+            // Past what the totals counted, they grow with the data.
+            let past = self.advanced.saturating_sub(counted) as i64;
+            if past > self.resized {
+                self.resize(past - self.resized);
+            }
+        }
+        self.pb.inc(self.pending);
+        if let Some(file) = self.file {
+            file.inc(self.pending);
+        }
+        if let Some(group) = self.group {
+            group.advance(self.pending);
+        }
+        self.pending = 0;
+        if !self.throttle.interval.is_zero() {
+            self.flushed = Instant::now();
+        }
+    }
+
+    /// Gives up on this copy before it is retried, growing the bar's total
+    /// by the bytes it had been advanced by, since they are copied again,
+    /// less what it was resized by for a file of unknown size.
+    pub(crate) fn restart(self) {
+        let grown = (self.advanced as i64 - self.resized) as u64;
+        self.pb.inc_length(grown);
+        if let Some(group) = self.group {
+            group.bar().inc_length(grown);
+        }
+    }
+}
+
+impl Drop for Progress<'_> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// The progress bars of one copy: bytes copied and, with `--verify`,
+/// bytes read back.
+pub(crate) struct Bars {
+    pub(crate) copy: ProgressBar,
+    pub(crate) verify: Option<ProgressBar>,
+    /// Files finished out of those found, in a recursive copy.
+    count: Option<ProgressBar>,
+    /// Files and bytes found so far by the scan of a recursive copy.
+    scan: Option<ProgressBar>,
+    scanned_bytes: AtomicU64,
+    /// Where a bar for each file in flight is shown below the others, in a
+    /// recursive copy.
+    files: Option<MultiProgress>,
+    /// A bar for each directory at the top of the tree.
+    groups: Option<Groups>,
+    /// Characters and theme of the bars added for files.
+    chars: String,
+    theme: Theme,
+    /// Files started so far and files found by the scan.
+    started: AtomicU64,
+    total: AtomicU64,
+    /// The bars, hidden until the total reaches `threshold` bytes, and
+    /// how they are then displayed.
+    hidden: Mutex<Option<(MultiProgress, ProgressMode)>>,
+    threshold: u64,
+    /// Where the workers wait for space on a full destination, if they do.
+    space: Option<Arc<space::Waiter>>,
+    memory: Option<Arc<Memory>>,
+}
+
+impl Bars {
+    pub(crate) fn new(
+        copy: ProgressBar,
+        verify: Option<ProgressBar>,
+        files: Option<MultiProgress>,
+        chars: &str,
+        theme: Theme,
+    ) -> Self {
+        let count =
+            files.as_ref().map(|multi| {
+                let count = multi.insert_after(&copy, ProgressBar::new(0));
+                count.set_style(
+                    ProgressStyle::default_bar()
+                        .template(&theme.template(
+                            "[{elapsed_precise}] [{bar:40.yellow/white}] {pos}/{len} files",
+                        ))
+                        .expect("Progress bar template error")
+                        .progress_chars(chars),
+                );
+                count
+            });
+        let scan = files.as_ref().map(|multi| {
+            let scan = multi.insert_before(&copy, ProgressBar::new_spinner());
+            scan.set_style(
+                ProgressStyle::default_spinner()
+                    .template("{spinner} scanning: {human_pos} files / {msg} found so far")
+                    .expect("Progress bar template error"),
+            );
+            scan.enable_steady_tick(Duration::from_millis(100));
+            scan
+        });
+        Self {
+            copy,
+            verify,
+            count,
+            scan,
+            scanned_bytes: AtomicU64::new(0),
+            files,
+            groups: None,
+            chars: chars.to_string(),
+            theme,
+            started: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            hidden: Mutex::new(None),
+            threshold: 0,
+            space: None,
+            memory: None,
+        }
+    }
+
+    /// Draws the bars of `multi`, created hidden, as `mode` says once the
+    /// copy is found to total at least `threshold` bytes, so that trivial
+    /// copies draw nothing.
+    pub(crate) fn hidden_below(
+        mut self,
+        threshold: u64,
+        multi: &MultiProgress,
+        mode: ProgressMode,
+    ) -> Self {
+        self.hidden = Mutex::new(Some((multi.clone(), mode)));
+        self.threshold = threshold;
+        self
+    }
+
+    /// Draws the hidden bars once the total has reached the threshold.
+    fn reveal(&self) {
+        if self.copy.length().unwrap_or(0) < self.threshold {
+            return;
+        }
+        if let Some((multi, mode)) = self.hidden.lock().unwrap().take() {
+            multi.set_draw_target(mode.draw_target());
+        }
+    }
+
+    /// Counts `path` as the next file and names it on the copy bar as the
+    /// one being copied.
+    pub(crate) fn start_file(&self, path: &Path) {
+        let started = self.started.fetch_add(1, Ordering::Relaxed) + 1;
+        let total = self.total.load(Ordering::Relaxed).max(started);
+        self.copy
+            .set_message(format!("file {}/{} {}", started, total, path.display()));
+    }
+
+    /// Counts the file at `path` as finished, whether it was copied or
+    /// not.
+    pub(crate) fn finish_file(&self, path: &Path) {
+        if let Some(count) = &self.count {
+            count.inc(1);
+        }
+        if let Some(group) = self.group_of(path) {
+            group.check_done();
+        }
+    }
+
+    /// Has the workers wait for space with `waiter` on a full destination.
+    pub(crate) fn waiting_for_space(mut self, waiter: Arc<space::Waiter>) -> Self {
+        self.space = Some(waiter);
+        self
+    }
+
+    pub(crate) fn space_waiter(&self) -> Option<&space::Waiter> {
+        self.space.as_deref()
+    }
+
+    /// Caps the memory of the copy buffers of the workers with `memory`.
+    pub(crate) fn capping_memory(mut self, memory: Arc<Memory>) -> Self {
+        self.memory = Some(memory);
+        self
+    }
+
+    pub(crate) fn memory(&self) -> Option<&Arc<Memory>> {
+        self.memory.as_ref()
+    }
+
+    /// Shows a bar for each directory at the top of the tree `root`, with
+    /// `list` also naming each in the listing once it is done, provided
+    /// there are a few of them: two to `MAX_GROUPS`.
+    pub(crate) fn grouped(mut self, root: &Path, list: bool) -> Self {
+        let Some(multi) = &self.files else {
+            return self;
+        };
+        let Ok(entries) = fs::read_dir(root) else {
+            return self;
+        };
+        let mut names: Vec<OsString> = entries
+            .flatten()
+            .filter(|entry| entry.file_type().is_ok_and(|kind| kind.is_dir()))
+            .map(|entry| entry.file_name())
+            .take(MAX_GROUPS + 1)
+            .collect();
+        if !(2..=MAX_GROUPS).contains(&names.len()) {
+            return self;
+        }
+        names.sort();
+        let width = names
+            .iter()
+            .map(|name| name.to_string_lossy().chars().count() + 1)
+            .max()
+            .unwrap_or(0);
+        let style = ProgressStyle::default_bar()
+            .template(
+                &self
+                    .theme
+                    .template("  {msg} [{bar:20.green/white}] {bytes}/{total_bytes}"),
+            )
+            .expect("Progress bar template error")
+            .progress_chars(&self.chars);
+        let mut after = self.count.clone().unwrap_or_else(|| self.copy.clone());
+        let groups = names
+            .into_iter()
+            .map(|name| {
+                let bar = multi.insert_after(&after, ProgressBar::new(0));
+                bar.set_style(style.clone());
+                bar.set_message(format!(
+                    "{:<width$}",
+                    format!("{}/", name.to_string_lossy())
+                ));
+                after = bar.clone();
+                GroupBar {
+                    name,
+                    bar,
+                    done: AtomicBool::new(false),
+                }
+            })
+            .collect();
+        self.groups = Some(Groups {
+            root: root.to_path_buf(),
+            bars: groups,
+            list,
+            scanned: AtomicBool::new(false),
+        });
+        self
+    }
+
+    /// The bar of the directory at the top of the tree that `path` is in.
+    pub(crate) fn group_of(&self, path: &Path) -> Option<Group<'_>> {
+        let groups = self.groups.as_ref()?;
+        let mut components = path.strip_prefix(&groups.root).ok()?.components();
+        let Some(Component::Normal(top)) = components.next() else {
+            return None;
+        };
+        components.next()?;
+        self.group_named(top)
+    }
+
+    fn group_named(&self, name: &OsStr) -> Option<Group<'_>> {
+        let groups = self.groups.as_ref()?;
+        let index = groups
+            .bars
+            .binary_search_by(|group| group.name.as_os_str().cmp(name))
+            .ok()?;
+        Some(Group { bars: self, index })
+    }
+
+    /// Advances the bars over a file of `len` bytes at `path` that needed
+    /// no copy.
+    pub(crate) fn pass(&self, path: &Path, len: u64) {
+        self.copy.inc(len);
+        if let Some(group) = self.group_of(path) {
+            group.advance(len);
+            group.check_done();
+        }
+    }
+
+    /// Adds a file of `len` bytes found by the scan to the totals, and to
+    /// the bar of `group`, the directory at the top of the tree it is in.
+    pub(crate) fn add_file_to_total(&self, group: Option<&OsStr>, len: u64) {
+        if let Some(group) = group.and_then(|name| self.group_named(name)) {
+            group.bar().inc_length(len);
+        }
+        self.total.fetch_add(1, Ordering::Relaxed);
+        if let Some(count) = &self.count {
+            count.inc_length(1);
+        }
+        let bytes = self.scanned_bytes.fetch_add(len, Ordering::Relaxed) + len;
+        if let Some(scan) = &self.scan {
+            scan.inc(1);
+            scan.set_message(format_size(bytes, BINARY));
+        }
+        self.inc_length(len);
+    }
+
+    /// The files and bytes found by the scan so far.
+    pub(crate) fn scanned(&self) -> (u64, u64) {
+        (
+            self.total.load(Ordering::Relaxed),
+            self.scanned_bytes.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Removes the scan spinner once the scan has ended, and finishes the
+    /// bars of the directories already copied, whose totals are now known.
+    pub(crate) fn finish_scan(&self) {
+        if let Some(scan) = &self.scan {
+            scan.finish_and_clear();
+        }
+        if let Some(groups) = &self.groups {
+            groups.scanned.store(true, Ordering::Relaxed);
+            for index in 0..groups.bars.len() {
+                Group { bars: self, index }.check_done();
+            }
+        }
+    }
+
+    /// Prints a line of the verbose listing to stdout, through the bars
+    /// while they are drawn on the same terminal so as not to garble them.
+    pub(crate) fn list(&self, line: String) {
+        if self.copy.is_hidden() || !io::stdout().is_terminal() {
+            println!("{}", line);
+        } else {
+            self.copy.println(line);
+        }
+    }
+
+    /// Leaves the bars at their final state once the copy is done.
+    pub(crate) fn finish(&self) {
+        self.copy.finish_with_message("Copy completed!");
+        for bar in [&self.count, &self.verify].into_iter().flatten() {
+            bar.finish();
+        }
+        for group in self.groups.iter().flat_map(|groups| &groups.bars) {
+            group.bar.finish();
+        }
+    }
+
+    /// Leaves the bars where an interrupted copy stopped.
+    pub(crate) fn interrupt(&self) {
+        self.copy.abandon_with_message("interrupted");
+        for bar in [&self.count, &self.verify, &self.scan]
+            .into_iter()
+            .flatten()
+        {
+            bar.abandon();
+        }
+        for group in self.groups.iter().flat_map(|groups| &groups.bars) {
+            group.bar.abandon();
+        }
+    }
+
+    /// Shows a bar for copying `path` until the returned guard is dropped.
+    pub(crate) fn add_file(&self, path: &Path) -> Option<FileBar<'_>> {
+        let multi = self.files.as_ref()?;
+        let pb = multi.add(ProgressBar::new(0));
+        pb.set_style(
+            ProgressStyle::default_bar()
+                .template(
+                    &self
+                        .theme
+                        .template("  {wide_msg} [{bar:20.cyan/blue}] {percent:>3}%"),
+                )
+                .expect("Progress bar template error")
+                .progress_chars(&self.chars),
+        );
+        pb.set_message(
+            path.file_name()
+                .unwrap_or(path.as_os_str())
+                .to_string_lossy()
+                .into_owned(),
+        );
+        Some(FileBar { multi, pb })
+    }
+
+    /// Sets the total of every bar.
+    pub(crate) fn set_length(&self, len: u64) {
+        self.copy.set_length(len);
+        if let Some(verify) = &self.verify {
+            verify.set_length(len);
+        }
+        self.reveal();
+    }
+
+    /// Adds `len` bytes to the total of every bar.
+    fn inc_length(&self, len: u64) {
+        self.copy.inc_length(len);
+        if let Some(verify) = &self.verify {
+            verify.inc_length(len);
+        }
+        self.reveal();
+    }
+}
+
+/// The bars of the directories at the top of a tree, in the order of
+/// their names.
+struct Groups {
+    root: PathBuf,
+    bars: Vec<GroupBar>,
+    /// Whether a directory is named in the listing once done.
+    list: bool,
+    /// Whether the scan has ended, so that the totals are complete.
+    scanned: AtomicBool,
+}
+
+struct GroupBar {
+    name: OsString,
+    bar: ProgressBar,
+    done: AtomicBool,
+}
+
+/// The bar of a directory at the top of a tree.
+#[derive(Clone, Copy)]
+pub(crate) struct Group<'a> {
+    bars: &'a Bars,
+    index: usize,
+}
+
+impl Group<'_> {
+    fn get(&self) -> &GroupBar {
+        &self.bars.groups.as_ref().unwrap().bars[self.index]
+    }
+
+    pub(crate) fn bar(&self) -> &ProgressBar {
+        &self.get().bar
+    }
+
+    /// Advances the bar by `n` bytes copied.
+    pub(crate) fn advance(&self, n: u64) {
+        self.bar().inc(n);
+    }
+
+    /// Finishes the bar once all of the directory is copied, naming it in
+    /// the listing after its last file.
+    fn check_done(&self) {
+        let groups = self.bars.groups.as_ref().unwrap();
+        let group = self.get();
+        if !groups.scanned.load(Ordering::Relaxed)
+            || group.bar.position() < group.bar.length().unwrap_or(0)
+            || group.done.swap(true, Ordering::Relaxed)
+        {
+            return;
+        }
+        group.bar.finish();
+        if groups.list {
+            self.bars.list(format!(
+                "{}/ done ({})",
+                group.name.to_string_lossy(),
+                format_size(group.bar.position(), BINARY)
+            ));
+        }
+    }
+}
+
+/// The bar of one file in flight, removed from the display when dropped.
+pub(crate) struct FileBar<'a> {
+    multi: &'a MultiProgress,
+    pb: ProgressBar,
+}
+
+impl FileBar<'_> {
+    pub(crate) fn bar(&self) -> &ProgressBar {
+        &self.pb
+    }
+}
+
+impl Drop for FileBar<'_> {
+    fn drop(&mut self) {
+        self.pb.finish_and_clear();
+        self.multi.remove(&self.pb);
+    }
+}
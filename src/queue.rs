@@ -0,0 +1,476 @@
+//! A queue of copies run by a background process, one after another or a
+//! few at a time: `cpv daemon` runs a `QueueDaemon` on a Unix domain
+//! socket, `cpv enqueue` adds copies to it and `cpv jobs` lists, reorders,
+//! pauses and cancels them.
+//!
+//! A job is a command line of the daemon's program, run as a child process
+//! in the directory it was enqueued from. Its progress is read from the
+//! `--progress-json` events on its stdout, and its error from the last line
+//! of its stderr. Requests and replies are JSON lines, one of each per
+//! connection.
+
+use crate::stage::state_dir;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// What a job of the queue is doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum JobState {
+    /// Waiting for its turn.
+    Queued,
+    Running,
+    /// Held: not started until resumed if it was queued, stopped if it
+    /// was running.
+    Paused,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+impl JobState {
+    /// Whether the job is over, for good or not.
+    pub fn is_finished(self) -> bool {
+        matches!(self, Self::Done | Self::Failed | Self::Cancelled)
+    }
+}
+
+impl fmt::Display for JobState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Padded, for the columns of `cpv jobs`.
+        f.pad(match self {
+            Self::Queued => "queued",
+            Self::Running => "running",
+            Self::Paused => "paused",
+            Self::Done => "done",
+            Self::Failed => "failed",
+            Self::Cancelled => "cancelled",
+        })
+    }
+}
+
+/// A job of the queue, as `QueueRequest::List` returns it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JobInfo {
+    pub id: u64,
+    pub state: JobState,
+    pub source: PathBuf,
+    pub dest: PathBuf,
+    /// Bytes copied and the total, as last reported by the copy.
+    pub bytes: u64,
+    pub total: u64,
+    /// Why the job failed.
+    pub error: Option<String>,
+}
+
+/// A request to a `QueueDaemon`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QueueRequest {
+    /// Adds the copy of `source` to `dest` at the end of the queue, to be
+    /// run as the program with `args` in the directory `dir`.
+    Enqueue {
+        args: Vec<String>,
+        dir: PathBuf,
+        source: PathBuf,
+        dest: PathBuf,
+    },
+    /// Lists the jobs, in the order they run.
+    List,
+    Pause(u64),
+    Resume(u64),
+    /// Cancels a job, stopping its copy if it is running.
+    Cancel(u64),
+    /// Moves a job to `position`, counted from 1, in the list.
+    Move {
+        id: u64,
+        position: usize,
+    },
+}
+
+/// The reply of a `QueueDaemon` to a `QueueRequest`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QueueReply {
+    /// The id of the job added.
+    Enqueued(u64),
+    Jobs(Vec<JobInfo>),
+    Ok,
+    Error(String),
+}
+
+impl QueueRequest {
+    /// Sends the request to the daemon listening at `socket` and returns
+    /// its reply.
+    pub fn send(&self, socket: &Path) -> io::Result<QueueReply> {
+        let stream = UnixStream::connect(socket).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!(
+                    "cannot reach the cpv daemon at '{}': {}",
+                    socket.display(),
+                    err
+                ),
+            )
+        })?;
+        let mut line = serde_json::to_string(self)?;
+        line.push('\n');
+        (&stream).write_all(line.as_bytes())?;
+        let mut reply = String::new();
+        BufReader::new(stream).read_line(&mut reply)?;
+        serde_json::from_str(&reply).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+/// A queue of jobs served on a Unix domain socket, running up to a number
+/// of them at once.
+#[derive(Debug)]
+pub struct QueueDaemon {
+    program: PathBuf,
+    concurrency: usize,
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    jobs: Mutex<Jobs>,
+    /// Signalled when a job may start.
+    changed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct Jobs {
+    last_id: u64,
+    list: Vec<Job>,
+}
+
+#[derive(Debug)]
+struct Job {
+    info: JobInfo,
+    args: Vec<String>,
+    dir: PathBuf,
+    /// The process of a job that was started and has not exited.
+    pid: Option<u32>,
+}
+
+impl Jobs {
+    fn get(&mut self, id: u64) -> Result<&mut Job, String> {
+        self.list
+            .iter_mut()
+            .find(|job| job.info.id == id)
+            .ok_or_else(|| format!("there is no job {}", id))
+    }
+
+    fn running(&self) -> usize {
+        self.list
+            .iter()
+            .filter(|job| job.info.state == JobState::Running)
+            .count()
+    }
+}
+
+impl QueueDaemon {
+    /// A daemon running jobs with `program`, `concurrency` of them at once,
+    /// at least one.
+    pub fn new(program: impl Into<PathBuf>, concurrency: usize) -> Self {
+        Self {
+            program: program.into(),
+            concurrency: concurrency.max(1),
+            shared: Arc::default(),
+        }
+    }
+
+    /// Where `cpv daemon` listens unless told otherwise: `cpv.sock` in
+    /// `$XDG_RUNTIME_DIR`, or in the per-user state directory without one.
+    pub fn default_socket() -> Option<PathBuf> {
+        match std::env::var_os("XDG_RUNTIME_DIR") {
+            Some(dir) if !dir.is_empty() => Some(Path::new(&dir).join("cpv.sock")),
+            _ => Some(state_dir()?.join("cpv.sock")),
+        }
+    }
+
+    /// Listens at `path`, which only the current user may connect to. A
+    /// socket left by a daemon that was killed is replaced, but not one
+    /// that is still served.
+    ///
+    /// The socket is bound in a directory only the current user can enter
+    /// and moved to `path` once it is private, so that nobody can connect
+    /// in between.
+    pub fn bind(path: &Path) -> io::Result<UnixListener> {
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("'{}' exists and is not a socket", path.display()),
+                ));
+            }
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("a cpv daemon is already listening at '{}'", path.display()),
+                ));
+            }
+        }
+        let parent = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(parent)?;
+        let private = tempfile::Builder::new()
+            .prefix(".cpv-sock")
+            .permissions(fs::Permissions::from_mode(0o700))
+            .tempdir_in(parent)?;
+        let bound = private.path().join("cpv.sock");
+        let listener = UnixListener::bind(&bound)?;
+        fs::set_permissions(&bound, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&bound, path)?;
+        Ok(listener)
+    }
+
+    /// Serves requests on `listener` and runs the jobs. Only returns if
+    /// accepting connections fails for good.
+    pub fn run(self, listener: UnixListener) -> io::Result<()> {
+        let scheduler = Arc::clone(&self.shared);
+        let (program, concurrency) = (self.program.clone(), self.concurrency);
+        thread::spawn(move || schedule(&scheduler, &program, concurrency));
+        let mut failures = 0;
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                // Out of descriptors, or a client gone before accepted.
+                Err(err) => {
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!(error = %err, "accepting a connection");
+                    failures += 1;
+                    if failures == 100 {
+                        return Err(err);
+                    }
+                    thread::sleep(Duration::from_millis(100));
+                    continue;
+                }
+            };
+            failures = 0;
+            let shared = Arc::clone(&self.shared);
+            thread::spawn(move || {
+                let _ = serve(&shared, stream);
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Answers the request of one client.
+fn serve(shared: &Shared, stream: UnixStream) -> io::Result<()> {
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    let reply = match serde_json::from_str(&line) {
+        Ok(request) => handle(shared, request).unwrap_or_else(QueueReply::Error),
+        Err(err) => QueueReply::Error(format!("invalid request: {}", err)),
+    };
+    let mut line = serde_json::to_string(&reply)?;
+    line.push('\n');
+    (&stream).write_all(line.as_bytes())
+}
+
+fn handle(shared: &Shared, request: QueueRequest) -> Result<QueueReply, String> {
+    let mut jobs = shared.jobs.lock().unwrap();
+    let reply = match request {
+        QueueRequest::Enqueue {
+            args,
+            dir,
+            source,
+            dest,
+        } => {
+            jobs.last_id += 1;
+            let id = jobs.last_id;
+            jobs.list.push(Job {
+                info: JobInfo {
+                    id,
+                    state: JobState::Queued,
+                    source,
+                    dest,
+                    bytes: 0,
+                    total: 0,
+                    error: None,
+                },
+                args,
+                dir,
+                pid: None,
+            });
+            QueueReply::Enqueued(id)
+        }
+        QueueRequest::List => {
+            QueueReply::Jobs(jobs.list.iter().map(|job| job.info.clone()).collect())
+        }
+        QueueRequest::Pause(id) => {
+            let job = jobs.get(id)?;
+            match (job.info.state, job.pid) {
+                (JobState::Queued, _) => {}
+                (JobState::Running, Some(pid)) => signal(pid, libc::SIGSTOP)?,
+                (state, _) => return Err(format!("job {} is {}", id, state)),
+            }
+            job.info.state = JobState::Paused;
+            QueueReply::Ok
+        }
+        QueueRequest::Resume(id) => {
+            let job = jobs.get(id)?;
+            if job.info.state != JobState::Paused {
+                return Err(format!("job {} is {}", id, job.info.state));
+            }
+            job.info.state = match job.pid {
+                Some(pid) => {
+                    signal(pid, libc::SIGCONT)?;
+                    JobState::Running
+                }
+                None => JobState::Queued,
+            };
+            QueueReply::Ok
+        }
+        QueueRequest::Cancel(id) => {
+            let job = jobs.get(id)?;
+            if job.info.state.is_finished() {
+                return Err(format!("job {} is {}", id, job.info.state));
+            }
+            if let Some(pid) = job.pid {
+                signal(pid, libc::SIGTERM)?;
+                // A stopped copy only sees the signal once continued, and
+                // one that was not may be gone already.
+                let _ = signal(pid, libc::SIGCONT);
+            }
+            job.info.state = JobState::Cancelled;
+            QueueReply::Ok
+        }
+        QueueRequest::Move { id, position } => {
+            let from = jobs
+                .list
+                .iter()
+                .position(|job| job.info.id == id)
+                .ok_or_else(|| format!("there is no job {}", id))?;
+            let job = jobs.list.remove(from);
+            let to = position.clamp(1, jobs.list.len() + 1) - 1;
+            jobs.list.insert(to, job);
+            QueueReply::Ok
+        }
+    };
+    shared.changed.notify_all();
+    Ok(reply)
+}
+
+fn signal(pid: u32, signal: libc::c_int) -> Result<(), String> {
+    // SAFETY: kill only sends a signal.
+    match unsafe { libc::kill(pid as libc::pid_t, signal) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error().to_string()),
+    }
+}
+
+/// Starts the queued jobs in order as slots free up, for ever.
+fn schedule(shared: &Arc<Shared>, program: &Path, concurrency: usize) {
+    let mut jobs = shared.jobs.lock().unwrap();
+    loop {
+        while jobs.running() < concurrency {
+            let Some(job) = jobs
+                .list
+                .iter_mut()
+                .find(|job| job.info.state == JobState::Queued)
+            else {
+                break;
+            };
+            job.info.state = JobState::Running;
+            match start(program, job) {
+                Ok(child) => {
+                    job.pid = Some(child.id());
+                    let (shared, id) = (Arc::clone(shared), job.info.id);
+                    thread::spawn(move || supervise(&shared, id, child));
+                }
+                Err(err) => {
+                    job.info.state = JobState::Failed;
+                    job.info.error = Some(format!("cannot run '{}': {}", program.display(), err));
+                }
+            }
+        }
+        jobs = shared.changed.wait(jobs).unwrap();
+    }
+}
+
+fn start(program: &Path, job: &Job) -> io::Result<Child> {
+    Command::new(program)
+        .args(&job.args)
+        .current_dir(&job.dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+}
+
+/// Follows the job `id` through the events of its copy until it exits.
+fn supervise(shared: &Shared, id: u64, mut child: Child) {
+    let stderr = child.stderr.take().map(|stderr| {
+        thread::spawn(move || {
+            BufReader::new(stderr)
+                .lines()
+                .map_while(Result::ok)
+                .filter(|line| !line.trim().is_empty())
+                .last()
+        })
+    });
+    if let Some(stdout) = child.stdout.take() {
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            let Ok(event) = serde_json::from_str::<Value>(&line) else {
+                continue;
+            };
+            let mut jobs = shared.jobs.lock().unwrap();
+            let Ok(job) = jobs.get(id) else {
+                continue;
+            };
+            match event["event"].as_str() {
+                Some("bytes-progress") => {
+                    job.info.bytes = event["bytes"].as_u64().unwrap_or(job.info.bytes);
+                    job.info.total = event["total"].as_u64().unwrap_or(job.info.total);
+                }
+                Some("summary") => {
+                    job.info.bytes = event["bytes_copied"].as_u64().unwrap_or(job.info.bytes);
+                    job.info.total = job.info.total.max(job.info.bytes);
+                }
+                _ => {}
+            }
+        }
+    }
+    let status = child.wait();
+    let last_error = stderr.and_then(|stderr| stderr.join().ok()).flatten();
+    let mut jobs = shared.jobs.lock().unwrap();
+    if let Ok(job) = jobs.get(id) {
+        job.pid = None;
+        match status {
+            _ if job.info.state == JobState::Cancelled => {}
+            Ok(status) if status.success() => {
+                job.info.state = JobState::Done;
+            }
+            Ok(status) => {
+                job.info.state = JobState::Failed;
+                job.info.error = Some(match last_error {
+                    Some(line) => line.trim_start_matches("cpv: ").to_string(),
+                    None => format!("the copy exited with {}", status),
+                });
+            }
+            Err(err) => {
+                job.info.state = JobState::Failed;
+                job.info.error = Some(err.to_string());
+            }
+        }
+    }
+    shared.changed.notify_all();
+}
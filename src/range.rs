@@ -0,0 +1,169 @@
+//! Part of a file copied on its own, for `CopyOptions::range`: `--offset`
+//! and `--length` copy a byte range of the source, such as a partition of
+//! a disk image, and `--dest-offset` writes it over the destination at an
+//! offset, as when repairing a damaged region, rather than into a file of
+//! its own. Verifying compares the range with what was written.
+
+use crate::vfs::{past_end, FileInfo, Filesystem};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// The bytes of a single source file to copy, and where they go.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct ByteRange {
+    /// Where the range starts in the source.
+    pub offset: u64,
+    /// How long it is, to the end of the source if not set.
+    pub length: Option<u64>,
+    /// Where to write it in the destination, which is then patched in
+    /// place and created only if missing. If not set, the destination is
+    /// replaced by a file holding just the range.
+    pub dest_offset: Option<u64>,
+}
+
+/// The error for the directory `path`, which has no bytes to copy.
+fn is_dir(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!(
+            "cannot copy a byte range of '{}': is a directory",
+            path.display()
+        ),
+    )
+}
+
+/// The source filesystem `inner` with files read as their `range`.
+#[derive(Debug)]
+pub(crate) struct Ranged {
+    inner: Arc<dyn Filesystem>,
+    range: ByteRange,
+}
+
+impl Ranged {
+    pub(crate) fn new(inner: Arc<dyn Filesystem>, range: ByteRange) -> Self {
+        Self { inner, range }
+    }
+}
+
+impl Filesystem for Ranged {
+    /// The source with the length of the range.
+    fn metadata(&self, path: &Path) -> io::Result<FileInfo> {
+        let mut info = self.inner.metadata(path)?;
+        if info.is_dir {
+            return Err(is_dir(path));
+        }
+        info.len = match (info.len, self.range.length) {
+            // Devices have no length, so the range is taken as given.
+            (0, length) => length.unwrap_or(0),
+            (len, _) if self.range.offset > len => return Err(past_end(path)),
+            (len, Some(length)) => length.min(len - self.range.offset),
+            (len, None) => len - self.range.offset,
+        };
+        Ok(info)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        self.inner.read_dir(path)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        let reader = self.inner.open_at(path, self.range.offset)?;
+        Ok(match self.range.length {
+            Some(length) => Box::new(reader.take(length)),
+            None => reader,
+        })
+    }
+
+    fn create(&self, _path: &Path) -> io::Result<Box<dyn Write + Send>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "byte ranges are only read",
+        ))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.inner.create_dir_all(path)
+    }
+}
+
+/// The destination filesystem `inner` with files written over at
+/// `offset`, and read back from there for verifying only as far as they
+/// were written.
+#[derive(Debug)]
+pub(crate) struct Patched {
+    inner: Arc<dyn Filesystem>,
+    offset: u64,
+    /// Bytes written by the last `create`.
+    written: Arc<AtomicU64>,
+}
+
+impl Patched {
+    pub(crate) fn new(inner: Arc<dyn Filesystem>, offset: u64) -> Self {
+        Self {
+            inner,
+            offset,
+            written: Arc::default(),
+        }
+    }
+}
+
+impl Filesystem for Patched {
+    fn metadata(&self, path: &Path) -> io::Result<FileInfo> {
+        self.inner.metadata(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        self.inner.read_dir(path)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        let reader = self.inner.open_at(path, self.offset)?;
+        Ok(Box::new(reader.take(self.written.load(Ordering::Relaxed))))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn Write + Send>> {
+        self.written.store(0, Ordering::Relaxed);
+        Ok(Box::new(Counted {
+            inner: self.inner.patch(path, self.offset)?,
+            written: Arc::clone(&self.written),
+        }))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.inner.create_dir_all(path)
+    }
+
+    fn set_modified(&self, path: &Path, modified: std::time::SystemTime) -> io::Result<()> {
+        self.inner.set_modified(path, modified)
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        self.inner.set_mode(path, mode)
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        self.inner.finish()
+    }
+}
+
+/// A writer that counts what goes through it.
+struct Counted {
+    inner: Box<dyn Write + Send>,
+    written: Arc<AtomicU64>,
+}
+
+impl Write for Counted {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
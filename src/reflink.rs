@@ -0,0 +1,119 @@
+//! Copy-on-write clones for `--reflink`.
+
+use crate::location::Location;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::str::FromStr;
+
+/// Whether to clone file data instead of copying it, mirroring
+/// `cp --reflink`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReflinkMode {
+    /// Clone where the filesystem supports it, copy bytes otherwise.
+    #[default]
+    Auto,
+    /// Fail instead of falling back to a byte copy.
+    Always,
+    /// Always copy bytes.
+    Never,
+}
+
+impl ReflinkMode {
+    /// The names of the modes, as `from_str` takes them and `Display`
+    /// writes them.
+    pub const NAMES: &[&str] = &["auto", "always", "never"];
+}
+
+impl FromStr for ReflinkMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            _ => Err(format!(
+                "invalid reflink mode '{}' (expected auto, always or never)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ReflinkMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Auto => "auto",
+            Self::Always => "always",
+            Self::Never => "never",
+        })
+    }
+}
+
+/// Creates `dest` as a copy-on-write clone of `source` and returns it
+/// opened for writing, so the remaining metadata steps can be applied.
+///
+/// On Linux this is the `FICLONE` ioctl (Btrfs, XFS, bcachefs, ...); on
+/// macOS it is `fclonefileat(2)` (APFS). Fails with the OS error when the
+/// filesystem or the pair of files does not support cloning, e.g. across
+/// filesystems or volumes.
+#[cfg(target_os = "linux")]
+pub(crate) fn reflink(source: &File, dest: &Location) -> io::Result<File> {
+    use std::os::unix::io::AsRawFd;
+
+    let dest = dest.create()?;
+    // SAFETY: FICLONE takes the source descriptor by value.
+    if unsafe { libc::ioctl(dest.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(dest)
+}
+
+#[cfg(target_os = "macos")]
+pub(crate) fn reflink(source: &File, dest: &Location) -> io::Result<File> {
+    use std::ffi::CString;
+    use std::fs::FileTimes;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::AsRawFd;
+    use std::time::SystemTime;
+
+    const CLONE_NOOWNERCOPY: u32 = 0x0002;
+
+    // clonefile(2) creates the destination itself and cannot replace one.
+    if dest.path().symlink_metadata().is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "clonefile cannot replace an existing file",
+        ));
+    }
+    let (dirfd, name) = match dest.parent() {
+        Some(parent) => (
+            parent.as_file().as_raw_fd(),
+            dest.path().file_name().unwrap_or_default(),
+        ),
+        None => (libc::AT_FDCWD, dest.path().as_os_str()),
+    };
+    let name = CString::new(name.as_bytes())?;
+    // SAFETY: `name` is NUL-terminated and both descriptors are open.
+    if unsafe { libc::fclonefileat(source.as_raw_fd(), dirfd, name.as_ptr(), CLONE_NOOWNERCOPY) }
+        < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+
+    // A clone carries the source's timestamps; give it the ones a fresh copy
+    // would have and let --preserve put them back if requested.
+    let dest = dest.open_with(libc::O_WRONLY)?;
+    let now = SystemTime::now();
+    dest.set_times(FileTimes::new().set_accessed(now).set_modified(now))?;
+    Ok(dest)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub(crate) fn reflink(_source: &File, _dest: &Location) -> io::Result<File> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "reflinks are not supported on this platform",
+    ))
+}
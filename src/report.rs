@@ -0,0 +1,283 @@
+//! The machine-readable report of a copy written by `--report`.
+//!
+//! The report is a JSON object in version `REPORT_VERSION` of this schema:
+//!
+//! ```text
+//! {
+//!   "version": 1,
+//!   "source": "src",                 // as given
+//!   "destination": "backup",         // as given
+//!   "checksum": "blake3",            // algorithm of the "checksum" fields
+//!   "duration_secs": 12.5,
+//!   "files_copied": 2,
+//!   "files_failed": 1,
+//!   "bytes_copied": 1048576,
+//!   "throughput": [524288, 524288],  // bytes per second over each second
+//!   "slowest_files": [               // at most TOP_FILES, slowest first
+//!     { "source": "src/a.txt", "bytes": 1048576, "duration_secs": 0.25 }
+//!   ],
+//!   "largest_files": [ … ],          // at most TOP_FILES, largest first
+//!   "verified_sample": {             // with --verify-sample only
+//!     "files": 2,                    // files copied
+//!     "verified": 1,                 // files read back, and their bytes
+//!     "bytes": 1048576,
+//!     "random": 1,                   // of those, picked at random
+//!     "mismatched": 0,
+//!     "bound": 0.95                  // share of the others at most corrupt
+//!   },                               // at 95% confidence, or null
+//!   "files": [
+//!     {
+//!       "source": "src/a.txt",
+//!       "dest": "backup/a.txt",
+//!       "status": "copied",          // copied, linked, skipped or failed
+//!       "bytes": 1048576,            // size of the source
+//!       "checksum": "af13…",         // hex digest of the source, or null
+//!       "verified": true,            // read back and compared
+//!       "retries": 0,
+//!       "repairs": 0,                // copies made again after --verify
+//!                                    // found them different
+//!       "error": null,               // message of a failed file
+//!       "duration_secs": 0.25
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! Fields may be added within a version; any other change increments it.
+//! Paths that are not valid Unicode are written lossily.
+
+use crate::checksum::Digest;
+use crate::{CopyOptions, CopyStats, VerifiedSample};
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Version of the report schema written by this release.
+pub const REPORT_VERSION: u32 = 1;
+
+/// How many of the slowest and largest files a copy keeps track of.
+pub const TOP_FILES: usize = 5;
+
+/// What became of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[serde(rename_all = "lowercase")]
+pub enum FileStatus {
+    /// Its data was copied, cloned or updated in place.
+    Copied,
+    /// It was hard-linked or symlinked instead of copied.
+    Linked,
+    /// A resumed copy found it already copied.
+    Skipped,
+    /// It could not be copied.
+    Failed,
+}
+
+impl fmt::Display for FileStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Copied => "copied",
+            Self::Linked => "linked",
+            Self::Skipped => "skipped",
+            Self::Failed => "failed",
+        })
+    }
+}
+
+/// The outcome of one file, collected in `CopyStats::files` with
+/// `CopyOptions::record_files`.
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+pub struct FileRecord {
+    #[serde(serialize_with = "lossy")]
+    pub source: PathBuf,
+    #[serde(serialize_with = "lossy")]
+    pub dest: PathBuf,
+    pub status: FileStatus,
+    /// Size of the source, or 0 if it could not be read.
+    pub bytes: u64,
+    /// Checksum of the source, when the copy computed one.
+    #[serde(serialize_with = "hex")]
+    pub checksum: Option<Digest>,
+    /// Whether the copy was read back and matched the source.
+    pub verified: bool,
+    pub retries: u32,
+    /// Times the copy was made again after it did not match the source.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub repairs: u32,
+    pub error: Option<String>,
+    #[serde(rename = "duration_secs", serialize_with = "secs")]
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "crate::serial::secs"))]
+    pub duration: Duration,
+}
+
+impl FileRecord {
+    /// The record of a file whose last attempt ended with `result`, given
+    /// the stats of that attempt.
+    pub(crate) fn new(
+        source: &Path,
+        dest: &Path,
+        result: &io::Result<std::fs::Metadata>,
+        attempt_stats: &CopyStats,
+        options: &CopyOptions,
+        retries: u32,
+        duration: Duration,
+    ) -> Self {
+        let linked = attempt_stats.files_linked + attempt_stats.files_symlinked > 0;
+        let (status, bytes, error) = match result {
+            Ok(metadata) if linked => (FileStatus::Linked, metadata.len(), None),
+            Ok(metadata) => (FileStatus::Copied, attempt_stats.file_len(metadata), None),
+            Err(err) => (FileStatus::Failed, 0, Some(err.to_string())),
+        };
+        Self {
+            source: source.to_path_buf(),
+            dest: dest.to_path_buf(),
+            status,
+            bytes,
+            checksum: attempt_stats
+                .checksums
+                .last()
+                .map(|(_, digest)| digest.clone()),
+            verified: options.verify && result.is_ok(),
+            retries,
+            repairs: 0,
+            error,
+            duration,
+        }
+    }
+
+    /// The record of a file skipped by a resumed copy.
+    pub(crate) fn skipped(
+        source: &Path,
+        dest: &Path,
+        bytes: u64,
+        checksum: Option<Digest>,
+    ) -> Self {
+        Self {
+            source: source.to_path_buf(),
+            dest: dest.to_path_buf(),
+            status: FileStatus::Skipped,
+            bytes,
+            checksum,
+            verified: false,
+            retries: 0,
+            repairs: 0,
+            error: None,
+            duration: Duration::ZERO,
+        }
+    }
+}
+
+/// A file among the slowest or largest of a copy, in
+/// `CopyStats::slowest_files` and `CopyStats::largest_files`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+pub struct FileTiming {
+    #[serde(serialize_with = "lossy")]
+    pub source: PathBuf,
+    pub bytes: u64,
+    #[serde(rename = "duration_secs", serialize_with = "secs")]
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "crate::serial::secs"))]
+    pub duration: Duration,
+}
+
+/// Adds `file` to `top`, which holds the `TOP_FILES` files greatest by
+/// `key`, greatest first.
+pub(crate) fn keep_top<K: Ord>(
+    top: &mut Vec<FileTiming>,
+    file: FileTiming,
+    key: impl Fn(&FileTiming) -> K,
+) {
+    let at = top.partition_point(|kept| key(kept) >= key(&file));
+    if at < TOP_FILES {
+        top.insert(at, file);
+        top.truncate(TOP_FILES);
+    }
+}
+
+/// The report of a finished copy.
+#[derive(Debug, Serialize)]
+pub struct Report<'a> {
+    pub version: u32,
+    #[serde(serialize_with = "lossy")]
+    pub source: &'a Path,
+    #[serde(serialize_with = "lossy")]
+    pub destination: &'a Path,
+    pub checksum: String,
+    #[serde(rename = "duration_secs", serialize_with = "secs")]
+    pub duration: Duration,
+    pub files_copied: usize,
+    pub files_failed: usize,
+    pub bytes_copied: u64,
+    pub throughput: &'a [u64],
+    pub slowest_files: &'a [FileTiming],
+    pub largest_files: &'a [FileTiming],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verified_sample: Option<SampleReport<'a>>,
+    pub files: &'a [FileRecord],
+}
+
+/// The files read back by `--verify-sample`, with the share of the others
+/// at most corrupt.
+#[derive(Debug, Serialize)]
+pub struct SampleReport<'a> {
+    #[serde(flatten)]
+    pub sample: &'a VerifiedSample,
+    pub bound: Option<f64>,
+}
+
+impl<'a> Report<'a> {
+    /// The report of copying `source` to `destination` with `options`,
+    /// which had `record_files` set.
+    pub fn new(
+        source: &'a Path,
+        destination: &'a Path,
+        options: &CopyOptions,
+        stats: &'a CopyStats,
+    ) -> Self {
+        Self {
+            version: REPORT_VERSION,
+            source,
+            destination,
+            checksum: options.checksum.to_string(),
+            duration: stats.time_taken,
+            files_copied: stats.files_copied,
+            files_failed: stats.failures.len(),
+            bytes_copied: stats.bytes_copied,
+            throughput: &stats.throughput,
+            slowest_files: &stats.slowest_files,
+            largest_files: &stats.largest_files,
+            verified_sample: stats.verified_sample.as_ref().map(|sample| SampleReport {
+                sample,
+                bound: sample.bound(),
+            }),
+            files: &stats.files,
+        }
+    }
+
+    /// Writes the report to `path` as pretty-printed JSON.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(&mut out, self)?;
+        writeln!(out)?;
+        out.flush()
+    }
+}
+
+fn lossy<P: AsRef<Path>, S: Serializer>(path: &P, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&path.as_ref().to_string_lossy())
+}
+
+fn hex<S: Serializer>(digest: &Option<Digest>, serializer: S) -> Result<S::Ok, S::Error> {
+    match digest {
+        Some(digest) => serializer.serialize_str(&digest.to_string()),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn secs<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
@@ -0,0 +1,154 @@
+//! Progress callbacks for applications that draw their own progress, such
+//! as GUIs, instead of the terminal bars.
+
+use crate::progress::{ProgressThrottle, Ticker};
+use crate::ui::ProgressBar;
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Least interval between calls of `ProgressReporter::on_bytes`, when the
+/// throttle allows any.
+const MIN_BYTES_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Receives the progress of a copy through `CopyOptions::reporter`.
+///
+/// Every method does nothing by default. They are called from the threads
+/// doing the copy, so they should return quickly. Set
+/// `CopyOptions::progress` to `ProgressMode::Hidden` to draw no bars
+/// alongside.
+pub trait ProgressReporter: fmt::Debug + Send + Sync {
+    /// The scan of a recursive source found `files` files of `bytes` bytes
+    /// in total. Copying starts before the scan is done.
+    fn on_scan(&self, files: u64, bytes: u64) {
+        let _ = (files, bytes);
+    }
+
+    /// Copying `source` to `dest` started.
+    fn on_file_start(&self, source: &Path, dest: &Path) {
+        let _ = (source, dest);
+    }
+
+    /// `done` of `total` bytes have been copied; called at the interval of
+    /// `CopyOptions::progress_throttle` (50 ms by default) while the copy
+    /// runs, and once more as it ends. `total` grows while a recursive
+    /// source is scanned.
+    fn on_bytes(&self, done: u64, total: u64) {
+        let _ = (done, total);
+    }
+
+    /// `source` of `bytes` bytes is at `dest`, copied, linked or found
+    /// already copied by a resumed copy.
+    fn on_file_done(&self, source: &Path, dest: &Path, bytes: u64) {
+        let _ = (source, dest, bytes);
+    }
+
+    /// `source` could not be copied, or, without a source, the whole copy
+    /// failed.
+    fn on_error(&self, source: Option<&Path>, err: &dyn Error) {
+        let _ = (source, err);
+    }
+}
+
+/// A call of a `ProgressReporter` as a value, for APIs that hand the
+/// progress of a copy over as a sequence of events.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "event", rename_all = "snake_case"))]
+pub enum CopyEvent {
+    /// See `ProgressReporter::on_scan`.
+    Scanned { files: u64, bytes: u64 },
+    /// See `ProgressReporter::on_file_start`.
+    FileStarted { source: PathBuf, dest: PathBuf },
+    /// See `ProgressReporter::on_bytes`.
+    Bytes { done: u64, total: u64 },
+    /// See `ProgressReporter::on_file_done`.
+    FileDone {
+        source: PathBuf,
+        dest: PathBuf,
+        bytes: u64,
+    },
+    /// See `ProgressReporter::on_error`.
+    Failed {
+        source: Option<PathBuf>,
+        message: String,
+    },
+}
+
+/// Turns the calls of a `ProgressReporter` into `CopyEvent`s handed to
+/// `send`.
+pub(crate) struct EventReporter<F>(pub(crate) F);
+
+impl<F> fmt::Debug for EventReporter<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventReporter").finish_non_exhaustive()
+    }
+}
+
+impl<F: Fn(CopyEvent) + Send + Sync> ProgressReporter for EventReporter<F> {
+    fn on_scan(&self, files: u64, bytes: u64) {
+        (self.0)(CopyEvent::Scanned { files, bytes });
+    }
+
+    fn on_file_start(&self, source: &Path, dest: &Path) {
+        (self.0)(CopyEvent::FileStarted {
+            source: source.to_path_buf(),
+            dest: dest.to_path_buf(),
+        });
+    }
+
+    fn on_bytes(&self, done: u64, total: u64) {
+        (self.0)(CopyEvent::Bytes { done, total });
+    }
+
+    fn on_file_done(&self, source: &Path, dest: &Path, bytes: u64) {
+        (self.0)(CopyEvent::FileDone {
+            source: source.to_path_buf(),
+            dest: dest.to_path_buf(),
+            bytes,
+        });
+    }
+
+    fn on_error(&self, source: Option<&Path>, err: &dyn Error) {
+        (self.0)(CopyEvent::Failed {
+            source: source.map(Path::to_path_buf),
+            message: err.to_string(),
+        });
+    }
+}
+
+/// Calls `on_bytes` of a reporter with the position of a bar until
+/// dropped, and once more then.
+pub(crate) struct BytesTicker {
+    reporter: Arc<dyn ProgressReporter>,
+    pb: ProgressBar,
+    _ticker: Ticker,
+}
+
+impl Drop for BytesTicker {
+    fn drop(&mut self) {
+        report_bytes(self.reporter.as_ref(), &self.pb);
+    }
+}
+
+fn report_bytes(reporter: &dyn ProgressReporter, pb: &ProgressBar) {
+    reporter.on_bytes(pb.position(), pb.length().unwrap_or(0));
+}
+
+/// Calls `on_bytes` of `reporter` with the position of `pb` every
+/// `throttle.interval` until the ticker is dropped.
+pub(crate) fn ticker(
+    reporter: &Arc<dyn ProgressReporter>,
+    pb: &ProgressBar,
+    throttle: ProgressThrottle,
+) -> BytesTicker {
+    let ticking = Arc::clone(reporter);
+    let interval = throttle.interval.max(MIN_BYTES_INTERVAL);
+    BytesTicker {
+        reporter: Arc::clone(reporter),
+        pb: pb.clone(),
+        _ticker: Ticker::start(pb, interval, move |pb| report_bytes(ticking.as_ref(), pb)),
+    }
+}
@@ -0,0 +1,116 @@
+//! Continuing interrupted copies for `--resume`.
+
+use crate::checksum::{ChecksumAlgo, Digest};
+use crate::location::Location;
+use crate::verify::read_chunk;
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom};
+
+/// Bytes hashed from each file per step.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Whether an existing destination can be continued.
+pub(crate) enum Partial {
+    /// There is nothing to continue from.
+    None,
+    /// The destination holds the first `len` bytes of the source. It is
+    /// open for writing and both files are positioned at `len`.
+    Prefix(File, u64),
+    /// The destination differs from the start of the source, or is longer
+    /// than it, and has to be copied again.
+    Mismatch,
+}
+
+/// Checks whether `dest` holds a prefix of `source`, `len` bytes long, by
+/// hashing the overlapping range of both files with `algo`.
+///
+/// An empty destination is not a prefix worth continuing. Neither is one
+/// with other hard links, which is removed so that appending to it does not
+/// change the other names. When no prefix is found the source is left at
+/// its start.
+pub(crate) fn partial(
+    source: &File,
+    dest: &Location,
+    len: u64,
+    algo: ChecksumAlgo,
+) -> io::Result<Partial> {
+    let dst_file = match dest.open_rw() {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Partial::None),
+        Err(err) => return Err(err),
+    };
+    let metadata = dst_file.metadata()?;
+    if !metadata.is_file() || metadata.len() == 0 {
+        return Ok(Partial::None);
+    }
+    #[cfg(unix)]
+    if std::os::unix::fs::MetadataExt::nlink(&metadata) > 1 {
+        drop(dst_file);
+        dest.remove()?;
+        return Ok(Partial::None);
+    }
+    let prefix = metadata.len();
+    if prefix > len {
+        return Ok(Partial::Mismatch);
+    }
+
+    let source_hash = hash_prefix(source, prefix, algo)?;
+    let dest_hash = hash_prefix(&dst_file, prefix, algo)?;
+    if source_hash != dest_hash {
+        { source }.seek(SeekFrom::Start(0))?;
+        return Ok(Partial::Mismatch);
+    }
+    Ok(Partial::Prefix(dst_file, prefix))
+}
+
+/// Moves `dest`, left partly written by an interrupted copy, to its `.part`
+/// name, so that it does not pass for a complete copy; `resume` and
+/// `--partial` continue it from there. A destination nothing was written
+/// to is removed instead. Returns where the data was left, if anywhere.
+pub(crate) fn set_aside(dest: &Location) -> io::Result<Option<Location>> {
+    let len = match dest.open() {
+        Ok(file) => file.metadata()?.len(),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    if len == 0 {
+        dest.remove()?;
+        return Ok(None);
+    }
+    let part = dest.part();
+    dest.rename(&part)?;
+    Ok(Some(part))
+}
+
+/// Moves the `.part` file `set_aside` left back to `dest` for `--resume` to
+/// continue, unless `dest` exists.
+pub(crate) fn restore(dest: &Location) -> io::Result<()> {
+    match dest.open() {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Ok(_) => return Ok(()),
+        Err(err) => return Err(err),
+    }
+    match dest.part().rename(dest) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        renamed => renamed,
+    }
+}
+
+/// Hashes the first `len` bytes of `file` from its start, leaving it
+/// positioned after them.
+fn hash_prefix(mut file: &File, len: u64, algo: ChecksumAlgo) -> io::Result<Digest> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut hasher = algo.hasher();
+    let mut buf = vec![0; len.min(CHUNK_SIZE as u64) as usize];
+    let mut left = len;
+    while left > 0 {
+        let step = left.min(buf.len() as u64) as usize;
+        let n = read_chunk(file, &mut buf[..step])?;
+        if n < step {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        hasher.update(&buf[..n]);
+        left -= n as u64;
+    }
+    Ok(hasher.finish())
+}
@@ -0,0 +1,77 @@
+//! Retrying files that fail with transient I/O errors for `--retries`.
+
+use crate::changes::{ChangePolicy, SourceChanged};
+use crate::context;
+use crate::{CopyOptions, ErrorAction, ErrorClass};
+use std::io;
+use std::time::Duration;
+
+/// Whether a file that failed with `err` on retry number `attempt` (from
+/// 0) is copied again.
+pub(crate) fn should_retry(err: &io::Error, attempt: u32, options: &CopyOptions) -> bool {
+    if SourceChanged::is(err) {
+        options.source_changes == ChangePolicy::Retry && attempt < options.retries.max(1)
+    } else {
+        match options.on_error.action(ErrorClass::of_io(err)) {
+            Some(ErrorAction::Retry) => attempt < options.retries.max(1),
+            Some(_) => false,
+            None => attempt < options.retries && is_transient(err),
+        }
+    }
+}
+
+/// Whether `err` is the kind of failure a network filesystem or a flaky
+/// USB device may not repeat: timeouts, I/O errors and stale handles.
+pub(crate) fn is_transient(err: &io::Error) -> bool {
+    let err = context::os_error(err);
+    if err.kind() == io::ErrorKind::TimedOut {
+        return true;
+    }
+    #[cfg(unix)]
+    {
+        matches!(
+            err.raw_os_error(),
+            Some(libc::EIO | libc::ETIMEDOUT | libc::ESTALE)
+        )
+    }
+    #[cfg(windows)]
+    {
+        use windows_sys::Win32::Foundation::{
+            ERROR_CRC, ERROR_NETNAME_DELETED, ERROR_SEM_TIMEOUT, ERROR_UNEXP_NET_ERR,
+        };
+        matches!(
+            err.raw_os_error().map(|code| code as u32),
+            Some(ERROR_CRC | ERROR_NETNAME_DELETED | ERROR_SEM_TIMEOUT | ERROR_UNEXP_NET_ERR)
+        )
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        false
+    }
+}
+
+/// The wait before retry number `attempt` (from 0): `delay`, doubled for
+/// every earlier retry.
+pub(crate) fn backoff(delay: Duration, attempt: u32) -> Duration {
+    delay.saturating_mul(1 << attempt.min(16))
+}
+
+/// Parses a duration such as `500ms`, `2s` or `1m`; a bare number is in
+/// seconds and may have a fraction, e.g. `0.5`.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let invalid = || format!("invalid duration '{}' (expected e.g. 500ms, 2s or 1m)", s);
+    let trimmed = s.trim();
+    let split = trimmed
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(trimmed.len());
+    let (number, suffix) = trimmed.split_at(split);
+
+    let unit = match suffix.to_ascii_lowercase().as_str() {
+        "ms" => 0.001,
+        "" | "s" => 1.0,
+        "m" => 60.0,
+        _ => return Err(invalid()),
+    };
+    let value: f64 = number.parse().map_err(|_| invalid())?;
+    Duration::try_from_secs_f64(value * unit).map_err(|_| invalid())
+}
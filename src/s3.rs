@@ -0,0 +1,928 @@
+//! Sources and destinations on Amazon S3 and compatible object stores,
+//! for `s3://bucket/key` arguments, with the `s3` feature.
+//!
+//! Keys are paths with `/` between their components, and a key that
+//! others start with followed by `/` is a directory, as in the S3
+//! console. Objects are read with several ranged requests in flight and
+//! written as multipart uploads whose parts go up in parallel. Every part
+//! carries its MD5 for the store to check, and the ETag the store returns
+//! is compared with the MD5 of what was sent or read wherever it is one.
+
+use crate::vfs::{FileInfo, Filesystem};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use sha2::{Digest as _, Sha256};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::{self, Write as _};
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+use std::{env, fs, mem};
+
+/// Bytes asked for per ranged download.
+const RANGE_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Parts a multipart upload is made of before their size doubles, so
+/// that the 10,000 parts S3 allows hold objects of up to its 5 TiB.
+const PARTS_PER_SIZE: usize = 1000;
+
+/// Splits an `s3://bucket/key` argument into the bucket and the key,
+/// without the trailing slash of a prefix. The key is empty for the whole
+/// bucket.
+pub fn parse_s3(arg: &str) -> Option<(&str, &str)> {
+    let rest = arg.strip_prefix("s3://")?;
+    let (bucket, key) = rest.split_once('/').unwrap_or((rest, ""));
+    (!bucket.is_empty()).then_some((bucket, key.trim_end_matches('/')))
+}
+
+/// Where an `S3Fs` connects and with which credentials.
+#[derive(Clone)]
+#[non_exhaustive]
+pub struct S3Config {
+    pub region: String,
+    /// The URL of a store other than AWS, such as MinIO, whose buckets
+    /// are then addressed in the path.
+    pub endpoint: Option<String>,
+    pub access_key: String,
+    pub secret_key: String,
+    pub session_token: Option<String>,
+    /// Bytes per part of multipart uploads, at least the 5 MiB S3 asks
+    /// for but of the last part. Doubled every 1,000 parts.
+    pub part_size: usize,
+    /// Requests in flight at once while reading or writing an object.
+    pub concurrency: usize,
+}
+
+impl fmt::Debug for S3Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("S3Config")
+            .field("region", &self.region)
+            .field("endpoint", &self.endpoint)
+            .field("access_key", &self.access_key)
+            .field("part_size", &self.part_size)
+            .field("concurrency", &self.concurrency)
+            .finish_non_exhaustive()
+    }
+}
+
+impl S3Config {
+    /// AWS in `us-east-1` with the given keys, 8 MiB parts and 4 requests
+    /// in flight.
+    pub fn new(access_key: impl Into<String>, secret_key: impl Into<String>) -> Self {
+        Self {
+            region: "us-east-1".to_string(),
+            endpoint: None,
+            access_key: access_key.into(),
+            secret_key: secret_key.into(),
+            session_token: None,
+            part_size: 8 * 1024 * 1024,
+            concurrency: 4,
+        }
+    }
+
+    /// The configuration the AWS CLI would use: credentials from
+    /// `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN`
+    /// or else the `AWS_PROFILE` (or default) profile of
+    /// `~/.aws/credentials`, the region from `AWS_REGION`,
+    /// `AWS_DEFAULT_REGION` or `~/.aws/config`, and the endpoint from
+    /// `AWS_ENDPOINT_URL_S3` or `AWS_ENDPOINT_URL`.
+    pub fn from_env() -> io::Result<Self> {
+        let var = |name: &str| env::var(name).ok().filter(|value| !value.is_empty());
+        let profile = var("AWS_PROFILE").unwrap_or_else(|| "default".to_string());
+        let home = var("HOME").or_else(|| var("USERPROFILE"));
+        let ini = |file: &str, section: &str, key: &str| {
+            let path = Path::new(home.as_deref()?).join(".aws").join(file);
+            ini_value(&fs::read_to_string(path).ok()?, section, key)
+        };
+        let credentials = |key: &str| ini("credentials", &profile, key);
+        let (access_key, secret_key, session_token) =
+            match (var("AWS_ACCESS_KEY_ID"), var("AWS_SECRET_ACCESS_KEY")) {
+                (Some(access_key), Some(secret_key)) => {
+                    (access_key, secret_key, var("AWS_SESSION_TOKEN"))
+                }
+                _ => match (
+                    credentials("aws_access_key_id"),
+                    credentials("aws_secret_access_key"),
+                ) {
+                    (Some(access_key), Some(secret_key)) => {
+                        (access_key, secret_key, credentials("aws_session_token"))
+                    }
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::NotFound,
+                            "no S3 credentials: set AWS_ACCESS_KEY_ID and \
+                             AWS_SECRET_ACCESS_KEY or add them to ~/.aws/credentials",
+                        ))
+                    }
+                },
+            };
+        let section = match profile.as_str() {
+            "default" => profile.clone(),
+            profile => format!("profile {}", profile),
+        };
+        let mut config = Self::new(access_key, secret_key);
+        config.session_token = session_token;
+        config.endpoint = var("AWS_ENDPOINT_URL_S3").or_else(|| var("AWS_ENDPOINT_URL"));
+        if let Some(region) = var("AWS_REGION")
+            .or_else(|| var("AWS_DEFAULT_REGION"))
+            .or_else(|| ini("config", &section, "region"))
+        {
+            config.region = region;
+        }
+        Ok(config)
+    }
+}
+
+/// The value of `key` in the `[section]` of an INI file.
+fn ini_value(text: &str, section: &str, key: &str) -> Option<String> {
+    let mut current = None;
+    for line in text.lines().map(str::trim) {
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            current = Some(name.trim());
+        } else if current == Some(section) {
+            match line.split_once('=') {
+                Some((name, value)) if name.trim() == key => return Some(value.trim().to_string()),
+                _ => {}
+            }
+        }
+    }
+    None
+}
+
+/// A bucket as a `Filesystem`, for `CopyOptions::source_fs` or
+/// `CopyOptions::dest_fs`.
+///
+/// Directories exist as long as keys are under them, so creating one does
+/// nothing. Objects are written when the copy of a file completes; one
+/// that fails leaves no object behind. Modification times cannot be set.
+#[derive(Debug)]
+pub struct S3Fs {
+    client: Arc<Client>,
+}
+
+impl S3Fs {
+    pub fn new(bucket: &str, config: S3Config) -> Self {
+        let agent = ureq::AgentBuilder::new()
+            .timeout_connect(Duration::from_secs(30))
+            .timeout_read(Duration::from_secs(300))
+            .build();
+        Self {
+            client: Arc::new(Client {
+                bucket: bucket.to_string(),
+                config,
+                agent,
+            }),
+        }
+    }
+}
+
+impl Filesystem for S3Fs {
+    fn metadata(&self, path: &Path) -> io::Result<FileInfo> {
+        let key = key(path);
+        let dir = FileInfo {
+            is_dir: true,
+            len: 0,
+            modified: None,
+            mode: None,
+        };
+        if key.is_empty() {
+            return Ok(dir);
+        }
+        match self.client.head(&key) {
+            Ok(head) => Ok(FileInfo {
+                is_dir: false,
+                len: head.len,
+                modified: head.modified,
+                mode: None,
+            }),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let (keys, prefixes) = self.client.list(&format!("{}/", key), false, Some(1))?;
+                match keys.is_empty() && prefixes.is_empty() {
+                    true => Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("no key or prefix '{}' in {}", key, self.client.bucket),
+                    )),
+                    false => Ok(dir),
+                }
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let prefix = match key(path) {
+            key if key.is_empty() => key,
+            key => format!("{}/", key),
+        };
+        let (keys, prefixes) = self.client.list(&prefix, true, None)?;
+        // An empty object named after the prefix is a folder made in the
+        // console, not an entry.
+        let names = keys
+            .iter()
+            .filter(|key| **key != prefix)
+            .chain(&prefixes)
+            .map(|key| key[prefix.len()..].trim_end_matches('/'))
+            .filter(|name| !name.is_empty());
+        Ok(names.map(|name| path.join(name)).collect())
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        let key = key(path);
+        let head = self.client.head(&key)?;
+        Ok(Box::new(S3Reader {
+            client: self.client.clone(),
+            md5: head.md5.map(|md5| (Md5::new(), md5)),
+            key,
+            etag: head.etag,
+            len: head.len,
+            next: 0,
+            pending: VecDeque::new(),
+            buffer: Vec::new(),
+            read: 0,
+        }))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn Write + Send>> {
+        Ok(Box::new(S3Writer {
+            client: self.client.clone(),
+            key: key(path),
+            buffer: Vec::new(),
+            part_size: self.client.config.part_size,
+            upload: None,
+            done: false,
+        }))
+    }
+
+    fn create_dir_all(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The key of `path`, its components joined with `/`.
+fn key(path: &Path) -> String {
+    let components: Vec<_> = path
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy()),
+            _ => None,
+        })
+        .collect();
+    components.join("/")
+}
+
+/// What a HEAD request says about an object.
+struct Head {
+    len: u64,
+    modified: Option<SystemTime>,
+    etag: Option<String>,
+    /// The MD5 of the object, when its ETag is one.
+    md5: Option<String>,
+}
+
+/// Signed requests to one bucket.
+struct Client {
+    bucket: String,
+    config: S3Config,
+    agent: ureq::Agent,
+}
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Client")
+            .field("bucket", &self.bucket)
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Client {
+    /// The scheme and authority requests go to, the host they are signed
+    /// for and the path of `key` on it: the bucket is in the host on AWS,
+    /// unless its name has dots that would not match the certificate, and
+    /// in the path elsewhere.
+    fn location(&self, key: &str) -> (String, String, String) {
+        let key = encode(key, false);
+        let path_style = |base: String| {
+            let host = base.split_once("://").map_or(&*base, |(_, host)| host);
+            let host = match base.starts_with("https://") {
+                true => host.strip_suffix(":443").unwrap_or(host),
+                false => host.strip_suffix(":80").unwrap_or(host),
+            };
+            let host = host.to_string();
+            let path = match key.is_empty() {
+                true => format!("/{}", self.bucket),
+                false => format!("/{}/{}", self.bucket, key),
+            };
+            (base, host, path)
+        };
+        match &self.config.endpoint {
+            Some(endpoint) => {
+                let endpoint = endpoint.trim_end_matches('/');
+                let authority_end = endpoint
+                    .find("://")
+                    .and_then(|scheme| Some(scheme + 3 + endpoint[scheme + 3..].find('/')?))
+                    .unwrap_or(endpoint.len());
+                path_style(endpoint[..authority_end].to_string())
+            }
+            None if self.bucket.contains('.') => {
+                path_style(format!("https://s3.{}.amazonaws.com", self.config.region))
+            }
+            None => {
+                let host = format!("{}.s3.{}.amazonaws.com", self.bucket, self.config.region);
+                (format!("https://{}", host), host, format!("/{}", key))
+            }
+        }
+    }
+
+    /// Sends a request for `key` with `body`, signing it and the headers
+    /// given with lowercase names. Error responses become errors.
+    fn send(
+        &self,
+        method: &str,
+        key: &str,
+        query: &[(&str, &str)],
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> io::Result<ureq::Response> {
+        let (base, host, path) = self.location(key);
+        let mut pairs: Vec<_> = query
+            .iter()
+            .map(|(name, value)| (encode(name, true), encode(value, true)))
+            .collect();
+        pairs.sort();
+        let query = pairs
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join("&");
+        let amz_date = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let mut signed = BTreeMap::new();
+        signed.insert("host".to_string(), host);
+        signed.insert(
+            "x-amz-content-sha256".to_string(),
+            hex(&Sha256::digest(body)),
+        );
+        signed.insert("x-amz-date".to_string(), amz_date.clone());
+        if let Some(token) = &self.config.session_token {
+            signed.insert("x-amz-security-token".to_string(), token.clone());
+        }
+        for (name, value) in headers {
+            signed.insert(name.to_string(), value.to_string());
+        }
+        let authorization = authorization(&self.config, method, &path, &query, &signed, &amz_date);
+
+        let url = match query.is_empty() {
+            true => format!("{}{}", base, path),
+            false => format!("{}{}?{}", base, path, query),
+        };
+        let mut request = self
+            .agent
+            .request(method, &url)
+            .set("authorization", &authorization);
+        // `ureq` sends the host it was given.
+        for (name, value) in signed.iter().filter(|(name, _)| *name != "host") {
+            request = request.set(name, value);
+        }
+        let response = match method {
+            "PUT" | "POST" => request.send_bytes(body),
+            _ => request.call(),
+        };
+        response.map_err(|err| match err {
+            ureq::Error::Status(status, response) => status_error(status, response),
+            ureq::Error::Transport(err) => io::Error::other(format!("S3 request failed: {}", err)),
+        })
+    }
+
+    fn head(&self, key: &str) -> io::Result<Head> {
+        let response = self.send("HEAD", key, &[], &[], &[])?;
+        Ok(Head {
+            len: response
+                .header("content-length")
+                .and_then(|len| len.parse().ok())
+                .unwrap_or(0),
+            modified: response
+                .header("last-modified")
+                .and_then(|date| DateTime::parse_from_rfc2822(date).ok())
+                .map(SystemTime::from),
+            md5: response
+                .header("etag")
+                .filter(|_| md5_etags(&response))
+                .and_then(md5_etag)
+                .filter(|etag| !etag.contains('-')),
+            etag: response.header("etag").map(str::to_string),
+        })
+    }
+
+    /// The keys and the common prefixes under `prefix`, all of them or the
+    /// first `max_keys`, split at the next `/` with `delimited`.
+    fn list(
+        &self,
+        prefix: &str,
+        delimited: bool,
+        max_keys: Option<usize>,
+    ) -> io::Result<(Vec<String>, Vec<String>)> {
+        let (mut keys, mut prefixes) = (Vec::new(), Vec::new());
+        let max_keys = max_keys.map(|max| max.to_string());
+        let mut token: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", prefix)];
+            if delimited {
+                query.push(("delimiter", "/"));
+            }
+            if let Some(max_keys) = &max_keys {
+                query.push(("max-keys", max_keys));
+            }
+            if let Some(token) = &token {
+                query.push(("continuation-token", token));
+            }
+            let body = self.send("GET", "", &query, &[], &[])?.into_string()?;
+            for contents in elements(&body, "Contents") {
+                keys.extend(element(contents, "Key"));
+            }
+            for common in elements(&body, "CommonPrefixes") {
+                prefixes.extend(element(common, "Prefix"));
+            }
+            token = element(&body, "NextContinuationToken");
+            if max_keys.is_some() || token.is_none() {
+                return Ok((keys, prefixes));
+            }
+        }
+    }
+
+    /// Bytes `start..end` of `key`, failing if the object is no longer
+    /// the one with `etag`.
+    fn get_range(
+        &self,
+        key: &str,
+        etag: Option<&str>,
+        start: u64,
+        end: u64,
+    ) -> io::Result<Vec<u8>> {
+        let range = format!("bytes={}-{}", start, end - 1);
+        let mut headers = vec![("range", range.as_str())];
+        if let Some(etag) = etag {
+            headers.push(("if-match", etag));
+        }
+        let response = self.send("GET", key, &[], &headers, &[])?;
+        if response.status() != 206 {
+            return Err(io::Error::other(format!(
+                "S3 ignored the range asked for of '{}'",
+                key
+            )));
+        }
+        let mut data = Vec::with_capacity((end - start) as usize);
+        response
+            .into_reader()
+            .take(end - start)
+            .read_to_end(&mut data)?;
+        if data.len() as u64 != end - start {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("'{}' ended early", key),
+            ));
+        }
+        Ok(data)
+    }
+
+    /// Uploads `data`, whose MD5 is `md5`, as `key` or a part of its
+    /// upload, returning the ETag.
+    fn put(
+        &self,
+        key: &str,
+        query: &[(&str, &str)],
+        data: &[u8],
+        md5: &[u8],
+    ) -> io::Result<String> {
+        let content_md5 = base64(md5);
+        let response = self.send("PUT", key, query, &[("content-md5", &content_md5)], data)?;
+        Ok(response.header("etag").unwrap_or_default().to_string())
+    }
+}
+
+/// Whether the ETag of the object of `response` is made of MD5s, as it
+/// is unless the object is encrypted with KMS or a key of the client's.
+fn md5_etags(response: &ureq::Response) -> bool {
+    let encryption = response.header("x-amz-server-side-encryption");
+    matches!(encryption, None | Some("AES256"))
+        && response
+            .header("x-amz-server-side-encryption-customer-algorithm")
+            .is_none()
+}
+
+/// `etag` unquoted if it is an MD5, or the MD5 of the MD5s of the parts
+/// of a multipart upload followed by `-` and their count.
+fn md5_etag(etag: &str) -> Option<String> {
+    let etag = etag.trim_matches('"');
+    let (md5, parts) = etag.split_once('-').unwrap_or((etag, "1"));
+    let is_md5 = md5.len() == 32 && md5.bytes().all(|b| b.is_ascii_hexdigit());
+    (is_md5 && parts.bytes().all(|b| b.is_ascii_digit())).then(|| etag.to_ascii_lowercase())
+}
+
+/// Fails unless `etag`, when there is one, is `expected`.
+fn check_etag(key: &str, etag: Option<String>, expected: &str) -> io::Result<()> {
+    match etag {
+        Some(etag) if etag != expected => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("'{}' was stored with ETag {}, not {}", key, etag, expected),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// The error of an S3 error response, with the code and message of its
+/// body when it has one.
+fn status_error(status: u16, response: ureq::Response) -> io::Error {
+    let kind = match status {
+        404 => io::ErrorKind::NotFound,
+        403 => io::ErrorKind::PermissionDenied,
+        _ => io::ErrorKind::Other,
+    };
+    let body = response.into_string().unwrap_or_default();
+    match (element(&body, "Code"), element(&body, "Message")) {
+        (Some(code), Some(message)) => io::Error::new(kind, format!("{}: {}", code, message)),
+        (Some(code), None) => io::Error::new(kind, code),
+        _ if kind != io::ErrorKind::Other => kind.into(),
+        _ => io::Error::other(format!("S3 answered with status {}", status)),
+    }
+}
+
+/// The `Authorization` header of a request, signed with AWS Signature
+/// Version 4. `path` and `query` are encoded already; all `headers` are
+/// signed and hold `x-amz-content-sha256`.
+pub(crate) fn authorization(
+    config: &S3Config,
+    method: &str,
+    path: &str,
+    query: &str,
+    headers: &BTreeMap<String, String>,
+    amz_date: &str,
+) -> String {
+    let signed_headers = headers.keys().cloned().collect::<Vec<_>>().join(";");
+    let mut canonical = format!("{}\n{}\n{}\n", method, path, query);
+    for (name, value) in headers {
+        let _ = writeln!(canonical, "{}:{}", name, value.trim());
+    }
+    let _ = write!(
+        canonical,
+        "\n{}\n{}",
+        signed_headers, headers["x-amz-content-sha256"]
+    );
+    let date = &amz_date[..8];
+    let scope = format!("{}/{}/s3/aws4_request", date, config.region);
+    let to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex(&Sha256::digest(canonical))
+    );
+    let mut key = hmac(format!("AWS4{}", config.secret_key).as_bytes(), date);
+    for part in [config.region.as_str(), "s3", "aws4_request"] {
+        key = hmac(&key, part);
+    }
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        config.access_key,
+        scope,
+        signed_headers,
+        hex(&hmac(&key, &to_sign))
+    )
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// `text` percent-encoded as S3 signs it: all but unreserved characters,
+/// and slashes outside queries.
+fn encode(text: &str, query: bool) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for &b in text.as_bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) || (b == b'/' && !query) {
+            encoded.push(b as char);
+        } else {
+            let _ = write!(encoded, "%{:02X}", b);
+        }
+    }
+    encoded
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{:02x}", b);
+        hex
+    })
+}
+
+pub(crate) fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => encoded.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 63] as char),
+                false => encoded.push('='),
+            }
+        }
+    }
+    encoded
+}
+
+/// The contents of each `<tag>` element of `xml`, as written. S3 puts
+/// no attributes on the elements read here.
+fn elements<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let (open, close) = (format!("<{}>", tag), format!("</{}>", tag));
+    let mut found = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        let Some(end) = rest.find(&close) else {
+            break;
+        };
+        found.push(&rest[..end]);
+        rest = &rest[end + close.len()..];
+    }
+    found
+}
+
+/// The text of the first `<tag>` element of `xml`.
+fn element(xml: &str, tag: &str) -> Option<String> {
+    elements(xml, tag).first().map(|text| unescape(text))
+}
+
+/// `text` with its XML entities and character references replaced.
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(at) = rest.find('&') {
+        unescaped.push_str(&rest[..at]);
+        rest = &rest[at..];
+        let entity = rest.find(';').map(|end| (&rest[1..end], end));
+        let c = entity.and_then(|(entity, _)| match entity {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => match entity.strip_prefix("#x") {
+                Some(code) => u32::from_str_radix(code, 16).ok(),
+                None => entity.strip_prefix('#').and_then(|code| code.parse().ok()),
+            }
+            .and_then(char::from_u32),
+        });
+        match (c, entity) {
+            (Some(c), Some((_, end))) => {
+                unescaped.push(c);
+                rest = &rest[end + 1..];
+            }
+            _ => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+/// An object being read, `RANGE_SIZE` bytes per request with
+/// `S3Config::concurrency` requests in flight.
+struct S3Reader {
+    client: Arc<Client>,
+    key: String,
+    /// The ETag of the object when opened, which it must keep.
+    etag: Option<String>,
+    len: u64,
+    /// Where the next request reads from.
+    next: u64,
+    pending: VecDeque<JoinHandle<io::Result<Vec<u8>>>>,
+    buffer: Vec<u8>,
+    /// How much of `buffer` has been read.
+    read: usize,
+    /// The MD5 of what was read so far and the one in the ETag, until
+    /// compared at the end.
+    md5: Option<(Md5, String)>,
+}
+
+impl S3Reader {
+    fn fetch(&mut self) {
+        while self.pending.len() < self.client.config.concurrency.max(1) && self.next < self.len {
+            let (start, end) = (self.next, self.len.min(self.next + RANGE_SIZE));
+            let (client, key, etag) = (self.client.clone(), self.key.clone(), self.etag.clone());
+            self.pending.push_back(thread::spawn(move || {
+                client.get_range(&key, etag.as_deref(), start, end)
+            }));
+            self.next = end;
+        }
+    }
+
+    fn check(&mut self) -> io::Result<()> {
+        match self.md5.take() {
+            Some((md5, expected)) => check_etag(&self.key, Some(expected), &hex(&md5.finalize())),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Read for S3Reader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.read == self.buffer.len() {
+            self.fetch();
+            let Some(range) = self.pending.pop_front() else {
+                self.check()?;
+                return Ok(0);
+            };
+            self.buffer = range
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic))?;
+            self.read = 0;
+            if let Some((md5, _)) = &mut self.md5 {
+                md5.update(&self.buffer);
+            }
+        }
+        let n = buf.len().min(self.buffer.len() - self.read);
+        buf[..n].copy_from_slice(&self.buffer[self.read..self.read + n]);
+        self.read += n;
+        Ok(n)
+    }
+}
+
+/// A multipart upload under way.
+struct Upload {
+    id: String,
+    /// The ETags of the parts uploaded, in order.
+    etags: Vec<String>,
+    /// The MD5s of all parts sent, one after the other, from which the
+    /// ETag of the object is made.
+    md5s: Vec<u8>,
+    pending: VecDeque<JoinHandle<io::Result<String>>>,
+}
+
+impl Upload {
+    fn parts(&self) -> usize {
+        self.md5s.len() / 16
+    }
+
+    fn wait_one(&mut self) -> io::Result<()> {
+        if let Some(part) = self.pending.pop_front() {
+            let etag = part
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic))?;
+            self.etags.push(etag);
+        }
+        Ok(())
+    }
+}
+
+/// An object being written. It is uploaded with a single request on
+/// `flush` if smaller than a part, and as a multipart upload completed
+/// on `flush` otherwise; nothing can be written after. An upload dropped
+/// before is aborted.
+struct S3Writer {
+    client: Arc<Client>,
+    key: String,
+    buffer: Vec<u8>,
+    part_size: usize,
+    upload: Option<Upload>,
+    done: bool,
+}
+
+impl S3Writer {
+    fn send_part(&mut self, data: Vec<u8>) -> io::Result<()> {
+        let upload = match &mut self.upload {
+            Some(upload) => upload,
+            None => {
+                let response = self
+                    .client
+                    .send("POST", &self.key, &[("uploads", "")], &[], &[])?;
+                let id = element(&response.into_string()?, "UploadId")
+                    .ok_or_else(|| io::Error::other("S3 started an upload without an ID"))?;
+                self.upload.insert(Upload {
+                    id,
+                    etags: Vec::new(),
+                    md5s: Vec::new(),
+                    pending: VecDeque::new(),
+                })
+            }
+        };
+        let md5 = Md5::digest(&data);
+        upload.md5s.extend_from_slice(&md5);
+        let number = upload.parts().to_string();
+        let (client, key, id) = (self.client.clone(), self.key.clone(), upload.id.clone());
+        upload.pending.push_back(thread::spawn(move || {
+            client.put(
+                &key,
+                &[("partNumber", &number), ("uploadId", &id)],
+                &data,
+                &md5,
+            )
+        }));
+        while upload.pending.len() >= self.client.config.concurrency.max(1) {
+            upload.wait_one()?;
+        }
+        if upload.parts() % PARTS_PER_SIZE == 0 {
+            self.part_size *= 2;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let data = mem::take(&mut self.buffer);
+        if self.upload.is_none() {
+            let md5 = Md5::digest(&data);
+            let content_md5 = base64(&md5);
+            let response = self.client.send(
+                "PUT",
+                &self.key,
+                &[],
+                &[("content-md5", &content_md5)],
+                &data,
+            )?;
+            let etag = response.header("etag").filter(|_| md5_etags(&response));
+            return check_etag(&self.key, etag.and_then(md5_etag), &hex(&md5));
+        }
+        if !data.is_empty() {
+            self.send_part(data)?;
+        }
+        let upload = self.upload.as_mut().expect("upload started");
+        while !upload.pending.is_empty() {
+            upload.wait_one()?;
+        }
+        let mut body = String::from("<CompleteMultipartUpload>");
+        for (number, etag) in upload.etags.iter().enumerate() {
+            let _ = write!(
+                body,
+                "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                number + 1,
+                etag
+            );
+        }
+        body.push_str("</CompleteMultipartUpload>");
+        let response = self.client.send(
+            "POST",
+            &self.key,
+            &[("uploadId", &upload.id)],
+            &[],
+            body.as_bytes(),
+        )?;
+        let md5_etags = md5_etags(&response);
+        // S3 may fail the completion after answering 200.
+        let result = response.into_string()?;
+        if let Some(error) = elements(&result, "Error").first() {
+            let code = element(error, "Code").unwrap_or_default();
+            let message = element(error, "Message").unwrap_or_default();
+            return Err(io::Error::other(format!("{}: {}", code, message)));
+        }
+        let etag = element(&result, "ETag").filter(|_| md5_etags);
+        let expected = format!("{}-{}", hex(&Md5::digest(&upload.md5s)), upload.parts());
+        self.upload = None;
+        check_etag(&self.key, etag.as_deref().and_then(md5_etag), &expected)
+    }
+}
+
+impl Write for S3Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.done {
+            return Err(io::Error::other(format!(
+                "'{}' is already uploaded",
+                self.key
+            )));
+        }
+        self.buffer.extend_from_slice(buf);
+        while self.buffer.len() >= self.part_size {
+            let rest = self.buffer.split_off(self.part_size);
+            let part = mem::replace(&mut self.buffer, rest);
+            self.send_part(part)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.done {
+            self.finish()?;
+            self.done = true;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for S3Writer {
+    fn drop(&mut self) {
+        if let Some(upload) = self.upload.take() {
+            for part in upload.pending {
+                let _ = part.join();
+            }
+            let _ = self
+                .client
+                .send("DELETE", &self.key, &[("uploadId", &upload.id)], &[], &[]);
+        }
+    }
+}
@@ -0,0 +1,181 @@
+//! Sampled verification, for `CopyOptions::verify_sample`: rather than
+//! reading back every file as `verify` does, which doubles the time of a
+//! large copy, a share of the files copied is picked at random and read
+//! back once the copy is done, along with the largest and any that needed
+//! retries, and the files that matched bound how many of the others can be
+//! corrupt.
+
+use crate::failure::{ErrorClass, FileError};
+use crate::location::Location;
+use crate::progress::Bars;
+use crate::report::{FileRecord, FileStatus, TOP_FILES};
+use crate::ui::{format_size, BINARY};
+use crate::{verify, CopyError, CopyOptions, CopyStats};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::{BuildHasher, RandomState};
+
+/// The chance that the bound `VerifiedSample::bound` gives is wrong.
+const RISK: f64 = 0.05;
+
+/// Parses the share of files to verify, a percentage such as `5%` or
+/// `0.5`, more than 0 and at most 100.
+pub fn parse_sample(s: &str) -> Result<f64, String> {
+    let percent: f64 = s
+        .trim()
+        .trim_end_matches('%')
+        .trim_end()
+        .parse()
+        .map_err(|_| format!("invalid sample '{}' (expected a percentage, e.g. 5%)", s))?;
+    if !(percent > 0.0 && percent <= 100.0) {
+        return Err(format!(
+            "invalid sample '{}' (expected more than 0% and at most 100%)",
+            s
+        ));
+    }
+    Ok(percent)
+}
+
+/// The files read back by `CopyOptions::verify_sample`, in
+/// `CopyStats::verified_sample`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+pub struct VerifiedSample {
+    /// Files copied, which the sample was taken from.
+    pub files: usize,
+    /// Files read back, and their bytes.
+    pub verified: usize,
+    pub bytes: u64,
+    /// How many of `verified` were picked at random rather than for being
+    /// among the largest or retried.
+    pub random: usize,
+    /// Files read back that did not match their source.
+    pub mismatched: usize,
+}
+
+impl VerifiedSample {
+    /// The share of the files not read back of which at most are corrupt,
+    /// at 95% confidence: with any more, one of the files picked at random
+    /// would most likely have been among them. `None` once a file did not
+    /// match.
+    pub fn bound(&self) -> Option<f64> {
+        match self.mismatched {
+            0 if self.verified >= self.files => Some(0.0),
+            0 => Some(1.0 - RISK.powf(1.0 / self.random as f64)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for VerifiedSample {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} files ({}) verified",
+            self.verified,
+            self.files,
+            format_size(self.bytes, BINARY)
+        )?;
+        match self.bound() {
+            Some(bound) if bound > 0.0 => write!(
+                f,
+                ", under {:.2}% of the others corrupt at 95% confidence",
+                bound * 100.0
+            ),
+            Some(_) => Ok(()),
+            None => write!(f, ", {} mismatched", self.mismatched),
+        }
+    }
+}
+
+/// The indices of the files of `records` to read back, in order: `percent`
+/// of those copied, picked at random, as well as the largest and those that
+/// needed retries. Also returns how many files were copied, and how many
+/// were picked at random.
+pub(crate) fn pick(records: &[FileRecord], percent: f64) -> (Vec<usize>, usize, usize) {
+    let copied: Vec<usize> = (0..records.len())
+        .filter(|&index| {
+            records[index].status == FileStatus::Copied && records[index].checksum.is_some()
+        })
+        .collect();
+    let mut largest = copied.clone();
+    largest.sort_by_key(|&index| std::cmp::Reverse(records[index].bytes));
+    let mut chosen: HashSet<usize> = largest.into_iter().take(TOP_FILES).collect();
+    chosen.extend(copied.iter().filter(|&&index| records[index].retries > 0));
+
+    // A seed of its own for every copy, so that each checks other files.
+    let seed = RandomState::new();
+    let mut others: Vec<usize> = copied
+        .iter()
+        .copied()
+        .filter(|index| !chosen.contains(index))
+        .collect();
+    others.sort_by_key(|&index| seed.hash_one(&records[index].dest));
+    let wanted = (copied.len() as f64 * percent / 100.0).ceil() as usize;
+    let random = wanted.min(others.len());
+    chosen.extend(&others[..random]);
+
+    let mut chosen: Vec<usize> = chosen.into_iter().collect();
+    chosen.sort_unstable();
+    (chosen, copied.len(), random)
+}
+
+/// Reads back a sample of the files `stats` recorded as copied, picked as
+/// `pick` does, marking them verified, and sets `stats.verified_sample`.
+/// A file that does not match fails the copy, or is recorded as failed
+/// with `keep_going`.
+pub(crate) fn verify(
+    percent: f64,
+    bars: &Bars,
+    options: &CopyOptions,
+    stats: &mut CopyStats,
+) -> Result<(), CopyError> {
+    let (chosen, files, random) = pick(&stats.files, percent);
+    if let Some(pb) = &bars.verify {
+        pb.set_length(chosen.iter().map(|&index| stats.files[index].bytes).sum());
+        pb.set_position(0);
+    }
+    let mut sample = VerifiedSample {
+        files,
+        random,
+        ..Default::default()
+    };
+    for index in chosen {
+        if let Some(cancel) = &options.cancel {
+            cancel.check()?;
+        }
+        let record = &mut stats.files[index];
+        let Some(digest) = &record.checksum else {
+            continue;
+        };
+        match verify::verify(
+            &Location::new(&record.source),
+            &Location::new(&record.dest),
+            digest,
+            options.checksum,
+            bars.verify.as_ref(),
+        ) {
+            Ok(read) => {
+                sample.verified += 1;
+                sample.bytes += read;
+                record.verified = true;
+            }
+            Err(err) if options.skips(ErrorClass::of_io(&err)) => {
+                if verify::is_mismatch(&err) {
+                    sample.verified += 1;
+                    sample.mismatched += 1;
+                }
+                record.status = FileStatus::Failed;
+                record.error = Some(err.to_string());
+                stats
+                    .failures
+                    .push(FileError::copy(record.source.clone(), err.into()));
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    stats.bytes_verified += sample.bytes;
+    stats.verified_sample = Some(sample);
+    Ok(())
+}
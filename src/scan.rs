@@ -0,0 +1,100 @@
+//! Measuring a source before copying it, for confirmation prompts and size
+//! estimates shown before the copy starts.
+
+use crate::report::TOP_FILES;
+use crate::{CopyError, CopyOptions};
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// What `scan` found under a source.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ScanSummary {
+    /// Bytes in all regular files.
+    pub total_bytes: u64,
+    pub files: u64,
+    /// Directories, the source itself included.
+    pub dirs: u64,
+    /// Symbolic links, which are not followed (nor copied).
+    pub symlinks: u64,
+    /// Entries whose metadata or directory listing could not be read.
+    pub unreadable: u64,
+    /// The `report::TOP_FILES` largest files, largest first.
+    pub largest_files: Vec<ScannedFile>,
+}
+
+/// A file of a `ScanSummary`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ScannedFile {
+    pub path: PathBuf,
+    pub bytes: u64,
+}
+
+impl ScanSummary {
+    fn add_file(&mut self, path: PathBuf, bytes: u64) {
+        self.files += 1;
+        self.total_bytes += bytes;
+        let at = self
+            .largest_files
+            .partition_point(|kept| kept.bytes >= bytes);
+        if at < TOP_FILES {
+            self.largest_files.insert(at, ScannedFile { path, bytes });
+            self.largest_files.truncate(TOP_FILES);
+        }
+    }
+}
+
+/// Walks `source` as a copy with `options` would, through
+/// `options.source_fs` if set, and sums up what it holds without copying
+/// anything. Entries that cannot be read are counted in `unreadable`;
+/// only a source that cannot be read at all is an error.
+pub fn scan(source: &Path, options: &CopyOptions) -> Result<ScanSummary, CopyError> {
+    let mut summary = ScanSummary::default();
+    match &options.source_fs {
+        Some(fs) => {
+            let info = fs.metadata(source)?;
+            if !info.is_dir {
+                summary.add_file(source.to_path_buf(), info.len);
+                return Ok(summary);
+            }
+            let mut dirs = vec![source.to_path_buf()];
+            while let Some(dir) = dirs.pop() {
+                summary.dirs += 1;
+                let Ok(entries) = fs.read_dir(&dir) else {
+                    summary.unreadable += 1;
+                    continue;
+                };
+                for entry in entries {
+                    match fs.metadata(&entry) {
+                        Ok(info) if info.is_dir => dirs.push(entry),
+                        Ok(info) => summary.add_file(entry, info.len),
+                        Err(_) => summary.unreadable += 1,
+                    }
+                }
+            }
+        }
+        None => {
+            // Fails on a source that is missing or unreadable.
+            source.symlink_metadata()?;
+            for entry in WalkDir::new(source) {
+                let Ok(entry) = entry else {
+                    summary.unreadable += 1;
+                    continue;
+                };
+                let file_type = entry.file_type();
+                if file_type.is_dir() {
+                    summary.dirs += 1;
+                } else if file_type.is_symlink() {
+                    summary.symlinks += 1;
+                } else if file_type.is_file() {
+                    match entry.metadata() {
+                        Ok(metadata) => summary.add_file(entry.into_path(), metadata.len()),
+                        Err(_) => summary.unreadable += 1,
+                    }
+                }
+            }
+        }
+    }
+    Ok(summary)
+}
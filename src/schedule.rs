@@ -0,0 +1,274 @@
+//! Copies in off-hours, for `CopyOptions::start_at` and
+//! `CopyOptions::pause_between`: a big transfer queued now waits for its
+//! start time, then stops moving data during the daily pause window, e.g.
+//! office hours, with the progress showing why and the ETA skipping the
+//! paused hours.
+
+use crate::cancel::CancellationToken;
+use crate::limit::RateLimiter;
+use crate::progress::{self, Rate};
+use crate::ui::ProgressBar;
+use crate::CopyOptions;
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta};
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// How often a waiting copy looks at the clock, which may jump after a
+/// suspend, and at its cancellation.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How far ahead the finish of a copy is looked for: one that would not be
+/// done within a year between pauses has no useful finish time.
+const HORIZON_DAYS: i64 = 366;
+
+/// A daily window of time, e.g. `08:00-18:00`, which ends the next day if
+/// it ends before it starts, as `22:00-06:00` does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl TimeWindow {
+    /// The window from `start` to `end`, which must differ.
+    pub fn new(start: NaiveTime, end: NaiveTime) -> Self {
+        assert!(start != end, "a time window must not be empty");
+        Self { start, end }
+    }
+
+    /// Whether `time` of day falls in the window, its start included and
+    /// its end not.
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+
+    /// When the window `at` falls in ends, or `None` if it is outside.
+    pub(crate) fn resumes(&self, at: DateTime<Local>) -> Option<DateTime<Local>> {
+        if !self.contains(at.time()) {
+            return None;
+        }
+        let date = at.date_naive();
+        match at.time() < self.end {
+            true => local(date, self.end),
+            false => local(date.succ_opt()?, self.end),
+        }
+    }
+
+    /// The next start of the window after `at`.
+    fn next_start(&self, at: DateTime<Local>) -> Option<DateTime<Local>> {
+        let date = at.date_naive();
+        match at.time() < self.start {
+            true => local(date, self.start),
+            false => local(date.succ_opt()?, self.start),
+        }
+    }
+
+    /// When a copy that needs `active` time of copying from `now` on is
+    /// done, copying only outside the window.
+    pub(crate) fn finish(&self, now: DateTime<Local>, active: Duration) -> Option<DateTime<Local>> {
+        let mut left = TimeDelta::from_std(active).ok()?;
+        let horizon = now.checked_add_signed(TimeDelta::try_days(HORIZON_DAYS)?)?;
+        let mut at = now;
+        while at < horizon {
+            if let Some(end) = self.resumes(at) {
+                at = end;
+            }
+            let pause = self.next_start(at)?;
+            if left <= pause - at {
+                return at.checked_add_signed(left);
+            }
+            left -= pause - at;
+            at = pause;
+        }
+        None
+    }
+}
+
+/// `time` on `date` in the local time zone; a time skipped by a change to
+/// daylight saving time is taken an hour later.
+fn local(date: NaiveDate, time: NaiveTime) -> Option<DateTime<Local>> {
+    let naive = date.and_time(time);
+    naive.and_local_timezone(Local).earliest().or_else(|| {
+        (naive + TimeDelta::hours(1))
+            .and_local_timezone(Local)
+            .earliest()
+    })
+}
+
+/// Parses a time of day, `HH:MM` or `HH:MM:SS`.
+fn parse_time(s: &str) -> Option<NaiveTime> {
+    NaiveTime::parse_from_str(s, "%H:%M")
+        .or_else(|_| NaiveTime::parse_from_str(s, "%H:%M:%S"))
+        .ok()
+}
+
+impl FromStr for TimeWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid time window '{}' (expected e.g. 08:00-18:00)", s);
+        let (start, end) = s.trim().split_once('-').ok_or_else(invalid)?;
+        let start = parse_time(start.trim()).ok_or_else(invalid)?;
+        let end = parse_time(end.trim()).ok_or_else(invalid)?;
+        if start == end {
+            return Err(format!("empty time window '{}'", s));
+        }
+        Ok(Self { start, end })
+    }
+}
+
+impl fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let time = |time: NaiveTime| match time.format("%S").to_string().as_str() {
+            "00" => time.format("%H:%M").to_string(),
+            _ => time.format("%H:%M:%S").to_string(),
+        };
+        write!(f, "{}-{}", time(self.start), time(self.end))
+    }
+}
+
+/// Parses the time a copy starts at: a time of day, `HH:MM` or
+/// `HH:MM:SS`, for its next occurrence, or a date and time such as
+/// `2024-05-06 22:00` or `2024-05-06T22:00`, in local time.
+pub fn parse_start_time(s: &str) -> Result<SystemTime, String> {
+    let invalid = || {
+        format!(
+            "invalid start time '{}' (expected e.g. 22:00 or 2024-05-06 22:00)",
+            s
+        )
+    };
+    let s = s.trim();
+    let at = match parse_time(s) {
+        Some(time) => {
+            let now = Local::now();
+            match now.time() < time {
+                true => local(now.date_naive(), time),
+                false => now
+                    .date_naive()
+                    .succ_opt()
+                    .and_then(|date| local(date, time)),
+            }
+        }
+        None => [
+            "%Y-%m-%d %H:%M",
+            "%Y-%m-%dT%H:%M",
+            "%Y-%m-%d %H:%M:%S",
+            "%Y-%m-%dT%H:%M:%S",
+        ]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(s, format).ok())
+        .and_then(|naive| local(naive.date(), naive.time())),
+    };
+    at.map(SystemTime::from).ok_or_else(invalid)
+}
+
+/// Holds the copy of `pb` until `until`, showing `why` and the time it
+/// goes on as the message of the bar, with `rate` not counting the wait.
+fn wait(
+    until: DateTime<Local>,
+    why: &str,
+    pb: &ProgressBar,
+    rate: &Rate,
+    cancel: Option<&CancellationToken>,
+) -> io::Result<()> {
+    let message = pb.message();
+    pb.set_message(format!(
+        "{} until {}",
+        why,
+        progress::clock(Local::now(), until)
+    ));
+    rate.hold(true);
+    let waited = loop {
+        if let Some(Err(err)) = cancel.map(CancellationToken::check) {
+            break Err(err);
+        }
+        match (until - Local::now()).to_std() {
+            Ok(left) if !left.is_zero() => thread::sleep(left.min(POLL_INTERVAL)),
+            _ => break Ok(()),
+        }
+    };
+    rate.hold(false);
+    pb.set_message(message);
+    waited
+}
+
+/// Waits for the start of a copy with `options`: its start time, then the
+/// end of the pause window if it starts in one.
+pub(crate) fn wait_for_start(
+    options: &CopyOptions,
+    pb: &ProgressBar,
+    rate: &Rate,
+) -> io::Result<()> {
+    if let Some(at) = options.start_at {
+        wait(at.into(), "waiting", pb, rate, options.cancel.as_ref())?;
+    }
+    if let Some(end) = options
+        .pause_between
+        .and_then(|window| window.resumes(Local::now()))
+    {
+        wait(end, "paused", pb, rate, options.cancel.as_ref())?;
+    }
+    Ok(())
+}
+
+/// Throttles with `inner`, if any, and stops the copy of `pb` for as long
+/// as the clock is in `window`.
+pub(crate) struct OffHours {
+    window: TimeWindow,
+    inner: Option<Arc<dyn RateLimiter>>,
+    pb: ProgressBar,
+    rate: Arc<Rate>,
+    cancel: Option<CancellationToken>,
+    /// Held by the worker that waits out a pause, which the others then
+    /// find over.
+    pausing: Mutex<()>,
+}
+
+impl OffHours {
+    pub(crate) fn new(
+        window: TimeWindow,
+        inner: Option<Arc<dyn RateLimiter>>,
+        options: &CopyOptions,
+        pb: &ProgressBar,
+        rate: &Arc<Rate>,
+    ) -> Self {
+        Self {
+            window,
+            inner,
+            pb: pb.clone(),
+            rate: Arc::clone(rate),
+            cancel: options.cancel.clone(),
+            pausing: Mutex::new(()),
+        }
+    }
+}
+
+impl fmt::Debug for OffHours {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OffHours")
+            .field("window", &self.window)
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RateLimiter for OffHours {
+    fn acquire(&self, bytes: u64) {
+        if let Some(inner) = &self.inner {
+            inner.acquire(bytes);
+        }
+        let _pausing = self.pausing.lock().unwrap();
+        if let Some(end) = self.window.resumes(Local::now()) {
+            // A cancelled wait is seen by the copy loop right after.
+            let _ = wait(end, "paused", &self.pb, &self.rate, self.cancel.as_ref());
+        }
+    }
+}
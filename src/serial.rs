@@ -0,0 +1,129 @@
+//! Serde support for the `serde` feature, so that options can be loaded
+//! from configuration files and statistics persisted.
+//!
+//! Settings are written in the spelling their command-line flags accept,
+//! e.g. `"largest-first"` or `"D755,F644"`; a few types have no such
+//! spelling and are derived where they are defined. Owner maps are
+//! written with numeric IDs.
+
+use crate::checksum::Digest;
+use crate::{
+    ChangePolicy, ChecksumAlgo, ChmodRules, Chown, CollisionPolicy, ColorChoice, Compression,
+    CopyBackend, CopyError, Dedupe, ErrorClass, ErrorRules, FileError, FileOp, FileOrder, IdMap,
+    LinkMode, NameNormalization, Parallelism, ProgressMode, ReflinkMode, SparseMode, SyncMode,
+    Theme, TimeWindow,
+};
+use serde::de::{self, Deserializer};
+use serde::{Deserialize, Serialize, Serializer};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Serializes each type as its `Display` form and parses it back with
+/// `parse`, `FromStr::from_str` unless given.
+macro_rules! via_str {
+    ($($ty:ty $(=> $parse:path)?,)*) => {
+        $(
+            impl Serialize for $ty {
+                fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                    serializer.collect_str(self)
+                }
+            }
+
+            impl<'de> Deserialize<'de> for $ty {
+                fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                    let s = String::deserialize(deserializer)?;
+                    via_str!(@parse s $(, $parse)?).map_err(de::Error::custom)
+                }
+            }
+        )*
+    };
+    (@parse $s:ident) => { $s.parse() };
+    (@parse $s:ident, $parse:path) => { $parse(&$s) };
+}
+
+via_str! {
+    NameNormalization,
+    CollisionPolicy,
+    ChmodRules,
+    Chown,
+    IdMap => parse_id_map,
+    SparseMode,
+    ReflinkMode,
+    Parallelism,
+    CopyBackend,
+    FileOrder,
+    SyncMode,
+    ChecksumAlgo,
+    Compression,
+    ChangePolicy,
+    ErrorRules => parse_error_rules,
+    LinkMode,
+    Dedupe,
+    ProgressMode,
+    Theme,
+    ColorChoice,
+    TimeWindow,
+    Digest => parse_digest,
+}
+
+fn parse_id_map(s: &str) -> Result<IdMap, String> {
+    match s {
+        "" => Ok(IdMap::default()),
+        s => IdMap::parse_users(s),
+    }
+}
+
+fn parse_error_rules(s: &str) -> Result<ErrorRules, String> {
+    match s {
+        "" => Ok(ErrorRules::default()),
+        s => s.parse(),
+    }
+}
+
+fn parse_digest(hex: &str) -> Result<Digest, String> {
+    Digest::from_hex(hex).ok_or_else(|| format!("invalid checksum '{}'", hex))
+}
+
+/// The form of a `FileError`, whose error comes back as its message.
+#[derive(Serialize, Deserialize)]
+struct FileErrorForm {
+    path: PathBuf,
+    op: FileOp,
+    #[serde(default = "other")]
+    class: ErrorClass,
+    error: String,
+}
+
+fn other() -> ErrorClass {
+    ErrorClass::Other
+}
+
+impl Serialize for FileError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        FileErrorForm {
+            path: self.path.clone(),
+            op: self.op,
+            class: self.class,
+            error: self.source.to_string(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for FileError {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let form = FileErrorForm::deserialize(deserializer)?;
+        Ok(Self {
+            path: form.path,
+            op: form.op,
+            class: form.class,
+            source: CopyError::Other(anyhow::anyhow!(form.error)),
+        })
+    }
+}
+
+/// Reads back a duration that `report` writes as seconds.
+pub(crate) fn secs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let secs = f64::deserialize(deserializer)?;
+    Duration::try_from_secs_f64(secs).map_err(de::Error::custom)
+}
@@ -0,0 +1,705 @@
+//! Remote sources and destinations over SFTP, for `[user@]host:path`
+//! arguments. The protocol (version 3, which every server speaks) runs
+//! over the `ssh` command, as `sftp` and `scp` do, so the user's SSH
+//! configuration, keys and agent apply unchanged.
+//!
+//! Reads and writes are pipelined, a few requests in flight at a time, so
+//! that a copy over a slow link is not one round trip per chunk.
+
+use crate::vfs::{FileInfo, Filesystem};
+use crate::wire::{self, Fields, Packet};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The protocol version spoken.
+const VERSION: u32 = 3;
+
+/// Bytes asked for or sent per request, the most every server accepts.
+const CHUNK_SIZE: usize = 32 * 1024;
+
+/// Requests in flight at once while reading or writing a file.
+const IN_FLIGHT: usize = 16;
+
+/// The longest packet accepted. Data packets are the largest: a chunk and
+/// a few fields.
+const MAX_PACKET: usize = 4 * CHUNK_SIZE + 1024;
+
+/// Packet types, from draft-ietf-secsh-filexfer-02.
+pub(crate) mod fxp {
+    pub const INIT: u8 = 1;
+    pub const VERSION: u8 = 2;
+    pub const OPEN: u8 = 3;
+    pub const CLOSE: u8 = 4;
+    pub const READ: u8 = 5;
+    pub const WRITE: u8 = 6;
+    pub const LSTAT: u8 = 7;
+    pub const SETSTAT: u8 = 9;
+    pub const OPENDIR: u8 = 11;
+    pub const READDIR: u8 = 12;
+    pub const REMOVE: u8 = 13;
+    pub const MKDIR: u8 = 14;
+    pub const RMDIR: u8 = 15;
+    pub const STAT: u8 = 17;
+    pub const STATUS: u8 = 101;
+    pub const HANDLE: u8 = 102;
+    pub const DATA: u8 = 103;
+    pub const NAME: u8 = 104;
+    pub const ATTRS: u8 = 105;
+}
+
+/// Status codes of `fxp::STATUS`.
+pub(crate) mod status {
+    pub const OK: u32 = 0;
+    pub const EOF: u32 = 1;
+    pub const NO_SUCH_FILE: u32 = 2;
+    pub const PERMISSION_DENIED: u32 = 3;
+    pub const OP_UNSUPPORTED: u32 = 8;
+}
+
+/// Flags of `fxp::OPEN`.
+pub(crate) mod open {
+    pub const READ: u32 = 0x01;
+    pub const WRITE: u32 = 0x02;
+    pub const CREATE: u32 = 0x08;
+    pub const TRUNCATE: u32 = 0x10;
+}
+
+/// Fields present in file attributes.
+pub(crate) mod attr {
+    pub const SIZE: u32 = 0x01;
+    pub const UIDGID: u32 = 0x02;
+    pub const PERMISSIONS: u32 = 0x04;
+    pub const ACMODTIME: u32 = 0x08;
+    pub const EXTENDED: u32 = 0x8000_0000;
+}
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+
+/// Splits a `[user@]host:path` argument into the host, with its user, and
+/// the remote path, the way `scp` tells remote arguments from local ones:
+/// a colon before any slash. An empty path is the remote home directory;
+/// a `scheme://` URL, or a path that exists here, is not a remote argument.
+pub fn parse_remote(arg: &str) -> Option<(&str, &str)> {
+    let (host, path) = match arg.strip_prefix('[') {
+        // `[user@]host` may hold colons in brackets, as IPv6 addresses do.
+        Some(rest) => {
+            let (host, path) = rest.split_once("]:")?;
+            (host, path)
+        }
+        None => arg.split_once(':')?,
+    };
+    // A drive letter such as `C:` is a local path on Windows.
+    if host.is_empty()
+        || host.contains('/')
+        || path.starts_with("//")
+        || (cfg!(windows) && host.len() == 1)
+        || Path::new(arg).symlink_metadata().is_ok()
+    {
+        return None;
+    }
+    Some((host, if path.is_empty() { "." } else { path }))
+}
+
+/// An SFTP server as a `Filesystem`, for `CopyOptions::source_fs` or
+/// `CopyOptions::dest_fs`.
+///
+/// Symbolic links on the server are followed. Remote files keep their
+/// modification times with `preserve_attrs`, but no other attributes.
+pub struct SftpFs {
+    host: String,
+    session: Arc<Mutex<Session>>,
+    ssh: Mutex<Option<Child>>,
+}
+
+impl fmt::Debug for SftpFs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SftpFs")
+            .field("host", &self.host)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SftpFs {
+    /// Connects to `host`, `[user@]host` as `ssh` takes it, by running
+    /// `ssh -s host sftp`. Passwords and host keys are asked for on the
+    /// terminal, as by `ssh`.
+    pub fn connect(host: &str) -> io::Result<Self> {
+        let mut ssh = Command::new("ssh")
+            .args(["-o", "ForwardX11=no", "-o", "ForwardAgent=no"])
+            .args([
+                "-o",
+                "ClearAllForwardings=yes",
+                "-o",
+                "PermitLocalCommand=no",
+            ])
+            .args(["-s", "--", host, "sftp"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|err| io::Error::new(err.kind(), format!("cannot run ssh: {}", err)))?;
+        let stdout = ssh.stdout.take().expect("stdout is piped");
+        let stdin = ssh.stdin.take().expect("stdin is piped");
+        let session = Session::start(Box::new(stdout), Box::new(stdin)).map_err(|err| {
+            // `ssh` has said why on stderr.
+            let err = match err.kind() {
+                io::ErrorKind::UnexpectedEof => "ssh closed the connection".to_string(),
+                _ => err.to_string(),
+            };
+            io::Error::other(format!("cannot start SFTP session with {}: {}", host, err))
+        });
+        let session = match session {
+            Ok(session) => session,
+            Err(err) => {
+                let _ = ssh.kill();
+                let _ = ssh.wait();
+                return Err(err);
+            }
+        };
+        Ok(Self {
+            host: host.to_string(),
+            session: Arc::new(Mutex::new(session)),
+            ssh: Mutex::new(Some(ssh)),
+        })
+    }
+
+    /// Speaks SFTP over `reader` and `writer`, e.g. the channel of an SSH
+    /// library, instead of running `ssh`.
+    pub fn from_streams(
+        reader: impl Read + Send + 'static,
+        writer: impl Write + Send + 'static,
+    ) -> io::Result<Self> {
+        let session = Session::start(Box::new(reader), Box::new(writer))?;
+        Ok(Self {
+            host: String::new(),
+            session: Arc::new(Mutex::new(session)),
+            ssh: Mutex::new(None),
+        })
+    }
+
+    fn request(&self, kind: u8, body: &[u8]) -> io::Result<(u8, Vec<u8>)> {
+        self.session.lock().unwrap().request(kind, body)
+    }
+
+    fn stat(&self, path: &Path) -> io::Result<Attrs> {
+        self.attrs(fxp::STAT, path)
+    }
+
+    /// The attributes of `path` with `fxp::STAT`, or `fxp::LSTAT` for
+    /// those of a symbolic link itself.
+    fn attrs(&self, kind: u8, path: &Path) -> io::Result<Attrs> {
+        let mut body = Packet::new();
+        body.string(&remote(path));
+        match self.request(kind, &body.0)? {
+            (fxp::ATTRS, payload) => Attrs::parse(&mut Fields(&payload)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn open_handle(&self, path: &Path, flags: u32) -> io::Result<Vec<u8>> {
+        let mut body = Packet::new();
+        body.string(&remote(path)).u32(flags).u32(0);
+        handle(self.request(fxp::OPEN, &body.0)?)
+    }
+}
+
+impl Drop for SftpFs {
+    fn drop(&mut self) {
+        // Closing its input ends the session; `ssh` then exits.
+        self.session.lock().unwrap().writer = BufWriter::new(Box::new(io::sink()));
+        if let Some(mut ssh) = self.ssh.lock().unwrap().take() {
+            let _ = ssh.wait();
+        }
+    }
+}
+
+impl Filesystem for SftpFs {
+    fn metadata(&self, path: &Path) -> io::Result<FileInfo> {
+        let attrs = self.stat(path)?;
+        let is_dir = attrs.is_dir();
+        Ok(FileInfo {
+            is_dir,
+            len: if is_dir { 0 } else { attrs.size.unwrap_or(0) },
+            modified: attrs
+                .mtime
+                .map(|secs| UNIX_EPOCH + Duration::from_secs(secs.into())),
+            mode: attrs.permissions.map(|mode| mode & 0o7777),
+        })
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut body = Packet::new();
+        body.string(&remote(path));
+        let dir = handle(self.request(fxp::OPENDIR, &body.0)?)?;
+        let mut entries = Vec::new();
+        let listed = loop {
+            let mut body = Packet::new();
+            body.string(&dir);
+            match self.request(fxp::READDIR, &body.0) {
+                Ok((fxp::NAME, payload)) => {
+                    let mut fields = Fields(&payload);
+                    for _ in 0..fields.u32()? {
+                        let name = fields.string()?;
+                        let _long_name = fields.string()?;
+                        Attrs::parse(&mut fields)?;
+                        if name != b"." && name != b".." {
+                            entries.push(path.join(local(name)));
+                        }
+                    }
+                }
+                Ok((fxp::STATUS, payload)) => match Status::parse(&payload)? {
+                    Status {
+                        code: status::EOF, ..
+                    } => break Ok(()),
+                    status => break Err(status.into_error()),
+                },
+                Ok(response) => break Err(unexpected(response)),
+                Err(err) => break Err(err),
+            }
+        };
+        let closed = self.session.lock().unwrap().close(&dir);
+        listed.and(closed)?;
+        Ok(entries)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        let handle = self.open_handle(path, open::READ)?;
+        Ok(Box::new(SftpReader {
+            session: Arc::clone(&self.session),
+            handle,
+            offset: 0,
+            buffer: Vec::new(),
+            read: 0,
+            eof: false,
+        }))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn Write + Send>> {
+        let handle = self.open_handle(path, open::WRITE | open::CREATE | open::TRUNCATE)?;
+        Ok(Box::new(SftpWriter {
+            session: Arc::clone(&self.session),
+            handle,
+            offset: 0,
+            pending: Vec::new(),
+        }))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        match self.stat(path) {
+            Ok(attrs) if attrs.is_dir() => return Ok(()),
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("'{}' is a file", path.display()),
+                ))
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            self.create_dir_all(parent)?;
+        }
+        let mut body = Packet::new();
+        body.string(&remote(path)).u32(0);
+        let made = expect_ok(self.request(fxp::MKDIR, &body.0)?);
+        // Another client may have made it in the meantime.
+        match made {
+            Err(_) if self.stat(path).is_ok_and(|attrs| attrs.is_dir()) => Ok(()),
+            made => made,
+        }
+    }
+
+    fn set_modified(&self, path: &Path, modified: SystemTime) -> io::Result<()> {
+        let secs = modified
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs().min(u32::MAX.into()) as u32);
+        let mut body = Packet::new();
+        // The access time is set along with it; it becomes the same.
+        body.string(&remote(path))
+            .u32(attr::ACMODTIME)
+            .u32(secs)
+            .u32(secs);
+        expect_ok(self.request(fxp::SETSTAT, &body.0)?)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        let is_dir = self.attrs(fxp::LSTAT, path)?.is_dir();
+        if is_dir {
+            for entry in self.read_dir(path)? {
+                self.remove(&entry)?;
+            }
+        }
+        let mut body = Packet::new();
+        body.string(&remote(path));
+        let kind = if is_dir { fxp::RMDIR } else { fxp::REMOVE };
+        expect_ok(self.request(kind, &body.0)?)
+    }
+}
+
+/// The connection to the server, shared by the filesystem and its open
+/// files, which take turns at it.
+struct Session {
+    reader: BufReader<Box<dyn Read + Send>>,
+    writer: BufWriter<Box<dyn Write + Send>>,
+    next_id: u32,
+}
+
+impl Session {
+    fn start(reader: Box<dyn Read + Send>, writer: Box<dyn Write + Send>) -> io::Result<Self> {
+        let mut session = Self {
+            reader: BufReader::new(reader),
+            writer: BufWriter::new(writer),
+            next_id: 0,
+        };
+        let mut init = Packet::new();
+        init.u8(fxp::INIT).u32(VERSION);
+        session.write_packet(&init.0)?;
+        session.writer.flush()?;
+        let (kind, payload) = session.read_packet()?;
+        let version = Fields(&payload).u32()?;
+        if kind != fxp::VERSION || version < VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("the server does not speak SFTP version {}", VERSION),
+            ));
+        }
+        Ok(session)
+    }
+
+    /// Sends a request, returning its id.
+    fn send(&mut self, kind: u8, body: &[u8]) -> io::Result<u32> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let mut packet = Packet::new();
+        packet.u8(kind).u32(id).bytes(body);
+        self.write_packet(&packet.0)?;
+        Ok(id)
+    }
+
+    /// Receives a response, returning its id, type and what follows the
+    /// id.
+    fn receive(&mut self) -> io::Result<(u32, u8, Vec<u8>)> {
+        let (kind, payload) = self.read_packet()?;
+        let id = Fields(&payload).u32()?;
+        Ok((id, kind, payload[4..].to_vec()))
+    }
+
+    fn request(&mut self, kind: u8, body: &[u8]) -> io::Result<(u8, Vec<u8>)> {
+        let id = self.send(kind, body)?;
+        self.writer.flush()?;
+        let (got, kind, payload) = self.receive()?;
+        if got != id {
+            return Err(bad_message("a response to another request"));
+        }
+        Ok((kind, payload))
+    }
+
+    /// Sends all `requests` before waiting for any response, and returns
+    /// the responses in the order of the requests.
+    fn pipeline(&mut self, requests: &[(u8, Vec<u8>)]) -> io::Result<Vec<(u8, Vec<u8>)>> {
+        let ids = requests
+            .iter()
+            .map(|(kind, body)| self.send(*kind, body))
+            .collect::<io::Result<Vec<_>>>()?;
+        self.writer.flush()?;
+        // Servers may answer in any order.
+        let mut responses = HashMap::new();
+        for _ in 0..ids.len() {
+            let (id, kind, payload) = self.receive()?;
+            responses.insert(id, (kind, payload));
+        }
+        ids.iter()
+            .map(|id| {
+                responses
+                    .remove(id)
+                    .ok_or_else(|| bad_message("a response to another request"))
+            })
+            .collect()
+    }
+
+    fn close(&mut self, handle: &[u8]) -> io::Result<()> {
+        let mut body = Packet::new();
+        body.string(handle);
+        expect_ok(self.request(fxp::CLOSE, &body.0)?)
+    }
+
+    fn write_packet(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.writer
+            .write_all(&(packet.len() as u32).to_be_bytes())?;
+        self.writer.write_all(packet)
+    }
+
+    fn read_packet(&mut self) -> io::Result<(u8, Vec<u8>)> {
+        wire::read_packet(&mut self.reader, MAX_PACKET)
+    }
+}
+
+/// A remote file being read.
+struct SftpReader {
+    session: Arc<Mutex<Session>>,
+    handle: Vec<u8>,
+    /// Where the next request reads from.
+    offset: u64,
+    buffer: Vec<u8>,
+    /// How much of `buffer` has been read.
+    read: usize,
+    eof: bool,
+}
+
+impl SftpReader {
+    /// Fetches the next `IN_FLIGHT` chunks into the buffer.
+    fn fill(&mut self) -> io::Result<()> {
+        let starts: Vec<u64> = (0..IN_FLIGHT as u64)
+            .map(|i| self.offset + i * CHUNK_SIZE as u64)
+            .collect();
+        let requests: Vec<_> = starts
+            .iter()
+            .map(|&start| {
+                let mut body = Packet::new();
+                body.string(&self.handle).u64(start).u32(CHUNK_SIZE as u32);
+                (fxp::READ, body.0)
+            })
+            .collect();
+        let responses = self.session.lock().unwrap().pipeline(&requests)?;
+        self.buffer.clear();
+        self.read = 0;
+        for (start, response) in starts.into_iter().zip(responses) {
+            // A short read leaves a gap; what was read past it is asked
+            // for again.
+            if start != self.offset || self.eof {
+                continue;
+            }
+            match response {
+                (fxp::DATA, payload) => {
+                    let data = Fields(&payload).string()?;
+                    self.buffer.extend_from_slice(data);
+                    self.offset += data.len() as u64;
+                }
+                (fxp::STATUS, payload) => match Status::parse(&payload)? {
+                    Status {
+                        code: status::EOF, ..
+                    } => self.eof = true,
+                    status => return Err(status.into_error()),
+                },
+                response => return Err(unexpected(response)),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for SftpReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.read == self.buffer.len() && !self.eof {
+            self.fill()?;
+        }
+        let n = buf.len().min(self.buffer.len() - self.read);
+        buf[..n].copy_from_slice(&self.buffer[self.read..self.read + n]);
+        self.read += n;
+        Ok(n)
+    }
+}
+
+impl Drop for SftpReader {
+    fn drop(&mut self) {
+        let _ = self.session.lock().unwrap().close(&self.handle);
+    }
+}
+
+/// A remote file being written. Data is sent `IN_FLIGHT` chunks at a
+/// time, and what is left on `flush`.
+struct SftpWriter {
+    session: Arc<Mutex<Session>>,
+    handle: Vec<u8>,
+    /// Where the pending data goes.
+    offset: u64,
+    pending: Vec<u8>,
+}
+
+impl SftpWriter {
+    fn send_pending(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let requests: Vec<_> = self
+            .pending
+            .chunks(CHUNK_SIZE)
+            .scan(self.offset, |offset, chunk| {
+                let mut body = Packet::new();
+                body.string(&self.handle).u64(*offset).string(chunk);
+                *offset += chunk.len() as u64;
+                Some((fxp::WRITE, body.0))
+            })
+            .collect();
+        let responses = self.session.lock().unwrap().pipeline(&requests)?;
+        responses.into_iter().try_for_each(expect_ok)?;
+        self.offset += self.pending.len() as u64;
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+impl Write for SftpWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        if self.pending.len() >= IN_FLIGHT * CHUNK_SIZE {
+            self.send_pending()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_pending()
+    }
+}
+
+impl Drop for SftpWriter {
+    fn drop(&mut self) {
+        let _ = self.send_pending();
+        let _ = self.session.lock().unwrap().close(&self.handle);
+    }
+}
+
+/// The attributes of a remote file that a copy needs.
+#[derive(Debug, Default)]
+struct Attrs {
+    size: Option<u64>,
+    permissions: Option<u32>,
+    mtime: Option<u32>,
+}
+
+impl Attrs {
+    fn parse(fields: &mut Fields<'_>) -> io::Result<Self> {
+        let flags = fields.u32()?;
+        let mut attrs = Self::default();
+        if flags & attr::SIZE != 0 {
+            attrs.size = Some(fields.u64()?);
+        }
+        if flags & attr::UIDGID != 0 {
+            fields.u32()?;
+            fields.u32()?;
+        }
+        if flags & attr::PERMISSIONS != 0 {
+            attrs.permissions = Some(fields.u32()?);
+        }
+        if flags & attr::ACMODTIME != 0 {
+            fields.u32()?;
+            attrs.mtime = Some(fields.u32()?);
+        }
+        if flags & attr::EXTENDED != 0 {
+            for _ in 0..fields.u32()? {
+                fields.string()?;
+                fields.string()?;
+            }
+        }
+        Ok(attrs)
+    }
+
+    fn is_dir(&self) -> bool {
+        self.permissions
+            .is_some_and(|mode| mode & S_IFMT == S_IFDIR)
+    }
+}
+
+/// A `fxp::STATUS` response.
+struct Status {
+    code: u32,
+    message: String,
+}
+
+impl Status {
+    fn parse(payload: &[u8]) -> io::Result<Self> {
+        let mut fields = Fields(payload);
+        let code = fields.u32()?;
+        // Servers of old drafts leave out the message.
+        let message = fields.string().unwrap_or_default();
+        Ok(Self {
+            code,
+            message: String::from_utf8_lossy(message).into_owned(),
+        })
+    }
+
+    fn into_error(self) -> io::Error {
+        let kind = match self.code {
+            status::NO_SUCH_FILE => io::ErrorKind::NotFound,
+            status::PERMISSION_DENIED => io::ErrorKind::PermissionDenied,
+            status::OP_UNSUPPORTED => io::ErrorKind::Unsupported,
+            _ => io::ErrorKind::Other,
+        };
+        match self.message.is_empty() {
+            true => io::Error::new(kind, format!("SFTP error {}", self.code)),
+            false => io::Error::new(kind, self.message),
+        }
+    }
+}
+
+/// Succeeds on an OK status and fails with any other response.
+fn expect_ok(response: (u8, Vec<u8>)) -> io::Result<()> {
+    match response {
+        (fxp::STATUS, payload) => match Status::parse(&payload)? {
+            Status {
+                code: status::OK, ..
+            } => Ok(()),
+            status => Err(status.into_error()),
+        },
+        response => Err(unexpected(response)),
+    }
+}
+
+/// The handle of a `fxp::HANDLE` response.
+fn handle(response: (u8, Vec<u8>)) -> io::Result<Vec<u8>> {
+    match response {
+        (fxp::HANDLE, payload) => Ok(Fields(&payload).string()?.to_vec()),
+        response => Err(unexpected(response)),
+    }
+}
+
+/// The error for a response other than the one expected, which says why
+/// if it is a status.
+fn unexpected((kind, payload): (u8, Vec<u8>)) -> io::Error {
+    match kind {
+        fxp::STATUS => match Status::parse(&payload) {
+            Ok(status) => status.into_error(),
+            Err(err) => err,
+        },
+        _ => bad_message(&format!("an unexpected response of type {}", kind)),
+    }
+}
+
+fn bad_message(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("the SFTP server sent {}", what),
+    )
+}
+
+/// `path` as the server takes it, with `/` between components.
+fn remote(path: &Path) -> Vec<u8> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        path.as_os_str().as_bytes().to_vec()
+    }
+    #[cfg(not(unix))]
+    {
+        path.to_string_lossy().replace('\\', "/").into_bytes()
+    }
+}
+
+/// A name from the server as a path component.
+fn local(name: &[u8]) -> PathBuf {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        std::ffi::OsStr::from_bytes(name).into()
+    }
+    #[cfg(not(unix))]
+    {
+        String::from_utf8_lossy(name).into_owned().into()
+    }
+}
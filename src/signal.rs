@@ -0,0 +1,108 @@
+//! Status snapshots on request: like dd, cpv prints a status line when it
+//! receives SIGUSR1 or, on BSD and macOS, SIGINFO (Ctrl-T). SIGINT and
+//! SIGTERM can also stop a copy cleanly, through its cancellation token.
+
+use crate::cancel::CancellationToken;
+use crate::progress::{self, Rate, Ticker};
+use crate::ui::ProgressBar;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Once, OnceLock};
+use std::time::Duration;
+
+/// Set by the signal handler and cleared once the status is printed.
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// The signals that request a status line.
+const SIGNALS: &[libc::c_int] = &[
+    libc::SIGUSR1,
+    #[cfg(any(
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "dragonfly",
+        target_os = "openbsd",
+        target_os = "netbsd"
+    ))]
+    libc::SIGINFO,
+];
+
+/// The token that SIGINT and SIGTERM cancel.
+static INTERRUPT: OnceLock<CancellationToken> = OnceLock::new();
+
+/// How often a request is looked for.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Prints a status line for `pb`, whose throughput `rate` tracks, on stderr
+/// whenever one is requested, until the ticker is dropped. The line is
+/// printed whatever the progress mode, as it was asked for.
+pub(crate) fn print_status_on_request(pb: &ProgressBar, rate: &Arc<Rate>) -> io::Result<Ticker> {
+    install()?;
+    let rate = Arc::clone(rate);
+    Ok(Ticker::start(pb, POLL_INTERVAL, move |pb| {
+        if take_request() {
+            eprintln!("{}", progress::status_line(pb, &rate));
+        }
+    }))
+}
+
+/// Whether a status line was requested since the last call.
+pub(crate) fn take_request() -> bool {
+    REQUESTED.swap(false, Ordering::Relaxed)
+}
+
+/// Installs the handler of the status signals, once per process.
+pub(crate) fn install() -> io::Result<()> {
+    static INSTALL: Once = Once::new();
+    let mut result = Ok(());
+    INSTALL.call_once(|| {
+        for &signal in SIGNALS {
+            if let Err(err) = set_handler(signal, handle) {
+                result = Err(err);
+            }
+        }
+    });
+    result
+}
+
+/// Cancels `token` when the process receives SIGINT or SIGTERM, so that
+/// the copy stops cleanly rather than being killed. Once it is cancelled,
+/// another of these signals ends the process at once. Only the first token
+/// given is cancelled.
+pub(crate) fn cancel_on_interrupt(token: &CancellationToken) -> io::Result<()> {
+    if INTERRUPT.set(token.clone()).is_err() {
+        return Ok(());
+    }
+    set_handler(libc::SIGINT, interrupt)?;
+    set_handler(libc::SIGTERM, interrupt)
+}
+
+/// Makes `handler` handle `signal`.
+fn set_handler(signal: libc::c_int, handler: extern "C" fn(libc::c_int)) -> io::Result<()> {
+    // SAFETY: the handlers only use atomics and `_exit`, which are
+    // async-signal-safe, and SA_RESTART keeps the copy's reads and writes
+    // from failing with EINTR.
+    let installed = unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handler as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(signal, &action, std::ptr::null_mut())
+    };
+    match installed {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+extern "C" fn handle(_: libc::c_int) {
+    REQUESTED.store(true, Ordering::Relaxed);
+}
+
+extern "C" fn interrupt(signal: libc::c_int) {
+    match INTERRUPT.get() {
+        Some(token) if !token.is_cancelled() => token.cancel(),
+        // SAFETY: `_exit` is async-signal-safe.
+        _ => unsafe { libc::_exit(128 + signal) },
+    }
+}
@@ -0,0 +1,32 @@
+//! Human-friendly byte sizes such as `4M`, `512KiB` or `1.5g`.
+
+/// Parses a byte count with an optional binary suffix (`K`, `M`, `G`, `T`,
+/// optionally followed by `B` or `iB`, any case). Fractions are allowed
+/// with a suffix, e.g. `1.5M`.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid size '{}' (expected e.g. 4096, 512K or 4M)", s);
+    let trimmed = s.trim();
+    let split = trimmed
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(trimmed.len());
+    let (number, suffix) = trimmed.split_at(split);
+
+    let shift = match suffix.to_ascii_lowercase().as_str() {
+        "" | "b" => 0,
+        "k" | "kb" | "kib" => 10,
+        "m" | "mb" | "mib" => 20,
+        "g" | "gb" | "gib" => 30,
+        "t" | "tb" | "tib" => 40,
+        _ => return Err(invalid()),
+    };
+
+    if shift == 0 {
+        return number.parse().map_err(|_| invalid());
+    }
+    let value: f64 = number.parse().map_err(|_| invalid())?;
+    let bytes = value * (1u64 << shift) as f64;
+    if !bytes.is_finite() || bytes >= u64::MAX as f64 {
+        return Err(invalid());
+    }
+    Ok(bytes as u64)
+}
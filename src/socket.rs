@@ -0,0 +1,203 @@
+//! A local socket for `--progress-socket` that serves the progress events
+//! to any number of clients, so that a monitor can attach to a running copy
+//! without owning its stdout or stderr.
+//!
+//! On Unix this is a Unix domain socket at the given path; on Windows a
+//! named pipe whose name is the last component of the path, under
+//! `\\.\pipe\`. Clients that connect late see the events from then on, and
+//! a client that falls behind or goes away is disconnected rather than
+//! slowing the copy down.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+#[cfg(any(unix, windows))]
+use std::thread;
+
+type Clients = Arc<Mutex<Vec<Box<dyn Write + Send>>>>;
+
+/// A listening socket that broadcasts everything written to it to the
+/// connected clients.
+pub struct EventSocket {
+    path: PathBuf,
+    clients: Clients,
+}
+
+impl fmt::Debug for EventSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventSocket")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl EventSocket {
+    /// Listens at `path`, accepting clients in the background.
+    #[cfg(unix)]
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        use std::fs;
+        use std::os::unix::fs::FileTypeExt;
+        use std::os::unix::net::{UnixListener, UnixStream};
+
+        // A socket left by a cpv that was killed is replaced, but not one
+        // that is still being served, nor any other kind of file.
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if metadata.file_type().is_socket() && UnixStream::connect(path).is_err() {
+                fs::remove_file(path)?;
+            }
+        }
+        let listener = UnixListener::bind(path)?;
+        let clients = Clients::default();
+        let accepted = Arc::clone(&clients);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                if stream.set_nonblocking(true).is_ok() {
+                    accepted.lock().unwrap().push(Box::new(stream));
+                }
+            }
+        });
+        Ok(Self {
+            path: path.to_path_buf(),
+            clients,
+        })
+    }
+
+    /// Listens on the named pipe `\\.\pipe\NAME`, where `NAME` is the last
+    /// component of `path`, accepting clients in the background.
+    #[cfg(windows)]
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        let name = path.file_name().unwrap_or(path.as_os_str());
+        let pipe = Path::new(r"\\.\pipe\").join(name);
+        let first = pipe::create(&pipe, true)?;
+        let clients = Clients::default();
+        let accepted = Arc::clone(&clients);
+        let name = pipe.clone();
+        thread::spawn(move || {
+            let mut next = Ok(first);
+            while let Ok(instance) = next {
+                if let Ok(client) = pipe::connect(instance) {
+                    accepted.lock().unwrap().push(Box::new(client));
+                }
+                next = pipe::create(&name, false);
+            }
+        });
+        Ok(Self {
+            path: pipe,
+            clients,
+        })
+    }
+
+    /// Sandboxes such as WASI have no local sockets to listen on.
+    #[cfg(not(any(unix, windows)))]
+    pub fn bind(_path: &Path) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "progress sockets are not supported on this platform",
+        ))
+    }
+
+    /// Where clients connect.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Write for EventSocket {
+    /// Writes `buf` to every client, dropping those that cannot take all of
+    /// it at once.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.clients
+            .lock()
+            .unwrap()
+            .retain_mut(|client| client.write_all(buf).is_ok());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+impl Drop for EventSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(windows)]
+mod pipe {
+    use crate::windows::wide;
+    use std::fs::File;
+    use std::io;
+    use std::os::windows::io::{FromRawHandle, OwnedHandle};
+    use std::path::Path;
+    use std::ptr;
+    use windows_sys::Win32::Foundation::{
+        GetLastError, ERROR_PIPE_CONNECTED, HANDLE, INVALID_HANDLE_VALUE,
+    };
+    use windows_sys::Win32::Storage::FileSystem::{
+        FILE_FLAG_FIRST_PIPE_INSTANCE, PIPE_ACCESS_OUTBOUND,
+    };
+    use windows_sys::Win32::System::Pipes::{
+        ConnectNamedPipe, CreateNamedPipeW, SetNamedPipeHandleState, PIPE_NOWAIT,
+        PIPE_REJECT_REMOTE_CLIENTS, PIPE_TYPE_BYTE, PIPE_UNLIMITED_INSTANCES, PIPE_WAIT,
+    };
+
+    /// Size of the buffer of each pipe instance, which holds the events a
+    /// client has not read yet.
+    const BUFFER_SIZE: u32 = 64 * 1024;
+
+    /// Creates an instance of the pipe `name` for the next client. The
+    /// first instance fails if another process already serves the name.
+    pub(super) fn create(name: &Path, first: bool) -> io::Result<OwnedHandle> {
+        let name = wide(name);
+        let flags = if first {
+            FILE_FLAG_FIRST_PIPE_INSTANCE
+        } else {
+            0
+        };
+        // SAFETY: the name is a NUL-terminated wide string that outlives
+        // the call, and the security attributes may be null.
+        let handle = unsafe {
+            CreateNamedPipeW(
+                name.as_ptr(),
+                PIPE_ACCESS_OUTBOUND | flags,
+                PIPE_TYPE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+                PIPE_UNLIMITED_INSTANCES,
+                BUFFER_SIZE,
+                0,
+                0,
+                ptr::null(),
+            )
+        };
+        if handle == INVALID_HANDLE_VALUE {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the handle was just created and nothing else owns it.
+        Ok(unsafe { OwnedHandle::from_raw_handle(handle as _) })
+    }
+
+    /// Waits for a client to open `instance`, then makes writes to it fail
+    /// instead of blocking once its buffer is full.
+    pub(super) fn connect(instance: OwnedHandle) -> io::Result<File> {
+        use std::os::windows::io::AsRawHandle;
+
+        let handle = instance.as_raw_handle() as HANDLE;
+        // SAFETY: the handle is a pipe instance owned by `instance`, and
+        // the call is synchronous, so it needs no OVERLAPPED.
+        if unsafe { ConnectNamedPipe(handle, ptr::null_mut()) } == 0
+            && unsafe { GetLastError() } != ERROR_PIPE_CONNECTED
+        {
+            return Err(io::Error::last_os_error());
+        }
+        let mode = PIPE_TYPE_BYTE | PIPE_NOWAIT;
+        // SAFETY: as above; the mode outlives the call and the other
+        // settings may be null.
+        if unsafe { SetNamedPipeHandleState(handle, &mode, ptr::null(), ptr::null()) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(File::from(instance))
+    }
+}
@@ -0,0 +1,191 @@
+//! Waiting for space on a full destination, for
+//! `CopyOptions::wait_for_space`: a file that fails because the destination
+//! filesystem is full makes every worker stop where it is, and the copy
+//! shows how much more space it needs until enough is freed for the file,
+//! which is then copied again, or until the copy is cancelled.
+
+use crate::context;
+use crate::limit::RateLimiter;
+use crate::progress::Rate;
+use crate::ui::{format_size, ProgressBar, BINARY};
+use crate::{CancellationToken, CopyOptions};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often free space is looked at while waiting.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Whether `err` says the destination filesystem has no space left. Quotas
+/// and files too large for it are not waited out.
+pub(crate) fn is_full(err: &io::Error) -> bool {
+    let err = context::os_error(err);
+    #[cfg(unix)]
+    {
+        err.raw_os_error() == Some(libc::ENOSPC)
+    }
+    #[cfg(windows)]
+    {
+        use windows_sys::Win32::Foundation::{ERROR_DISK_FULL, ERROR_HANDLE_DISK_FULL};
+        matches!(
+            err.raw_os_error().map(|code| code as u32),
+            Some(ERROR_DISK_FULL | ERROR_HANDLE_DISK_FULL)
+        )
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = err;
+        false
+    }
+}
+
+/// Where the workers of a copy wait for space together.
+pub(crate) struct Waiter {
+    pb: ProgressBar,
+    rate: Arc<Rate>,
+    cancel: Option<CancellationToken>,
+    /// Held by the worker waiting for space, which the others block on in
+    /// `pass`.
+    waiting: Mutex<()>,
+}
+
+impl Waiter {
+    pub(crate) fn new(pb: &ProgressBar, rate: &Arc<Rate>, options: &CopyOptions) -> Self {
+        Self {
+            pb: pb.clone(),
+            rate: Arc::clone(rate),
+            cancel: options.cancel.clone(),
+            waiting: Mutex::new(()),
+        }
+    }
+
+    /// Blocks while a worker waits for space.
+    fn pass(&self) {
+        drop(self.waiting.lock().unwrap_or_else(|err| err.into_inner()));
+    }
+
+    /// Waits until the filesystem of `dest`, which a file of `size` bytes
+    /// failed to fit in, has room for it, counting what was written of it.
+    /// Returns how long it waited, or the error of a cancelled copy.
+    pub(crate) fn wait(&self, dest: &Path, size: u64) -> io::Result<Duration> {
+        let _waiting = self.waiting.lock().unwrap_or_else(|err| err.into_inner());
+        let started = Instant::now();
+        let message = self.pb.message();
+        self.rate.hold(true);
+        let waited = loop {
+            // At least one poll, so that a file that keeps failing is not
+            // copied again in a busy loop.
+            thread::sleep(POLL_INTERVAL);
+            if let Some(Err(err)) = self.cancel.as_ref().map(CancellationToken::check) {
+                break Err(err);
+            }
+            // What was written of the file is freed when it is copied again.
+            let written = fs::metadata(dest).map_or(0, |metadata| metadata.len());
+            // A bare relative destination is in the current directory.
+            let dir = match dest.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            let Some(free) = available(dir) else {
+                break Ok(started.elapsed());
+            };
+            let room = free + written;
+            if room >= size {
+                break Ok(started.elapsed());
+            }
+            // The rest of the copy, which counts what was written of the
+            // file as done: that needs no more than is free.
+            let left = self
+                .pb
+                .length()
+                .unwrap_or(0)
+                .saturating_sub(self.pb.position());
+            let needed = left.saturating_sub(free).max(size - room);
+            self.pb.set_message(format!(
+                "destination full: {} more needed, waiting for space",
+                format_size(needed, BINARY)
+            ));
+        };
+        self.rate.hold(false);
+        self.pb.set_message(message);
+        waited
+    }
+}
+
+impl fmt::Debug for Waiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Waiter").finish_non_exhaustive()
+    }
+}
+
+/// Throttles with `inner`, if any, and holds the worker while another
+/// waits for space.
+#[derive(Debug)]
+pub(crate) struct Gate {
+    pub(crate) inner: Option<Arc<dyn RateLimiter>>,
+    pub(crate) waiter: Arc<Waiter>,
+}
+
+impl RateLimiter for Gate {
+    fn acquire(&self, bytes: u64) {
+        if let Some(inner) = &self.inner {
+            inner.acquire(bytes);
+        }
+        self.waiter.pass();
+    }
+}
+
+/// The bytes free to unprivileged users on the filesystem of `dir`, none
+/// if it has no inodes left, or `None` if it cannot tell.
+#[cfg(unix)]
+fn available(dir: &Path) -> Option<u64> {
+    use std::ffi::CString;
+    use std::mem;
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = CString::new(dir.as_os_str().as_bytes()).ok()?;
+    // SAFETY: `dir` is NUL-terminated and `stat` is a writable statvfs.
+    let stat = unsafe {
+        let mut stat: libc::statvfs = mem::zeroed();
+        if libc::statvfs(dir.as_ptr(), &mut stat) != 0 {
+            return None;
+        }
+        stat
+    };
+    #[allow(clippy::useless_conversion)]
+    let (blocks, size, files, inodes) = (
+        u64::from(stat.f_bavail),
+        u64::from(stat.f_frsize),
+        u64::from(stat.f_files),
+        u64::from(stat.f_favail),
+    );
+    // Filesystems without a fixed number of inodes report none at all.
+    match files > 0 && inodes == 0 {
+        true => Some(0),
+        false => Some(blocks * size),
+    }
+}
+
+#[cfg(windows)]
+fn available(dir: &Path) -> Option<u64> {
+    use std::os::windows::ffi::OsStrExt;
+    use std::ptr;
+    use windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+
+    let dir: Vec<u16> = dir.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut free = 0u64;
+    // SAFETY: `dir` is a NUL-terminated wide string and `free` a writable
+    // u64; the other counts are not asked for.
+    let found =
+        unsafe { GetDiskFreeSpaceExW(dir.as_ptr(), &mut free, ptr::null_mut(), ptr::null_mut()) };
+    (found != 0).then_some(free)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn available(_dir: &Path) -> Option<u64> {
+    None
+}
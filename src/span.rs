@@ -0,0 +1,122 @@
+//! Copies spanning several volumes swapped at the same mount point, for
+//! `CopyOptions::span`: when the destination fills up, the file that did
+//! not fit is removed and `CopyHooks::on_volume_full` is asked to have the
+//! next volume mounted in place of the full one, such as the next of a
+//! stack of drives; the copy then continues on it with that file. Files
+//! are copied one at a time, so that none is open on a volume being
+//! swapped, and each is written whole to a single volume.
+//!
+//! The manifest of the volumes, which must be kept off them, lists the
+//! files copied to each in order, relative to the destination root:
+//!
+//! ```text
+//! volume 1
+//! photos/2023/a.jpg
+//! photos/2023/b.jpg
+//! volume 2
+//! photos/2024/c.jpg
+//! ```
+//!
+//! with names escaped as in the journal. Names that are not valid UTF-8
+//! are not listed. Each line is written as soon as the file is copied, so
+//! the manifest stays accurate should the copy stop short.
+
+use crate::hooks::CopyHooks;
+use crate::journal::{escape, Journal};
+use crate::location::Location;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// The manifest of a copy spanning volumes, written as it goes.
+pub struct Volumes {
+    state: Mutex<State>,
+}
+
+struct State {
+    manifest: File,
+    /// The volume being copied to, from 1.
+    volume: u32,
+    /// Files copied to it so far.
+    files: u64,
+}
+
+impl fmt::Debug for Volumes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Volumes").finish_non_exhaustive()
+    }
+}
+
+impl Volumes {
+    /// Starts the manifest at `path`, replacing any there.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut manifest = File::create(path)?;
+        manifest.write_all(b"volume 1\n")?;
+        Ok(Self {
+            state: Mutex::new(State {
+                manifest,
+                volume: 1,
+                files: 0,
+            }),
+        })
+    }
+
+    /// The volume being copied to, from 1.
+    pub fn volume(&self) -> u32 {
+        self.state
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .volume
+    }
+
+    /// Lists `dest`, copied into the tree at `root`, on the current
+    /// volume. The manifest does not stop the copy, so errors are ignored.
+    pub(crate) fn file(&self, root: &Path, dest: &Path) {
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        state.files += 1;
+        let name = dest.strip_prefix(root).unwrap_or(dest);
+        if let Some(name) = name.to_str() {
+            let _ = writeln!(state.manifest, "{}", escape(name));
+        }
+    }
+
+    /// Has `hooks` swap the full volume that `dest`, a file in the tree at
+    /// `root`, did not fit in for the next one, starting `journal` anew for
+    /// it. Returns the new volume, or
+    /// `None` if it was not swapped: without hooks, when they decline, or
+    /// when nothing fit on the volume at all, which another of the same
+    /// size would not change.
+    pub(crate) fn next(
+        &self,
+        dest: &Location,
+        root: &Path,
+        hooks: Option<&dyn CopyHooks>,
+        journal: Option<&Journal>,
+    ) -> io::Result<Option<u32>> {
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        let Some(hooks) = hooks.filter(|_| state.files > 0) else {
+            return Ok(None);
+        };
+        if let Err(err) = dest.remove() {
+            if err.kind() != io::ErrorKind::NotFound {
+                return Err(err);
+            }
+        }
+        let volume = state.volume;
+        let swap = || hooks.on_volume_full(root, volume);
+        let swapped = match journal {
+            Some(journal) => journal.across_volumes(swap)?,
+            None => swap(),
+        };
+        if !swapped {
+            return Ok(None);
+        }
+        state.volume += 1;
+        state.files = 0;
+        let line = format!("volume {}\n", state.volume);
+        state.manifest.write_all(line.as_bytes())?;
+        Ok(Some(state.volume))
+    }
+}
@@ -0,0 +1,164 @@
+//! Sparse file handling for `--sparse`.
+
+use crate::progress::Progress;
+use std::fmt;
+use std::fs::{File, Metadata};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::str::FromStr;
+
+/// Granularity at which all-zero blocks become holes.
+pub(crate) const BLOCK_SIZE: usize = 8192;
+
+/// When to recreate holes in destination files, mirroring `cp --sparse`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SparseMode {
+    /// Keep holes for sources that are themselves sparse.
+    #[default]
+    Auto,
+    /// Turn every all-zero block into a hole, even in dense sources.
+    Always,
+    /// Always write every byte.
+    Never,
+}
+
+impl SparseMode {
+    /// The names of the modes, as `from_str` takes them and `Display`
+    /// writes them.
+    pub const NAMES: &[&str] = &["auto", "always", "never"];
+
+    /// Whether a source with the given metadata should be copied sparsely.
+    pub(crate) fn applies_to(self, metadata: &Metadata) -> bool {
+        match self {
+            Self::Auto => is_sparse(metadata),
+            Self::Always => true,
+            Self::Never => false,
+        }
+    }
+}
+
+impl FromStr for SparseMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            _ => Err(format!(
+                "invalid sparse mode '{}' (expected auto, always or never)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SparseMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Auto => "auto",
+            Self::Always => "always",
+            Self::Never => "never",
+        })
+    }
+}
+
+#[cfg(unix)]
+fn is_sparse(metadata: &Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    metadata.blocks() * 512 < metadata.len()
+}
+
+#[cfg(not(unix))]
+fn is_sparse(_metadata: &Metadata) -> bool {
+    false
+}
+
+/// Copies `len` bytes from `source` to `dest`, leaving holes wherever the
+/// source has a hole or a whole buffer of zeros.
+///
+/// Data regions are located with `SEEK_DATA`/`SEEK_HOLE` where available so
+/// large holes are never read; all-zero blocks inside data regions (or
+/// everywhere, without that support) are skipped as well. The progress bar
+/// still advances by the logical length of the file.
+pub(crate) fn copy_sparse(
+    mut source: &File,
+    mut dest: &File,
+    len: u64,
+    progress: &mut Progress<'_>,
+    buffer: &mut [u8],
+) -> io::Result<u64> {
+    let segments = data_segments(source, len).unwrap_or_else(|| std::iter::once(0..len).collect());
+    let mut dest_pos = 0;
+    let mut skipped = 0;
+
+    for segment in segments {
+        progress.skip(segment.start - skipped)?;
+        source.seek(SeekFrom::Start(segment.start))?;
+        let mut pos = segment.start;
+
+        while pos < segment.end {
+            let want = buffer.len().min((segment.end - pos) as usize);
+            let n = match source.read(&mut buffer[..want]) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            if buffer[..n].iter().any(|&b| b != 0) {
+                if dest_pos != pos {
+                    dest.seek(SeekFrom::Start(pos))?;
+                }
+                dest.write_all(&buffer[..n])?;
+                dest_pos = pos + n as u64;
+            }
+            pos += n as u64;
+            progress.inc_data(&buffer[..n])?;
+        }
+        skipped = pos;
+    }
+
+    progress.skip(len.saturating_sub(skipped))?;
+    // Extending the length materialises any trailing hole.
+    dest.set_len(len)?;
+    Ok(len)
+}
+
+/// Returns the data regions of `file`, or `None` if the platform or
+/// filesystem cannot report them.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn data_segments(file: &File, len: u64) -> Option<Vec<Range<u64>>> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    let mut segments = Vec::new();
+    let mut pos = 0;
+
+    while pos < len {
+        // SAFETY: lseek only inspects the descriptor, which stays open.
+        let start = unsafe { libc::lseek(fd, pos as libc::off_t, libc::SEEK_DATA) };
+        if start < 0 {
+            // ENXIO: no more data past `pos`, the rest is one hole.
+            return match io::Error::last_os_error().raw_os_error() {
+                Some(libc::ENXIO) => Some(segments),
+                _ => None,
+            };
+        }
+        // SAFETY: as above.
+        let end = unsafe { libc::lseek(fd, start, libc::SEEK_HOLE) };
+        if end < 0 {
+            return None;
+        }
+        let end = (end as u64).min(len);
+        segments.push(start as u64..end);
+        pos = end;
+    }
+
+    Some(segments)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn data_segments(_file: &File, _len: u64) -> Option<Vec<Range<u64>>> {
+    None
+}
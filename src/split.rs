@@ -0,0 +1,283 @@
+//! Files in fixed-size parts, for media and uploads that cap the size of a
+//! file: `--split` writes every destination file as `NAME.000`,
+//! `NAME.001`, … and `--join` reads such parts back as the whole file.
+
+use crate::vfs::{FileInfo, Filesystem};
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// The part `index` of the file `path`: `path.000` for the first.
+fn part(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{:03}", index));
+    name.into()
+}
+
+/// The name of the file that `name` is a part of, with the index of the
+/// part, if it is the name of a part.
+fn part_of(name: &str) -> Option<(&str, usize)> {
+    let (whole, index) = name.rsplit_once('.')?;
+    if whole.is_empty() || index.len() < 3 || !index.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some((whole, index.parse().ok()?))
+}
+
+/// The file `path` made of its parts on `fs`: their total size and the
+/// time the last was modified.
+fn parts_info(fs: &dyn Filesystem, path: &Path) -> io::Result<FileInfo> {
+    let mut info = match fs.metadata(&part(path, 0)) {
+        Ok(info) if !info.is_dir => info,
+        Ok(_) => return Err(io::ErrorKind::NotFound.into()),
+        Err(err) => return Err(err),
+    };
+    let mut index = 1;
+    while let Ok(next) = fs.metadata(&part(path, index)) {
+        if next.is_dir {
+            break;
+        }
+        info.len += next.len;
+        info.modified = info.modified.max(next.modified);
+        index += 1;
+    }
+    Ok(info)
+}
+
+/// Reads the parts of a file one after the other.
+struct Parts {
+    fs: Arc<dyn Filesystem>,
+    path: PathBuf,
+    next: usize,
+    current: Box<dyn Read + Send>,
+}
+
+impl Parts {
+    fn open(fs: Arc<dyn Filesystem>, path: &Path) -> io::Result<Self> {
+        let current = fs.open(&part(path, 0))?;
+        Ok(Self {
+            fs,
+            path: path.to_path_buf(),
+            next: 1,
+            current,
+        })
+    }
+}
+
+impl Read for Parts {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.current.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            let next = part(&self.path, self.next);
+            if self.fs.metadata(&next).map_or(true, |info| info.is_dir) {
+                return Ok(0);
+            }
+            self.current = self.fs.open(&next)?;
+            self.next += 1;
+        }
+    }
+}
+
+/// The destination filesystem `inner` with its files split: `path` is
+/// written as parts `path.000`, `path.001`, … of `size` bytes, the last
+/// one shorter, and read back whole.
+#[derive(Debug)]
+pub(crate) struct Split {
+    inner: Arc<dyn Filesystem>,
+    size: u64,
+}
+
+impl Split {
+    pub(crate) fn new(inner: Arc<dyn Filesystem>, size: u64) -> Self {
+        Self {
+            inner,
+            size: size.max(1),
+        }
+    }
+
+    /// The parts of the file `path` that exist.
+    fn parts(&self, path: &Path) -> Vec<PathBuf> {
+        (0..)
+            .map(|index| part(path, index))
+            .take_while(|part| self.inner.metadata(part).is_ok_and(|info| !info.is_dir))
+            .collect()
+    }
+}
+
+impl Filesystem for Split {
+    fn metadata(&self, path: &Path) -> io::Result<FileInfo> {
+        match self.inner.metadata(path) {
+            Ok(info) if info.is_dir => Ok(info),
+            _ => parts_info(self.inner.as_ref(), path),
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        self.inner.read_dir(path)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(Parts::open(Arc::clone(&self.inner), path)?))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn Write + Send>> {
+        let first = self.inner.create(&part(path, 0))?;
+        Ok(Box::new(SplitFile {
+            inner: Arc::clone(&self.inner),
+            path: path.to_path_buf(),
+            size: self.size,
+            index: 0,
+            written: 0,
+            current: first,
+        }))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.inner.create_dir_all(path)
+    }
+
+    fn set_modified(&self, path: &Path, modified: SystemTime) -> io::Result<()> {
+        self.parts(path)
+            .iter()
+            .try_for_each(|part| self.inner.set_modified(part, modified))
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        self.parts(path)
+            .iter()
+            .try_for_each(|part| self.inner.set_mode(part, mode))
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        self.inner.finish()
+    }
+}
+
+/// A file being written in parts.
+struct SplitFile {
+    inner: Arc<dyn Filesystem>,
+    path: PathBuf,
+    size: u64,
+    /// The part being written, and the bytes written to it.
+    index: usize,
+    written: u64,
+    current: Box<dyn Write + Send>,
+}
+
+impl Write for SplitFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.written == self.size {
+            self.current.flush()?;
+            self.index += 1;
+            self.current = self.inner.create(&part(&self.path, self.index))?;
+            self.written = 0;
+        }
+        let room = (self.size - self.written).min(buf.len() as u64) as usize;
+        let n = self.current.write(&buf[..room])?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    /// Also removes the parts left by a longer file of the same name, which
+    /// would be read as part of this one.
+    fn flush(&mut self) -> io::Result<()> {
+        self.current.flush()?;
+        let mut stale = self.index + 1;
+        while self
+            .inner
+            .metadata(&part(&self.path, stale))
+            .is_ok_and(|info| !info.is_dir)
+        {
+            self.inner.remove(&part(&self.path, stale))?;
+            stale += 1;
+        }
+        Ok(())
+    }
+}
+
+/// The source filesystem `inner` with the parts of split files read as
+/// the files: a file `NAME.000`, with `NAME.001` and so on after it, is
+/// read as `NAME`, unless that is also in its directory.
+#[derive(Debug)]
+pub(crate) struct Joined {
+    inner: Arc<dyn Filesystem>,
+}
+
+impl Joined {
+    pub(crate) fn new(inner: Arc<dyn Filesystem>) -> Self {
+        Self { inner }
+    }
+}
+
+impl Filesystem for Joined {
+    fn metadata(&self, path: &Path) -> io::Result<FileInfo> {
+        match self.inner.metadata(path) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                parts_info(self.inner.as_ref(), path).map_err(|_| err)
+            }
+            found => found,
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let children = self.inner.read_dir(path)?;
+        let names: HashSet<OsString> = children
+            .iter()
+            .filter_map(|child| child.file_name())
+            .map(|name| name.to_owned())
+            .collect();
+        // The files whose parts are listed, unless they are there too.
+        let wholes: HashSet<&str> = names
+            .iter()
+            .filter_map(|name| part_of(name.to_str()?))
+            .filter(|&(whole, index)| index == 0 && !names.contains(&OsString::from(whole)))
+            .map(|(whole, _)| whole)
+            .collect();
+        let mut entries = Vec::new();
+        for child in &children {
+            let part = child
+                .file_name()
+                .and_then(|name| part_of(name.to_str()?))
+                .filter(|(whole, _)| wholes.contains(whole));
+            if let Some((whole, index)) = part {
+                if !self.inner.metadata(child)?.is_dir {
+                    if index == 0 {
+                        entries.push(path.join(whole));
+                    }
+                    continue;
+                }
+            }
+            entries.push(child.clone());
+        }
+        Ok(entries)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        match self.inner.metadata(path) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                Ok(Box::new(Parts::open(Arc::clone(&self.inner), path)?))
+            }
+            _ => self.inner.open(path),
+        }
+    }
+
+    fn create(&self, _path: &Path) -> io::Result<Box<dyn Write + Send>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "parts are joined only as they are read",
+        ))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.inner.create_dir_all(path)
+    }
+}
@@ -0,0 +1,151 @@
+//! The staging list of `cpv stage` and `cpv paste`, which copy and paste
+//! files as a file manager does: paths staged from any shell and directory
+//! are kept, absolute, in a per-user file until they are pasted.
+//!
+//! The file starts with a version line, followed by one path per line,
+//! with backslashes and newlines escaped as in the journal.
+
+use crate::journal::{escape, unescape};
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// First line of a staging list in the current format.
+const HEADER: &str = "cpv staged 1";
+
+/// The paths staged to be pasted, stored in a file.
+#[derive(Debug, Clone)]
+pub struct Staging {
+    path: PathBuf,
+}
+
+impl Staging {
+    /// `staged` in the per-user state directory.
+    pub fn default_path() -> Option<PathBuf> {
+        Some(state_dir()?.join("staged"))
+    }
+
+    /// The staging list stored at `path`, which need not exist yet.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// The paths staged, in the order they were staged, none if the list
+    /// does not exist or is in another format.
+    pub fn list(&self) -> io::Result<Vec<PathBuf>> {
+        let text = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+        let mut lines = text.lines();
+        if lines.next() != Some(HEADER) {
+            return Ok(Vec::new());
+        }
+        Ok(lines.filter_map(unescape).map(PathBuf::from).collect())
+    }
+
+    /// Stages `paths`, which must exist, after those staged already, and
+    /// returns the whole list. Paths are staged absolute, and only once.
+    pub fn add<P: AsRef<Path>>(
+        &self,
+        paths: impl IntoIterator<Item = P>,
+    ) -> io::Result<Vec<PathBuf>> {
+        let mut staged = self.list()?;
+        for path in paths {
+            let path = path.as_ref();
+            let absolute = absolute(path).map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!("cannot stage '{}': {}", path.display(), err),
+                )
+            })?;
+            if absolute.to_str().is_none() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "cannot stage '{}': the name is not valid UTF-8",
+                        path.display()
+                    ),
+                ));
+            }
+            if !staged.contains(&absolute) {
+                staged.push(absolute);
+            }
+        }
+        self.save(&staged)?;
+        Ok(staged)
+    }
+
+    /// Unstages `paths`, such as those that were pasted.
+    pub fn remove(&self, paths: &[PathBuf]) -> io::Result<()> {
+        let mut staged = self.list()?;
+        staged.retain(|path| !paths.contains(path));
+        self.save(&staged)
+    }
+
+    /// Empties the list.
+    pub fn clear(&self) -> io::Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes `staged` as the list, replacing it atomically, or removes
+    /// the list if it is empty.
+    fn save(&self, staged: &[PathBuf]) -> io::Result<()> {
+        if staged.is_empty() {
+            return self.clear();
+        }
+        let mut text = format!("{}\n", HEADER);
+        for path in staged.iter().filter_map(|path| path.to_str()) {
+            text.push_str(&escape(path));
+            text.push('\n');
+        }
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        fs::create_dir_all(dir)?;
+        // A temporary file of its own, so that runs saving at the same time
+        // do not write into each other's.
+        let mut temp = tempfile::NamedTempFile::new_in(dir)?;
+        temp.write_all(text.as_bytes())?;
+        temp.persist(&self.path).map_err(|err| err.error)?;
+        Ok(())
+    }
+}
+
+/// The per-user state directory: `$XDG_STATE_HOME/cpv` or
+/// `~/.local/state/cpv`, `~/Library/Application Support/cpv` on macOS and
+/// `%LOCALAPPDATA%\cpv` on Windows.
+pub(crate) fn state_dir() -> Option<PathBuf> {
+    let dir = if cfg!(windows) {
+        PathBuf::from(env::var_os("LOCALAPPDATA")?)
+    } else if cfg!(target_os = "macos") {
+        PathBuf::from(env::var_os("HOME")?).join("Library/Application Support")
+    } else if let Some(dir) = env::var_os("XDG_STATE_HOME").filter(|dir| !dir.is_empty()) {
+        PathBuf::from(dir)
+    } else {
+        PathBuf::from(env::var_os("HOME")?).join(".local/state")
+    };
+    Some(dir.join("cpv"))
+}
+
+/// `path` made absolute without resolving the symbolic link it may be,
+/// which is staged as a link, as it would be copied.
+fn absolute(path: &Path) -> io::Result<PathBuf> {
+    fs::symlink_metadata(path)?;
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => {
+            let parent = match parent.as_os_str().is_empty() {
+                true => env::current_dir()?,
+                false => fs::canonicalize(parent)?,
+            };
+            Ok(parent.join(name))
+        }
+        _ => fs::canonicalize(path),
+    }
+}
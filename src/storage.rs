@@ -0,0 +1,262 @@
+//! Storage detection used to tune parallelism and buffer sizes.
+
+use std::fmt;
+use std::path::Path;
+
+use crate::ui::{format_size, BINARY};
+
+/// Buffer size used when either side is a network filesystem, where each
+/// request pays a round trip.
+const NETWORK_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+
+/// Upper bound on the jobs chosen for solid-state storage.
+const MAX_SOLID_STATE_JOBS: usize = 16;
+
+/// Jobs used when either side is a network filesystem, enough to hide
+/// latency without flooding the server.
+const NETWORK_JOBS: usize = 4;
+
+/// The kind of device a path is stored on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum StorageKind {
+    /// A spinning disk, where concurrent access causes seeks.
+    Rotational,
+    /// An SSD or NVMe device.
+    SolidState,
+    /// An NFS, SMB or other network filesystem.
+    Network,
+    /// Anything that could not be identified.
+    Unknown,
+}
+
+impl StorageKind {
+    /// Identifies the storage holding `path`, or the nearest existing
+    /// ancestor of it.
+    pub fn detect(path: &Path) -> Self {
+        path.ancestors()
+            .map(|p| {
+                if p.as_os_str().is_empty() {
+                    Path::new(".")
+                } else {
+                    p
+                }
+            })
+            .find(|p| p.exists())
+            .map_or(Self::Unknown, sys::detect)
+    }
+
+    /// Files to copy at once on a device of this kind, for
+    /// `CopyOptions::per_device`.
+    pub(crate) fn jobs(self) -> usize {
+        match self {
+            Self::Rotational | Self::Unknown => 1,
+            Self::Network => NETWORK_JOBS,
+            Self::SolidState => solid_state_jobs(),
+        }
+    }
+}
+
+/// One job per CPU, up to `MAX_SOLID_STATE_JOBS`.
+fn solid_state_jobs() -> usize {
+    std::thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(MAX_SOLID_STATE_JOBS)
+}
+
+impl fmt::Display for StorageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Rotational => "rotational disk",
+            Self::SolidState => "solid-state",
+            Self::Network => "network",
+            Self::Unknown => "unknown",
+        })
+    }
+}
+
+/// The storage on both ends of a copy and the tuning derived from it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StorageProfile {
+    pub source: StorageKind,
+    pub dest: StorageKind,
+}
+
+impl StorageProfile {
+    pub fn detect(source: &Path, dest: &Path) -> Self {
+        Self {
+            source: StorageKind::detect(source),
+            dest: StorageKind::detect(dest),
+        }
+    }
+
+    fn involves(&self, kind: StorageKind) -> bool {
+        self.source == kind || self.dest == kind
+    }
+
+    /// Files to copy concurrently: one for spinning disks, a few for
+    /// network filesystems and one per CPU (up to 16) for solid-state.
+    pub fn jobs(&self) -> usize {
+        if self.involves(StorageKind::Rotational) || self.involves(StorageKind::Unknown) {
+            1
+        } else if self.involves(StorageKind::Network) {
+            NETWORK_JOBS
+        } else {
+            solid_state_jobs()
+        }
+    }
+
+    /// A fixed buffer size for network filesystems; `None` keeps the
+    /// adaptive buffer.
+    pub fn buffer_size(&self) -> Option<usize> {
+        self.involves(StorageKind::Network)
+            .then_some(NETWORK_BUFFER_SIZE)
+    }
+}
+
+impl fmt::Display for StorageProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "source {}, destination {}: {} job{}, {} buffer",
+            self.source,
+            self.dest,
+            self.jobs(),
+            if self.jobs() == 1 { "" } else { "s" },
+            match self.buffer_size() {
+                Some(size) => format_size(size, BINARY),
+                None => "adaptive".to_string(),
+            }
+        )
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use super::StorageKind;
+    use std::ffi::CString;
+    use std::fs;
+    use std::mem;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
+
+    /// `statfs` magic numbers of network filesystems: NFS, SMB, CIFS,
+    /// SMB2, Ceph, 9P and AFS.
+    const NETWORK_MAGIC: [u64; 7] = [
+        0x6969,
+        0x517b,
+        0xff53_4d42,
+        0xfe53_4d42,
+        0x00c3_6400,
+        0x0102_1997,
+        0x6b41_4653,
+    ];
+
+    pub(super) fn detect(path: &Path) -> StorageKind {
+        if is_network(path) {
+            return StorageKind::Network;
+        }
+        let Ok(metadata) = fs::metadata(path) else {
+            return StorageKind::Unknown;
+        };
+        let dev = metadata.dev();
+        let device = format!("/sys/dev/block/{}:{}", libc::major(dev), libc::minor(dev));
+        // Partitions keep their queue settings on the parent device.
+        ["queue/rotational", "../queue/rotational"]
+            .iter()
+            .find_map(|file| fs::read_to_string(Path::new(&device).join(file)).ok())
+            .map_or(StorageKind::Unknown, |flag| match flag.trim() {
+                "1" => StorageKind::Rotational,
+                "0" => StorageKind::SolidState,
+                _ => StorageKind::Unknown,
+            })
+    }
+
+    fn is_network(path: &Path) -> bool {
+        let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+            return false;
+        };
+        // SAFETY: `path` is NUL-terminated and `stat` is a writable statfs.
+        unsafe {
+            let mut stat: libc::statfs = mem::zeroed();
+            libc::statfs(path.as_ptr(), &mut stat) == 0
+                && NETWORK_MAGIC.contains(&(stat.f_type as u64))
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod sys {
+    use super::StorageKind;
+    use std::ffi::{CStr, CString};
+    use std::mem;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    const NETWORK_FILESYSTEMS: [&str; 4] = ["nfs", "smbfs", "afpfs", "webdav"];
+
+    pub(super) fn detect(path: &Path) -> StorageKind {
+        let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+            return StorageKind::Unknown;
+        };
+        // SAFETY: `path` is NUL-terminated, `stat` is a writable statfs and
+        // f_fstypename is NUL-terminated by the kernel.
+        let fstype = unsafe {
+            let mut stat: libc::statfs = mem::zeroed();
+            if libc::statfs(path.as_ptr(), &mut stat) != 0 {
+                return StorageKind::Unknown;
+            }
+            CStr::from_ptr(stat.f_fstypename.as_ptr())
+                .to_string_lossy()
+                .into_owned()
+        };
+        if NETWORK_FILESYSTEMS.contains(&fstype.as_str()) {
+            StorageKind::Network
+        } else {
+            StorageKind::Unknown
+        }
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use super::StorageKind;
+    use std::os::windows::ffi::OsStrExt;
+    use std::path::{Component, Path, Prefix};
+    use windows_sys::Win32::Storage::FileSystem::GetDriveTypeW;
+
+    const DRIVE_REMOTE: u32 = 4;
+
+    pub(super) fn detect(path: &Path) -> StorageKind {
+        let Ok(path) = std::fs::canonicalize(path) else {
+            return StorageKind::Unknown;
+        };
+        let Some(Component::Prefix(prefix)) = path.components().next() else {
+            return StorageKind::Unknown;
+        };
+        if matches!(prefix.kind(), Prefix::UNC(..) | Prefix::VerbatimUNC(..)) {
+            return StorageKind::Network;
+        }
+        let mut root = prefix.as_os_str().to_os_string();
+        root.push("\\");
+        let root: Vec<u16> = root.encode_wide().chain(Some(0)).collect();
+        // SAFETY: `root` is a NUL-terminated wide string.
+        match unsafe { GetDriveTypeW(root.as_ptr()) } {
+            DRIVE_REMOTE => StorageKind::Network,
+            _ => StorageKind::Unknown,
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+mod sys {
+    use super::StorageKind;
+    use std::path::Path;
+
+    pub(super) fn detect(_path: &Path) -> StorageKind {
+        StorageKind::Unknown
+    }
+}
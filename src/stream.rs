@@ -0,0 +1,129 @@
+//! Files whose size is not known before they are read: those under `/proc`
+//! and `/sys` report a size of 0, or of a page, whatever reading them
+//! returns, as do some FUSE filesystems. They are copied until EOF with the
+//! read/write loop, which neither preallocates, nor seeks over holes, nor
+//! stops at the size reported, and the progress totals are grown, or
+//! shrunk, to what was read. So are files still being written, such as a
+//! recording or a log, with `CopyOptions::follow`, which keeps copying what
+//! is appended to them until they stop growing.
+
+use crate::buffer::IoBuffer;
+use crate::changes::SourceChanged;
+use crate::progress::Progress;
+use std::fs::{File, Metadata};
+use std::io::{self, Read, Seek, Write};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often a followed file is looked at for more data.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Whether the size `metadata` reports for the regular `file` may not be
+/// that of its contents.
+pub(crate) fn size_unknown(file: &File, metadata: &Metadata) -> bool {
+    metadata.is_file() && (metadata.len() == 0 || sys::is_pseudo(file))
+}
+
+/// Copies `source`, from its current offset, into `dest` until EOF,
+/// returning the bytes copied.
+pub(crate) fn copy(
+    source: &File,
+    dest: &File,
+    progress: &mut Progress<'_>,
+    buffer: &mut IoBuffer,
+) -> io::Result<u64> {
+    let copied = copy_to_end(source, dest, progress, buffer)?;
+    progress.settle();
+    Ok(copied)
+}
+
+/// Keeps copying what is appended to `source`, at `path`, once `copy`
+/// has reached its end, until it has not grown for `quiet`, and returns
+/// the bytes copied. Fails if the source is truncated meanwhile.
+pub(crate) fn follow(
+    source: &File,
+    path: &Path,
+    dest: &File,
+    quiet: Duration,
+    progress: &mut Progress<'_>,
+    buffer: &mut IoBuffer,
+) -> io::Result<u64> {
+    let mut copied = 0;
+    let mut grown = Instant::now();
+    loop {
+        thread::sleep(POLL_INTERVAL.min(quiet));
+        progress.check_cancelled()?;
+        let mut reader = source;
+        if source.metadata()?.len() < reader.stream_position()? {
+            return Err(SourceChanged::error(path));
+        }
+        match copy_to_end(source, dest, progress, buffer)? {
+            0 if grown.elapsed() >= quiet => return Ok(copied),
+            0 => {}
+            more => {
+                copied += more;
+                grown = Instant::now();
+            }
+        }
+    }
+}
+
+fn copy_to_end(
+    mut source: &File,
+    mut dest: &File,
+    progress: &mut Progress<'_>,
+    buffer: &mut IoBuffer,
+) -> io::Result<u64> {
+    let mut copied = 0;
+    loop {
+        let n = match source.read(buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        dest.write_all(&buffer[..n])?;
+        copied += n as u64;
+        progress.inc_data(&buffer[..n])?;
+    }
+    Ok(copied)
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::fs::File;
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    /// `statfs` magic numbers of the filesystems whose files are made up
+    /// when read: proc, sysfs, debugfs, tracefs, securityfs, configfs and
+    /// cgroup2.
+    const PSEUDO_MAGIC: [u64; 7] = [
+        0x9fa0,
+        0x6265_6572,
+        0x6462_6720,
+        0x7472_6163,
+        0x7363_6673,
+        0x6265_6570,
+        0x6367_7270,
+    ];
+
+    pub(super) fn is_pseudo(file: &File) -> bool {
+        // SAFETY: the descriptor stays open and `stat` is a writable statfs.
+        unsafe {
+            let mut stat: libc::statfs = mem::zeroed();
+            libc::fstatfs(file.as_raw_fd(), &mut stat) == 0
+                && PSEUDO_MAGIC.contains(&(stat.f_type as u64))
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::fs::File;
+
+    pub(super) fn is_pseudo(_file: &File) -> bool {
+        false
+    }
+}
@@ -0,0 +1,173 @@
+//! The final statistics of a copy in a machine-readable form, for
+//! `--stats-format`, so that wrappers need not parse `format_summary`.
+
+use crate::CopyStats;
+use serde::Serialize;
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+/// How `Summary::write` formats the statistics.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StatsFormat {
+    /// A JSON object on one line.
+    #[default]
+    Json,
+    /// A header line naming the fields, then a line of their values.
+    Csv,
+}
+
+impl StatsFormat {
+    /// The names of the formats, as `from_str` takes them and `Display`
+    /// writes them.
+    pub const NAMES: &[&str] = &["json", "csv"];
+}
+
+impl FromStr for StatsFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(Self::Json),
+            "csv" => Ok(Self::Csv),
+            _ => Err(format!(
+                "invalid stats format '{}' (expected json or csv)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for StatsFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Json => "json",
+            Self::Csv => "csv",
+        })
+    }
+}
+
+/// The counters of a finished copy. Fields may be added, at the end in
+/// CSV, but are not renamed or removed.
+#[derive(Debug, Clone, Serialize)]
+pub struct Summary {
+    pub bytes_copied: u64,
+    pub files_copied: usize,
+    pub dirs_created: usize,
+    pub files_symlinked: usize,
+    pub files_linked: usize,
+    pub files_reflinked: usize,
+    pub files_skipped: usize,
+    pub files_failed: usize,
+    pub retries: usize,
+    pub warnings: usize,
+    pub elapsed_secs: f64,
+    /// Average throughput over the whole copy, in bytes per second.
+    pub bytes_per_sec: f64,
+    pub files_overwritten: usize,
+    /// See `CopyStats::bytes_saved`.
+    pub bytes_saved: u64,
+    pub bytes_compressed: u64,
+    /// Files read back by `CopyOptions::verify_sample`, and the share of
+    /// the others at most corrupt, from `VerifiedSample::bound`.
+    pub files_sampled: usize,
+    pub sample_bound: Option<f64>,
+    /// See `CopyStats::repairs`.
+    pub repairs: usize,
+    /// Destination files and directories removed by `--delete`, and files
+    /// `--mirror` left alone as up to date.
+    pub files_deleted: usize,
+    pub dirs_deleted: usize,
+    pub files_up_to_date: usize,
+    /// Existing destination files left alone by `--overwrite skip`.
+    pub files_kept: usize,
+}
+
+impl Summary {
+    pub fn new(stats: &CopyStats) -> Self {
+        let elapsed_secs = stats.time_taken.as_secs_f64();
+        Self {
+            bytes_copied: stats.bytes_copied,
+            files_copied: stats.files_copied,
+            dirs_created: stats.dirs_created,
+            files_symlinked: stats.files_symlinked,
+            files_linked: stats.files_linked,
+            files_reflinked: stats.files_reflinked,
+            files_skipped: stats.files_skipped,
+            files_failed: stats.files_failed(),
+            retries: stats.retries,
+            warnings: stats.warnings.len(),
+            elapsed_secs,
+            bytes_per_sec: if elapsed_secs > 0.0 {
+                stats.bytes_copied as f64 / elapsed_secs
+            } else {
+                0.0
+            },
+            files_overwritten: stats.files_overwritten,
+            bytes_saved: stats.bytes_saved(),
+            bytes_compressed: stats.bytes_compressed,
+            files_sampled: stats
+                .verified_sample
+                .as_ref()
+                .map_or(0, |sample| sample.verified),
+            sample_bound: stats
+                .verified_sample
+                .as_ref()
+                .and_then(|sample| sample.bound()),
+            repairs: stats.repairs,
+            files_deleted: stats.files_deleted,
+            dirs_deleted: stats.dirs_deleted,
+            files_up_to_date: stats.files_up_to_date,
+            files_kept: stats.files_kept,
+        }
+    }
+
+    /// The CSV columns, in the order of the JSON fields.
+    fn fields(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("bytes_copied", self.bytes_copied.to_string()),
+            ("files_copied", self.files_copied.to_string()),
+            ("dirs_created", self.dirs_created.to_string()),
+            ("files_symlinked", self.files_symlinked.to_string()),
+            ("files_linked", self.files_linked.to_string()),
+            ("files_reflinked", self.files_reflinked.to_string()),
+            ("files_skipped", self.files_skipped.to_string()),
+            ("files_failed", self.files_failed.to_string()),
+            ("retries", self.retries.to_string()),
+            ("warnings", self.warnings.to_string()),
+            ("elapsed_secs", format!("{:.3}", self.elapsed_secs)),
+            ("bytes_per_sec", format!("{:.0}", self.bytes_per_sec)),
+            ("files_overwritten", self.files_overwritten.to_string()),
+            ("bytes_saved", self.bytes_saved.to_string()),
+            ("bytes_compressed", self.bytes_compressed.to_string()),
+            ("files_sampled", self.files_sampled.to_string()),
+            (
+                "sample_bound",
+                self.sample_bound
+                    .map_or_else(String::new, |bound| format!("{:.6}", bound)),
+            ),
+            ("repairs", self.repairs.to_string()),
+            ("files_deleted", self.files_deleted.to_string()),
+            ("dirs_deleted", self.dirs_deleted.to_string()),
+            ("files_up_to_date", self.files_up_to_date.to_string()),
+            ("files_kept", self.files_kept.to_string()),
+        ]
+    }
+
+    /// Writes the summary to `out` in `format`, ending with a newline.
+    pub fn write(&self, format: StatsFormat, mut out: impl Write) -> io::Result<()> {
+        match format {
+            StatsFormat::Json => {
+                serde_json::to_writer(&mut out, self)?;
+                writeln!(out)
+            }
+            StatsFormat::Csv => {
+                let fields = self.fields();
+                let names: Vec<_> = fields.iter().map(|(name, _)| *name).collect();
+                let values: Vec<_> = fields.into_iter().map(|(_, value)| value).collect();
+                writeln!(out, "{}", names.join(","))?;
+                writeln!(out, "{}", values.join(","))
+            }
+        }
+    }
+}
@@ -0,0 +1,116 @@
+//! Notifications to systemd, for `CopyOptions::systemd_notify`: a copy run
+//! by a service, which finds the socket of the service manager in
+//! `NOTIFY_SOCKET`, reports itself ready, shows its progress as the status
+//! of the unit in `systemctl status` and keeps its watchdog fed.
+
+use crate::progress::{Rate, Ticker};
+use crate::ui::{format_size, ProgressBar, BINARY};
+use std::env;
+use std::ffi::OsStr;
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Interval between status updates, when the watchdog asks for none
+/// sooner.
+const STATUS_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The socket of the service manager.
+struct Manager {
+    socket: UnixDatagram,
+}
+
+impl Manager {
+    /// Connects to the socket `path`, as found in `NOTIFY_SOCKET`: a path
+    /// or, starting with `@`, an abstract name.
+    fn connect(path: &OsStr) -> io::Result<Self> {
+        let addr = match path.as_encoded_bytes().strip_prefix(b"@") {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(path)?,
+        };
+        let socket = UnixDatagram::unbound()?;
+        socket.connect_addr(&addr)?;
+        Ok(Self { socket })
+    }
+
+    fn send(&self, state: &str) {
+        // The manager going away is no reason to stop copying.
+        let _ = self.socket.send(state.as_bytes());
+    }
+}
+
+/// How often the watchdog of the service must be fed, if it has one that
+/// watches this process.
+fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    let for_us = env::var("WATCHDOG_PID").map_or(true, |pid| pid.parse() == Ok(process::id()));
+    (usec > 0 && for_us).then(|| Duration::from_micros(usec))
+}
+
+/// Tells the service manager that the copy of `pb` is under way, then
+/// updates the status of the unit with its progress, feeding the watchdog
+/// at least twice per its timeout, until dropped.
+pub(crate) struct Notifier {
+    manager: Arc<Manager>,
+    ticker: Option<Ticker>,
+}
+
+impl Notifier {
+    /// Starts notifying when run by a service that listens, and not
+    /// otherwise.
+    pub(crate) fn start(pb: &ProgressBar, rate: &Arc<Rate>) -> Option<Self> {
+        let socket = env::var_os("NOTIFY_SOCKET").filter(|socket| !socket.is_empty())?;
+        Self::notify(&socket, watchdog_interval(), pb, rate).ok()
+    }
+
+    /// Notifies the manager listening at `socket`, feeding its watchdog if
+    /// it has one with the timeout `watchdog`.
+    pub(crate) fn notify(
+        socket: &OsStr,
+        watchdog: Option<Duration>,
+        pb: &ProgressBar,
+        rate: &Arc<Rate>,
+    ) -> io::Result<Self> {
+        let manager = Arc::new(Manager::connect(socket)?);
+        let interval = watchdog.map_or(STATUS_INTERVAL, |timeout| {
+            (timeout / 2).min(STATUS_INTERVAL)
+        });
+        manager.send("READY=1\nSTATUS=Copying");
+        let rate = Arc::clone(rate);
+        let notified = Arc::clone(&manager);
+        let ticker = Ticker::start(pb, interval, move |pb| {
+            let mut state = format!("STATUS={}", status(pb, &rate));
+            if watchdog.is_some() {
+                state.push_str("\nWATCHDOG=1");
+            }
+            notified.send(&state);
+        });
+        Ok(Self {
+            manager,
+            ticker: Some(ticker),
+        })
+    }
+}
+
+impl Drop for Notifier {
+    fn drop(&mut self) {
+        drop(self.ticker.take());
+        self.manager.send("STATUS=Copy finished");
+    }
+}
+
+/// E.g. `Copying: 30%, 1.20 GiB of 4 GiB at 85.30 MiB/s`.
+fn status(pb: &ProgressBar, rate: &Rate) -> String {
+    let position = pb.position();
+    let length = pb.length().unwrap_or(0).max(position);
+    format!(
+        "Copying: {}%, {} of {} at {}/s",
+        (position * 100).checked_div(length).unwrap_or(100),
+        format_size(position, BINARY),
+        format_size(length, BINARY),
+        format_size(rate.per_sec() as u64, BINARY)
+    )
+}
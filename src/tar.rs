@@ -0,0 +1,518 @@
+//! Tar archives as destinations: `TarFs` streams the files of a copy into
+//! a ustar archive as they are read, with PAX headers for long names and
+//! large files, so that a tree is archived without a temporary copy. The
+//! archives of `ArchiveFs` are read here too, with the GNU and PAX
+//! extensions for long names and large files.
+
+use crate::archive::{Data, Entry, Index};
+use crate::vfs::{FileInfo, Filesystem};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufReader, Read, Seek, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The size of headers, and the unit data is padded to.
+const BLOCK: usize = 512;
+
+/// The largest number the 12-byte octal fields of a header hold.
+const MAX_OCTAL: u64 = 0o77777777777;
+
+/// Entry types of the header's type flag.
+const FILE: u8 = b'0';
+const HARD_LINK: u8 = b'1';
+const SYMLINK: u8 = b'2';
+const CONTIGUOUS: u8 = b'7';
+const DIR: u8 = b'5';
+const PAX: u8 = b'x';
+const PAX_GLOBAL: u8 = b'g';
+const GNU_LONG_NAME: u8 = b'L';
+const GNU_LONG_LINK: u8 = b'K';
+
+/// A tar archive being written, as the destination of a copy.
+///
+/// The archive's root is the empty path, a directory: copying `src` into
+/// it adds `src/` and the files under it, as `tar -c src` would. Entries
+/// record the size, permission bits and modification time of their
+/// source; directories get `0755` and the time they were added. Files
+/// are added one at a time and the archive cannot be read back, so copies
+/// into it cannot be verified. It ends with `Filesystem::finish`.
+#[derive(Clone)]
+pub struct TarFs {
+    archive: Arc<Mutex<Archive>>,
+}
+
+struct Archive {
+    out: Box<dyn Write + Send>,
+    /// The paths added so far, and whether they are directories.
+    entries: HashMap<PathBuf, bool>,
+    /// Set while the data of a file is being written.
+    writing: bool,
+    finished: bool,
+}
+
+impl TarFs {
+    /// An archive written to `out`, which is best buffered.
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        Self {
+            archive: Arc::new(Mutex::new(Archive {
+                out: Box::new(out),
+                entries: HashMap::new(),
+                writing: false,
+                finished: false,
+            })),
+        }
+    }
+}
+
+impl fmt::Debug for TarFs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TarFs").finish_non_exhaustive()
+    }
+}
+
+impl Archive {
+    fn check(&self) -> io::Result<()> {
+        if self.finished {
+            return Err(io::Error::other("the tar archive is already finished"));
+        }
+        if self.writing {
+            return Err(io::Error::other(
+                "another file is being added to the tar archive",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Adds the directory `dir` and those above it that are missing.
+    fn add_dirs(&mut self, dir: &Path) -> io::Result<()> {
+        let mut missing: Vec<_> = dir
+            .ancestors()
+            .filter(|dir| !entry_name(dir).is_empty() && !self.entries.contains_key(*dir))
+            .collect();
+        missing.reverse();
+        for dir in missing {
+            self.add(dir, DIR, 0o755, 0, SystemTime::now())?;
+        }
+        Ok(())
+    }
+
+    /// Writes the header of `path`, preceded by a PAX header for what
+    /// does not fit in it.
+    fn add(
+        &mut self,
+        path: &Path,
+        kind: u8,
+        mode: u32,
+        len: u64,
+        modified: SystemTime,
+    ) -> io::Result<()> {
+        let mut name = entry_name(path);
+        if kind == DIR {
+            name.push(b'/');
+        }
+        let mtime = modified
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs().min(MAX_OCTAL));
+        let mut records = Vec::new();
+        if name.len() > 100 {
+            record(&mut records, "path", &name);
+        }
+        if len > MAX_OCTAL {
+            record(&mut records, "size", len.to_string().as_bytes());
+        }
+        if !records.is_empty() {
+            let len = records.len() as u64;
+            self.out
+                .write_all(&header(b"././@PaxHeader", PAX, 0o644, len, mtime))?;
+            self.out.write_all(&records)?;
+            pad(&mut self.out, len)?;
+        }
+        let size = if len > MAX_OCTAL { 0 } else { len };
+        self.out
+            .write_all(&header(&name, kind, mode & 0o7777, size, mtime))?;
+        self.entries.insert(path.to_path_buf(), kind == DIR);
+        Ok(())
+    }
+}
+
+/// A ustar header; `name` is cut to the 100 bytes it holds.
+fn header(name: &[u8], kind: u8, mode: u32, len: u64, mtime: u64) -> [u8; BLOCK] {
+    let mut header = [0; BLOCK];
+    let name = &name[..name.len().min(100)];
+    header[..name.len()].copy_from_slice(name);
+    octal(&mut header[100..108], mode.into());
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    octal(&mut header[124..136], len);
+    octal(&mut header[136..148], mtime);
+    header[148..156].fill(b' ');
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    let sum: u32 = header.iter().map(|&byte| u32::from(byte)).sum();
+    octal(&mut header[148..155], sum.into());
+    header
+}
+
+/// Writes `value` into `field` as zero-padded octal digits and a NUL.
+fn octal(field: &mut [u8], value: u64) {
+    let width = field.len() - 1;
+    field[..width].copy_from_slice(format!("{:0width$o}", value, width = width).as_bytes());
+    field[width] = 0;
+}
+
+/// Appends the PAX record `key=value`, which starts with its own length.
+fn record(records: &mut Vec<u8>, key: &str, value: &[u8]) {
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while len != rest + len.to_string().len() {
+        len = rest + len.to_string().len();
+    }
+    records.extend_from_slice(format!("{} {}=", len, key).as_bytes());
+    records.extend_from_slice(value);
+    records.push(b'\n');
+}
+
+/// Writes the zeros that take data of `len` bytes to a whole block.
+fn pad(out: &mut dyn Write, len: u64) -> io::Result<()> {
+    zeros(out, (BLOCK as u64 - len % BLOCK as u64) % BLOCK as u64)
+}
+
+fn zeros(out: &mut dyn Write, len: u64) -> io::Result<()> {
+    io::copy(&mut io::repeat(0).take(len), out).map(|_| ())
+}
+
+/// `path` as named in the archive: its components joined with `/`.
+fn entry_name(path: &Path) -> Vec<u8> {
+    let mut name = Vec::new();
+    for part in path.components().filter_map(|component| match component {
+        Component::Normal(part) => Some(part),
+        _ => None,
+    }) {
+        if !name.is_empty() {
+            name.push(b'/');
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            name.extend_from_slice(part.as_bytes());
+        }
+        #[cfg(not(unix))]
+        name.extend_from_slice(part.to_string_lossy().as_bytes());
+    }
+    name
+}
+
+fn unreadable() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "a tar archive being written cannot be read back",
+    )
+}
+
+impl Filesystem for TarFs {
+    fn metadata(&self, path: &Path) -> io::Result<FileInfo> {
+        let is_dir = if entry_name(path).is_empty() {
+            true
+        } else {
+            match self.archive.lock().unwrap().entries.get(path) {
+                Some(&is_dir) => is_dir,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("'{}' is not in the archive", path.display()),
+                    ))
+                }
+            }
+        };
+        Ok(FileInfo {
+            is_dir,
+            len: 0,
+            modified: None,
+            mode: None,
+        })
+    }
+
+    fn read_dir(&self, _path: &Path) -> io::Result<Vec<PathBuf>> {
+        Err(unreadable())
+    }
+
+    fn open(&self, _path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        Err(unreadable())
+    }
+
+    fn create(&self, _path: &Path) -> io::Result<Box<dyn Write + Send>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "a tar archive needs the size of a file before its data",
+        ))
+    }
+
+    fn create_for(&self, path: &Path, source: &FileInfo) -> io::Result<Box<dyn Write + Send>> {
+        let mut archive = self.archive.lock().unwrap();
+        archive.check()?;
+        if let Some(parent) = path.parent() {
+            archive.add_dirs(parent)?;
+        }
+        archive.add(
+            path,
+            FILE,
+            source.mode.unwrap_or(0o644),
+            source.len,
+            source.modified.unwrap_or_else(SystemTime::now),
+        )?;
+        archive.writing = true;
+        Ok(Box::new(TarFile {
+            archive: self.archive.clone(),
+            path: path.to_path_buf(),
+            len: source.len,
+            written: 0,
+            done: false,
+        }))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut archive = self.archive.lock().unwrap();
+        archive.check()?;
+        archive.add_dirs(path)
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        let mut archive = self.archive.lock().unwrap();
+        if archive.finished {
+            return Ok(());
+        }
+        archive.check()?;
+        zeros(&mut archive.out, 2 * BLOCK as u64)?;
+        archive.out.flush()?;
+        archive.finished = true;
+        Ok(())
+    }
+}
+
+/// The data of a file being added to a `TarFs`, complete on flush. A file
+/// that ends short of the size in its header is padded with zeros to keep
+/// the archive readable, and fails.
+struct TarFile {
+    archive: Arc<Mutex<Archive>>,
+    path: PathBuf,
+    len: u64,
+    written: u64,
+    done: bool,
+}
+
+impl TarFile {
+    fn changed_size(&self) -> io::Error {
+        io::Error::other(format!(
+            "'{}' changed size while being added to the archive",
+            self.path.display()
+        ))
+    }
+
+    fn end(&mut self) -> io::Result<()> {
+        self.done = true;
+        let mut archive = self.archive.lock().unwrap();
+        archive.writing = false;
+        zeros(&mut archive.out, self.len - self.written)?;
+        pad(&mut archive.out, self.len)?;
+        if self.written < self.len {
+            return Err(self.changed_size());
+        }
+        Ok(())
+    }
+}
+
+impl Write for TarFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.done || buf.len() as u64 > self.len - self.written {
+            return Err(self.changed_size());
+        }
+        let n = self.archive.lock().unwrap().out.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.done {
+            return Ok(());
+        }
+        self.end()
+    }
+}
+
+impl Drop for TarFile {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = self.end();
+        }
+    }
+}
+
+/// Reads the entries of the tar archive in `reader` into `index`, with
+/// the offsets of their data, skipping over the data itself.
+pub(crate) fn read_index<R: Read + Seek>(
+    reader: &mut BufReader<R>,
+    index: &mut Index,
+) -> io::Result<()> {
+    let end = reader.seek(io::SeekFrom::End(0))?;
+    reader.rewind()?;
+    let mut offset = 0;
+    let mut header = [0; BLOCK];
+    let mut pax: HashMap<String, Vec<u8>> = HashMap::new();
+    let mut long_name = None;
+    let mut long_link = None;
+    loop {
+        // An archive may end without its two blocks of zeros.
+        match crate::verify::read_chunk(&mut *reader, &mut header)? {
+            0 => break,
+            BLOCK => {}
+            _ if offset == 0 => return Err(damaged("not a tar, gzipped tar or zip archive")),
+            _ => return Err(damaged("a truncated tar archive")),
+        }
+        offset += BLOCK as u64;
+        if header.iter().all(|&byte| byte == 0) {
+            break;
+        }
+        let sum: u64 = header.iter().map(|&byte| u64::from(byte)).sum::<u64>()
+            - header[148..156]
+                .iter()
+                .map(|&byte| u64::from(byte))
+                .sum::<u64>()
+            + 8 * u64::from(b' ');
+        if number(&header[148..156])? != sum {
+            return Err(damaged(if offset == BLOCK as u64 {
+                "not a tar, gzipped tar or zip archive"
+            } else {
+                "a damaged tar header"
+            }));
+        }
+        let kind = header[156];
+        let len = match pax.get("size") {
+            Some(size) => decimal(size)?,
+            None => number(&header[124..136])?,
+        };
+        if offset.saturating_add(len) > end {
+            return Err(damaged("a truncated tar archive"));
+        }
+        let padded = len.div_ceil(BLOCK as u64) * BLOCK as u64;
+        if matches!(kind, PAX | PAX_GLOBAL | GNU_LONG_NAME | GNU_LONG_LINK) {
+            if len > 1 << 20 {
+                return Err(damaged("an extended header of over 1 MiB"));
+            }
+            let mut data = vec![0; padded as usize];
+            reader.read_exact(&mut data)?;
+            offset += padded;
+            data.truncate(len as usize);
+            match kind {
+                PAX => pax = records(&data)?,
+                GNU_LONG_NAME => long_name = Some(c_string(&data).to_vec()),
+                GNU_LONG_LINK => long_link = Some(c_string(&data).to_vec()),
+                _ => {}
+            }
+            continue;
+        }
+
+        let name = match (pax.remove("path"), long_name.take()) {
+            (Some(name), _) | (None, Some(name)) => name,
+            _ => {
+                let name = c_string(&header[..100]);
+                let prefix = c_string(&header[345..500]);
+                match &header[257..262] == b"ustar" && !prefix.is_empty() {
+                    true => [prefix, b"/", name].concat(),
+                    false => name.to_vec(),
+                }
+            }
+        };
+        let link = match (pax.remove("linkpath"), long_link.take()) {
+            (Some(link), _) | (None, Some(link)) => link,
+            _ => c_string(&header[157..257]).to_vec(),
+        };
+        let mtime = match pax.remove("mtime") {
+            Some(mtime) => {
+                let seconds = mtime.split(|&byte| byte == b'.').next().unwrap_or(b"");
+                decimal(seconds).unwrap_or(0)
+            }
+            None => number(&header[136..148])?,
+        };
+        let modified = Some(UNIX_EPOCH + Duration::from_secs(mtime));
+        let mode = Some(number(&header[100..108])? as u32 & 0o7777);
+        pax.clear();
+        match kind {
+            FILE | CONTIGUOUS | 0 if name.ends_with(b"/") => {
+                index.add(&name, Entry::dir(modified, mode));
+            }
+            FILE | CONTIGUOUS | 0 => {
+                let data = Data::Tar { offset };
+                index.add(&name, Entry::file(len, modified, mode, data));
+            }
+            DIR => index.add(&name, Entry::dir(modified, mode)),
+            HARD_LINK => index.link(&name, &link, false),
+            SYMLINK => index.link(&name, &link, true),
+            _ => {}
+        }
+        reader.seek_relative(padded as i64)?;
+        offset += padded;
+    }
+    Ok(())
+}
+
+/// A numeric field: octal digits, or for large values base-256 after a
+/// first byte with its high bit set.
+fn number(field: &[u8]) -> io::Result<u64> {
+    if field[0] & 0x80 != 0 {
+        let value = field[1..]
+            .iter()
+            .fold(u64::from(field[0] & 0x7f), |value, &byte| {
+                value << 8 | u64::from(byte)
+            });
+        return Ok(value);
+    }
+    let digits = c_string(field);
+    let digits = std::str::from_utf8(digits).unwrap_or("").trim();
+    match digits.is_empty() {
+        true => Ok(0),
+        false => u64::from_str_radix(digits, 8).map_err(|_| damaged("a damaged tar header")),
+    }
+}
+
+fn decimal(digits: &[u8]) -> io::Result<u64> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse().ok())
+        .ok_or_else(|| damaged("a damaged PAX header"))
+}
+
+/// The PAX records of `data`, by key.
+fn records(mut data: &[u8]) -> io::Result<HashMap<String, Vec<u8>>> {
+    let mut records = HashMap::new();
+    while !data.is_empty() {
+        let space = data
+            .iter()
+            .position(|&byte| byte == b' ')
+            .ok_or_else(|| damaged("a damaged PAX header"))?;
+        let len = decimal(&data[..space])? as usize;
+        if len <= space || len > data.len() {
+            return Err(damaged("a damaged PAX header"));
+        }
+        let record = &data[space + 1..len];
+        let record = record.strip_suffix(b"\n").unwrap_or(record);
+        if let Some(equals) = record.iter().position(|&byte| byte == b'=') {
+            let key = String::from_utf8_lossy(&record[..equals]).into_owned();
+            records.insert(key, record[equals + 1..].to_vec());
+        }
+        data = &data[len..];
+    }
+    Ok(records)
+}
+
+/// `field` up to its first NUL.
+fn c_string(field: &[u8]) -> &[u8] {
+    let end = field.iter().position(|&byte| byte == 0);
+    &field[..end.unwrap_or(field.len())]
+}
+
+fn damaged(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what)
+}
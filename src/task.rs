@@ -0,0 +1,57 @@
+//! `copy_with_progress_async`, which runs a copy without blocking the
+//! threads of a tokio runtime.
+
+use crate::reporter::EventReporter;
+use crate::{copy_with_progress, CopyError, CopyEvent, CopyOptions, CopyStats};
+use std::io;
+use std::panic;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// A copy running on tokio's blocking thread pool.
+#[derive(Debug)]
+pub struct CopyTask {
+    events: mpsc::UnboundedReceiver<CopyEvent>,
+    copy: JoinHandle<Result<CopyStats, CopyError>>,
+}
+
+/// Starts copying `source` to `dest` with `options` on the blocking thread
+/// pool of the current tokio runtime, whose progress is read with
+/// `CopyTask::next_event`. `options.reporter` is replaced by the events.
+///
+/// # Panics
+///
+/// Panics if called outside of a tokio runtime.
+pub fn copy_with_progress_async(
+    source: impl Into<PathBuf>,
+    dest: impl Into<PathBuf>,
+    mut options: CopyOptions,
+) -> CopyTask {
+    let (source, dest) = (source.into(), dest.into());
+    let (sender, events) = mpsc::unbounded_channel();
+    options.reporter = Some(Arc::new(EventReporter(move |event| {
+        // The task may be finished without reading its events.
+        let _ = sender.send(event);
+    })));
+    let copy = tokio::task::spawn_blocking(move || copy_with_progress(&source, &dest, &options));
+    CopyTask { events, copy }
+}
+
+impl CopyTask {
+    /// The next progress event, or `None` once the copy is over and every
+    /// event has been read. Events are buffered until they are read.
+    pub async fn next_event(&mut self) -> Option<CopyEvent> {
+        self.events.recv().await
+    }
+
+    /// Waits for the copy to end, discarding the events not read.
+    pub async fn finish(self) -> Result<CopyStats, CopyError> {
+        match self.copy.await {
+            Ok(result) => result,
+            Err(err) if err.is_panic() => panic::resume_unwind(err.into_panic()),
+            Err(err) => Err(CopyError::Io(io::Error::other(err))),
+        }
+    }
+}
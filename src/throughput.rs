@@ -0,0 +1,162 @@
+//! The throughput of past copies, which seeds the rate and ETA of the
+//! next copy between the same devices until it has measured its own, so
+//! that its first seconds do not show wild estimates.
+//!
+//! Devices are identified by their kind of storage and, on Unix, their
+//! device number, so that a copy between devices never seen together
+//! still starts from copies between the same kinds of storage. The
+//! history is a text file with a version line followed by one
+//! `source dest bytes-per-second` line per pair of devices, such as
+//! `solid-state:2049 rotational:2065 98304000`, read whole when opened and
+//! rewritten by `save`.
+
+use crate::stage::state_dir;
+use crate::storage::StorageKind;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// First line of a history in the current format.
+const HEADER: &str = "cpv throughput 1";
+
+/// Copies shorter than this are left out, as they mostly measure caches
+/// and setting up.
+const MIN_DURATION: Duration = Duration::from_secs(5);
+
+/// Weight of a new copy in the throughput kept for its devices.
+const WEIGHT: f64 = 0.5;
+
+type Key = (String, String);
+
+/// Throughput of past copies by pair of devices.
+#[derive(Debug)]
+pub struct ThroughputHistory {
+    path: PathBuf,
+    entries: Mutex<HashMap<Key, f64>>,
+}
+
+impl ThroughputHistory {
+    /// `throughput` in the per-user state directory.
+    pub fn default_path() -> Option<PathBuf> {
+        Some(state_dir()?.join("throughput"))
+    }
+
+    /// Loads the history stored at `path`, starting empty if there is none
+    /// or it is in another format.
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let entries = match fs::read_to_string(&path) {
+            Ok(text) => parse(&text),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err),
+        };
+        Ok(Self {
+            path,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    /// Writes the history back to its file, replacing it atomically.
+    pub fn save(&self) -> io::Result<()> {
+        let mut text = format!("{}\n", HEADER);
+        for ((source, dest), per_sec) in self.entries.lock().unwrap().iter() {
+            text.push_str(&format!("{} {} {}\n", source, dest, *per_sec as u64));
+        }
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        fs::create_dir_all(dir)?;
+        // A temporary file of its own, so that runs saving at the same time
+        // do not write into each other's.
+        let mut temp = tempfile::NamedTempFile::new_in(dir)?;
+        temp.write_all(text.as_bytes())?;
+        temp.persist(&self.path).map_err(|err| err.error)?;
+        Ok(())
+    }
+
+    /// The bytes per second expected of a copy from `source` to `dest`:
+    /// that of past copies between their devices, or else the average of
+    /// those between the same kinds of storage.
+    pub(crate) fn expected(&self, source: &Path, dest: &Path) -> Option<f64> {
+        let key = (device(source), device(dest));
+        let entries = self.entries.lock().unwrap();
+        if let Some(per_sec) = entries.get(&key) {
+            return Some(*per_sec);
+        }
+        let alike: Vec<f64> = entries
+            .iter()
+            .filter(|((source, dest), _)| {
+                kind(source) == kind(&key.0) && kind(dest) == kind(&key.1)
+            })
+            .map(|(_, per_sec)| *per_sec)
+            .collect();
+        (!alike.is_empty()).then(|| alike.iter().sum::<f64>() / alike.len() as f64)
+    }
+
+    /// Adds a copy of `bytes` from `source` to `dest` that took `elapsed`,
+    /// unless it was too short to tell.
+    pub(crate) fn record(&self, source: &Path, dest: &Path, bytes: u64, elapsed: Duration) {
+        if elapsed < MIN_DURATION || bytes == 0 {
+            return;
+        }
+        let observed = bytes as f64 / elapsed.as_secs_f64();
+        self.entries
+            .lock()
+            .unwrap()
+            .entry((device(source), device(dest)))
+            .and_modify(|per_sec| *per_sec += WEIGHT * (observed - *per_sec))
+            .or_insert(observed);
+    }
+}
+
+/// The name of the device holding `path`, or the nearest existing
+/// ancestor of it: its kind of storage and, on Unix, its device number.
+fn device(path: &Path) -> String {
+    let kind = match StorageKind::detect(path) {
+        StorageKind::Rotational => "rotational",
+        StorageKind::SolidState => "solid-state",
+        StorageKind::Network => "network",
+        StorageKind::Unknown => "unknown",
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        let metadata = path
+            .ancestors()
+            .find_map(|path| match path.as_os_str().is_empty() {
+                true => fs::metadata(".").ok(),
+                false => fs::metadata(path).ok(),
+            });
+        if let Some(metadata) = metadata {
+            return format!("{}:{}", kind, metadata.dev());
+        }
+    }
+    kind.to_string()
+}
+
+/// The kind of storage in the name of a device.
+fn kind(device: &str) -> &str {
+    device.split(':').next().unwrap_or(device)
+}
+
+/// Reads the entries of a history, ignoring malformed lines.
+fn parse(text: &str) -> HashMap<Key, f64> {
+    let mut lines = text.lines();
+    if lines.next() != Some(HEADER) {
+        return HashMap::new();
+    }
+    lines
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            let source = fields.next()?.to_string();
+            let dest = fields.next()?.to_string();
+            let per_sec = fields.next()?.parse::<u64>().ok()?;
+            Some(((source, dest), per_sec as f64))
+        })
+        .collect()
+}
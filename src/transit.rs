@@ -0,0 +1,114 @@
+//! Compression of file data in transit, for `cpv://` copies over slow
+//! links: with the `zstd` feature on both sides, chunks go compressed
+//! where that makes them smaller. Files whose data is compressed already,
+//! as their extension or the entropy of their first chunk tells, go as
+//! they are without spending time on them.
+
+use std::io;
+use std::path::Path;
+
+/// Extensions of formats whose data is compressed or encrypted.
+const COMPRESSED: &[&str] = &[
+    "7z", "aac", "age", "apk", "avif", "br", "bz2", "deb", "docx", "epub", "flac", "gif", "gz",
+    "heic", "jar", "jpeg", "jpg", "lz", "lz4", "lzma", "m4a", "mkv", "mov", "mp3", "mp4", "odt",
+    "ogg", "opus", "png", "pptx", "rar", "rpm", "tbz2", "tgz", "txz", "webm", "webp", "xlsx", "xz",
+    "zip", "zst",
+];
+
+/// Bits per byte above which data is taken for compressed already.
+const MAX_ENTROPY: f64 = 7.5;
+
+/// A fast level, as the point is to keep up with the link.
+#[cfg(feature = "zstd")]
+const LEVEL: i32 = 1;
+
+/// Whether this build can compress and decompress data in transit.
+pub(crate) fn supported() -> bool {
+    cfg!(feature = "zstd")
+}
+
+/// The Shannon entropy of `data`, in bits per byte.
+pub(crate) fn entropy(data: &[u8]) -> f64 {
+    let mut counts = [0usize; 256];
+    for &byte in data {
+        counts[usize::from(byte)] += 1;
+    }
+    let len = data.len() as f64;
+    counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Whether `path` has the extension of a compressed format.
+pub(crate) fn compressed_name(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| COMPRESSED.contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+/// Compresses the chunks of one file as they are sent.
+pub(crate) struct Packer {
+    /// Undecided until the first chunk is seen.
+    worthwhile: Option<bool>,
+    #[cfg(feature = "zstd")]
+    compressor: Option<zstd::bulk::Compressor<'static>>,
+}
+
+impl Packer {
+    /// A packer for the file `path`, which compresses nothing unless
+    /// `enabled`.
+    pub(crate) fn new(enabled: bool, path: &Path) -> Self {
+        Self {
+            worthwhile: (!enabled || !supported() || compressed_name(path)).then_some(false),
+            #[cfg(feature = "zstd")]
+            compressor: None,
+        }
+    }
+
+    /// `chunk` compressed, if that is worth sending instead.
+    pub(crate) fn pack(&mut self, chunk: &[u8]) -> Option<Vec<u8>> {
+        if !*self
+            .worthwhile
+            .get_or_insert_with(|| entropy(chunk) <= MAX_ENTROPY)
+        {
+            return None;
+        }
+        #[cfg(feature = "zstd")]
+        {
+            let compressor = match &mut self.compressor {
+                Some(compressor) => compressor,
+                None => self
+                    .compressor
+                    .insert(zstd::bulk::Compressor::new(LEVEL).ok()?),
+            };
+            compressor
+                .compress(chunk)
+                .ok()
+                .filter(|packed| packed.len() < chunk.len())
+        }
+        #[cfg(not(feature = "zstd"))]
+        None
+    }
+}
+
+/// The chunk of at most `max_len` bytes `packed` holds.
+pub(crate) fn unpack(packed: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+    #[cfg(feature = "zstd")]
+    {
+        zstd::bulk::decompress(packed, max_len)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+    #[cfg(not(feature = "zstd"))]
+    {
+        let _ = (packed, max_len);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "compressed data received by a cpv built without the zstd feature",
+        ))
+    }
+}
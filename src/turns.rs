@@ -0,0 +1,192 @@
+//! Taking turns with other cpv processes on the same disks, for
+//! `CopyOptions::take_turns`: two copies at full speed to one spinning
+//! disk are slower than the same copies one after the other, so a copy
+//! waits while another that takes turns reads or writes a disk it uses.
+//!
+//! Each disk has a lock file in the per-user state directory, named after
+//! the whole disk a partition is on where Linux tells, which the copy
+//! holds an exclusive advisory lock on until it ends, and which names the
+//! process holding it. The locks of the source and destination disks are
+//! taken together or not at all, so that two copies never wait on each
+//! other. Locks go with the process, so a killed copy leaves none behind.
+//! Disks are told apart on Unix only; elsewhere copies do not wait.
+
+use crate::progress::Rate;
+use crate::stage::state_dir;
+use crate::ui::ProgressBar;
+use crate::CancellationToken;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+/// How often the locks are tried while another copy holds one.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The directory of the lock files: `turns` in the per-user state
+/// directory.
+pub(crate) fn dir() -> Option<PathBuf> {
+    Some(state_dir()?.join("turns"))
+}
+
+/// The turn of a copy on its disks, which ends when it is dropped.
+#[derive(Debug)]
+pub(crate) struct Turn {
+    _locks: Vec<File>,
+}
+
+/// Waits until no other copy taking turns uses the disks of `source` and
+/// `dest`, with the lock files in `dir`, saying so on `pb` and leaving the
+/// wait out of `rate`. Returns the turn, or the error of a cancelled copy.
+pub(crate) fn take(
+    dir: &Path,
+    source: &Path,
+    dest: &Path,
+    pb: &ProgressBar,
+    rate: &Rate,
+    cancel: Option<&CancellationToken>,
+) -> io::Result<Turn> {
+    let mut disks: Vec<String> = [source, dest].into_iter().filter_map(disk).collect();
+    // Always in the same order, though all are tried at once.
+    disks.sort();
+    disks.dedup();
+    if disks.is_empty() {
+        return Ok(Turn { _locks: Vec::new() });
+    }
+    fs::create_dir_all(dir)?;
+    let mut files = disks
+        .iter()
+        .map(|disk| {
+            fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(dir.join(disk))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    let message = pb.message();
+    let mut waiting = false;
+    let taken = loop {
+        match try_lock_all(&files) {
+            Ok(true) => break Ok(()),
+            Ok(false) => {}
+            Err(err) => break Err(err),
+        }
+        if !waiting {
+            waiting = true;
+            rate.hold(true);
+            pb.set_message(format!(
+                "waiting for {} to finish with the disk",
+                holder(&mut files)
+            ));
+        }
+        if let Some(Err(err)) = cancel.map(CancellationToken::check) {
+            break Err(err);
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+    if waiting {
+        rate.hold(false);
+        pb.set_message(message);
+    }
+    taken?;
+    // Named for the copies that wait on this one.
+    for file in &mut files {
+        file.set_len(0)?;
+        file.rewind()?;
+        write!(file, "cpv (process {})", std::process::id())?;
+    }
+    Ok(Turn { _locks: files })
+}
+
+/// Locks every one of `files`, or none of them if another process holds
+/// one. Returns whether they were locked.
+fn try_lock_all(files: &[File]) -> io::Result<bool> {
+    for (locked, file) in files.iter().enumerate() {
+        if !try_lock(file)? {
+            for file in &files[..locked] {
+                unlock(file);
+            }
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Who holds one of the locks of `files`, as written in it.
+fn holder(files: &mut [File]) -> String {
+    let mut holder = String::new();
+    for file in files {
+        holder.clear();
+        if file.rewind().is_ok() && file.read_to_string(&mut holder).is_ok() && !holder.is_empty() {
+            return holder;
+        }
+    }
+    "another cpv".to_string()
+}
+
+#[cfg(unix)]
+fn try_lock(file: &File) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: the descriptor of `file` is open for the duration of the call.
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    match io::Error::last_os_error() {
+        err if err.raw_os_error() == Some(libc::EWOULDBLOCK) => Ok(false),
+        err => Err(err),
+    }
+}
+
+#[cfg(unix)]
+fn unlock(file: &File) {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: as in `try_lock`.
+    unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_UN) };
+}
+
+#[cfg(not(unix))]
+fn try_lock(_file: &File) -> io::Result<bool> {
+    Ok(true)
+}
+
+#[cfg(not(unix))]
+fn unlock(_file: &File) {}
+
+/// The name of the lock file of the disk holding `path`, or the nearest
+/// existing ancestor of it: the device number of the whole disk on Linux,
+/// of the filesystem elsewhere on Unix.
+#[cfg(unix)]
+fn disk(path: &Path) -> Option<String> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = path
+        .ancestors()
+        .find_map(|path| match path.as_os_str().is_empty() {
+            true => fs::metadata(".").ok(),
+            false => fs::metadata(path).ok(),
+        })?;
+    let dev = metadata.dev();
+    #[allow(clippy::unnecessary_cast)]
+    let (major, minor) = (libc::major(dev) as u64, libc::minor(dev) as u64);
+    #[cfg(target_os = "linux")]
+    {
+        // The parent of a partition in sysfs is its disk.
+        let device = PathBuf::from(format!("/sys/dev/block/{}:{}", major, minor));
+        if device.join("partition").exists() {
+            if let Ok(whole) = fs::read_to_string(device.join("../dev")) {
+                return Some(format!("disk-{}", whole.trim().replace(':', "-")));
+            }
+        }
+    }
+    Some(format!("disk-{}-{}", major, minor))
+}
+
+#[cfg(not(unix))]
+fn disk(_path: &Path) -> Option<String> {
+    None
+}
@@ -0,0 +1,254 @@
+//! The progress bar types the copy reports to: indicatif's with the
+//! `progress-bar` feature, and stand-ins that only keep count without it,
+//! so that reporters, events and statistics work the same either way.
+
+#[cfg(feature = "progress-bar")]
+pub(crate) use humansize::{format_size, BINARY};
+#[cfg(feature = "progress-bar")]
+pub(crate) use indicatif::{
+    HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressState,
+    ProgressStyle,
+};
+
+#[cfg(not(feature = "progress-bar"))]
+pub(crate) use headless::*;
+
+#[cfg(not(feature = "progress-bar"))]
+mod headless {
+    use std::borrow::Cow;
+    use std::convert::Infallible;
+    use std::fmt;
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    /// Units of 1024 bytes, as humansize's `BINARY`.
+    pub(crate) struct Binary;
+
+    pub(crate) const BINARY: Binary = Binary;
+
+    /// `bytes` in the largest binary unit it reaches, e.g. `1.50 MiB`.
+    pub(crate) fn format_size(bytes: impl TryInto<u64>, _: Binary) -> String {
+        const UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+        let bytes = bytes.try_into().unwrap_or(u64::MAX);
+        if bytes < 1024 {
+            return format!("{} B", bytes);
+        }
+        let mut size = bytes as f64 / 1024.0;
+        let mut unit = 0;
+        while size >= 1024.0 && unit < UNITS.len() - 1 {
+            size /= 1024.0;
+            unit += 1;
+        }
+        let size = format!("{:.2}", size);
+        let size = size.trim_end_matches('0').trim_end_matches('.');
+        format!("{} {}", size, UNITS[unit])
+    }
+
+    pub(crate) struct HumanBytes(pub(crate) u64);
+
+    impl fmt::Display for HumanBytes {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str(&format_size(self.0, BINARY))
+        }
+    }
+
+    pub(crate) struct HumanDuration(pub(crate) Duration);
+
+    impl fmt::Display for HumanDuration {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}s", self.0.as_secs())
+        }
+    }
+
+    /// Where bars would be drawn; nowhere.
+    pub(crate) struct ProgressDrawTarget;
+
+    impl ProgressDrawTarget {
+        pub(crate) fn hidden() -> Self {
+            Self
+        }
+
+        pub(crate) fn stderr() -> Self {
+            Self
+        }
+    }
+
+    /// What the keys of a style are computed from.
+    pub(crate) struct ProgressState {
+        pos: u64,
+        len: Option<u64>,
+    }
+
+    impl ProgressState {
+        pub(crate) fn pos(&self) -> u64 {
+            self.pos
+        }
+
+        pub(crate) fn len(&self) -> Option<u64> {
+            self.len
+        }
+    }
+
+    /// A style that is never drawn, so any template is accepted.
+    #[derive(Clone)]
+    pub(crate) struct ProgressStyle;
+
+    impl ProgressStyle {
+        pub(crate) fn default_bar() -> Self {
+            Self
+        }
+
+        pub(crate) fn default_spinner() -> Self {
+            Self
+        }
+
+        pub(crate) fn template(self, _: &str) -> Result<Self, Infallible> {
+            Ok(self)
+        }
+
+        pub(crate) fn progress_chars(self, _: &str) -> Self {
+            self
+        }
+
+        pub(crate) fn with_key(
+            self,
+            _: &'static str,
+            _: impl Fn(&ProgressState, &mut dyn fmt::Write) + Send + Sync + 'static,
+        ) -> Self {
+            self
+        }
+    }
+
+    #[derive(Default)]
+    struct State {
+        pos: AtomicU64,
+        len: Mutex<Option<u64>>,
+        message: Mutex<String>,
+        finished: AtomicBool,
+    }
+
+    /// A position and a length, shared by clones, that are never drawn.
+    #[derive(Clone, Default)]
+    pub(crate) struct ProgressBar {
+        state: Arc<State>,
+    }
+
+    impl ProgressBar {
+        pub(crate) fn new(len: u64) -> Self {
+            let pb = Self::default();
+            pb.set_length(len);
+            pb
+        }
+
+        #[cfg(test)]
+        pub(crate) fn hidden() -> Self {
+            Self::default()
+        }
+
+        pub(crate) fn new_spinner() -> Self {
+            Self::default()
+        }
+
+        pub(crate) fn with_draw_target(len: Option<u64>, _: ProgressDrawTarget) -> Self {
+            let pb = Self::default();
+            *pb.state.len.lock().unwrap() = len;
+            pb
+        }
+
+        pub(crate) fn position(&self) -> u64 {
+            self.state.pos.load(Ordering::Relaxed)
+        }
+
+        pub(crate) fn set_position(&self, pos: u64) {
+            self.state.pos.store(pos, Ordering::Relaxed);
+        }
+
+        pub(crate) fn inc(&self, delta: u64) {
+            self.state.pos.fetch_add(delta, Ordering::Relaxed);
+        }
+
+        pub(crate) fn length(&self) -> Option<u64> {
+            *self.state.len.lock().unwrap()
+        }
+
+        pub(crate) fn set_length(&self, len: u64) {
+            *self.state.len.lock().unwrap() = Some(len);
+        }
+
+        pub(crate) fn inc_length(&self, delta: u64) {
+            let mut len = self.state.len.lock().unwrap();
+            *len = Some(len.unwrap_or(0) + delta);
+        }
+
+        pub(crate) fn message(&self) -> String {
+            self.state.message.lock().unwrap().clone()
+        }
+
+        pub(crate) fn set_message(&self, message: impl Into<Cow<'static, str>>) {
+            *self.state.message.lock().unwrap() = message.into().into_owned();
+        }
+
+        pub(crate) fn set_style(&self, _: ProgressStyle) {}
+
+        pub(crate) fn is_hidden(&self) -> bool {
+            true
+        }
+
+        pub(crate) fn println(&self, _: impl AsRef<str>) {}
+
+        pub(crate) fn finish(&self) {
+            self.state.finished.store(true, Ordering::Relaxed);
+        }
+
+        pub(crate) fn finish_and_clear(&self) {
+            self.finish();
+        }
+
+        pub(crate) fn finish_with_message(&self, message: impl Into<Cow<'static, str>>) {
+            self.set_message(message);
+            self.finish();
+        }
+
+        pub(crate) fn abandon(&self) {
+            self.finish();
+        }
+
+        pub(crate) fn abandon_with_message(&self, message: impl Into<Cow<'static, str>>) {
+            self.finish_with_message(message);
+        }
+
+        #[cfg(test)]
+        pub(crate) fn is_finished(&self) -> bool {
+            self.state.finished.load(Ordering::Relaxed)
+        }
+
+        pub(crate) fn enable_steady_tick(&self, _: Duration) {}
+    }
+
+    /// A group of bars that are never drawn.
+    #[derive(Clone)]
+    pub(crate) struct MultiProgress;
+
+    impl MultiProgress {
+        pub(crate) fn with_draw_target(_: ProgressDrawTarget) -> Self {
+            Self
+        }
+
+        pub(crate) fn set_draw_target(&self, _: ProgressDrawTarget) {}
+
+        pub(crate) fn add(&self, pb: ProgressBar) -> ProgressBar {
+            pb
+        }
+
+        pub(crate) fn insert_before(&self, _: &ProgressBar, pb: ProgressBar) -> ProgressBar {
+            pb
+        }
+
+        pub(crate) fn insert_after(&self, _: &ProgressBar, pb: ProgressBar) -> ProgressBar {
+            pb
+        }
+
+        pub(crate) fn remove(&self, _: &ProgressBar) {}
+    }
+}
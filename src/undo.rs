@@ -0,0 +1,369 @@
+//! The record of the last copy, for `cpv undo`: which files it created or
+//! replaced, and what became of those it replaced, with the size and
+//! modification time it left them with, and which directories it created.
+//! Undoing it removes the copies that are still as the copy left them,
+//! moves back the files it backed up or moved to the trash, and removes the
+//! directories it created once empty. Copies modified since are kept, as
+//! are those that overwrote a file nothing was kept of.
+//!
+//! The record starts with a version line, followed by one line per file
+//! or directory, in the order they were copied:
+//!
+//! ```text
+//! dir PATH
+//! created|overwritten|trashed|backed-up LEN MTIME PATH
+//! backup PATH
+//! ```
+//!
+//! where a `backup` line follows the `backed-up` file it is the backup of,
+//! and paths are absolute, escaped as in the journal. Names that are not
+//! valid UTF-8 are not recorded, so never undone.
+
+use crate::journal::{escape, unescape, Stamp};
+use crate::location::Location;
+use crate::overwrite;
+use crate::stage::state_dir;
+use std::env;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tempfile::{NamedTempFile, TempPath};
+
+/// First line of a record in the current format.
+const HEADER: &str = "cpv undo 1";
+
+/// What became of the destination file a copy replaced.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Replaced {
+    /// There was none.
+    Nothing,
+    /// It was overwritten, so it cannot be brought back.
+    Overwritten,
+    /// It was moved to this backup.
+    BackedUp(PathBuf),
+    /// It was moved to the system trash.
+    Trashed,
+}
+
+impl Replaced {
+    fn word(&self) -> &'static str {
+        match self {
+            Self::Nothing => "created",
+            Self::Overwritten => "overwritten",
+            Self::BackedUp(_) => "backed-up",
+            Self::Trashed => "trashed",
+        }
+    }
+}
+
+/// The record of a copy being made, written as it goes.
+pub struct UndoLog {
+    /// The working directory, which relative paths are recorded under.
+    cwd: PathBuf,
+    file: Mutex<File>,
+    /// A new record, written to a temporary file of its own so that copies
+    /// running at the same time do not write into each other's, and the
+    /// path it replaces once the copy is over.
+    pending: Mutex<Option<(TempPath, PathBuf)>>,
+}
+
+impl fmt::Debug for UndoLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UndoLog").finish_non_exhaustive()
+    }
+}
+
+impl UndoLog {
+    /// `last-copy` in the per-user state directory.
+    pub fn default_path() -> Option<PathBuf> {
+        Some(state_dir()?.join("last-copy"))
+    }
+
+    /// Starts a record that replaces the one at `path`, that of the copy
+    /// before, when saved or dropped.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let dir = parent(path);
+        fs::create_dir_all(dir)?;
+        let (mut file, temp) = NamedTempFile::new_in(dir)?.into_parts();
+        writeln!(file, "{}", HEADER)?;
+        Ok(Self {
+            cwd: env::current_dir()?,
+            file: Mutex::new(file),
+            pending: Mutex::new(Some((temp, path.to_path_buf()))),
+        })
+    }
+
+    /// Continues the record at `path`, for copies undone together with
+    /// the one that started it, such as those of `cpv paste`.
+    pub fn append(path: &Path) -> io::Result<Self> {
+        fs::create_dir_all(parent(path))?;
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        if file.metadata()?.len() == 0 {
+            writeln!(file, "{}", HEADER)?;
+        }
+        Ok(Self {
+            cwd: env::current_dir()?,
+            file: Mutex::new(file),
+            pending: Mutex::new(None),
+        })
+    }
+
+    /// Puts a new record in place of the one of the copy before. Only the
+    /// first call does anything.
+    pub fn save(&self) -> io::Result<()> {
+        if let Some((temp, path)) = self.pending.lock().unwrap().take() {
+            temp.persist(path)?;
+        }
+        Ok(())
+    }
+
+    /// Records that the directory `path` was created.
+    pub(crate) fn dir(&self, path: &Path) {
+        if let Some(path) = self.cwd.join(path).to_str() {
+            self.write(format!("dir {}\n", escape(path)));
+        }
+    }
+
+    /// Records the file, or link, copied to `dest` and what became of the
+    /// one it replaced. The record does not stop the copy, so errors are
+    /// ignored.
+    pub(crate) fn file(&self, dest: &Path, replaced: &Replaced) {
+        let dest = self.cwd.join(dest);
+        let (Some(path), Ok(metadata)) = (dest.to_str(), fs::symlink_metadata(&dest)) else {
+            return;
+        };
+        let stamp = Stamp::of(&metadata);
+        let mut line = format!(
+            "{} {} {} {}\n",
+            replaced.word(),
+            stamp.len,
+            stamp.mtime,
+            escape(path)
+        );
+        if let Replaced::BackedUp(backup) = replaced {
+            match self.cwd.join(backup).to_str() {
+                Some(backup) => line.push_str(&format!("backup {}\n", escape(backup))),
+                None => return,
+            }
+        }
+        self.write(line);
+    }
+
+    fn write(&self, line: String) {
+        let _ = self.file.lock().unwrap().write_all(line.as_bytes());
+    }
+}
+
+impl Drop for UndoLog {
+    fn drop(&mut self) {
+        let _ = self.save();
+    }
+}
+
+/// The directory `path` is in.
+fn parent(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+/// What `undo` did.
+#[derive(Debug, Clone, Default)]
+pub struct Undone {
+    /// Copies removed.
+    pub files_removed: usize,
+    /// Files moved back from a backup or the trash.
+    pub files_restored: usize,
+    /// Directories the copy created, removed.
+    pub dirs_removed: usize,
+    /// Copies left where they are, with the reason.
+    pub kept: Vec<(PathBuf, String)>,
+}
+
+/// A file recorded by the copy.
+struct Entry {
+    path: PathBuf,
+    stamp: Stamp,
+    replaced: Replaced,
+}
+
+/// Undoes the copy recorded at `path`, newest files first, and removes the
+/// record. Fails if there is no record, or it is in another format.
+pub fn undo(path: &Path) -> io::Result<Undone> {
+    let text = fs::read_to_string(path).map_err(|err| match err.kind() {
+        io::ErrorKind::NotFound => io::Error::new(err.kind(), "there is no copy to undo"),
+        _ => err,
+    })?;
+    let (files, dirs) = parse(&text).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("'{}' is not a record of a copy", path.display()),
+        )
+    })?;
+    let mut undone = Undone::default();
+    for entry in files.iter().rev() {
+        if let Err(reason) = undo_file(entry, &mut undone) {
+            undone.kept.push((entry.path.clone(), reason));
+        }
+    }
+    for dir in dirs.iter().rev() {
+        // Directories holding files that were kept, or put there since,
+        // stay.
+        if fs::remove_dir(dir).is_ok() {
+            undone.dirs_removed += 1;
+        }
+    }
+    fs::remove_file(path)?;
+    Ok(undone)
+}
+
+fn parse(text: &str) -> Option<(Vec<Entry>, Vec<PathBuf>)> {
+    let mut lines = text.lines();
+    if lines.next() != Some(HEADER) {
+        return None;
+    }
+    let (mut files, mut dirs) = (Vec::<Entry>::new(), Vec::new());
+    for line in lines {
+        let (word, rest) = line.split_once(' ')?;
+        match word {
+            "dir" => dirs.push(PathBuf::from(unescape(rest)?)),
+            "backup" => match files.last_mut() {
+                Some(Entry {
+                    replaced: replaced @ Replaced::Overwritten,
+                    ..
+                }) => *replaced = Replaced::BackedUp(PathBuf::from(unescape(rest)?)),
+                _ => return None,
+            },
+            _ => {
+                let mut fields = rest.splitn(3, ' ');
+                let stamp = Stamp {
+                    len: fields.next()?.parse().ok()?,
+                    mtime: fields.next()?.parse().ok()?,
+                };
+                let path = PathBuf::from(unescape(fields.next()?)?);
+                let replaced = match word {
+                    "created" => Replaced::Nothing,
+                    // The backup line that follows names the backup.
+                    "overwritten" | "backed-up" => Replaced::Overwritten,
+                    "trashed" => Replaced::Trashed,
+                    _ => return None,
+                };
+                files.push(Entry {
+                    path,
+                    stamp,
+                    replaced,
+                });
+            }
+        }
+    }
+    Some((files, dirs))
+}
+
+/// Removes the copy of `entry` and moves back the file it replaced, or
+/// returns why it is kept.
+fn undo_file(entry: &Entry, undone: &mut Undone) -> Result<(), String> {
+    let copy = Location::new(&entry.path);
+    let present = match fs::symlink_metadata(&entry.path) {
+        Ok(metadata) if Stamp::of(&metadata) != entry.stamp => {
+            return Err("modified since the copy".to_string())
+        }
+        Ok(metadata) if metadata.is_dir() => return Err("replaced by a directory".to_string()),
+        Ok(_) => true,
+        // Removed since, but what it replaced can still be moved back.
+        Err(err) if err.kind() == io::ErrorKind::NotFound => false,
+        Err(err) => return Err(err.to_string()),
+    };
+    match &entry.replaced {
+        Replaced::Nothing => {}
+        Replaced::Overwritten if present => {
+            return Err("it overwrote a file that was not kept".to_string())
+        }
+        Replaced::Overwritten => return Ok(()),
+        Replaced::BackedUp(backup) => {
+            if fs::symlink_metadata(backup).is_err() {
+                return Err(format!("its backup '{}' is gone", backup.display()));
+            }
+            if present {
+                copy.remove().map_err(|err| err.to_string())?;
+                undone.files_removed += 1;
+            }
+            overwrite::move_file(&Location::new(backup), &entry.path)
+                .map_err(|err| format!("cannot restore '{}': {}", backup.display(), err))?;
+            undone.files_restored += 1;
+            return Ok(());
+        }
+        Replaced::Trashed => {
+            let trashed = from_trash::find(&entry.path)?;
+            if present {
+                copy.remove().map_err(|err| err.to_string())?;
+                undone.files_removed += 1;
+            }
+            from_trash::restore(trashed)?;
+            undone.files_restored += 1;
+            return Ok(());
+        }
+    }
+    if present {
+        copy.remove().map_err(|err| err.to_string())?;
+        undone.files_removed += 1;
+    }
+    Ok(())
+}
+
+/// Files moved back from the trash, where the system trash can be listed.
+#[cfg(any(
+    windows,
+    all(
+        unix,
+        not(target_os = "macos"),
+        not(target_os = "ios"),
+        not(target_os = "android")
+    )
+))]
+mod from_trash {
+    use std::path::Path;
+    use trash::os_limited;
+    use trash::TrashItem;
+
+    /// The file last moved to the trash from `path`.
+    pub(super) fn find(path: &Path) -> Result<TrashItem, String> {
+        let items = os_limited::list().map_err(|err| err.to_string())?;
+        items
+            .into_iter()
+            .filter(|item| item.original_path() == path)
+            .max_by_key(|item| item.time_deleted)
+            .ok_or_else(|| "the file it replaced is no longer in the trash".to_string())
+    }
+
+    pub(super) fn restore(item: TrashItem) -> Result<(), String> {
+        os_limited::restore_all([item])
+            .map_err(|err| format!("cannot restore it from the trash: {}", err))
+    }
+}
+
+#[cfg(not(any(
+    windows,
+    all(
+        unix,
+        not(target_os = "macos"),
+        not(target_os = "ios"),
+        not(target_os = "android")
+    )
+)))]
+mod from_trash {
+    use std::path::Path;
+
+    pub(super) fn find(_path: &Path) -> Result<(), String> {
+        Err("the file it replaced is in the trash; restore it from there".to_string())
+    }
+
+    pub(super) fn restore(_item: ()) -> Result<(), String> {
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+//! Read-back verification for `--verify`.
+
+use crate::checksum::{ChecksumAlgo, Digest};
+use crate::location::Location;
+use crate::ui::ProgressBar;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// Bytes read from each file per step.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// The error of a copy that does not match its source.
+#[derive(Debug)]
+struct Mismatch(String);
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for Mismatch {}
+
+/// Whether `err` is the error of `verify` finding a copy that does not
+/// match its source.
+pub(crate) fn is_mismatch(err: &io::Error) -> bool {
+    err.get_ref().is_some_and(|inner| inner.is::<Mismatch>())
+}
+
+/// Re-reads `dest` and compares its `algo` hash with `expected`, the hash
+/// of `source`, advancing `pb` by the bytes read.
+///
+/// Data still in the page cache is read from memory, so this catches
+/// corruption on the way through the copy rather than on the device.
+/// Returns the number of bytes verified, or an `InvalidData` error naming
+/// both files when they differ.
+pub(crate) fn verify(
+    source: &Location,
+    dest: &Location,
+    expected: &Digest,
+    algo: ChecksumAlgo,
+    pb: Option<&ProgressBar>,
+) -> io::Result<u64> {
+    let (actual, verified) = hash_file(dest, algo, pb)?;
+    if actual != *expected {
+        return Err(mismatch(source.path(), dest.path(), algo));
+    }
+    Ok(verified)
+}
+
+/// The error of `dest` not matching `source` when compared with `algo`.
+pub(crate) fn mismatch(source: &Path, dest: &Path, algo: ChecksumAlgo) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        Mismatch(format!(
+            "verification failed: '{}' does not match '{}' ({})",
+            dest.display(),
+            source.display(),
+            algo
+        )),
+    )
+}
+
+/// Hashes the contents of `file` with `algo`, advancing `pb` as it is
+/// read. Returns the digest and the number of bytes read.
+pub(crate) fn hash_file(
+    file: &Location,
+    algo: ChecksumAlgo,
+    pb: Option<&ProgressBar>,
+) -> io::Result<(Digest, u64)> {
+    hash_reader(file.open()?, algo, pb)
+}
+
+/// Hashes everything `reader` yields with `algo`, like `hash_file`.
+pub(crate) fn hash_reader(
+    mut file: impl Read,
+    algo: ChecksumAlgo,
+    pb: Option<&ProgressBar>,
+) -> io::Result<(Digest, u64)> {
+    let mut hasher = algo.hasher();
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut read = 0;
+    loop {
+        let n = read_chunk(&mut file, &mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        read += n as u64;
+        if let Some(pb) = pb {
+            pb.inc(n as u64);
+        }
+    }
+    Ok((hasher.finish(), read))
+}
+
+/// Fills `buf` unless EOF comes first, returning the bytes read.
+pub(crate) fn read_chunk(mut file: impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
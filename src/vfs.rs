@@ -0,0 +1,691 @@
+//! Sources and destinations that are not local paths, behind the
+//! `Filesystem` trait, so that backends such as SFTP, S3 or archives can
+//! reuse the copy loop, progress, hooks and verification.
+//!
+//! A copy goes through `copy` here once `CopyOptions::source_fs` or
+//! `CopyOptions::dest_fs` is set. It sees files only as streams of bytes:
+//! cloning, sparse files, attributes other than modification times and
+//! permission bits, linking, resuming and the other options that work on local files are
+//! not applied, and files are copied one at a time.
+
+use crate::cancel::Cancelled;
+use crate::context::{IoOp, ResultExt};
+use crate::failure::{ErrorClass, FileError};
+use crate::progress::{Bars, Progress};
+use crate::{
+    fat, hooks, listing, mirror, overwrite, verify, CopyError, CopyOptions, CopyStats,
+    FileDecision, OverwritePolicy,
+};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+
+/// Bytes read from a source per step.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// The contents of a `MemoryFs` by path, with `None` for directories.
+type Entries = BTreeMap<PathBuf, Option<Vec<u8>>>;
+
+/// What a copy needs to know about a file or directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileInfo {
+    pub is_dir: bool,
+    /// Size in bytes; 0 for directories.
+    pub len: u64,
+    pub modified: Option<SystemTime>,
+    /// Permission bits, where the filesystem has them.
+    pub mode: Option<u32>,
+}
+
+/// Where a copy reads its source from or writes its destination to.
+///
+/// Paths are those given to `copy_with_progress` and the ones this
+/// filesystem lists from them; symbolic links, where the filesystem has
+/// them, are followed. Methods are called from the threads doing the
+/// copy.
+pub trait Filesystem: fmt::Debug + Send + Sync {
+    fn metadata(&self, path: &Path) -> io::Result<FileInfo>;
+
+    /// The entries of the directory `path`, as paths under it.
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>>;
+
+    /// Opens the file `path` to read from `offset` on, for
+    /// `CopyOptions::range`. By default the bytes before it are read and
+    /// dropped; filesystems that can seek override this.
+    fn open_at(&self, path: &Path, offset: u64) -> io::Result<Box<dyn Read + Send>> {
+        let mut reader = self.open(path)?;
+        let skipped = io::copy(&mut reader.by_ref().take(offset), &mut io::sink())?;
+        if skipped < offset {
+            return Err(past_end(path));
+        }
+        Ok(reader)
+    }
+
+    /// Opens the file `path`, created if missing, to write from `offset`
+    /// on over what it holds, for `ByteRange::dest_offset`. Filesystems
+    /// that cannot fail with `ErrorKind::Unsupported`, as by default.
+    fn patch(&self, path: &Path, offset: u64) -> io::Result<Box<dyn Write + Send>> {
+        let _ = (path, offset);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "patching files is not supported on this destination",
+        ))
+    }
+
+    /// Creates or truncates the file `path`, whose parent exists.
+    fn create(&self, path: &Path) -> io::Result<Box<dyn Write + Send>>;
+
+    /// Creates the file `path` as `create` does, for the source file
+    /// described by `source`, whose `len` bytes are then written to it.
+    /// Archives, which record sizes, times and modes ahead of the data,
+    /// need this; others default to `create`.
+    fn create_for(&self, path: &Path, source: &FileInfo) -> io::Result<Box<dyn Write + Send>> {
+        let _ = source;
+        self.create(path)
+    }
+
+    /// Creates the directory `path` and any missing parents.
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Sets the modification time of the file `path`, for
+    /// `preserve_attrs`. Filesystems without one fail with
+    /// `ErrorKind::Unsupported`, as by default, and keep theirs.
+    fn set_modified(&self, path: &Path, modified: SystemTime) -> io::Result<()> {
+        let _ = (path, modified);
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Sets the permission bits of the file `path`, for `preserve_attrs`.
+    /// Filesystems without them fail with `ErrorKind::Unsupported`, as by
+    /// default.
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        let _ = (path, mode);
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Removes the file `path`, or the directory `path` with everything
+    /// under it, for `CopyOptions::delete`. Filesystems that cannot fail
+    /// with `ErrorKind::Unsupported`, as by default.
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        let _ = path;
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "deleting is not supported on this destination",
+        ))
+    }
+
+    /// Completes a copy into this filesystem once its files are written,
+    /// even when it failed, as archives need to. Does nothing by default.
+    fn finish(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The local filesystem, used when no other is set.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LocalFs;
+
+impl Filesystem for LocalFs {
+    fn metadata(&self, path: &Path) -> io::Result<FileInfo> {
+        let metadata = fs::metadata(path)?;
+        Ok(FileInfo {
+            is_dir: metadata.is_dir(),
+            len: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: metadata.modified().ok(),
+            #[cfg(unix)]
+            mode: Some(std::os::unix::fs::PermissionsExt::mode(&metadata.permissions()) & 0o7777),
+            #[cfg(not(unix))]
+            mode: None,
+        })
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        fs::read_dir(path)?.map(|entry| Ok(entry?.path())).collect()
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn open_at(&self, path: &Path, offset: u64) -> io::Result<Box<dyn Read + Send>> {
+        let mut file = File::open(path)?;
+        // Devices have no length to check against.
+        let metadata = file.metadata()?;
+        if metadata.is_file() && offset > metadata.len() {
+            return Err(past_end(path));
+        }
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(Box::new(file))
+    }
+
+    fn patch(&self, path: &Path, offset: u64) -> io::Result<Box<dyn Write + Send>> {
+        let mut file = File::options()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(Box::new(file))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn Write + Send>> {
+        Ok(Box::new(File::create(path)?))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn set_modified(&self, path: &Path, modified: SystemTime) -> io::Result<()> {
+        File::options()
+            .write(true)
+            .open(path)?
+            .set_modified(modified)
+    }
+
+    #[cfg(unix)]
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+    }
+
+    /// A symbolic link is removed itself, not what it points to.
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        match fs::symlink_metadata(path)?.is_dir() {
+            true => fs::remove_dir_all(path),
+            false => fs::remove_file(path),
+        }
+    }
+}
+
+/// Files and directories held in memory, for tests and for embedders that
+/// produce or consume data without touching the disk. Clones share the
+/// same contents.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryFs {
+    entries: Arc<Mutex<Entries>>,
+}
+
+impl MemoryFs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Puts a file at `path`, creating its parent directories.
+    pub fn insert(&self, path: impl Into<PathBuf>, data: impl Into<Vec<u8>>) {
+        let path = path.into();
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(parent) = path.parent() {
+            add_dirs(&mut entries, parent);
+        }
+        entries.insert(path, Some(data.into()));
+    }
+
+    /// The contents of the file at `path`, if there is one.
+    pub fn read(&self, path: impl AsRef<Path>) -> Option<Vec<u8>> {
+        let entries = self.entries.lock().unwrap();
+        entries.get(path.as_ref())?.clone()
+    }
+}
+
+fn add_dirs(entries: &mut Entries, dir: &Path) {
+    for dir in dir.ancestors().filter(|dir| !dir.as_os_str().is_empty()) {
+        entries.entry(dir.to_path_buf()).or_insert(None);
+    }
+}
+
+/// The error for reading `path` from past its end.
+pub(crate) fn past_end(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        format!("'{}' ends before the offset", path.display()),
+    )
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("'{}' does not exist", path.display()),
+    )
+}
+
+impl Filesystem for MemoryFs {
+    fn metadata(&self, path: &Path) -> io::Result<FileInfo> {
+        match self.entries.lock().unwrap().get(path) {
+            Some(data) => Ok(FileInfo {
+                is_dir: data.is_none(),
+                len: data.as_ref().map_or(0, |data| data.len() as u64),
+                modified: None,
+                mode: None,
+            }),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let entries = self.entries.lock().unwrap();
+        match entries.get(path) {
+            Some(None) => Ok(entries
+                .keys()
+                .filter(|entry| entry.parent() == Some(path))
+                .cloned()
+                .collect()),
+            Some(Some(_)) => Err(io::Error::other(format!(
+                "'{}' is not a directory",
+                path.display()
+            ))),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        match self.entries.lock().unwrap().get(path) {
+            Some(Some(data)) => Ok(Box::new(io::Cursor::new(data.clone()))),
+            Some(None) => Err(io::Error::other(format!(
+                "'{}' is a directory",
+                path.display()
+            ))),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn Write + Send>> {
+        let mut entries = self.entries.lock().unwrap();
+        let parent = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty());
+        if parent.is_some_and(|parent| !matches!(entries.get(parent), Some(None))) {
+            return Err(not_found(parent.unwrap()));
+        }
+        if let Some(None) = entries.get(path) {
+            return Err(io::Error::other(format!(
+                "'{}' is a directory",
+                path.display()
+            )));
+        }
+        entries.insert(path.to_path_buf(), Some(Vec::new()));
+        Ok(Box::new(MemoryFile {
+            fs: self.clone(),
+            path: path.to_path_buf(),
+            data: Vec::new(),
+        }))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(Some(_)) = entries.get(path) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("'{}' is a file", path.display()),
+            ));
+        }
+        add_dirs(&mut entries, path);
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.contains_key(path) {
+            return Err(not_found(path));
+        }
+        entries.retain(|entry, _| !entry.starts_with(path));
+        Ok(())
+    }
+}
+
+/// A file of a `MemoryFs` being written, whose contents replace the file's
+/// on every flush.
+struct MemoryFile {
+    fs: MemoryFs,
+    path: PathBuf,
+    data: Vec<u8>,
+}
+
+impl Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let data = self.data.clone();
+        self.fs
+            .entries
+            .lock()
+            .unwrap()
+            .insert(self.path.clone(), Some(data));
+        Ok(())
+    }
+}
+
+impl Drop for MemoryFile {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// Copies `source` on `source_fs` to `dest` on `dest_fs`, recursively if
+/// `options.recursive` is set.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn copy(
+    source_fs: &dyn Filesystem,
+    source: &Path,
+    dest_fs: &dyn Filesystem,
+    dest: &Path,
+    bars: &Bars,
+    options: &CopyOptions,
+    stats: &mut CopyStats,
+) -> Result<(), CopyError> {
+    let info = source_fs.metadata(source)?;
+    let into_dir = dest_fs.metadata(dest).is_ok_and(|dest| dest.is_dir);
+    let target = match source.file_name() {
+        Some(name) if into_dir => dest.join(options.target_name(name)),
+        _ => dest.to_path_buf(),
+    };
+    if !info.is_dir {
+        bars.set_length(info.len);
+        copy_file(
+            (source_fs, dest_fs),
+            (source, &info),
+            &target,
+            bars,
+            options,
+            stats,
+        )?;
+        return Ok(());
+    }
+    if !options.recursive {
+        return Err(CopyError::IsADirectory(source.to_path_buf()));
+    }
+
+    dest_fs
+        .create_dir_all(&target)
+        .context(IoOp::CreateDir, &target)?;
+    let mut files = Vec::new();
+    scan(
+        (source_fs, dest_fs),
+        source,
+        &target,
+        bars,
+        options,
+        stats,
+        &mut files,
+    )?;
+    bars.finish_scan();
+    if let Some(reporter) = &options.reporter {
+        let (files, bytes) = bars.scanned();
+        reporter.on_scan(files, bytes);
+    }
+    for (source, info, target) in files {
+        if let Err(err) = copy_file(
+            (source_fs, dest_fs),
+            (&source, &info),
+            &target,
+            bars,
+            options,
+            stats,
+        ) {
+            if !options.skips(ErrorClass::of_io(&err)) || Cancelled::is(&err) {
+                return Err(err.into());
+            }
+            stats.failures.push(FileError::copy(source, err.into()));
+        }
+    }
+    Ok(())
+}
+
+/// Lists the files under the directory `dir` with their targets under
+/// `target` into `files`, creating the directories on the way.
+fn scan(
+    (source_fs, dest_fs): (&dyn Filesystem, &dyn Filesystem),
+    dir: &Path,
+    target: &Path,
+    bars: &Bars,
+    options: &CopyOptions,
+    stats: &mut CopyStats,
+    files: &mut Vec<(PathBuf, FileInfo, PathBuf)>,
+) -> Result<(), CopyError> {
+    let entries = match source_fs.read_dir(dir).context(IoOp::ReadDir, dir) {
+        Ok(mut entries) => {
+            entries.sort();
+            entries
+        }
+        Err(err) if options.skips(ErrorClass::of_io(&err)) => {
+            stats
+                .failures
+                .push(FileError::scan(dir.to_path_buf(), err.into()));
+            return Ok(());
+        }
+        Err(err) => return Err(err.into()),
+    };
+    for entry in entries {
+        let Some(name) = entry.file_name() else {
+            continue;
+        };
+        let entry_target = target.join(options.target_name(name));
+        let info = match source_fs.metadata(&entry).context(IoOp::Stat, &entry) {
+            Ok(info) => info,
+            Err(err) if options.skips(ErrorClass::of_io(&err)) => {
+                stats.failures.push(FileError::scan(entry, err.into()));
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if !info.is_dir {
+            bars.add_file_to_total(None, info.len);
+            files.push((entry, info, entry_target));
+            continue;
+        }
+        match dest_fs
+            .create_dir_all(&entry_target)
+            .context(IoOp::CreateDir, &entry_target)
+        {
+            Ok(()) => {}
+            Err(err) if options.skips(ErrorClass::of_io(&err)) => {
+                stats.failures.push(FileError::copy(entry, err.into()));
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        }
+        scan(
+            (source_fs, dest_fs),
+            &entry,
+            &entry_target,
+            bars,
+            options,
+            stats,
+            files,
+        )?;
+    }
+    Ok(())
+}
+
+/// Copies the file `source`, described by `info`, to `dest`, as
+/// `crate::copy_file` does for local files, returning its size.
+fn copy_file(
+    (source_fs, dest_fs): (&dyn Filesystem, &dyn Filesystem),
+    (source, info): (&Path, &FileInfo),
+    dest: &Path,
+    bars: &Bars,
+    options: &CopyOptions,
+    stats: &mut CopyStats,
+) -> io::Result<u64> {
+    if let Some(token) = &options.cancel {
+        token.check()?;
+    }
+    if let Some(hooks) = &options.hooks {
+        match hooks.on_file_start(source, dest) {
+            FileDecision::Copy => {}
+            FileDecision::Skip => return declined(info, stats),
+            FileDecision::Fail => return Err(hooks::refused(source)),
+        }
+    }
+    let existing = dest_fs.metadata(dest).ok().filter(|dest| !dest.is_dir);
+    if options.mirror
+        && existing
+            .as_ref()
+            .is_some_and(|copy| mirror::up_to_date(info, copy, options))
+    {
+        bars.copy.inc(info.len);
+        stats.files_up_to_date += 1;
+        stats.bytes_up_to_date += info.len;
+        return Ok(info.len);
+    }
+    let existed = existing.is_some();
+    if existed {
+        if let Some(hooks) = &options.hooks {
+            match hooks.on_conflict(source, dest) {
+                FileDecision::Copy => {}
+                FileDecision::Skip => return declined(info, stats),
+                FileDecision::Fail => return Err(overwrite::exists(dest)),
+            }
+        }
+    }
+    let renamed;
+    let dest = match &options.overwrite {
+        _ if !existed => dest,
+        OverwritePolicy::Overwrite => dest,
+        OverwritePolicy::Prompt if options.hooks.is_some() => dest,
+        OverwritePolicy::Skip => {
+            stats.files_kept += 1;
+            return Ok(info.len);
+        }
+        OverwritePolicy::Error | OverwritePolicy::Prompt => return Err(overwrite::exists(dest)),
+        OverwritePolicy::RenameNew => {
+            renamed = overwrite::free_name(dest, |path| Ok(dest_fs.metadata(path).is_ok()))?;
+            &renamed
+        }
+        policy => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("the {} overwrite policy needs local files", policy),
+            ))
+        }
+    };
+    let started = Instant::now();
+    bars.start_file(source);
+    if let Some(reporter) = &options.reporter {
+        reporter.on_file_start(source, dest);
+    }
+    if let Some(events) = &options.progress_json {
+        events.file_started(source, dest);
+    }
+    let result = transfer(
+        (source_fs, dest_fs),
+        (source, info),
+        dest,
+        bars,
+        options,
+        stats,
+    );
+    if options.verbose && result.is_ok() {
+        bars.list(listing(source, dest, &[]));
+    }
+    if let Some(hooks) = &options.hooks {
+        match &result {
+            Ok(bytes) => hooks.on_file_complete(source, dest, *bytes, started.elapsed()),
+            Err(err) => hooks.on_error(source, dest, err),
+        }
+    }
+    bars.finish_file(source);
+    if let Some(reporter) = &options.reporter {
+        match &result {
+            Ok(bytes) => reporter.on_file_done(source, dest, *bytes),
+            Err(err) => reporter.on_error(Some(source), err),
+        }
+    }
+    if let Some(events) = &options.progress_json {
+        match &result {
+            Ok(bytes) => events.file_done(source, dest, *bytes, false),
+            Err(err) => events.file_error(source, dest, err),
+        }
+    }
+    result
+}
+
+/// Leaves out the file of `info`, which a hook declined, returning its
+/// size.
+fn declined(info: &FileInfo, stats: &mut CopyStats) -> io::Result<u64> {
+    stats.files_declined += 1;
+    Ok(info.len)
+}
+
+/// Streams `source` into `dest` and, with `options.verify`, reads the copy
+/// back and compares it with the source.
+fn transfer(
+    (source_fs, dest_fs): (&dyn Filesystem, &dyn Filesystem),
+    (source, info): (&Path, &FileInfo),
+    dest: &Path,
+    bars: &Bars,
+    options: &CopyOptions,
+    stats: &mut CopyStats,
+) -> io::Result<u64> {
+    let mut reader = source_fs.open(source).context(IoOp::OpenSource, source)?;
+    let mut writer = dest_fs
+        .create_for(dest, info)
+        .context(IoOp::CreateDest, dest)?;
+    let mut progress = Progress::new(&bars.copy, options.rate_limiter.as_deref())
+        .throttled(options.progress_throttle)
+        .cancellable(options.cancel.as_ref())
+        .memory(bars.memory());
+    if options.verify || options.collects_checksums() {
+        progress = progress.hashing(options.checksum);
+    }
+    let mut buf = progress.buffer(0, Some(options.buffer_size.unwrap_or(CHUNK_SIZE)));
+    let mut copied = 0;
+    loop {
+        let n = verify::read_chunk(&mut reader, &mut buf)?;
+        if n == 0 {
+            break;
+        }
+        writer.write_all(&buf[..n])?;
+        progress.inc_data(&buf[..n])?;
+        copied += n as u64;
+    }
+    writer.flush()?;
+    drop(writer);
+    progress.flush();
+    if options.compress.is_some() {
+        stats.bytes_compressed += dest_fs.metadata(dest)?.len;
+    }
+    let digest = progress.source_digest(copied);
+    if let Some(expected) = digest.as_ref().filter(|_| options.verify) {
+        let copy = dest_fs.open(dest)?;
+        let (actual, verified) = verify::hash_reader(copy, options.checksum, bars.verify.as_ref())?;
+        if actual != *expected {
+            return Err(verify::mismatch(source, dest, options.checksum));
+        }
+        stats.bytes_verified += verified;
+    }
+    if let Some(digest) = digest.filter(|_| options.collects_checksums()) {
+        stats.checksums.push((dest.to_path_buf(), digest));
+    }
+    if options.preserve_attrs {
+        // The time first, as the mode may leave the file read-only.
+        let modified = info.modified.map(|modified| match options.fat_compat {
+            true => dest_fs.set_modified(dest, fat::round_time(modified)),
+            false => dest_fs.set_modified(dest, modified),
+        });
+        let mode = info
+            .mode
+            .filter(|_| !options.fat_compat)
+            .map(|mode| dest_fs.set_mode(dest, mode));
+        for result in [modified, mode].into_iter().flatten() {
+            match result {
+                Err(err) if err.kind() != io::ErrorKind::Unsupported => {
+                    return Err(err).context(IoOp::PreserveAttributes, dest);
+                }
+                _ => {}
+            }
+        }
+    }
+    stats.files_copied += 1;
+    stats.bytes_copied += copied;
+    Ok(copied)
+}
@@ -0,0 +1,109 @@
+//! Volume Shadow Copy snapshots for `--vss` on Windows.
+//!
+//! Snapshots are created through the WMI `Win32_ShadowCopy` provider and
+//! read through their `\\?\GLOBALROOT\Device\...` device path, which lets
+//! files held open by other processes be copied without sharing violations.
+//! Creating a snapshot requires an elevated process.
+
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+
+/// A client-accessible shadow copy of one volume, deleted on drop.
+pub(crate) struct Snapshot {
+    id: String,
+    device: PathBuf,
+    volume: PathBuf,
+}
+
+impl Snapshot {
+    /// Snapshots the volume containing `path`.
+    pub(crate) fn create(path: &Path) -> io::Result<Self> {
+        let volume = volume_root(&fs::canonicalize(path)?)?;
+        let drive = volume
+            .to_str()
+            .and_then(|volume| volume.strip_prefix(r"\\?\"))
+            .ok_or_else(|| other(format!("unsupported volume '{}'", volume.display())))?;
+
+        let script = format!(
+            "$r = (Get-WmiObject -List Win32_ShadowCopy).Create('{}', 'ClientAccessible'); \
+             if ($r.ReturnValue -ne 0) {{ exit $r.ReturnValue }}; \
+             $s = Get-WmiObject Win32_ShadowCopy -Filter \"ID='$($r.ShadowID)'\"; \
+             Write-Output $s.ID; Write-Output $s.DeviceObject",
+            drive
+        );
+        let output = powershell(&script)?;
+        let mut lines = output.lines().map(str::trim).filter(|l| !l.is_empty());
+        match (lines.next(), lines.next()) {
+            (Some(id), Some(device)) => Ok(Self {
+                id: id.to_string(),
+                device: PathBuf::from(device),
+                volume,
+            }),
+            _ => Err(other(format!(
+                "unexpected output from shadow copy creation: {}",
+                output
+            ))),
+        }
+    }
+
+    /// Maps a path on the snapshotted volume to the same path inside the
+    /// snapshot.
+    pub(crate) fn translate(&self, path: &Path) -> io::Result<PathBuf> {
+        let path = fs::canonicalize(path)?;
+        let relative = path.strip_prefix(&self.volume).map_err(|_| {
+            other(format!(
+                "'{}' is not on snapshotted volume '{}'",
+                path.display(),
+                self.volume.display()
+            ))
+        })?;
+        Ok(self.device.join(relative))
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let _ = Command::new("vssadmin")
+            .args([
+                "delete",
+                "shadows",
+                &format!("/shadow={}", self.id),
+                "/quiet",
+            ])
+            .output();
+    }
+}
+
+/// Returns the `\\?\C:\` root of a canonicalized path.
+fn volume_root(path: &Path) -> io::Result<PathBuf> {
+    let mut components = path.components();
+    match (components.next(), components.next()) {
+        (Some(prefix @ Component::Prefix(_)), Some(root @ Component::RootDir)) => {
+            Ok([prefix, root].iter().collect())
+        }
+        _ => Err(other(format!(
+            "cannot determine the volume of '{}'",
+            path.display()
+        ))),
+    }
+}
+
+fn powershell(script: &str) -> io::Result<String> {
+    let output = Command::new("powershell")
+        .args(["-NoProfile", "-NonInteractive", "-Command", script])
+        .output()?;
+    if !output.status.success() {
+        return Err(other(format!(
+            "shadow copy creation failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn other(message: String) -> io::Error {
+    io::Error::other(message)
+}
@@ -0,0 +1,342 @@
+//! Watch mode, with the `watch` feature: after a first copy, the source
+//! directory is followed with the platform's notifications (inotify,
+//! FSEvents, ReadDirectoryChangesW) and the files created or changed in it
+//! are copied as they settle, a one-way sync that runs until cancelled.
+
+use crate::exclude;
+use crate::progress::ProgressMode;
+use crate::ui::{ProgressBar, ProgressStyle};
+use crate::vfs::{Filesystem, LocalFs};
+use crate::{copy_with_progress, CopyError, CopyOptions, CopyStats, FileError};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use walkdir::WalkDir;
+
+/// How long a path must go without events before it is copied, so that a
+/// file being written is copied once, when done.
+const SETTLE: Duration = Duration::from_millis(500);
+
+/// How often pending paths and the cancellation token are looked at.
+const TICK: Duration = Duration::from_millis(100);
+
+/// Copies the directory `source` to `dest` as `copy_with_progress` does,
+/// recursively, then keeps `dest` in step with it: files created or
+/// changed under `source` are copied once they have settled, and
+/// directories created there are copied whole. What is removed from
+/// `source` is removed from `dest` too with `options.delete`, and left
+/// there otherwise.
+///
+/// A status line shows the paths queued and in flight, and with
+/// `verbose` every copy is listed. Failed copies do not stop the watch;
+/// they are kept in the statistics, which cover every copy made until
+/// `options.cancel` is cancelled.
+pub fn watch(source: &Path, dest: &Path, options: &CopyOptions) -> Result<CopyStats, CopyError> {
+    if !source.is_dir() {
+        return Err(CopyError::NotADirectory(source.to_path_buf()));
+    }
+    let root = fs::canonicalize(source)?;
+    let target = match source.file_name() {
+        Some(name) if dest.is_dir() => dest.join(options.target_name(name)),
+        _ => dest.to_path_buf(),
+    };
+    if options.dest_fs.is_none() && fs::canonicalize(dest).is_ok_and(|dest| dest.starts_with(&root))
+    {
+        return Err(CopyError::Other(anyhow::anyhow!(
+            "cannot watch '{}', which contains the destination",
+            source.display()
+        )));
+    }
+
+    // Watching from before the first copy, so that changes made during it
+    // are caught.
+    let (sender, events) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender).map_err(watch_error)?;
+    watcher
+        .watch(&root, RecursiveMode::Recursive)
+        .map_err(watch_error)?;
+
+    let first = CopyOptions {
+        recursive: true,
+        ..options.clone()
+    };
+    let mut stats = copy_with_progress(source, dest, &first)?;
+
+    let status = ProgressBar::with_draw_target(None, options.progress.draw_target());
+    status.set_style(
+        ProgressStyle::default_spinner()
+            .template("{spinner} {msg}")
+            .expect("Progress bar template error"),
+    );
+    status.enable_steady_tick(TICK);
+    let mut watched = Watched {
+        root,
+        target,
+        dest_fs: options.dest_fs.clone().unwrap_or_else(|| Arc::new(LocalFs)),
+        options: CopyOptions {
+            recursive: false,
+            verbose: false,
+            progress: ProgressMode::Hidden,
+            ..options.clone()
+        },
+        verbose: options.verbose,
+        status,
+        pending: HashMap::new(),
+        copied: 0,
+        source: source.to_path_buf(),
+    };
+    watched.show(None);
+
+    loop {
+        if options
+            .cancel
+            .as_ref()
+            .is_some_and(|token| token.is_cancelled())
+        {
+            watched.status.finish_and_clear();
+            return Ok(stats);
+        }
+        match events.recv_timeout(TICK) {
+            Ok(Ok(event)) => watched.queue(event),
+            Ok(Err(err)) => return Err(watch_error(err).into()),
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                return Err(io::Error::other("the watch of the source ended").into())
+            }
+        }
+        while let Ok(event) = events.try_recv() {
+            watched.queue(event.map_err(watch_error)?);
+        }
+        watched.copy_settled(&mut stats);
+    }
+}
+
+fn watch_error(err: notify::Error) -> io::Error {
+    io::Error::other(format!("cannot watch the source: {}", err))
+}
+
+/// The state of a watch once the first copy is done.
+struct Watched {
+    /// The source as given, and canonical as events name it.
+    source: PathBuf,
+    root: PathBuf,
+    /// Where `root` is copied to.
+    target: PathBuf,
+    dest_fs: Arc<dyn Filesystem>,
+    /// The options of the copy of each path.
+    options: CopyOptions,
+    verbose: bool,
+    status: ProgressBar,
+    /// Paths with events, by when they had the last.
+    pending: HashMap<PathBuf, Instant>,
+    copied: u64,
+}
+
+impl Watched {
+    fn queue(&mut self, event: Event) {
+        if matches!(event.kind, EventKind::Access(_)) {
+            return;
+        }
+        let now = Instant::now();
+        if event.need_rescan() {
+            // Events were lost: everything is looked at again.
+            self.pending.insert(self.root.clone(), now);
+        }
+        for path in event.paths {
+            if path.starts_with(&self.root) && !self.left_out(&path) {
+                self.pending.insert(path, now);
+            }
+        }
+    }
+
+    /// Whether `path`, under `root`, or a directory it is in is left out of
+    /// the copy by `exclude` or `deselected`.
+    fn left_out(&self, path: &Path) -> bool {
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        let mut dir = self.source.clone();
+        for name in relative {
+            if exclude::left_out(&self.options, &dir, name) {
+                return true;
+            }
+            dir.push(name);
+        }
+        false
+    }
+
+    /// Copies the pending paths that have settled.
+    fn copy_settled(&mut self, stats: &mut CopyStats) {
+        let now = Instant::now();
+        let mut settled: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|(_, &last)| now.duration_since(last) >= SETTLE)
+            .map(|(path, _)| path.clone())
+            .collect();
+        // Directories before what they hold, which their sync covers.
+        settled.sort();
+        let mut synced: Option<PathBuf> = None;
+        for path in settled {
+            self.pending.remove(&path);
+            if synced.as_ref().is_some_and(|dir| path.starts_with(dir)) {
+                continue;
+            }
+            if self.sync(&path, stats) {
+                synced = Some(path);
+            }
+        }
+        self.show(None);
+    }
+
+    /// Brings the copy of `path` up to date: a file is copied, a directory
+    /// has the files that differ from their copies copied. Returns whether
+    /// it was a directory.
+    fn sync(&mut self, path: &Path, stats: &mut CopyStats) -> bool {
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                // Removed since, and its copy with it when deleting.
+                if self.options.delete {
+                    self.remove(path, stats);
+                }
+                return false;
+            }
+            Err(_) => return false,
+        };
+        if !metadata.is_dir() {
+            self.copy(path, stats);
+            return false;
+        }
+        let mut walk = WalkDir::new(path).sort_by_file_name().into_iter();
+        while let Some(entry) = walk.next() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    let at = err.path().unwrap_or(path).to_path_buf();
+                    stats.failures.push(FileError::scan(at, err.into()));
+                    continue;
+                }
+            };
+            if entry.depth() > 0 && self.left_out(entry.path()) {
+                if entry.file_type().is_dir() {
+                    walk.skip_current_dir();
+                }
+                continue;
+            }
+            let dest = self.dest(entry.path());
+            let changed = match (entry.metadata(), self.dest_fs.metadata(&dest)) {
+                (Ok(source), _) if source.is_dir() => {
+                    if let Err(err) = self.dest_fs.create_dir_all(&dest) {
+                        stats
+                            .failures
+                            .push(FileError::copy(entry.path().to_path_buf(), err.into()));
+                    }
+                    false
+                }
+                (Ok(source), Ok(copy)) => {
+                    source.len() != copy.len
+                        || source
+                            .modified()
+                            .ok()
+                            .zip(copy.modified)
+                            .is_some_and(|(source, copy)| source > copy)
+                }
+                _ => true,
+            };
+            if changed {
+                self.copy(entry.path(), stats);
+            }
+        }
+        true
+    }
+
+    /// Where `path`, under `root`, is copied to.
+    fn dest(&self, path: &Path) -> PathBuf {
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        relative.iter().fold(self.target.clone(), |dest, name| {
+            dest.join(self.options.target_name(name))
+        })
+    }
+
+    /// Copies the file `path` to its place under `target`.
+    fn copy(&mut self, path: &Path, stats: &mut CopyStats) {
+        let dest = self.dest(path);
+        let source = self
+            .source
+            .join(path.strip_prefix(&self.root).unwrap_or(path));
+        self.show(Some(&source));
+        let made = match dest.parent() {
+            Some(parent) => self.dest_fs.create_dir_all(parent).map_err(CopyError::from),
+            None => Ok(()),
+        };
+        match made.and_then(|()| copy_with_progress(&source, &dest, &self.options)) {
+            Ok(copied) => {
+                self.copied += copied.files_copied as u64;
+                if self.verbose {
+                    self.print(
+                        format!("'{}' -> '{}'", source.display(), dest.display()),
+                        false,
+                    );
+                }
+                stats.merge(copied);
+            }
+            Err(err) => {
+                self.print(format!("cannot copy '{}': {}", source.display(), err), true);
+                stats.failures.push(FileError::copy(source, err));
+            }
+        }
+    }
+
+    /// Removes the copy of `path`, which the source no longer has, if there
+    /// is one.
+    fn remove(&mut self, path: &Path, stats: &mut CopyStats) {
+        let dest = self.dest(path);
+        let Ok(info) = self.dest_fs.metadata(&dest) else {
+            return;
+        };
+        match self.dest_fs.remove(&dest) {
+            Ok(()) => {
+                match info.is_dir {
+                    true => stats.dirs_deleted += 1,
+                    false => stats.files_deleted += 1,
+                }
+                if self.verbose {
+                    self.print(format!("removed '{}'", dest.display()), false);
+                }
+            }
+            Err(err) => {
+                self.print(format!("cannot remove '{}': {}", dest.display(), err), true);
+                stats.failures.push(FileError::copy(dest, err.into()));
+            }
+        }
+    }
+
+    /// Prints `line` above the status line, or on stdout or stderr, as
+    /// `error` says, without one.
+    fn print(&self, line: String, error: bool) {
+        match (self.status.is_hidden(), error) {
+            (false, _) => self.status.println(line),
+            (true, false) => println!("{}", line),
+            (true, true) => eprintln!("{}", line),
+        }
+    }
+
+    /// Updates the status line, with the path being copied if any.
+    fn show(&self, in_flight: Option<&Path>) {
+        let mut message = format!(
+            "watching '{}': {} queued, {} in flight, {} copied",
+            self.source.display(),
+            self.pending.len(),
+            in_flight.is_some() as u8,
+            self.copied
+        );
+        if let Some(path) = in_flight {
+            message += &format!(" (now '{}')", path.display());
+        }
+        self.status.set_message(message);
+    }
+}
@@ -0,0 +1,125 @@
+//! The outcome of a copy posted to an HTTP endpoint when it ends, for
+//! `--notify-url`, so that long copies can ping a chat channel or a
+//! monitor such as healthchecks.io without a wrapper parsing cpv's
+//! output.
+//!
+//! The body is a JSON object:
+//!
+//! ```json
+//! {"status": "partial", "exit_code": 2, "source": "/data", "dest": "/backup",
+//!  "summary": {"bytes_copied": 1024, ...},
+//!  "failures": [{"path": "/data/locked", "op": "copy", "class": "permission",
+//!                "message": "..."}],
+//!  "error": null, "text": "cpv: copying /data to /backup partial: ..."}
+//! ```
+//!
+//! where `summary` has the fields of `--stats-format json`, and is null
+//! with the `error` of a copy that stopped at one. `text` is a line for
+//! chat webhooks, which show it.
+
+use crate::{CopyError, CopyStats, ExitStatus, Summary};
+use serde_json::{json, Value};
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+/// Where the outcome of a copy is posted.
+#[derive(Debug)]
+pub struct Webhook {
+    url: String,
+    agent: ureq::Agent,
+}
+
+impl Webhook {
+    /// The endpoint at the `http://` or `https://` `url`.
+    pub fn new(url: &str) -> io::Result<Self> {
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid URL '{}' (expected http:// or https://)", url),
+            ));
+        }
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(30))
+            .build();
+        Ok(Self {
+            url: url.to_string(),
+            agent,
+        })
+    }
+
+    /// Posts the outcome of the copy of `source` to `dest` that returned
+    /// `result`.
+    pub fn send(
+        &self,
+        source: &Path,
+        dest: &Path,
+        result: &Result<CopyStats, CopyError>,
+    ) -> io::Result<()> {
+        let body = payload(source, dest, result).to_string();
+        match self
+            .agent
+            .post(&self.url)
+            .set("Content-Type", "application/json")
+            .send_string(&body)
+        {
+            Ok(_) => Ok(()),
+            Err(ureq::Error::Status(status, _)) => Err(io::Error::other(format!(
+                "'{}' answered with status {}",
+                self.url, status
+            ))),
+            Err(ureq::Error::Transport(err)) => Err(io::Error::other(err.to_string())),
+        }
+    }
+}
+
+/// The body posted for `result`.
+pub(crate) fn payload(source: &Path, dest: &Path, result: &Result<CopyStats, CopyError>) -> Value {
+    let status = ExitStatus::of(result);
+    let stats = match result {
+        Ok(stats) => Some(stats),
+        Err(CopyError::Cancelled(stats)) => Some(&**stats),
+        Err(_) => None,
+    };
+    let failures: Vec<_> = stats
+        .iter()
+        .flat_map(|stats| &stats.failures)
+        .map(|failure| {
+            json!({
+                "path": failure.path.to_string_lossy(),
+                "op": failure.op.to_string(),
+                "class": failure.class.to_string(),
+                "message": failure.source.to_string(),
+            })
+        })
+        .collect();
+    let error = result.as_ref().err().filter(|_| stats.is_none());
+    let word = match status {
+        ExitStatus::Completed => "completed",
+        ExitStatus::Failed => "failed",
+        ExitStatus::Partial => "partial",
+        ExitStatus::VerifyFailed => "verify_failed",
+        ExitStatus::Interrupted => "interrupted",
+    };
+    let text = format!(
+        "cpv: copying {} to {} {}: {}",
+        source.display(),
+        dest.display(),
+        word.replace('_', " "),
+        match (stats, error) {
+            (Some(stats), _) => stats.format_summary(),
+            (None, Some(err)) => err.to_string(),
+            (None, None) => String::new(),
+        }
+    );
+    json!({
+        "status": word,
+        "exit_code": status.exit_code(),
+        "source": source.to_string_lossy(),
+        "dest": dest.to_string_lossy(),
+        "summary": stats.map(Summary::new),
+        "failures": failures,
+        "error": error.map(|err| err.to_string()),
+        "text": text,
+    })
+}
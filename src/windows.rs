@@ -0,0 +1,101 @@
+//! Native single-file copies with `CopyFileExW` on Windows.
+
+use crate::limit::RateLimiter;
+use crate::ui::ProgressBar;
+use std::ffi::c_void;
+use std::fs::File;
+use std::io;
+use std::os::windows::ffi::OsStrExt;
+use std::os::windows::fs::OpenOptionsExt;
+use std::path::Path;
+use std::ptr;
+use windows_sys::Win32::Foundation::HANDLE;
+use windows_sys::Win32::Storage::FileSystem::{
+    CopyFileExW, COPYPROGRESSROUTINE_PROGRESS, FILE_FLAG_BACKUP_SEMANTICS, FILE_READ_ATTRIBUTES,
+    FILE_WRITE_ATTRIBUTES, LPPROGRESS_ROUTINE_CALLBACK_REASON, PROGRESS_CONTINUE,
+};
+
+struct Progress<'a> {
+    pb: &'a ProgressBar,
+    limiter: Option<&'a dyn RateLimiter>,
+    reported: u64,
+}
+
+/// Copies `source` to `dest` with `CopyFileExW`, which handles long paths,
+/// alternate data streams and attributes, and drives `pb` from its progress
+/// routine, which also sleeps off any bandwidth limit.
+///
+/// Returns `Ok(None)` if the copy failed, after rewinding the progress bar,
+/// so that the caller can retry with the portable loop; that retry also
+/// surfaces a genuine error with the usual message.
+pub(crate) fn copy_file_ex(
+    source: &Path,
+    dest: &Path,
+    pb: &ProgressBar,
+    limiter: Option<&dyn RateLimiter>,
+) -> io::Result<Option<u64>> {
+    let source = wide(source);
+    let dest = wide(dest);
+    let mut progress = Progress {
+        pb,
+        limiter,
+        reported: 0,
+    };
+
+    // SAFETY: both paths are NUL-terminated and `progress` outlives the
+    // synchronous call that hands it to the callback.
+    let ok = unsafe {
+        CopyFileExW(
+            source.as_ptr(),
+            dest.as_ptr(),
+            Some(on_progress),
+            (&mut progress as *mut Progress).cast::<c_void>(),
+            ptr::null_mut(),
+            0,
+        )
+    };
+
+    if ok == 0 {
+        pb.set_position(pb.position().saturating_sub(progress.reported));
+        return Ok(None);
+    }
+    Ok(Some(progress.reported))
+}
+
+#[allow(clippy::too_many_arguments)]
+unsafe extern "system" fn on_progress(
+    _total_file_size: i64,
+    total_bytes_transferred: i64,
+    _stream_size: i64,
+    _stream_bytes_transferred: i64,
+    _stream_number: u32,
+    _reason: LPPROGRESS_ROUTINE_CALLBACK_REASON,
+    _source: HANDLE,
+    _dest: HANDLE,
+    data: *const c_void,
+) -> COPYPROGRESSROUTINE_PROGRESS {
+    // SAFETY: `data` is the `Progress` passed to CopyFileExW above.
+    let progress = unsafe { &mut *(data as *mut Progress) };
+    let transferred = total_bytes_transferred as u64;
+    let delta = transferred.saturating_sub(progress.reported);
+    progress.pb.inc(delta);
+    if let Some(limiter) = progress.limiter {
+        limiter.acquire(delta);
+    }
+    progress.reported = transferred;
+    PROGRESS_CONTINUE
+}
+
+/// Opens a file copied by `CopyFileExW`, or a created directory, for the
+/// metadata steps. Only attribute access is requested, since the copy may
+/// already carry the source's read-only attribute.
+pub(crate) fn open_attributes(path: &Path) -> io::Result<File> {
+    File::options()
+        .access_mode(FILE_READ_ATTRIBUTES | FILE_WRITE_ATTRIBUTES)
+        .custom_flags(FILE_FLAG_BACKUP_SEMANTICS)
+        .open(path)
+}
+
+pub(crate) fn wide(path: &Path) -> Vec<u16> {
+    path.as_os_str().encode_wide().chain(Some(0)).collect()
+}
@@ -0,0 +1,94 @@
+//! The framing shared by the SFTP client and the cpv protocol of `net`:
+//! packets of a big-endian `u32` length, a type byte and fields, with
+//! strings written as their `u32` length and bytes.
+
+use std::io::{self, Read, Write};
+
+/// The fields of a packet being written.
+#[derive(Debug, Default)]
+pub(crate) struct Packet(pub(crate) Vec<u8>);
+
+impl Packet {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn u8(&mut self, value: u8) -> &mut Self {
+        self.0.push(value);
+        self
+    }
+
+    pub(crate) fn u32(&mut self, value: u32) -> &mut Self {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    pub(crate) fn u64(&mut self, value: u64) -> &mut Self {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    pub(crate) fn string(&mut self, value: &[u8]) -> &mut Self {
+        self.u32(value.len() as u32).bytes(value)
+    }
+
+    pub(crate) fn bytes(&mut self, value: &[u8]) -> &mut Self {
+        self.0.extend_from_slice(value);
+        self
+    }
+}
+
+/// The fields of a packet being read.
+pub(crate) struct Fields<'a>(pub(crate) &'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(invalid("a truncated packet"));
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    pub(crate) fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn string(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+}
+
+/// Reads one packet of at most `max_len` bytes from `reader`, returning
+/// its type and the rest.
+pub(crate) fn read_packet(reader: &mut dyn Read, max_len: usize) -> io::Result<(u8, Vec<u8>)> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len == 0 || len > max_len {
+        return Err(invalid("a packet of invalid length"));
+    }
+    let mut packet = vec![0; len];
+    reader.read_exact(&mut packet)?;
+    let kind = packet.remove(0);
+    Ok((kind, packet))
+}
+
+/// Writes the packet of type `kind` with `body` to `writer`.
+pub(crate) fn write_packet(writer: &mut dyn Write, kind: u8, body: &[u8]) -> io::Result<()> {
+    writer.write_all(&(body.len() as u32 + 1).to_be_bytes())?;
+    writer.write_all(&[kind])?;
+    writer.write_all(body)
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("received {}", what))
+}
@@ -0,0 +1,288 @@
+//! Zip archives as sources of `ArchiveFs`, read through their central
+//! directory: stored and deflated entries, zip64 sizes and offsets, and a
+//! CRC-32 check of every entry as it is read.
+
+use crate::archive::{Data, Entry, Index};
+use chrono::{Local, NaiveDate, TimeZone};
+use flate2::read::DeflateDecoder;
+use flate2::Crc;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Signatures of the records of an archive.
+const END: u32 = 0x0605_4b50;
+const END64: u32 = 0x0606_4b50;
+const END64_LOCATOR: u32 = 0x0706_4b50;
+const CENTRAL: u32 = 0x0201_4b50;
+const LOCAL: u32 = 0x0403_4b50;
+
+/// The size of the end of central directory record, without a comment.
+const END_LEN: usize = 22;
+
+/// Compression methods.
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+
+/// Extra fields.
+const ZIP64: u16 = 0x0001;
+const TIMESTAMP: u16 = 0x5455;
+
+/// Where and how the data of an entry is stored.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Member {
+    /// The offset of its local header.
+    header: u64,
+    method: u16,
+    flags: u16,
+    compressed: u64,
+    crc: u32,
+}
+
+/// Little-endian fields read from the front of a slice.
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(damaged("a truncated zip record"));
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+/// Reads the entries of the zip archive `file` into `index` from its
+/// central directory.
+pub(crate) fn read_index(file: &mut File, index: &mut Index) -> io::Result<()> {
+    let len = file.seek(SeekFrom::End(0))?;
+    let tail_len = len.min((END_LEN + 0xffff) as u64);
+    file.seek(SeekFrom::Start(len - tail_len))?;
+    let mut tail = vec![0; tail_len as usize];
+    file.read_exact(&mut tail)?;
+    let end = (0..(tail.len() + 1).saturating_sub(END_LEN))
+        .rev()
+        .find(|&at| tail[at..at + 4] == END.to_le_bytes())
+        .ok_or_else(|| damaged("no zip central directory"))?;
+    let mut fields = Fields(&tail[end + 10..]);
+    let mut count = u64::from(fields.u16()?);
+    let mut size = u64::from(fields.u32()?);
+    let mut start = u64::from(fields.u32()?);
+    if count == 0xffff || size == 0xffff_ffff || start == 0xffff_ffff {
+        let locator = end
+            .checked_sub(20)
+            .filter(|&at| tail[at..at + 4] == END64_LOCATOR.to_le_bytes())
+            .ok_or_else(|| damaged("no zip64 central directory"))?;
+        let offset = Fields(&tail[locator + 8..]).u64()?;
+        let mut record = [0; 56];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut record)?;
+        let mut fields = Fields(&record);
+        if fields.u32()? != END64 {
+            return Err(damaged("no zip64 central directory"));
+        }
+        fields.take(28)?;
+        count = fields.u64()?;
+        size = fields.u64()?;
+        start = fields.u64()?;
+    }
+    if start.saturating_add(size) > len {
+        return Err(damaged("a damaged zip central directory"));
+    }
+
+    let mut directory = vec![0; size as usize];
+    file.seek(SeekFrom::Start(start))?;
+    file.read_exact(&mut directory)?;
+    let mut fields = Fields(&directory);
+    for _ in 0..count {
+        if fields.u32()? != CENTRAL {
+            return Err(damaged("a damaged zip central directory"));
+        }
+        let made_by = fields.u16()?;
+        fields.u16()?;
+        let flags = fields.u16()?;
+        let method = fields.u16()?;
+        let time = fields.u16()?;
+        let date = fields.u16()?;
+        let crc = fields.u32()?;
+        let mut compressed = u64::from(fields.u32()?);
+        let mut len = u64::from(fields.u32()?);
+        let name_len = fields.u16()?;
+        let extra_len = fields.u16()?;
+        let comment_len = fields.u16()?;
+        fields.take(4)?;
+        let external = fields.u32()?;
+        let mut header = u64::from(fields.u32()?);
+        let name = fields.take(name_len.into())?;
+        let mut extra = Fields(fields.take(extra_len.into())?);
+        fields.take(comment_len.into())?;
+
+        let mut modified = dos_time(date, time);
+        while extra.0.len() >= 4 {
+            let id = extra.u16()?;
+            let data_len = extra.u16()?;
+            let mut data = Fields(extra.take(data_len.into())?);
+            match id {
+                ZIP64 => {
+                    if len == 0xffff_ffff {
+                        len = data.u64()?;
+                    }
+                    if compressed == 0xffff_ffff {
+                        compressed = data.u64()?;
+                    }
+                    if header == 0xffff_ffff {
+                        header = data.u64()?;
+                    }
+                }
+                // Flags, with the first bit for a modification time.
+                TIMESTAMP if data.u8()? & 1 != 0 => {
+                    let seconds = data.u32()? as i32;
+                    modified = u64::try_from(seconds)
+                        .ok()
+                        .map(|seconds| UNIX_EPOCH + Duration::from_secs(seconds));
+                }
+                _ => {}
+            }
+        }
+
+        // The upper half of the attributes is a Unix mode, for archives
+        // made on Unix.
+        let unix = (made_by >> 8 == 3).then_some(external >> 16);
+        let mode = unix.filter(|&mode| mode != 0).map(|mode| mode & 0o7777);
+        match unix.map(|mode| mode & 0o170000) {
+            Some(0o120000) => {}
+            Some(0o040000) => index.add(name, Entry::dir(modified, mode)),
+            _ if name.ends_with(b"/") => index.add(name, Entry::dir(modified, mode)),
+            _ => {
+                let member = Member {
+                    header,
+                    method,
+                    flags,
+                    compressed,
+                    crc,
+                };
+                index.add(name, Entry::file(len, modified, mode, Data::Zip(member)));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The data of `member`, `path` of `len` bytes in the archive `file`.
+pub(crate) fn open_entry(
+    mut file: File,
+    path: &Path,
+    member: &Member,
+    len: u64,
+) -> io::Result<Box<dyn Read + Send>> {
+    if member.flags & 1 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("'{}' is encrypted", path.display()),
+        ));
+    }
+    let mut header = [0; 30];
+    file.seek(SeekFrom::Start(member.header))?;
+    file.read_exact(&mut header)?;
+    let mut fields = Fields(&header);
+    if fields.u32()? != LOCAL {
+        return Err(damaged("a damaged zip local header"));
+    }
+    fields.take(22)?;
+    let skip = i64::from(fields.u16()?) + i64::from(fields.u16()?);
+    file.seek(SeekFrom::Current(skip))?;
+    let raw = BufReader::new(file).take(member.compressed);
+    let data: Box<dyn Read + Send> = match member.method {
+        STORED => Box::new(raw),
+        DEFLATED => Box::new(DeflateDecoder::new(raw)),
+        method => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "'{}' is compressed with method {}, which cpv cannot read",
+                    path.display(),
+                    method
+                ),
+            ))
+        }
+    };
+    Ok(Box::new(Checked {
+        data,
+        crc: Crc::new(),
+        read: 0,
+        len,
+        expected: member.crc,
+        path: path.to_path_buf(),
+    }))
+}
+
+/// The data of an entry, checked against its size and CRC-32 once read.
+struct Checked {
+    data: Box<dyn Read + Send>,
+    crc: Crc,
+    read: u64,
+    len: u64,
+    expected: u32,
+    path: PathBuf,
+}
+
+impl Read for Checked {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.data.read(buf)?;
+        self.crc.update(&buf[..n]);
+        self.read += n as u64;
+        let ended = n == 0 && !buf.is_empty();
+        if self.read > self.len
+            || (ended && (self.read < self.len || self.crc.sum() != self.expected))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "'{}' is damaged: its size or CRC-32 does not match the archive's",
+                    self.path.display()
+                ),
+            ));
+        }
+        Ok(n)
+    }
+}
+
+/// An MS-DOS date and time, in local time.
+fn dos_time(date: u16, time: u16) -> Option<SystemTime> {
+    let day = NaiveDate::from_ymd_opt(
+        1980 + i32::from(date >> 9),
+        u32::from(date >> 5 & 0xf),
+        u32::from(date & 0x1f),
+    )?;
+    let at = day.and_hms_opt(
+        u32::from(time >> 11),
+        u32::from(time >> 5 & 0x3f),
+        u32::from(time & 0x1f) * 2,
+    )?;
+    Local
+        .from_local_datetime(&at)
+        .earliest()
+        .map(SystemTime::from)
+}
+
+fn damaged(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what)
+}
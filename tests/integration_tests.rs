@@ -1,4 +1,4 @@
-use cpv::{copy_with_progress, CopyOptions};
+use cpv::{copy_with_progress, CopyOptions, Parallelism};
 use std::fs::{self, File};
 use std::io::Write;
 use std::path::PathBuf;
@@ -17,12 +17,11 @@ fn test_copy_to_nonexistent_directory() {
     let source = create_test_file(&temp, "source.txt", b"test");
     let dest = temp.path().join("nonexistent").join("dest.txt");
 
-    let options = CopyOptions {
-        preserve_attrs: false,
-        force: false,
-        verbose: false,
-        recursive: false,
-    };
+    let options = CopyOptions::builder()
+        .preserve_attrs(false)
+        .verbose(false)
+        .recursive(false)
+        .build();
 
     let result = copy_with_progress(&source, &dest, &options);
     assert!(result.is_err());
@@ -34,12 +33,11 @@ fn test_copy_large_file() {
     let source = create_test_file(&temp, "large.bin", &vec![0u8; 1024 * 1024]); // 1MB file
     let dest = temp.path().join("large_copy.bin");
 
-    let options = CopyOptions {
-        preserve_attrs: false,
-        force: false,
-        verbose: true,
-        recursive: false,
-    };
+    let options = CopyOptions::builder()
+        .preserve_attrs(false)
+        .verbose(true)
+        .recursive(false)
+        .build();
 
     let result = copy_with_progress(&source, &dest, &options);
     assert!(result.is_ok());
@@ -58,12 +56,11 @@ fn test_nested_directory_copy() {
 
     let dest_dir = temp.path().join("dest");
 
-    let options = CopyOptions {
-        preserve_attrs: false,
-        force: false,
-        verbose: true,
-        recursive: true,
-    };
+    let options = CopyOptions::builder()
+        .preserve_attrs(false)
+        .verbose(true)
+        .recursive(true)
+        .build();
 
     let result = copy_with_progress(&source_dir, &dest_dir, &options);
     assert!(result.is_ok());
@@ -72,3 +69,78 @@ fn test_nested_directory_copy() {
     assert!(dest_dir.join("file1.txt").exists());
     assert!(dest_dir.join("subdir/file2.txt").exists());
 }
+
+#[test]
+fn test_parallel_directory_copy() {
+    let temp = TempDir::new().unwrap();
+    let source_dir = temp.path().join("source");
+    fs::create_dir(&source_dir).unwrap();
+    for dir in ["a", "b", "a/deep"] {
+        fs::create_dir(source_dir.join(dir)).unwrap();
+    }
+    let mut total = 0;
+    for i in 0..30 {
+        let dir = ["a", "b", "a/deep"][i % 3];
+        let content = vec![b'x'; i * 100];
+        total += content.len() as u64;
+        create_test_file(&temp, &format!("source/{}/file{}.txt", dir, i), &content);
+    }
+
+    let dest_dir = temp.path().join("dest");
+
+    let options = CopyOptions::builder()
+        .recursive(true)
+        .parallelism(Parallelism::Exact(4))
+        .build();
+
+    let stats = copy_with_progress(&source_dir, &dest_dir, &options).unwrap();
+    assert_eq!(stats.files_copied, 30);
+    assert_eq!(stats.dirs_created, 4);
+    assert_eq!(stats.bytes_copied, total);
+    for i in 0..30 {
+        let dir = ["a", "b", "a/deep"][i % 3];
+        let copied = fs::read(dest_dir.join(format!("{}/file{}.txt", dir, i))).unwrap();
+        assert_eq!(copied.len(), i * 100);
+    }
+}
+
+#[test]
+fn test_chunked_large_file_copy() {
+    let temp = TempDir::new().unwrap();
+    // Several ranges plus a partial one at the end.
+    let content: Vec<u8> = (0..(40 * 1024 * 1024 + 12345))
+        .map(|i: u32| (i % 253) as u8)
+        .collect();
+    let source = create_test_file(&temp, "huge.bin", &content);
+    let dest = temp.path().join("huge_copy.bin");
+
+    let options = CopyOptions::builder()
+        .chunked_threads(4)
+        .reflink(cpv::ReflinkMode::Never)
+        .build();
+
+    let stats = copy_with_progress(&source, &dest, &options).unwrap();
+    assert_eq!(stats.bytes_copied, content.len() as u64);
+    assert!(fs::read(&dest).unwrap() == content);
+}
+
+#[test]
+fn test_options_builder() {
+    let temp = TempDir::new().unwrap();
+    let source = create_test_file(&temp, "source.txt", b"built");
+    let dest = temp.path().join("dest.txt");
+    let manifest = temp.path().join("manifest.txt");
+
+    // Optional fields take a value or an Option.
+    let options = CopyOptions::builder()
+        .manifest(manifest.clone())
+        .buffer_size(None)
+        .retries(2)
+        .build();
+    assert_eq!(options.retries, 2);
+    assert_eq!(options.buffer_size, None);
+
+    copy_with_progress(&source, &dest, &options).unwrap();
+    assert_eq!(fs::read(&dest).unwrap(), b"built");
+    assert!(manifest.exists());
+}